@@ -0,0 +1,352 @@
+use crate::config::CrossingConfig;
+use crate::heightmap::Heightmap;
+use crate::utils::distance_transform;
+use crate::water::RiverLine;
+use rayon::prelude::*;
+
+// slope in degrees for every pixel, from central differences with the heights scaled to
+// meters. border pixels use one sided differences.
+pub fn compute_slope_map(heightmap: &Heightmap, cell_size: f64, max_elevation: f32) -> Heightmap {
+    let (width, height) = (heightmap.width(), heightmap.height());
+    let w = width as usize;
+    let h = height as usize;
+    let heightmap = heightmap.data();
+    let cell_size = cell_size.max(0.001) as f32;
+    let mut slope = vec![0.0f32; w * h];
+
+    slope.par_chunks_mut(w).enumerate().for_each(|(y, row)| {
+        let (y0, y1) = (y.saturating_sub(1), (y + 1).min(h - 1));
+        for (x, value) in row.iter_mut().enumerate() {
+            let (x0, x1) = (x.saturating_sub(1), (x + 1).min(w - 1));
+            let dx = if x1 > x0 {
+                (heightmap[y * w + x1] - heightmap[y * w + x0]) / ((x1 - x0) as f32 * cell_size)
+            } else {
+                0.0
+            };
+            let dy = if y1 > y0 {
+                (heightmap[y1 * w + x] - heightmap[y0 * w + x]) / ((y1 - y0) as f32 * cell_size)
+            } else {
+                0.0
+            };
+            let gradient = (dx * dx + dy * dy).sqrt() * max_elevation;
+            *value = gradient.atan().to_degrees();
+        }
+    });
+    Heightmap::from_vec(slope, width, height)
+}
+
+// number of buckets the height histogram splits [0,1] into
+pub const HISTOGRAM_BUCKETS: usize = 256;
+// larger maps are sampled with a stride, the shape of the distribution barely changes
+const HISTOGRAM_MAX_SAMPLES: usize = 1 << 20;
+
+// share of the heights that falls into each of `buckets` equal steps of [0,1], so
+// histograms of maps with different sizes can be compared directly
+pub fn height_histogram(heightmap: &Heightmap, buckets: usize) -> Vec<f32> {
+    let stride = heightmap.len().div_ceil(HISTOGRAM_MAX_SAMPLES).max(1);
+    let mut counts = vec![0u32; buckets];
+    let mut samples = 0;
+    for &value in heightmap.iter().step_by(stride).filter(|v| v.is_finite()) {
+        let bucket = (value.clamp(0.0, 1.0) * buckets as f32) as usize;
+        counts[bucket.min(buckets - 1)] += 1;
+        samples += 1;
+    }
+    let samples = samples.max(1) as f32;
+    counts.into_iter().map(|c| c as f32 / samples).collect()
+}
+
+// connected patch of gentle land, usable as a building site. coordinates are in pixels.
+#[derive(Debug, Clone, Copy)]
+pub struct FlatSite {
+    pub centroid: (f32, f32),
+    pub area: u32,
+    // inclusive corners of the bounding box
+    pub min: (u32, u32),
+    pub max: (u32, u32),
+}
+
+// 4-connected regions above sea level where the slope (in degrees, see compute_slope_map)
+// stays at or below `max_slope`, with at least `min_area` pixels. largest sites first.
+pub fn find_flat_sites(
+    heightmap: &Heightmap,
+    slope: &Heightmap,
+    sea_level: f32,
+    max_slope: f32,
+    min_area: u32,
+) -> Vec<FlatSite> {
+    let (w, h) = (heightmap.width() as usize, heightmap.height() as usize);
+    let mut open: Vec<bool> = heightmap
+        .iter()
+        .zip(slope.iter())
+        .map(|(&height, &s)| height >= sea_level && s <= max_slope)
+        .collect();
+
+    let mut sites = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..open.len() {
+        if !open[start] {
+            continue;
+        }
+        // flood fill, every pixel is visited once over all sites
+        open[start] = false;
+        stack.push(start);
+        let (mut area, mut sum_x, mut sum_y) = (0u32, 0.0f64, 0.0f64);
+        let (mut min, mut max) = ((usize::MAX, usize::MAX), (0, 0));
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i % w, i / w);
+            area += 1;
+            sum_x += x as f64;
+            sum_y += y as f64;
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+            let neighbors = [
+                (x > 0).then(|| i - 1),
+                (x + 1 < w).then(|| i + 1),
+                (y > 0).then(|| i - w),
+                (y + 1 < h).then(|| i + w),
+            ];
+            for n in neighbors.into_iter().flatten() {
+                if open[n] {
+                    open[n] = false;
+                    stack.push(n);
+                }
+            }
+        }
+        if area >= min_area.max(1) {
+            sites.push(FlatSite {
+                centroid: ((sum_x / area as f64) as f32, (sum_y / area as f64) as f32),
+                area,
+                min: (min.0 as u32, min.1 as u32),
+                max: (max.0 as u32, max.1 as u32),
+            });
+        }
+    }
+    sites.sort_by_key(|site| std::cmp::Reverse(site.area));
+    sites
+}
+
+// cells of the open sea: those below `sea_level` that are 4-connected to the map border, or
+// to `seed` if one is given. cells below the sea level the flood doesn't reach are inland
+// depressions, not sea.
+pub fn compute_ocean_mask(
+    heightmap: &Heightmap,
+    sea_level: f32,
+    seed: Option<(u32, u32)>,
+) -> Vec<bool> {
+    let (w, h) = (heightmap.width() as usize, heightmap.height() as usize);
+    let data = heightmap.data();
+    let mut ocean = vec![false; data.len()];
+    if data.is_empty() {
+        return ocean;
+    }
+    let mut stack: Vec<usize> = match seed {
+        Some((x, y)) => vec![(y as usize).min(h - 1) * w + (x as usize).min(w - 1)],
+        None => (0..w)
+            .flat_map(|x| [x, (h - 1) * w + x])
+            .chain((0..h).flat_map(|y| [y * w, y * w + w - 1]))
+            .collect(),
+    };
+    while let Some(i) = stack.pop() {
+        if ocean[i] || data[i] >= sea_level {
+            continue;
+        }
+        ocean[i] = true;
+        let (x, y) = (i % w, i / w);
+        let neighbors = [
+            (x > 0).then(|| i - 1),
+            (x + 1 < w).then(|| i + 1),
+            (y > 0).then(|| i - w),
+            (y + 1 < h).then(|| i + w),
+        ];
+        stack.extend(neighbors.into_iter().flatten());
+    }
+    ocean
+}
+
+// place along a river where a road can cross it, coordinates are in pixels. the width is in
+// meters and the bank slope is the steeper of the two banks in degrees.
+#[derive(Debug, Clone, Copy)]
+pub struct Crossing {
+    pub position: (u32, u32),
+    pub width: f32,
+    pub bank_slope: f32,
+}
+
+// points of the rivers outside of lakes that are narrow enough and have gentle banks on both
+// sides, see CrossingConfig. the banks are sampled a little past the edge of the water, square
+// to the direction the river runs in. the narrowest and flattest crossings come first, and a
+// crossing is dropped when a better one is closer than `min_spacing`.
+pub fn find_river_crossings(
+    rivers: &[RiverLine],
+    lake_map: &[f32],
+    slope: &Heightmap,
+    cell_size: f64,
+    config: &CrossingConfig,
+) -> Vec<Crossing> {
+    let (w, h) = (slope.width() as i64, slope.height() as i64);
+    let bank = |x: f32, y: f32| {
+        let (x, y) = (x.round() as i64, y.round() as i64);
+        (x >= 0 && y >= 0 && x < w && y < h).then(|| (y * w + x) as usize)
+    };
+    let max_width = config.max_width.max(1e-3);
+    let max_bank_slope = config.max_bank_slope.max(1e-3);
+
+    let mut candidates = Vec::new();
+    for river in rivers {
+        for stretch in river.points.windows(3) {
+            let point = &stretch[1];
+            let (x, y) = point.position;
+            let width = point.width * cell_size as f32;
+            if lake_map[(y as i64 * w + x as i64) as usize] > 0.0 || width > max_width {
+                continue;
+            }
+            let (dx, dy) = (
+                stretch[2].position.0 as f32 - stretch[0].position.0 as f32,
+                stretch[2].position.1 as f32 - stretch[0].position.1 as f32,
+            );
+            let length = dx.hypot(dy);
+            if length == 0.0 {
+                continue;
+            }
+            // square to the river, two cells past the edge of the water
+            let reach = point.width * 0.5 + 2.0;
+            let (nx, ny) = (-dy / length * reach, dx / length * reach);
+            let (x, y) = (x as f32, y as f32);
+            let (Some(left), Some(right)) = (bank(x + nx, y + ny), bank(x - nx, y - ny)) else {
+                continue;
+            };
+            if lake_map[left] > 0.0 || lake_map[right] > 0.0 {
+                continue;
+            }
+            let bank_slope = slope.data()[left].max(slope.data()[right]);
+            if bank_slope > max_bank_slope {
+                continue;
+            }
+            let score = width / max_width + bank_slope / max_bank_slope;
+            candidates.push((
+                score,
+                Crossing {
+                    position: point.position,
+                    width,
+                    bank_slope,
+                },
+            ));
+        }
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let spacing = config.min_spacing as f32;
+    let mut crossings: Vec<Crossing> = Vec::new();
+    for (_, crossing) in candidates {
+        let (x, y) = crossing.position;
+        let crowded = crossings.iter().any(|other| {
+            let (ox, oy) = other.position;
+            (x as f32 - ox as f32).hypot(y as f32 - oy as f32) < spacing
+        });
+        if !crowded {
+            crossings.push(crossing);
+        }
+    }
+    crossings
+}
+
+// summary of the lakes and rivers of a water run
+pub struct WaterStats {
+    pub lakes: usize,
+    // areas in km²
+    pub lake_area: f64,
+    pub largest_lake: f64,
+    // in km
+    pub river_length: f64,
+    pub rivers_to_sea: usize,
+    // share of the dry land within the given distance of a lake or river, from 0 to 1
+    pub land_near_water: f64,
+}
+
+// lakes are the 4-connected regions of lake cells. a river reaches the sea when it doesn't
+// join another one and ends in or next to the open `ocean`. `near_water` is in meters.
+pub fn water_statistics(
+    lake_map: &[f32],
+    river_depths: &[f32],
+    rivers: &[RiverLine],
+    ocean: &[bool],
+    (w, h): (u32, u32),
+    cell_size: f64,
+    near_water: f64,
+) -> WaterStats {
+    let (width, height) = (w as usize, h as usize);
+    let neighbors = |i: usize| {
+        let (x, y) = (i % width, i / width);
+        [
+            (x > 0).then(|| i - 1),
+            (x + 1 < width).then(|| i + 1),
+            (y > 0).then(|| i - width),
+            (y + 1 < height).then(|| i + width),
+        ]
+        .into_iter()
+        .flatten()
+    };
+    let cell_area = cell_size * cell_size / 1e6;
+
+    let mut seen = vec![false; lake_map.len()];
+    let mut lake_sizes = Vec::new();
+    for start in 0..lake_map.len() {
+        if seen[start] || lake_map[start] <= 0.0 {
+            continue;
+        }
+        seen[start] = true;
+        let mut stack = vec![start];
+        let mut size = 0;
+        while let Some(i) = stack.pop() {
+            size += 1;
+            for n in neighbors(i) {
+                if !seen[n] && lake_map[n] > 0.0 {
+                    seen[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+        lake_sizes.push(size);
+    }
+
+    let river_length: f64 = rivers
+        .iter()
+        .flat_map(|river| river.points.windows(2))
+        .map(|pair| {
+            let (a, b) = (pair[0].position, pair[1].position);
+            (a.0 as f64 - b.0 as f64).hypot(a.1 as f64 - b.1 as f64)
+        })
+        .sum();
+    let rivers_to_sea = rivers
+        .iter()
+        .filter(|river| river.joins.is_none())
+        .filter_map(|river| river.points.last())
+        .filter(|end| {
+            let i = end.position.1 as usize * width + end.position.0 as usize;
+            ocean[i] || neighbors(i).any(|n| ocean[n])
+        })
+        .count();
+
+    let water: Vec<bool> = lake_map
+        .iter()
+        .zip(river_depths)
+        .map(|(&lake, &river)| lake > 0.0 || river > 0.0)
+        .collect();
+    let distance = distance_transform(&water, w, h);
+    let land: Vec<usize> = (0..water.len())
+        .filter(|&i| !water[i] && !ocean[i])
+        .collect();
+    let near = land
+        .iter()
+        .filter(|&&i| distance[i] as f64 * cell_size <= near_water)
+        .count();
+
+    WaterStats {
+        lakes: lake_sizes.len(),
+        lake_area: lake_sizes.iter().sum::<usize>() as f64 * cell_area,
+        largest_lake: lake_sizes.iter().max().copied().unwrap_or(0) as f64 * cell_area,
+        river_length: river_length * cell_size / 1000.0,
+        rivers_to_sea,
+        land_near_water: near as f64 / land.len().max(1) as f64,
+    }
+}
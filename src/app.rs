@@ -1,9 +1,65 @@
-use crate::biomes::generate_biome_map;
-use crate::config::{BiomeConfig, MapConfig, RefinerConfig, WaterConfig};
-use crate::{preview::get_color_for_height, refiner::refine_heightmap, terrain::generate_map};
-use crate::utils::export_heightmap_to_asc;
+use crate::analysis::{
+    Crossing, FlatSite, HISTOGRAM_BUCKETS, WaterStats, compute_ocean_mask, compute_slope_map,
+    find_flat_sites, find_river_crossings, height_histogram, water_statistics,
+};
+use crate::biomes::{
+    Biome, BiomeMap, BiomeTerrain, default_biome_rules, generate_biome_map, load_biome_rules,
+    save_biome_rules,
+};
+use crate::config::{
+    BiomeConfig, BiomeRule, Brush, BrushFalloff, BrushTool, Canal, Crop, CrossingConfig,
+    DEFAULT_MAX_ELEVATION, DeadEndRemedy, ErosionConfig, FlatSiteConfig, IslandShape, MapConfig,
+    NoiseAlgorithm, NoiseLayer, NoiseMode, NoiseStack, Normalization, OverlayMode, PaintBlend,
+    RefineMask, RefineOp, RefinerConfig, Region, RiverMode, RiverProfile, SmoothingMode, Stamp,
+    StampBlend, StampKind, Symmetry, WaterConfig, default_refine_pipeline,
+};
+use crate::curve::{CURVE_PRESETS, build_curve_lut, sample_curve_lut, sanitize_curve_points};
+use crate::dem::{is_dem_file, load_dem};
+use crate::erosion::{hydraulic_erosion, thermal_erosion};
+use crate::heightmap::Heightmap;
+use crate::history::{History, MAX_HISTORY_DEPTH};
+use crate::jobs::{Job, Progress, take_finished};
+use crate::sculpt::{flatten, raise_lower, smooth};
+use crate::stamps::stamp_feature;
+use crate::{
+    preview::{
+        build_preview, build_preview_patch, get_color_for_height, get_color_for_slope,
+        get_color_for_wetness, preview_size,
+    },
+    refiner::{CoastDistance, Refined, refine_heightmap},
+    terrain::{
+        GeneratedMap, apply_island_falloff, generate_heightmap, generate_map, terrain_color,
+        uses_previous_map,
+    },
+    water::{
+        LakeOutlet, NoLake, RiverLine, WaterContext, WaterMap, Waterfall, canal_path,
+        composite_water, dig_canal, generate_water_map, river_line,
+    },
+};
+use crate::utils::{
+    ResampleFilter, crop_grid, export_biome_ids_to_png, export_depth_mask_to_png,
+    export_heightmap_to_asc, export_rivers_to_csv, export_rivers_to_geojson,
+    export_water_masks_to_png, export_water_surface_to_asc, export_waterfalls_to_csv,
+    export_wetness_to_png, resample_heightmap, resample_nearest,
+};
 use eframe::egui;
 use image::{ImageBuffer, Rgba};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// largest side of the live preview and the pause after the last edit before it regenerates
+const LIVE_PREVIEW_SIZE: u32 = 256;
+const LIVE_PREVIEW_DEBOUNCE: f64 = 0.25;
+// largest side of the copy the refinement preview works on
+const REFINE_PREVIEW_SIZE: u32 = 512;
+// largest side of the copy that is recolored while the sea level slider is dragged
+const SEA_LEVEL_PREVIEW_SIZE: u32 = 512;
+// distance in meters within which land counts as near water in the water statistics
+const DEFAULT_NEAR_WATER_DISTANCE: f64 = 500.0;
+
+// open sea mask with the sea level and ocean seed it was built for
+type OceanMask = (f64, Option<(u32, u32)>, Vec<bool>);
 
 enum GenerationStep {
     Terrain,
@@ -29,6 +85,174 @@ impl Clone for GenerationStep {
 
 impl Copy for GenerationStep {}
 
+#[derive(PartialEq, Clone, Copy)]
+enum PreviewMode {
+    Height,
+    Slope,
+    Wetness,
+}
+
+// what a click on the preview places in the water step
+#[derive(PartialEq, Clone, Copy)]
+enum WaterPlacement {
+    RiverSource,
+    Lake,
+    // adds a point to the canal being drawn
+    Canal,
+}
+
+// label, progress bar and cancel button of a running job
+fn job_status<T: Send + 'static>(ui: &mut egui::Ui, job: &Job<T>) {
+    ui.label(format!("{}...", job.label));
+    ui.horizontal(|ui| {
+        ui.add(egui::ProgressBar::new(job.progress.fraction()).show_percentage());
+        if ui.button("Cancel").clicked() {
+            job.progress.cancel();
+        }
+    });
+}
+
+// asks where to write an export, `name` is the suggested file name
+fn save_dialog(title: &str, filter: &str, extension: &str, name: &str) -> Option<PathBuf> {
+    rfd::FileDialog::new()
+        .add_filter(filter, &[extension])
+        .set_title(title)
+        .set_file_name(name)
+        .save_file()
+}
+
+// photoshop style curve editor. drag to move a point, click to add one and right click
+// to remove one. `dragging` remembers the point under the pointer between frames.
+fn curve_editor(ui: &mut egui::Ui, points: &mut Vec<(f32, f32)>, dragging: &mut Option<usize>) {
+    const PICK_RADIUS: f32 = 8.0;
+    let side = ui.available_width().clamp(64.0, 256.0);
+    let (response, painter) =
+        ui.allocate_painter(egui::vec2(side, side), egui::Sense::click_and_drag());
+    let rect = response.rect;
+    let to_screen = |(x, y): (f32, f32)| {
+        egui::pos2(
+            rect.left() + x * rect.width(),
+            rect.bottom() - y * rect.height(),
+        )
+    };
+    let from_screen = |pos: egui::Pos2| {
+        (
+            ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0),
+            ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0),
+        )
+    };
+    let pick = |points: &[(f32, f32)], pos: egui::Pos2| {
+        points
+            .iter()
+            .map(|&p| to_screen(p).distance(pos))
+            .enumerate()
+            .filter(|&(_, d)| d < PICK_RADIUS)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    };
+
+    if let Some(pos) = response.interact_pointer_pos() {
+        if response.drag_started() {
+            *dragging = pick(points, pos).or_else(|| {
+                points.push(from_screen(pos));
+                Some(points.len() - 1)
+            });
+        } else if response.secondary_clicked() {
+            // the curve needs at least two points
+            if let Some(i) = pick(points, pos).filter(|_| points.len() > 2) {
+                points.remove(i);
+            }
+        } else if response.clicked() && pick(points, pos).is_none() {
+            points.push(from_screen(pos));
+        }
+        if let Some(i) = dragging.filter(|&i| i < points.len()) {
+            points[i] = from_screen(pos);
+        }
+    }
+    if response.drag_released() {
+        *dragging = None;
+    }
+    if dragging.is_none() {
+        *points = sanitize_curve_points(points);
+    }
+
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 0.0, visuals.extreme_bg_color);
+    let grid = egui::Stroke::new(1.0, visuals.weak_text_color());
+    for i in 1..4 {
+        let t = i as f32 / 4.0;
+        painter.line_segment([to_screen((t, 0.0)), to_screen((t, 1.0))], grid);
+        painter.line_segment([to_screen((0.0, t)), to_screen((1.0, t))], grid);
+    }
+    let lut = build_curve_lut(points);
+    let line: Vec<egui::Pos2> = (0..=128)
+        .map(|i| {
+            let x = i as f32 / 128.0;
+            to_screen((x, sample_curve_lut(&lut, x)))
+        })
+        .collect();
+    painter.add(egui::Shape::line(
+        line,
+        egui::Stroke::new(2.0, visuals.strong_text_color()),
+    ));
+    for &point in points.iter() {
+        painter.circle_filled(to_screen(point), 4.0, visuals.selection.bg_fill);
+    }
+}
+
+fn refine_op_name(op: RefineOp) -> &'static str {
+    match op {
+        RefineOp::Offset => "Height Offset",
+        RefineOp::Coeff => "Height Coefficient",
+        RefineOp::Exponent => "Height Exponent",
+        RefineOp::Smooth => "Smoothing",
+        RefineOp::Curve => "Height Curve",
+        RefineOp::Clip => "Clip",
+        RefineOp::Paint => "Paint Overlay",
+        RefineOp::Noise => "Detail Noise",
+        RefineOp::Terrace => "Terraces",
+        RefineOp::Normalize => "Normalization",
+        RefineOp::SlopeLimit => "Slope Limit",
+    }
+}
+
+fn biome_combo(ui: &mut egui::Ui, id: &str, biome: &mut Biome) {
+    egui::ComboBox::from_id_source(id)
+        .selected_text(biome.name())
+        .show_ui(ui, |ui| {
+            for option in Biome::ALL {
+                ui.selectable_value(biome, option, option.name());
+            }
+        });
+}
+
+// returns whether another algorithm was picked
+fn noise_algorithm_combo(ui: &mut egui::Ui, id: &str, algorithm: &mut NoiseAlgorithm) -> bool {
+    let mut changed = false;
+    egui::ComboBox::from_id_source(id)
+        .selected_text(match algorithm {
+            NoiseAlgorithm::Perlin => "Perlin",
+            NoiseAlgorithm::OpenSimplex => "OpenSimplex",
+            NoiseAlgorithm::Value => "Value",
+            NoiseAlgorithm::Worley => "Worley",
+        })
+        .show_ui(ui, |ui| {
+            changed |= ui
+                .selectable_value(algorithm, NoiseAlgorithm::Perlin, "Perlin")
+                .changed();
+            changed |= ui
+                .selectable_value(algorithm, NoiseAlgorithm::OpenSimplex, "OpenSimplex")
+                .changed();
+            changed |= ui
+                .selectable_value(algorithm, NoiseAlgorithm::Value, "Value")
+                .changed();
+            changed |= ui
+                .selectable_value(algorithm, NoiseAlgorithm::Worley, "Worley")
+                .changed();
+        });
+    changed
+}
+
 /// The main application structure holding the configuration and preview texture.
 pub struct DayZMapApp {
     current_step: GenerationStep,
@@ -36,10 +260,116 @@ pub struct DayZMapApp {
     refiner_config: RefinerConfig,
     biome_config: BiomeConfig,
     water_config: WaterConfig,
+    erosion_config: ErosionConfig,
+    erosion_job: Option<Job<Option<Heightmap>>>,
+    generate_job: Option<Job<Option<GeneratedMap>>>,
+    biome_job: Option<Job<Option<BiomeMap>>>,
+    water_job: Option<Job<Option<WaterMap>>>,
+    live_preview: bool,
+    live_job: Option<Job<Option<GeneratedMap>>>,
+    live_preview_texture: Option<egui::TextureHandle>,
+    // downsampled heightmap and the preview made from it while the sea level is dragged, the
+    // full preview is rebuilt once the slider is let go
+    sea_level_source: Option<Heightmap>,
+    sea_level_texture: Option<egui::TextureHandle>,
+    // when the terrain settings last changed, used to debounce slider drags
+    live_changed_at: Option<f64>,
+    stamp: Stamp,
+    region: Region,
+    region_job: Option<Job<Option<(Heightmap, Region)>>>,
+    refine_job: Option<Job<Option<Refined>>>,
+    // heightmap the refinement is applied to. set by the first Apply so applying again starts
+    // over from it instead of refining the result, cleared by Bake or any other edit.
+    refine_base: Option<Heightmap>,
+    // distance to the coast of `refine_base`, so applying again skips working it out
+    refine_coast: Option<Arc<CoastDistance>>,
+    crop: Crop,
     preview_texture: Option<egui::TextureHandle>,
     preview_image: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
-    heightmap_data: Option<Vec<f32>>,
+    heightmap_data: Option<Heightmap>,
+    // slope in degrees, recomputed whenever the heightmap changes
+    slope_map: Option<Heightmap>,
+    // sea cells connected to the border or the ocean seed, built lazily for the sea level
+    // and seed it is stored with
+    ocean_mask: Option<OceanMask>,
+    preview_mode: PreviewMode,
+    // height distribution of the current map and of the refinement preview, built lazily
+    height_histogram: Option<Vec<f32>>,
+    refine_histogram: Option<Vec<f32>>,
     biome_map: Option<Vec<u8>>,
+    // heightmap in front of the water channels, set by the first carving run so generating
+    // again starts over from it, cleared by any other edit
+    water_base: Option<Heightmap>,
+    // lake water levels and river depths from the water step, in normalized heights
+    lake_map: Option<Vec<f32>>,
+    lake_depths: Option<Vec<f32>>,
+    river_depths: Option<Vec<f32>>,
+    river_surface: Option<Vec<f32>>,
+    river_widths: Option<Vec<f32>>,
+    // the water drawn over the current preview, rebuilt from the maps above whenever the
+    // preview changes
+    water_preview_texture: Option<egui::TextureHandle>,
+    lake_outlets: Vec<LakeOutlet>,
+    // wet ground around the water, fed back into the biomes
+    wetness: Option<Vec<f32>>,
+    waterfalls: Vec<Waterfall>,
+    // where a tributary of the last water run joins another river
+    river_junctions: Vec<(u32, u32)>,
+    // every carved river of the last water run in order from its source, for the vector export
+    river_lines: Vec<RiverLine>,
+    // statistics of the last water run for the distance to water in meters they were made for
+    water_stats: Option<(f64, WaterStats)>,
+    near_water_distance: f64,
+    // where roads can cross the rivers of the last water run, best first. bridges placed in
+    // the objects step snap to these.
+    crossing_config: CrossingConfig,
+    river_crossings: Vec<Crossing>,
+    // rivers of the last water run that got a pond or were removed for not reaching water
+    dead_end_rivers: Option<(usize, usize)>,
+    // random lakes of the last water run that were made and rejected for their spacing
+    lake_counts: Option<(usize, usize)>,
+    // lake seeds of the last water run that didn't make a lake and why
+    unplaced_lakes: Vec<((u32, u32), NoLake)>,
+    // land cells in the river source band of the last water run if it was ignored for being
+    // too small
+    small_source_band: Option<usize>,
+    // cells the shoreline smoothing of the last water run added and removed, and the lake
+    // area before it
+    shore_change: Option<(usize, usize, usize)>,
+    water_placement: Option<WaterPlacement>,
+    // canal whose points are being placed, it joins `water_config.canals` once it is dug
+    canal_draft: Canal,
+    resample_filter: ResampleFilter,
+    // live refinement preview on a downsampled copy of the heightmap, nothing is written to
+    // `heightmap_data` until the refinement is applied
+    refine_live: bool,
+    refine_source: Option<Heightmap>,
+    refine_source_coast: Option<Arc<CoastDistance>>,
+    refine_preview_texture: Option<egui::TextureHandle>,
+    refine_seen: Option<(RefinerConfig, f64)>,
+    refine_changed_at: Option<f64>,
+    // cells the slope limit changed in the last refinement and whether it was the preview
+    slope_limit_report: Option<(usize, usize, bool)>,
+    // before/after comparison of the refinement preview, the divider is a share of the width
+    split_view: bool,
+    split_position: f32,
+    // control point of the height curve that is being dragged
+    curve_drag: Option<usize>,
+    inland_curve_drag: Option<usize>,
+    // preset waiting for confirmation because it would replace a custom curve
+    pending_curve_preset: Option<usize>,
+    // sculpting on the preview in the refinement step
+    sculpting: bool,
+    // heightmap from before the current brush stroke, kept for the undo history
+    sculpt_before: Option<Heightmap>,
+    brush: Brush,
+    history: History,
+    // candidate building sites for the objects step, cleared when the heightmap changes
+    flat_site_config: FlatSiteConfig,
+    flat_sites: Vec<FlatSite>,
+    // real elevations in meters that the normalized heights 0 and 1 correspond to
+    min_elevation: f32,
+    max_elevation: f32,
 }
 
 impl Default for DayZMapApp {
@@ -50,129 +380,1354 @@ impl Default for DayZMapApp {
             refiner_config: RefinerConfig::default(),
             biome_config: BiomeConfig::default(),
             water_config: WaterConfig::default(),
+            erosion_config: ErosionConfig::default(),
+            erosion_job: None,
+            generate_job: None,
+            biome_job: None,
+            water_job: None,
+            live_preview: false,
+            live_job: None,
+            live_preview_texture: None,
+            sea_level_source: None,
+            sea_level_texture: None,
+            live_changed_at: None,
+            stamp: Stamp::default(),
+            region: Region::default(),
+            region_job: None,
+            refine_job: None,
+            refine_base: None,
+            refine_coast: None,
+            water_base: None,
+            crop: Crop::default(),
             preview_texture: None,
             preview_image: None,
             heightmap_data: None,
+            slope_map: None,
+            ocean_mask: None,
+            preview_mode: PreviewMode::Height,
+            height_histogram: None,
+            refine_histogram: None,
             biome_map: None,
+            lake_map: None,
+            lake_depths: None,
+            river_depths: None,
+            river_surface: None,
+            river_widths: None,
+            water_preview_texture: None,
+            lake_outlets: Vec::new(),
+            wetness: None,
+            waterfalls: Vec::new(),
+            river_junctions: Vec::new(),
+            river_lines: Vec::new(),
+            water_stats: None,
+            near_water_distance: DEFAULT_NEAR_WATER_DISTANCE,
+            crossing_config: CrossingConfig::default(),
+            river_crossings: Vec::new(),
+            dead_end_rivers: None,
+            lake_counts: None,
+            unplaced_lakes: Vec::new(),
+            small_source_band: None,
+            shore_change: None,
+            water_placement: None,
+            canal_draft: Canal::default(),
+            resample_filter: ResampleFilter::Bicubic,
+            refine_live: false,
+            refine_source: None,
+            refine_source_coast: None,
+            refine_preview_texture: None,
+            refine_seen: None,
+            refine_changed_at: None,
+            slope_limit_report: None,
+            split_view: false,
+            split_position: 0.5,
+            curve_drag: None,
+            inland_curve_drag: None,
+            pending_curve_preset: None,
+            sculpting: false,
+            sculpt_before: None,
+            brush: Brush::default(),
+            history: History::default(),
+            flat_site_config: FlatSiteConfig::default(),
+            flat_sites: Vec::new(),
+            min_elevation: 0.0,
+            max_elevation: DEFAULT_MAX_ELEVATION,
         }
     }
 }
 
 impl DayZMapApp {
+    // recomputes what is derived from the heightmap, call after every change to it
+    fn heightmap_changed(&mut self) {
+        // the refinement preview works on a downsampled copy, which is now outdated
+        self.refine_source = None;
+        self.refine_source_coast = None;
+        self.sea_level_source = None;
+        // an edit on top of the refined map bakes the refinement in
+        self.refine_base = None;
+        self.refine_coast = None;
+        self.water_base = None;
+        self.refine_changed_at = Some(f64::NEG_INFINITY);
+        self.height_histogram = None;
+        // the statistics count the cells next to the open sea
+        self.ocean_mask = None;
+        self.water_stats = None;
+        self.flat_sites.clear();
+        let elevation_range = (self.max_elevation - self.min_elevation).max(1.0);
+        self.slope_map = self.heightmap_data.as_ref().map(|heightmap| {
+            compute_slope_map(heightmap, self.config.cell_size_m, elevation_range)
+        });
+    }
+
+    // the open sea of the current heightmap, see compute_ocean_mask
+    fn ocean_mask(&mut self) -> Option<&[bool]> {
+        let heightmap = self.heightmap_data.as_ref()?;
+        let key = (self.config.sea_level, self.config.ocean_seed);
+        let stale = self
+            .ocean_mask
+            .as_ref()
+            .is_none_or(|(sea_level, seed, _)| (*sea_level, *seed) != key);
+        if stale {
+            let mask = compute_ocean_mask(heightmap, key.0 as f32, key.1);
+            self.ocean_mask = Some((key.0, key.1, mask));
+            self.water_stats = None;
+        }
+        self.ocean_mask.as_ref().map(|(_, _, mask)| mask.as_slice())
+    }
+
+    // swaps in a new heightmap and keeps the old one in the undo history
+    fn replace_heightmap(&mut self, label: &str, heightmap: Heightmap) {
+        if let Some(before) = self.heightmap_data.replace(heightmap) {
+            let elevation = (self.min_elevation, self.max_elevation);
+            let after = self.heightmap_data.as_ref().unwrap();
+            self.history.record(label, before, after, elevation);
+        }
+        self.heightmap_changed();
+    }
+
+    // swaps in a generated or imported heightmap. the biomes and the water belong to the old
+    // terrain, so they go with it.
+    fn load_heightmap(&mut self, label: &str, heightmap: Heightmap) {
+        self.biome_map = None;
+        self.clear_water_state();
+        self.replace_heightmap(label, heightmap);
+    }
+
+    // forgets everything the last water run made, for when the map it was made for is gone
+    fn clear_water_state(&mut self) {
+        self.lake_map = None;
+        self.lake_depths = None;
+        self.river_depths = None;
+        self.river_surface = None;
+        self.river_widths = None;
+        self.lake_outlets.clear();
+        self.wetness = None;
+        self.waterfalls.clear();
+        self.river_junctions.clear();
+        self.river_lines.clear();
+        self.water_stats = None;
+        self.river_crossings.clear();
+        self.dead_end_rivers = None;
+        self.lake_counts = None;
+        self.unplaced_lakes.clear();
+        self.small_source_band = None;
+        self.shore_change = None;
+        self.water_preview_texture = None;
+    }
+
+    // generated and image maps have no real heights, they go back to the default range so
+    // the range of a DEM loaded earlier doesn't carry over to them
+    fn reset_elevation_range(&mut self) {
+        let default = (0.0, DEFAULT_MAX_ELEVATION);
+        if (self.min_elevation, self.max_elevation) != default {
+            (self.min_elevation, self.max_elevation) = default;
+            // the slope map was worked out with the old range
+            self.heightmap_changed();
+        }
+    }
+
+    // steps back (or forward again) through the undo history
+    fn step_history(&mut self, ctx: &egui::Context, redo: bool) {
+        // running jobs work on a copy of the current map and would bring the change back
+        if self.any_job_running() || self.sculpt_before.is_some() {
+            return;
+        }
+        let Some(heightmap) = &mut self.heightmap_data else {
+            return;
+        };
+        let mut elevation = (self.min_elevation, self.max_elevation);
+        let label = if redo {
+            self.history.redo(heightmap, &mut elevation)
+        } else {
+            self.history.undo(heightmap, &mut elevation)
+        };
+        let Some(label) = label else {
+            return;
+        };
+        println!("{} {}", if redo { "Redo:" } else { "Undo:" }, label);
+        (self.min_elevation, self.max_elevation) = elevation;
+        self.config.width = heightmap.width();
+        self.config.height = heightmap.height();
+        // the biomes and the water were made for a different map
+        self.biome_map = None;
+        self.clear_water_state();
+        self.heightmap_changed();
+        self.update_heightmap_preview(ctx);
+    }
+
+    // rebuilds the terrain preview from the current heightmap or slope map
+    fn update_heightmap_preview(&mut self, ctx: &egui::Context) {
+        let sea_level = self.config.sea_level;
+        let (color_image, preview) = match self.preview_mode {
+            PreviewMode::Height => {
+                let Some(heightmap) = &self.heightmap_data else {
+                    return;
+                };
+                build_preview(heightmap, |h| get_color_for_height(h as f64, sea_level))
+            }
+            PreviewMode::Slope => {
+                let Some(slope) = &self.slope_map else {
+                    return;
+                };
+                build_preview(slope, get_color_for_slope)
+            }
+            PreviewMode::Wetness => {
+                let heightmap = self.heightmap_data.as_ref();
+                let fits = |w: &&Vec<f32>| heightmap.is_some_and(|h| w.len() == h.len());
+                let wetness = self.wetness.as_ref().filter(fits);
+                let (Some(heightmap), Some(wetness)) = (heightmap, wetness) else {
+                    // nothing to show before the water is generated for this map
+                    self.preview_mode = PreviewMode::Height;
+                    self.update_heightmap_preview(ctx);
+                    return;
+                };
+                let (width, height) = (heightmap.width(), heightmap.height());
+                let wetness = Heightmap::from_vec(wetness.clone(), width, height);
+                build_preview(&wetness, get_color_for_wetness)
+            }
+        };
+        self.preview_image = Some(preview);
+        self.preview_texture =
+            Some(ctx.load_texture("preview", color_image, egui::TextureOptions::default()));
+        self.live_preview_texture = None;
+        self.water_preview_texture = None;
+        self.sea_level_texture = None;
+    }
+
+    // sea level slider that recolors the preview on the way. while it is dragged only a small
+    // copy of the map is colored, the full preview follows when it is let go.
+    fn render_sea_level(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        ui.label("Sea Level:");
+        let response =
+            ui.add(egui::Slider::new(&mut self.config.sea_level, 0.0..=1.0).text("Sea Level"));
+        if response.changed() {
+            self.config_changed(ctx);
+        }
+        if response.dragged() {
+            if response.changed() {
+                self.update_sea_level_preview(ctx);
+            }
+        } else if response.changed() || response.drag_released() {
+            self.update_heightmap_preview(ctx);
+        }
+    }
+
+    fn update_sea_level_preview(&mut self, ctx: &egui::Context) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let source = self.sea_level_source.get_or_insert_with(|| {
+            let largest = heightmap.width().max(heightmap.height());
+            let scale = (SEA_LEVEL_PREVIEW_SIZE as f32 / largest as f32).min(1.0);
+            let width = ((heightmap.width() as f32 * scale) as u32).max(1);
+            let height = ((heightmap.height() as f32 * scale) as u32).max(1);
+            resample_heightmap(heightmap, width, height, ResampleFilter::Bilinear)
+        });
+        let sea_level = self.config.sea_level;
+        let (color_image, _) = build_preview(source, |h| get_color_for_height(h as f64, sea_level));
+        self.sea_level_texture = Some(ctx.load_texture(
+            "sea_level_preview",
+            color_image,
+            egui::TextureOptions::default(),
+        ));
+    }
+
+    // draws the lakes and rivers over the current preview, which may show the terrain or the
+    // biomes
+    fn update_water_preview(&mut self, ctx: &egui::Context) {
+        let (Some(base), Some(heightmap), Some(lakes), Some(depths), Some(rivers)) = (
+            &self.preview_image,
+            &self.heightmap_data,
+            &self.lake_map,
+            &self.lake_depths,
+            &self.river_widths,
+        ) else {
+            return;
+        };
+        let Some(surface) = &self.river_surface else {
+            return;
+        };
+        if lakes.len() != heightmap.len() {
+            return;
+        }
+        let (color_image, _) = composite_water(
+            base,
+            heightmap,
+            (lakes, depths),
+            (surface, rivers),
+            &self.water_config,
+            &self.config,
+            (self.max_elevation - self.min_elevation).max(1.0),
+        );
+        self.water_preview_texture = Some(ctx.load_texture(
+            "water_preview",
+            color_image,
+            egui::TextureOptions::default(),
+        ));
+    }
+
+    // called by the widgets that edit the terrain settings
+    fn config_changed(&mut self, ctx: &egui::Context) {
+        self.live_changed_at = Some(ctx.input(|i| i.time));
+        ctx.request_repaint();
+    }
+
+    // regenerates a small version of the map shortly after the terrain settings stop changing.
+    // it only ever touches its own texture, never the heightmap used by the later steps.
+    fn update_live_preview(&mut self, ctx: &egui::Context) {
+        let now = ctx.input(|i| i.time);
+        let Some(changed_at) = self.live_changed_at else {
+            return;
+        };
+        if self.live_job.is_some() || now - changed_at < LIVE_PREVIEW_DEBOUNCE {
+            ctx.request_repaint_after(Duration::from_secs_f64(LIVE_PREVIEW_DEBOUNCE));
+            return;
+        }
+        self.live_changed_at = None;
+
+        let mut config = self.config.clone();
+        let largest = config.width.max(config.height);
+        if largest > LIVE_PREVIEW_SIZE {
+            config.resize(LIVE_PREVIEW_SIZE as f64 / largest as f64);
+        }
+        let seed = self.config.seed;
+        self.live_job = Some(Job::spawn("Live Preview", move |progress| {
+            generate_map(&config, seed, &None, progress)
+        }));
+    }
+
+    // reruns the refinement on the downsampled copy shortly after the settings change
+    fn update_refine_preview(&mut self, ctx: &egui::Context) {
+        let Some(heightmap) = self.refine_base.as_ref().or(self.heightmap_data.as_ref()) else {
+            return;
+        };
+        let now = ctx.input(|i| i.time);
+        // the sea level is part of the key since it changes the colors
+        let seen = (self.refiner_config.clone(), self.config.sea_level);
+        if self.refine_seen.as_ref() != Some(&seen) {
+            self.refine_seen = Some(seen);
+            self.refine_changed_at = Some(now);
+        }
+        let Some(changed_at) = self.refine_changed_at else {
+            return;
+        };
+        if now - changed_at < LIVE_PREVIEW_DEBOUNCE {
+            ctx.request_repaint_after(Duration::from_secs_f64(LIVE_PREVIEW_DEBOUNCE));
+            return;
+        }
+        self.refine_changed_at = None;
+
+        let largest = heightmap.width().max(heightmap.height());
+        let scale = (REFINE_PREVIEW_SIZE as f32 / largest as f32).min(1.0);
+        let source = self.refine_source.get_or_insert_with(|| {
+            let width = ((heightmap.width() as f32 * scale) as u32).max(1);
+            let height = ((heightmap.height() as f32 * scale) as u32).max(1);
+            resample_heightmap(heightmap, width, height, ResampleFilter::Bilinear)
+        });
+        let sea_level = self.config.sea_level;
+        let Some(refined) = refine_heightmap(
+            source,
+            &self.refiner_config,
+            sea_level as f32,
+            scale,
+            self.refine_source_coast.clone(),
+            &Progress::default(),
+        ) else {
+            return;
+        };
+        self.refine_source_coast = refined.coast;
+        self.slope_limit_report = refined
+            .slope_limited_cells
+            .map(|cells| (cells, source.len(), true));
+        let refined = refined.heightmap;
+        self.refine_histogram = Some(height_histogram(&refined, HISTOGRAM_BUCKETS));
+        let (color_image, _) =
+            build_preview(&refined, |h| get_color_for_height(h as f64, sea_level));
+        self.refine_preview_texture = Some(ctx.load_texture(
+            "refine_preview",
+            color_image,
+            egui::TextureOptions::default(),
+        ));
+    }
+
+    // repaints only the part of the preview that shows the region, falls back to a full
+    // rebuild if the preview currently shows something else
+    fn repaint_preview_region(&mut self, ctx: &egui::Context, region: Region) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let (Some(texture), Some(image)) = (&mut self.preview_texture, &mut self.preview_image)
+        else {
+            self.update_heightmap_preview(ctx);
+            return;
+        };
+        let expected = preview_size(heightmap.width(), heightmap.height());
+        if self.preview_mode != PreviewMode::Height
+            || texture.size() != expected
+            || image.dimensions() != (expected[0] as u32, expected[1] as u32)
+        {
+            self.update_heightmap_preview(ctx);
+            return;
+        }
+        let rect = (
+            region.x,
+            region.y,
+            region.x + region.width,
+            region.y + region.height,
+        );
+        let (pos, patch) = build_preview_patch(heightmap, rect, |h| terrain_color(&self.config, h));
+        if patch.pixels.is_empty() {
+            return;
+        }
+        for (i, pixel) in patch.pixels.iter().enumerate() {
+            let x = (pos[0] + i % patch.size[0]) as u32;
+            let y = (pos[1] + i / patch.size[0]) as u32;
+            image.put_pixel(x, y, Rgba([pixel.r(), pixel.g(), pixel.b(), 255]));
+        }
+        texture.set_partial(pos, patch, egui::TextureOptions::default());
+        self.water_preview_texture = None;
+    }
+
+    // swaps in the results of finished background jobs. cancelled or failed jobs leave the
+    // previous heightmap and preview untouched.
+    fn poll_jobs(&mut self, ctx: &egui::Context) {
+        if let Some(eroded) = take_finished(&mut self.erosion_job) {
+            self.replace_heightmap("Erosion", eroded);
+            self.update_heightmap_preview(ctx);
+        }
+        if let Some((color_image, preview_img, heightmap_data)) =
+            take_finished(&mut self.generate_job)
+        {
+            self.preview_texture =
+                Some(ctx.load_texture("preview", color_image, egui::TextureOptions::default()));
+            self.preview_image = Some(preview_img);
+            self.load_heightmap("Generate Map", heightmap_data);
+            self.reset_elevation_range();
+            self.live_preview_texture = None;
+            if self.preview_mode != PreviewMode::Height {
+                self.update_heightmap_preview(ctx);
+            }
+        }
+        if let Some((color_image, _, _)) = take_finished(&mut self.live_job) {
+            self.live_preview_texture = Some(ctx.load_texture(
+                "live_preview",
+                color_image,
+                egui::TextureOptions::default(),
+            ));
+        }
+        if let Some((heightmap, region)) = take_finished(&mut self.region_job) {
+            self.replace_heightmap("Regenerate Region", heightmap);
+            self.repaint_preview_region(ctx, region);
+        }
+        if let Some(refined) = take_finished(&mut self.refine_job) {
+            let cells = refined.heightmap.len();
+            self.slope_limit_report = refined
+                .slope_limited_cells
+                .map(|changed| (changed, cells, false));
+            // the result is not baked yet, the next Apply starts from the same base
+            let base = self.refine_base.take();
+            self.replace_heightmap("Refinement", refined.heightmap);
+            self.refine_base = base;
+            self.refine_coast = refined.coast;
+            self.update_heightmap_preview(ctx);
+            // the settings are baked in now, the preview returns once they change again
+            self.refine_preview_texture = None;
+            self.refine_histogram = None;
+            self.refine_changed_at = None;
+        }
+        if let Some((color_image, preview, biome)) = take_finished(&mut self.biome_job) {
+            self.biome_map = Some(biome);
+            self.preview_texture =
+                Some(ctx.load_texture("preview", color_image, egui::TextureOptions::default()));
+            self.preview_image = Some(preview);
+            self.water_preview_texture = None;
+        }
+        if let Some(water) = take_finished(&mut self.water_job) {
+            self.finish_water(water, ctx);
+        }
+    }
+
+    // resizes the current heightmap (and biome map) to the configured map size
+    fn resample_to_config(&mut self, ctx: &egui::Context) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let (old_width, old_height) = (heightmap.width(), heightmap.height());
+        let (width, height) = (self.config.width, self.config.height);
+        if (old_width, old_height) == (width, height) {
+            return;
+        }
+        let start = std::time::Instant::now();
+        let resampled = resample_heightmap(heightmap, width, height, self.resample_filter);
+        self.biome_map = self
+            .biome_map
+            .take()
+            .filter(|biomes| biomes.len() == heightmap.len())
+            .map(|biomes| resample_nearest(&biomes, old_width, old_height, width, height));
+        // the water maps are regenerated rather than resampled, the channels would blur
+        self.clear_water_state();
+        println!(
+            "Resampled heightmap {}x{} to {}x{} in {:.2?}",
+            old_width,
+            old_height,
+            width,
+            height,
+            start.elapsed()
+        );
+        self.replace_heightmap("Resample", resampled);
+        self.update_heightmap_preview(ctx);
+    }
+
+    // cuts the map down to the crop rectangle, which is clamped to the map first
+    // the crop settings only offer this once there is a heightmap
+    fn crop_map(&mut self, ctx: &egui::Context) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let (old_width, old_height) = (heightmap.width(), heightmap.height());
+        let x = self.crop.x.min(old_width - 1);
+        let y = self.crop.y.min(old_height - 1);
+        let width = self.crop.width.clamp(1, old_width - x);
+        let height = self.crop.height.clamp(1, old_height - y);
+
+        let data = crop_grid(heightmap.data(), old_width, x, y, width, height);
+        let mut cropped = Heightmap::from_vec(data, width, height);
+        self.biome_map = self
+            .biome_map
+            .take()
+            .filter(|biomes| biomes.len() == heightmap.len())
+            .map(|biomes| crop_grid(&biomes, old_width, x, y, width, height));
+        self.clear_water_state();
+
+        self.config.width = width;
+        self.config.height = height;
+        if self.crop.taper_edges {
+            apply_island_falloff(&mut cropped, &self.config);
+            // the biomes don't know about the new coast anymore
+            self.biome_map = None;
+        }
+        println!("Cropped map to {}x{} at ({}, {})", width, height, x, y);
+        self.crop = Crop {
+            x: 0,
+            y: 0,
+            width,
+            height,
+            ..self.crop
+        };
+        self.replace_heightmap("Crop", cropped);
+        self.update_heightmap_preview(ctx);
+    }
+
+    // paints with the brush while the pointer is held on the preview. only the touched part
+    // of the preview is redrawn during the stroke, the slope map follows when it ends.
+    fn sculpt_on_preview(&mut self, ctx: &egui::Context, ui: &egui::Ui, response: &egui::Response) {
+        if !self.sculpting
+            || !matches!(self.current_step, GenerationStep::Refinement)
+            || self.any_job_running()
+        {
+            return;
+        }
+        let Some(heightmap) = &mut self.heightmap_data else {
+            return;
+        };
+        let rect = response.rect;
+        // heightmap pixels per screen point
+        let scale = heightmap.width() as f32 / rect.width();
+        let to_map =
+            |pos: egui::Pos2| ((pos.x - rect.left()) * scale, (pos.y - rect.top()) * scale);
+
+        if let Some(pos) = response.hover_pos() {
+            ui.painter().circle_stroke(
+                pos,
+                self.brush.radius / scale,
+                egui::Stroke::new(1.0, egui::Color32::WHITE),
+            );
+        }
+
+        let (dt, shift, alt) = ctx.input(|i| (i.stable_dt, i.modifiers.shift, i.modifiers.alt));
+        if alt {
+            // alt-click picks the flatten height instead of painting
+            if let Some(pos) = response
+                .interact_pointer_pos()
+                .filter(|_| response.clicked())
+            {
+                let (x, y) = to_map(pos);
+                self.brush.flatten_target = heightmap.get_clamped(x as i64, y as i64);
+            }
+        } else if response.is_pointer_button_down_on() {
+            if let Some(pos) = response.interact_pointer_pos() {
+                if self.sculpt_before.is_none() {
+                    self.sculpt_before = Some(heightmap.clone());
+                }
+                let (x, y) = to_map(pos);
+                let amount = self.brush.strength * dt.min(0.1);
+                let changed = match self.brush.tool {
+                    BrushTool::RaiseLower => {
+                        let direction = if shift { -1.0 } else { 1.0 };
+                        raise_lower(heightmap, &self.brush, x, y, amount * direction)
+                    }
+                    BrushTool::Flatten => {
+                        let sea_level = self.config.sea_level as f32;
+                        let keep_coast = self.brush.flatten_keep_coast.then_some(sea_level);
+                        flatten(heightmap, &self.brush, x, y, amount, keep_coast)
+                    }
+                    BrushTool::Smooth => smooth(heightmap, &self.brush, x, y, amount),
+                };
+                if let Some(region) = changed
+                    && self.preview_mode == PreviewMode::Height
+                {
+                    self.repaint_preview_region(ctx, region);
+                }
+                // keep painting while the pointer rests in one place
+                ctx.request_repaint();
+            }
+        } else if let Some(before) = self.sculpt_before.take() {
+            let elevation = (self.min_elevation, self.max_elevation);
+            self.history
+                .record("Brush Stroke", before, heightmap, elevation);
+            self.heightmap_changed();
+            if self.preview_mode != PreviewMode::Height {
+                self.update_heightmap_preview(ctx);
+            }
+        }
+    }
+
+    // outlines the bounding boxes of the flat sites on the preview image at `rect`
+    fn draw_flat_sites(&self, ui: &egui::Ui, rect: egui::Rect) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        // screen points per heightmap pixel
+        let scale = rect.width() / heightmap.width() as f32;
+        let stroke = egui::Stroke::new(1.5, egui::Color32::from_rgb(255, 80, 200));
+        for site in &self.flat_sites {
+            let min = rect.min + egui::vec2(site.min.0 as f32, site.min.1 as f32) * scale;
+            let max =
+                rect.min + egui::vec2(site.max.0 as f32 + 1.0, site.max.1 as f32 + 1.0) * scale;
+            ui.painter()
+                .rect_stroke(egui::Rect::from_min_max(min, max), 0.0, stroke);
+        }
+    }
+
+    // marks the river crossings with a short bar
+    fn draw_river_crossings(&self, ui: &egui::Ui, rect: egui::Rect) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        // screen points per heightmap pixel
+        let scale = rect.width() / heightmap.width() as f32;
+        let stroke = egui::Stroke::new(3.0, egui::Color32::from_rgb(240, 220, 60));
+        for crossing in &self.river_crossings {
+            let (x, y) = crossing.position;
+            let center = rect.min + egui::vec2(x as f32 + 0.5, y as f32 + 0.5) * scale;
+            let half = egui::vec2(5.0, 0.0);
+            ui.painter()
+                .line_segment([center - half, center + half], stroke);
+        }
+    }
+
+    // links every drained lake to the start of its outlet river
+    fn draw_lake_outlets(&self, ui: &egui::Ui, rect: egui::Rect) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        // screen points per heightmap pixel
+        let scale = rect.width() / heightmap.width() as f32;
+        let color = egui::Color32::from_rgb(80, 220, 255);
+        for outlet in &self.lake_outlets {
+            let lake = rect.min + egui::vec2(outlet.lake.0 + 0.5, outlet.lake.1 + 0.5) * scale;
+            let (x, y) = outlet.outlet;
+            let start = rect.min + egui::vec2(x as f32 + 0.5, y as f32 + 0.5) * scale;
+            ui.painter()
+                .line_segment([lake, start], egui::Stroke::new(1.5, color));
+            ui.painter()
+                .circle_stroke(start, 4.0, egui::Stroke::new(2.0, color));
+        }
+    }
+
+    // adds a lake seed or river source where the preview is clicked
+    fn place_water_on_preview(&mut self, response: &egui::Response) {
+        let Some(placement) = self.water_placement else {
+            return;
+        };
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let Some(pos) = response
+            .interact_pointer_pos()
+            .filter(|_| response.clicked())
+        else {
+            return;
+        };
+        let rect = response.rect;
+        // heightmap pixels per screen point
+        let scale = heightmap.width() as f32 / rect.width();
+        let x = ((pos.x - rect.left()) * scale) as u32;
+        let y = ((pos.y - rect.top()) * scale) as u32;
+        if x >= heightmap.width() || y >= heightmap.height() {
+            return;
+        }
+        match placement {
+            WaterPlacement::RiverSource => self.water_config.river_sources.push((x, y)),
+            WaterPlacement::Lake => self.water_config.lake_seeds.push((x, y)),
+            WaterPlacement::Canal => self.canal_draft.points.push((x, y)),
+        }
+    }
+
+    // hand placed lakes as rings and river sources as dots
+    fn draw_water_seeds(&self, ui: &egui::Ui, rect: egui::Rect) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        // screen points per heightmap pixel
+        let scale = rect.width() / heightmap.width() as f32;
+        let to_screen =
+            |(x, y): (u32, u32)| rect.min + egui::vec2(x as f32 + 0.5, y as f32 + 0.5) * scale;
+        let lake_color = egui::Color32::from_rgb(30, 90, 255);
+        for &seed in &self.water_config.lake_seeds {
+            ui.painter()
+                .circle_stroke(to_screen(seed), 6.0, egui::Stroke::new(2.0, lake_color));
+        }
+        let source_color = egui::Color32::from_rgb(0, 230, 200);
+        for &source in &self.water_config.river_sources {
+            let center = to_screen(source);
+            ui.painter().circle_filled(center, 4.0, source_color);
+            ui.painter()
+                .circle_stroke(center, 4.0, egui::Stroke::new(1.0, egui::Color32::BLACK));
+        }
+
+        // the canal being drawn along the cells it will be dug through, with its points
+        let canal_color = egui::Color32::WHITE;
+        let width = heightmap.width() as usize;
+        let line: Vec<egui::Pos2> =
+            canal_path(&self.canal_draft, heightmap.width(), heightmap.height())
+                .into_iter()
+                .map(|cell| to_screen(((cell % width) as u32, (cell / width) as u32)))
+                .collect();
+        ui.painter()
+            .add(egui::Shape::line(line, egui::Stroke::new(2.0, canal_color)));
+        for &point in &self.canal_draft.points {
+            ui.painter()
+                .circle_stroke(to_screen(point), 4.0, egui::Stroke::new(2.0, canal_color));
+        }
+    }
+
+    // marks the waterfalls, bigger drops get bigger markers
+    fn draw_waterfalls(&self, ui: &egui::Ui, rect: egui::Rect) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        // screen points per heightmap pixel
+        let scale = rect.width() / heightmap.width() as f32;
+        let color = egui::Color32::from_rgb(255, 140, 0);
+        for waterfall in &self.waterfalls {
+            let (x, y) = waterfall.position;
+            let center = rect.min + egui::vec2(x as f32 + 0.5, y as f32 + 0.5) * scale;
+            let radius = (3.0 + waterfall.drop.sqrt()).min(10.0);
+            ui.painter().circle_filled(center, radius, color);
+        }
+    }
+
+    // draws the refinement preview over the part of the image right of the divider, dragging
+    // on the image moves the divider
+    fn draw_split_view(
+        &mut self,
+        ui: &egui::Ui,
+        response: &egui::Response,
+        after: egui::TextureId,
+    ) {
+        let rect = response.rect;
+        if let Some(pos) = response
+            .interact_pointer_pos()
+            .filter(|_| response.dragged() || response.clicked())
+        {
+            self.split_position = ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+        }
+        let x = rect.left() + rect.width() * self.split_position;
+        let right = egui::Rect::from_min_max(egui::pos2(x, rect.top()), rect.max);
+        let uv =
+            egui::Rect::from_min_max(egui::pos2(self.split_position, 0.0), egui::pos2(1.0, 1.0));
+        ui.painter().image(after, right, uv, egui::Color32::WHITE);
+        ui.painter().vline(
+            x,
+            rect.y_range(),
+            egui::Stroke::new(2.0, egui::Color32::WHITE),
+        );
+    }
+
+    fn any_job_running(&self) -> bool {
+        self.erosion_job.is_some()
+            || self.generate_job.is_some()
+            || self.biome_job.is_some()
+            || self.water_job.is_some()
+            || self.live_job.is_some()
+            || self.region_job.is_some()
+            || self.refine_job.is_some()
+    }
+
+    fn render_history_buttons(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        let enabled = !self.any_job_running();
+        let undo_label = self.history.undo_label().map(str::to_string);
+        let undo = ui.add_enabled(enabled && undo_label.is_some(), egui::Button::new("Undo"));
+        if let Some(label) = undo_label {
+            undo.clone()
+                .on_hover_text(format!("Undo {} (Ctrl+Z)", label));
+        }
+        let redo_label = self.history.redo_label().map(str::to_string);
+        let redo = ui.add_enabled(enabled && redo_label.is_some(), egui::Button::new("Redo"));
+        if let Some(label) = redo_label {
+            redo.clone()
+                .on_hover_text(format!("Redo {} (Ctrl+Y)", label));
+        }
+        if undo.clicked() {
+            self.step_history(ctx, false);
+        } else if redo.clicked() {
+            self.step_history(ctx, true);
+        }
+
+        let mut depth = self.history.depth();
+        let depth_response = ui
+            .add(
+                egui::DragValue::new(&mut depth)
+                    .clamp_range(1..=MAX_HISTORY_DEPTH)
+                    .suffix(" steps"),
+            )
+            .on_hover_text(format!(
+                "Undo history depth, currently using {:.1} MB",
+                self.history.size_bytes() as f64 / (1024.0 * 1024.0)
+            ));
+        if depth_response.changed() {
+            self.history.set_depth(depth);
+        }
+    }
+
     fn render_terrain_settings(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
         ui.heading("Map Settings");
         ui.separator();
+        // set by every widget that edits the config, the live preview follows the edits
+        let mut changed = false;
 
         // Width / Height as text fields
         ui.horizontal(|ui| {
             ui.label("Width (px):");
             let mut width_str = self.config.width.to_string();
-            if ui.text_edit_singleline(&mut width_str).changed() {
-                if let Ok(w) = width_str.parse() {
-                    self.config.width = w;
-                }
+            if ui.text_edit_singleline(&mut width_str).changed()
+                && let Ok(w) = width_str.parse()
+            {
+                self.config.width = w;
+                changed = true;
             }
         });
 
         ui.horizontal(|ui| {
             ui.label("Height (px):");
             let mut height_str = self.config.height.to_string();
-            if ui.text_edit_singleline(&mut height_str).changed() {
-                if let Ok(h) = height_str.parse() {
-                    self.config.height = h;
-                }
+            if ui.text_edit_singleline(&mut height_str).changed()
+                && let Ok(h) = height_str.parse()
+            {
+                self.config.height = h;
+                changed = true;
             }
         });
 
+        ui.horizontal(|ui| {
+            ui.label("Cell Size (m):");
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut self.config.cell_size_m)
+                        .speed(0.1)
+                        .clamp_range(0.01..=1000.0),
+                )
+                .changed();
+        });
+        ui.label(format!(
+            "World Size: {:.1} km x {:.1} km",
+            self.config.width as f64 * self.config.cell_size_m / 1000.0,
+            self.config.height as f64 * self.config.cell_size_m / 1000.0
+        ));
+        changed |= ui
+            .checkbox(&mut self.config.scales_in_meters, "Noise Scales in Meters")
+            .on_hover_text("Keep landforms the same size when changing resolution")
+            .changed();
+
         ui.horizontal(|ui| {
             ui.label("Quick Resize:");
             for &size in [0.25, 0.5, 2.0, 4.0].iter() {
                 if ui.button(format!("{:.2}x", size)).clicked() {
-                    self.config.width = (self.config.width as f32 * size) as u32;
-                    self.config.height = (self.config.height as f32 * size) as u32;
-                    self.config.scale_base = (self.config.scale_base as f32 * size) as f64;
-                    self.config.scale_mid = (self.config.scale_mid as f32 * size) as f64;
-                    self.config.scale_detail = (self.config.scale_detail as f32 * size) as f64;
+                    self.config.resize(size);
+                    changed = true;
+                    // running jobs still expect the old size
+                    if !self.any_job_running() {
+                        self.resample_to_config(ctx);
+                    }
                 }
             }
         });
 
+        if let Some(heightmap) = &self.heightmap_data
+            && (heightmap.width(), heightmap.height()) != (self.config.width, self.config.height)
+        {
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Filter")
+                    .selected_text(format!("{:?}", self.resample_filter))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut self.resample_filter,
+                            ResampleFilter::Bilinear,
+                            "Bilinear",
+                        );
+                        ui.selectable_value(
+                            &mut self.resample_filter,
+                            ResampleFilter::Bicubic,
+                            "Bicubic",
+                        );
+                    });
+                let idle = !self.any_job_running();
+                if ui
+                    .add_enabled(idle, egui::Button::new("Resample to current size"))
+                    .on_hover_text("Shrinking averages pixels, growing uses the filter")
+                    .clicked()
+                {
+                    self.resample_to_config(ctx);
+                }
+            });
+        }
+
         ui.separator();
 
-        ui.checkbox(&mut self.config.use_random_seed, "Use Random Seed");
+        changed |= ui
+            .checkbox(&mut self.config.use_random_seed, "Use Random Seed")
+            .changed();
 
         if !self.config.use_random_seed {
             ui.label("Seed:");
-            ui.add(egui::DragValue::new(&mut self.config.seed).speed(1));
+            changed |= ui
+                .add(egui::DragValue::new(&mut self.config.seed).speed(1))
+                .changed();
         } else {
             ui.label(format!("Random Seed: {}", self.config.seed));
         }
 
-        ui.label("Sea Level:");
-        ui.add(egui::Slider::new(&mut self.config.sea_level, 0.0..=1.0));
+        self.render_sea_level(ui, ctx);
+        self.render_ocean_seed(ui);
+
+        changed |= ui
+            .checkbox(&mut self.config.continental_shelf, "Continental Shelf")
+            .changed();
+        if self.config.continental_shelf {
+            changed |= ui
+                .add(
+                    egui::Slider::new(&mut self.config.shelf_width, 1.0..=500.0)
+                        .text("Shelf Width (px)")
+                        .clamp_to_range(false),
+                )
+                .changed();
+            changed |= ui
+                .add(egui::Slider::new(&mut self.config.shelf_depth, 0.0..=0.5).text("Shelf Depth"))
+                .changed();
+            changed |= ui
+                .add(egui::Slider::new(&mut self.config.abyss_depth, 0.0..=1.0).text("Abyss Depth"))
+                .changed();
+        }
+
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.sea_floor_detail, 0.0..=0.1)
+                    .text("Sea Floor Detail")
+                    .clamp_to_range(false),
+            )
+            .changed();
+        if self.config.sea_floor_detail > 0.0 {
+            changed |= ui
+                .add(
+                    egui::Slider::new(&mut self.config.sea_floor_scale, 2.0..=200.0)
+                        .text("Sea Floor Scale")
+                        .clamp_to_range(false),
+                )
+                .changed();
+            changed |= ui
+                .add(
+                    egui::Slider::new(&mut self.config.sea_floor_fade_px, 1.0..=200.0)
+                        .text("Shore Fade (px)")
+                        .clamp_to_range(false),
+                )
+                .changed();
+        }
+
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.beach_strength, 0.0..=1.0)
+                    .text("Beach Strength"),
+            )
+            .changed();
+        if self.config.beach_strength > 0.0 {
+            changed |= ui
+                .add(
+                    egui::Slider::new(&mut self.config.beach_width_px, 1.0..=200.0)
+                        .text("Beach Width (px)")
+                        .clamp_to_range(false),
+                )
+                .changed();
+            changed |= ui
+                .add(
+                    egui::Slider::new(&mut self.config.beach_slope, 0.0..=20.0)
+                        .text("Beach Slope (%)")
+                        .clamp_to_range(false),
+                )
+                .changed();
+        }
 
         ui.separator();
         ui.heading("Island Shaping");
 
-        ui.checkbox(&mut self.config.island_mode, "Enable Island Mode");
-        ui.add(
-            egui::Slider::new(&mut self.config.island_border, 0.01..=0.5).text("Island Border %"),
-        );
-        ui.add(egui::Slider::new(&mut self.config.island_curve, 1.0..=10.0).text("Falloff Curve"));
+        changed |= ui
+            .checkbox(&mut self.config.tileable, "Tileable (wrap around edges)")
+            .changed();
+        if self.config.tileable && (self.config.island_mode || self.config.shape_mask.is_some()) {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                "Island shaping is ignored while tileable is enabled.",
+            );
+        }
+
+        egui::ComboBox::from_label("Symmetry")
+            .selected_text(match self.config.symmetry {
+                Symmetry::None => "None",
+                Symmetry::MirrorX => "Mirror X",
+                Symmetry::MirrorY => "Mirror Y",
+                Symmetry::Quad => "Quad",
+                Symmetry::Rotational180 => "Rotational 180",
+            })
+            .show_ui(ui, |ui| {
+                let symmetry = &mut self.config.symmetry;
+                changed |= ui
+                    .selectable_value(symmetry, Symmetry::None, "None")
+                    .changed();
+                changed |= ui
+                    .selectable_value(symmetry, Symmetry::MirrorX, "Mirror X")
+                    .changed();
+                changed |= ui
+                    .selectable_value(symmetry, Symmetry::MirrorY, "Mirror Y")
+                    .changed();
+                changed |= ui
+                    .selectable_value(symmetry, Symmetry::Quad, "Quad")
+                    .changed();
+                changed |= ui
+                    .selectable_value(symmetry, Symmetry::Rotational180, "Rotational 180")
+                    .changed();
+            });
+
+        changed |= ui
+            .checkbox(&mut self.config.island_mode, "Enable Island Mode")
+            .changed();
+        egui::ComboBox::from_label("Island Shape")
+            .selected_text(match self.config.island_shape {
+                IslandShape::Square => "Square",
+                IslandShape::SquareAdditive => "Square (Additive)",
+                IslandShape::Radial => "Radial",
+                IslandShape::Superellipse => "Superellipse",
+            })
+            .show_ui(ui, |ui| {
+                changed |= ui
+                    .selectable_value(&mut self.config.island_shape, IslandShape::Square, "Square")
+                    .changed();
+                changed |= ui
+                    .selectable_value(
+                        &mut self.config.island_shape,
+                        IslandShape::SquareAdditive,
+                        "Square (Additive)",
+                    )
+                    .changed();
+                changed |= ui
+                    .selectable_value(&mut self.config.island_shape, IslandShape::Radial, "Radial")
+                    .changed();
+                changed |= ui
+                    .selectable_value(
+                        &mut self.config.island_shape,
+                        IslandShape::Superellipse,
+                        "Superellipse",
+                    )
+                    .changed();
+            });
+        if self.config.island_shape == IslandShape::Superellipse {
+            changed |= ui
+                .add(
+                    egui::Slider::new(&mut self.config.island_exponent, 1.0..=20.0)
+                        .text("Shape Exponent"),
+                )
+                .changed();
+        }
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.island_border, 0.01..=0.5)
+                    .text("Island Border %"),
+            )
+            .changed();
+        changed |= ui
+            .add(egui::Slider::new(&mut self.config.island_curve, 1.0..=10.0).text("Falloff Curve"))
+            .changed();
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.coast_noise_strength, 0.0..=0.3)
+                    .text("Coast Noise Strength"),
+            )
+            .changed();
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.coast_noise_scale, 10.0..=2000.0)
+                    .text("Coast Noise Scale")
+                    .clamp_to_range(false),
+            )
+            .changed();
+
+        ui.horizontal(|ui| {
+            if ui.button("Load Shape Mask").clicked()
+                && let Some(path) = rfd::FileDialog::new()
+                    .add_filter("Image", &["png", "jpg", "jpeg", "bmp"])
+                    .set_title("Select a shape mask image")
+                    .pick_file()
+            {
+                match image::open(&path) {
+                    Ok(img) => {
+                        let gray = img.to_luma8();
+                        let (w, h) = gray.dimensions();
+                        self.config.shape_mask = Some(Arc::new(Heightmap::from_vec(
+                            gray.pixels().map(|p| p[0] as f32 / 255.0).collect(),
+                            w,
+                            h,
+                        )));
+                        changed = true;
+                    }
+                    Err(e) => eprintln!("Error loading shape mask: {}", e),
+                }
+            }
+            if self.config.shape_mask.is_some() && ui.button("Clear Mask").clicked() {
+                self.config.shape_mask = None;
+                changed = true;
+            }
+        });
+        if let Some(mask) = &self.config.shape_mask {
+            ui.label(format!("Shape mask: {}x{}", mask.width(), mask.height()));
+            changed |= ui
+                .add(
+                    egui::Slider::new(&mut self.config.shape_mask_blend, 0.0..=1.0)
+                        .text("Mask Blend"),
+                )
+                .changed();
+        }
 
         ui.separator();
         ui.label("Terrain Contrast (Mountains)");
-        ui.add(egui::Slider::new(&mut self.config.mountainous, 0.3..=3.0).text("Mountainous"));
+        changed |= ui
+            .add(egui::Slider::new(&mut self.config.mountainous, 0.3..=3.0).text("Mountainous"))
+            .changed();
 
         ui.separator();
         ui.heading("Noise Layers");
 
-        ui.label("Base Noise");
-        ui.add(
-            egui::Slider::new(&mut self.config.scale_base, 10.0..=10000.0)
-                .text("Scale")
-                .clamp_to_range(false),
-        );
-        ui.add(
-            egui::Slider::new(&mut self.config.amp_base, 0.0..=2.0)
-                .text("Amp")
-                .clamp_to_range(false),
-        );
+        ui.horizontal(|ui| {
+            ui.label("Presets:");
+            if ui.button("Classic 3-Layer").clicked() {
+                self.config.apply_classic_preset();
+                changed = true;
+            }
+            if ui.button("fBm").clicked() {
+                self.config.apply_fbm_preset();
+                changed = true;
+            }
+        });
 
-        ui.label("Mid Noise");
-        ui.add(
-            egui::Slider::new(&mut self.config.scale_mid, 10.0..=1000.0)
-                .text("Scale")
-                .clamp_to_range(false),
-        );
-        ui.add(
-            egui::Slider::new(&mut self.config.amp_mid, 0.0..=2.0)
-                .text("Amp")
-                .clamp_to_range(false),
-        );
+        egui::ComboBox::from_label("Noise Stack")
+            .selected_text(match self.config.noise_stack {
+                NoiseStack::Classic => "Classic",
+                NoiseStack::Fbm => "fBm",
+            })
+            .show_ui(ui, |ui| {
+                changed |= ui
+                    .selectable_value(&mut self.config.noise_stack, NoiseStack::Classic, "Classic")
+                    .changed();
+                changed |= ui
+                    .selectable_value(&mut self.config.noise_stack, NoiseStack::Fbm, "fBm")
+                    .changed();
+            });
 
-        ui.label("Detail Noise");
-        ui.add(
-            egui::Slider::new(&mut self.config.scale_detail, 5.0..=100.0)
-                .text("Scale")
-                .clamp_to_range(false),
-        );
-        ui.add(
-            egui::Slider::new(&mut self.config.amp_detail, 0.0..=2.0)
-                .text("Amp")
-                .clamp_to_range(false),
-        );
+        changed |= ui
+            .checkbox(&mut self.config.independent_layer_seeds, "Independent Layer Seeds")
+            .on_hover_text("Seed every layer separately so their features don't line up")
+            .changed();
+
+        egui::ComboBox::from_label("Noise Mode")
+            .selected_text(match self.config.noise_mode {
+                NoiseMode::Standard => "Standard",
+                NoiseMode::Ridged => "Ridged",
+                NoiseMode::Billow => "Billow",
+            })
+            .show_ui(ui, |ui| {
+                changed |= ui
+                    .selectable_value(&mut self.config.noise_mode, NoiseMode::Standard, "Standard")
+                    .changed();
+                changed |= ui
+                    .selectable_value(&mut self.config.noise_mode, NoiseMode::Ridged, "Ridged")
+                    .changed();
+                changed |= ui
+                    .selectable_value(&mut self.config.noise_mode, NoiseMode::Billow, "Billow")
+                    .changed();
+            });
+
+        match self.config.noise_stack {
+            NoiseStack::Classic => changed |= self.render_noise_layers(ui),
+            NoiseStack::Fbm => {
+                ui.horizontal(|ui| {
+                    ui.label("Algorithm");
+                    let algorithm = &mut self.config.fbm_algorithm;
+                    changed |= noise_algorithm_combo(ui, "fbm_algorithm", algorithm);
+                });
+                changed |= ui
+                    .add(egui::Slider::new(&mut self.config.octaves, 1..=12).text("Octaves"))
+                    .changed();
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut self.config.base_frequency, 0.0001..=0.1)
+                            .text("Base Frequency")
+                            .logarithmic(true),
+                    )
+                    .changed();
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut self.config.lacunarity, 1.0..=4.0)
+                            .text("Lacunarity")
+                            .clamp_to_range(false),
+                    )
+                    .changed();
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut self.config.persistence, 0.0..=1.0)
+                            .text("Persistence"),
+                    )
+                    .changed();
+            }
+        }
+
+        ui.separator();
+        ui.label("Plains Flattening");
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.plains_threshold, 0.0..=1.0)
+                    .text("Plains Threshold"),
+            )
+            .changed();
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.plains_strength, 0.0..=1.0)
+                    .text("Plains Strength"),
+            )
+            .changed();
+
+        ui.separator();
+        ui.label("Tectonic Plates");
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.tectonic_strength, 0.0..=1.0)
+                    .text("Tectonic Strength"),
+            )
+            .changed();
+        changed |= ui
+            .add(egui::Slider::new(&mut self.config.plate_count, 1..=64).text("Plate Count"))
+            .changed();
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.plate_elevation_variance, 0.0..=0.5)
+                    .text("Elevation Variance"),
+            )
+            .changed();
+
+        ui.separator();
+        ui.label("Terracing");
+        changed |= ui
+            .add(egui::Slider::new(&mut self.config.terrace_steps, 2..=64).text("Terrace Steps"))
+            .changed();
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.terrace_sharpness, 0.0..=1.0)
+                    .text("Terrace Sharpness"),
+            )
+            .changed();
+
+        ui.separator();
+        ui.label("Domain Warping");
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.warp_strength, 0.0..=500.0)
+                    .text("Warp Strength")
+                    .clamp_to_range(false),
+            )
+            .changed();
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.warp_scale, 10.0..=2000.0)
+                    .text("Warp Scale")
+                    .clamp_to_range(false),
+            )
+            .changed();
 
         ui.separator();
         ui.label("Overlay Generation");
-        ui.add(
-            egui::Slider::new(&mut self.config.overlay, 0.0..=100.0)
-                .text("Overlay Strength")
-                .clamp_to_range(false),
-        );
+        egui::ComboBox::from_label("Overlay Mode")
+            .selected_text(match self.config.overlay_mode {
+                OverlayMode::Lerp => "Lerp",
+                OverlayMode::Add => "Add",
+                OverlayMode::Multiply => "Multiply",
+                OverlayMode::Screen => "Screen",
+                OverlayMode::Max => "Max",
+                OverlayMode::Min => "Min",
+            })
+            .show_ui(ui, |ui| {
+                let mode = &mut self.config.overlay_mode;
+                changed |= ui
+                    .selectable_value(mode, OverlayMode::Lerp, "Lerp")
+                    .changed();
+                changed |= ui.selectable_value(mode, OverlayMode::Add, "Add").changed();
+                changed |= ui
+                    .selectable_value(mode, OverlayMode::Multiply, "Multiply")
+                    .changed();
+                changed |= ui
+                    .selectable_value(mode, OverlayMode::Screen, "Screen")
+                    .changed();
+                changed |= ui.selectable_value(mode, OverlayMode::Max, "Max").changed();
+                changed |= ui.selectable_value(mode, OverlayMode::Min, "Min").changed();
+            });
+        changed |= ui
+            .add(
+                egui::Slider::new(&mut self.config.overlay, 0.0..=100.0)
+                    .text("Overlay Strength")
+                    .clamp_to_range(false),
+            )
+            .changed();
+        if changed {
+            self.config_changed(ctx);
+        }
 
         ui.horizontal(|ui| {
-            if ui.button("Generate Map").clicked() {
+            if self.generate_job.is_none() && ui.button("Generate Map").clicked() {
                 let seed = if self.config.use_random_seed {
                     let new_seed = rand::random::<u32>();
                     self.config.seed = new_seed;
@@ -181,71 +1736,715 @@ impl DayZMapApp {
                     self.config.seed
                 };
 
-                let (color_image, preview_img, heightmap_data) =
-                    generate_map(&self.config, seed, &self.heightmap_data);
-                self.preview_texture =
-                    Some(ctx.load_texture("preview", color_image, egui::TextureOptions::default()));
-                self.preview_image = Some(preview_img);
-                self.heightmap_data = Some(heightmap_data);
+                // an edit still waiting for the live preview shouldn't replace the full result
+                self.live_changed_at = None;
+
+                // only copy the old map into the worker when the overlay actually reads it
+                let previous = if uses_previous_map(&self.config) {
+                    self.heightmap_data.clone()
+                } else {
+                    None
+                };
+                let config = self.config.clone();
+                self.generate_job = Some(Job::spawn("Generating Map", move |progress| {
+                    generate_map(&config, seed, &previous, progress)
+                }));
+            }
+
+            if ui
+                .checkbox(&mut self.live_preview, "Live Preview")
+                .changed()
+            {
+                // show the current settings right away
+                self.live_changed_at = Some(f64::NEG_INFINITY);
             }
 
-            if ui.button("Load Map").clicked() {
-                if let Some(path) = rfd::FileDialog::new()
+            if ui.button("Load Map").clicked()
+                && let Some(path) = rfd::FileDialog::new()
                     .add_filter("Image", &["png", "jpg", "jpeg", "bmp"])
+                    .add_filter("Elevation Model", &["hgt", "asc", "tif", "tiff"])
                     .set_title("Select a heightmap image")
                     .pick_file()
-                {
-                    if let Ok(img) = image::open(&path) {
-                        let gray = img.to_luma8();
-                        let (w, h) = gray.dimensions();
+            {
+                if is_dem_file(&path) {
+                    match load_dem(&path) {
+                        Ok(dem) => {
+                            println!(
+                                "Loaded DEM {}x{} with elevations {:.1}m to {:.1}m",
+                                dem.heightmap.width(),
+                                dem.heightmap.height(),
+                                dem.min_elevation,
+                                dem.max_elevation
+                            );
+                            self.config.width = dem.heightmap.width();
+                            self.config.height = dem.heightmap.height();
+                            self.load_heightmap("Import DEM", dem.heightmap);
+                            self.min_elevation = dem.min_elevation;
+                            self.max_elevation = dem.max_elevation;
+                            self.heightmap_changed();
+                            self.update_heightmap_preview(ctx);
+                        }
+                        Err(e) => eprintln!("Error loading DEM: {}", e),
+                    }
+                } else if let Ok(img) = image::open(&path) {
+                    let gray = img.to_luma8();
+                    let (w, h) = gray.dimensions();
 
-                        self.config.width = w;
-                        self.config.height = h;
+                    self.config.width = w;
+                    self.config.height = h;
 
-                        let heightmap: Vec<f32> =
-                            gray.pixels().map(|p| p[0] as f32 / 255.0).collect();
+                    let heightmap = Heightmap::from_vec(
+                        gray.pixels().map(|p| p[0] as f32 / 255.0).collect(),
+                        w,
+                        h,
+                    );
 
-                        let mut preview = ImageBuffer::new(w, h);
-                        for y in 0..h {
-                            for x in 0..w {
-                                let i = (y * w + x) as usize;
-                                let h = heightmap[i];
-                                let (r, g, b) =
-                                    get_color_for_height(h as f64, self.config.sea_level);
-                                preview.put_pixel(x, y, Rgba([r, g, b, 255]));
-                            }
+                    self.load_heightmap("Import Heightmap", heightmap);
+                    self.reset_elevation_range();
+                    self.update_heightmap_preview(ctx);
+                }
+            }
+        });
+
+        if let Some(job) = &self.generate_job {
+            job_status(ui, job);
+        }
+
+        self.render_region_settings(ui);
+        self.render_crop_settings(ui, ctx);
+    }
+
+    fn render_crop_settings(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        ui.collapsing("Crop", |ui| {
+            let Some(heightmap) = &self.heightmap_data else {
+                ui.label("Generate or load a map first.");
+                return;
+            };
+            let (width, height) = (heightmap.width(), heightmap.height());
+            ui.horizontal(|ui| {
+                ui.label("X:");
+                ui.add(egui::DragValue::new(&mut self.crop.x).clamp_range(0..=width - 1));
+                ui.label("Y:");
+                ui.add(egui::DragValue::new(&mut self.crop.y).clamp_range(0..=height - 1));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Width:");
+                ui.add(egui::DragValue::new(&mut self.crop.width).clamp_range(1..=width));
+                ui.label("Height:");
+                ui.add(egui::DragValue::new(&mut self.crop.height).clamp_range(1..=height));
+            });
+            ui.checkbox(&mut self.crop.taper_edges, "Taper Edges")
+                .on_hover_text("Apply the island falloff to the cropped map");
+            let idle = !self.any_job_running();
+            if ui
+                .add_enabled(idle, egui::Button::new("Crop Map"))
+                .clicked()
+            {
+                self.crop_map(ctx);
+            }
+        });
+    }
+
+    // returns whether a layer was edited
+    fn render_noise_layers(&mut self, ui: &mut egui::Ui) -> bool {
+        enum LayerAction {
+            MoveUp(usize),
+            MoveDown(usize),
+            Remove(usize),
+        }
+        let mut action = None;
+        let mut changed = false;
+        let count = self.config.layers.len();
+        for (i, layer) in self.config.layers.iter_mut().enumerate() {
+            egui::CollapsingHeader::new(format!("Layer {}", i + 1))
+                .id_source(("noise_layer", i))
+                .default_open(true)
+                .show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        changed |= ui.checkbox(&mut layer.enabled, "Enabled").changed();
+                        changed |= noise_algorithm_combo(
+                            ui,
+                            &format!("layer_algorithm_{}", i),
+                            &mut layer.algorithm,
+                        );
+                    });
+                    changed |= ui
+                        .add(
+                            egui::Slider::new(&mut layer.scale, 1.0..=10000.0)
+                                .text("Scale")
+                                .logarithmic(true)
+                                .clamp_to_range(false),
+                        )
+                        .changed();
+                    changed |= ui
+                        .add(
+                            egui::Slider::new(&mut layer.amplitude, 0.0..=2.0)
+                                .text("Amp")
+                                .clamp_to_range(false),
+                        )
+                        .changed();
+                    ui.horizontal(|ui| {
+                        ui.label("Seed Offset:");
+                        changed |= ui
+                            .add(egui::DragValue::new(&mut layer.seed_offset))
+                            .changed();
+                        if ui.add_enabled(i > 0, egui::Button::new("Up")).clicked() {
+                            action = Some(LayerAction::MoveUp(i));
+                        }
+                        if ui
+                            .add_enabled(i + 1 < count, egui::Button::new("Down"))
+                            .clicked()
+                        {
+                            action = Some(LayerAction::MoveDown(i));
                         }
+                        if ui.button("Remove").clicked() {
+                            action = Some(LayerAction::Remove(i));
+                        }
+                    });
+                });
+        }
+        changed |= action.is_some();
+        match action {
+            Some(LayerAction::MoveUp(i)) => self.config.layers.swap(i - 1, i),
+            Some(LayerAction::MoveDown(i)) => self.config.layers.swap(i, i + 1),
+            Some(LayerAction::Remove(i)) => {
+                self.config.layers.remove(i);
+            }
+            None => {}
+        }
 
-                        self.heightmap_data = Some(heightmap);
-                        self.preview_image = Some(preview.clone());
-
-                        let color_image = egui::ColorImage {
-                            size: [w as usize, h as usize],
-                            pixels: preview
-                                .pixels()
-                                .map(|p| egui::Color32::from_rgb(p[0], p[1], p[2]))
-                                .collect(),
-                        };
-                        self.preview_texture = Some(ctx.load_texture(
-                            "preview",
-                            color_image,
-                            egui::TextureOptions::default(),
-                        ));
+        if ui.button("Add Layer").clicked() {
+            // finer than the last layer and seeded differently from all of them
+            let scale = self
+                .config
+                .layers
+                .last()
+                .map_or(400.0, |layer| layer.scale / 4.0);
+            let seed_offset = self
+                .config
+                .layers
+                .iter()
+                .map(|layer| layer.seed_offset + 1)
+                .max()
+                .unwrap_or(0);
+            self.config
+                .layers
+                .push(NoiseLayer::new(scale.max(1.0), 0.1, seed_offset));
+            changed = true;
+        }
+        changed
+    }
+
+    fn render_region_settings(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Regenerate Region", |ui| {
+            ui.horizontal(|ui| {
+                ui.label("X:");
+                ui.add(egui::DragValue::new(&mut self.region.x).clamp_range(0..=self.config.width));
+                ui.label("Y:");
+                ui.add(
+                    egui::DragValue::new(&mut self.region.y).clamp_range(0..=self.config.height),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label("Width:");
+                ui.add(
+                    egui::DragValue::new(&mut self.region.width).clamp_range(1..=self.config.width),
+                );
+                ui.label("Height:");
+                ui.add(
+                    egui::DragValue::new(&mut self.region.height)
+                        .clamp_range(1..=self.config.height),
+                );
+            });
+            ui.add(
+                egui::Slider::new(&mut self.region.feather, 0..=256)
+                    .text("Feather (px)")
+                    .clamp_to_range(false),
+            );
+
+            // the region is blended into the current map, so it has to match the map size
+            let matches_map = self.heightmap_data.as_ref().is_some_and(|heightmap| {
+                heightmap.width() == self.config.width && heightmap.height() == self.config.height
+            });
+            if !matches_map {
+                ui.label("Generate or load a map of the current size first.");
+            } else if self.region_job.is_none() && ui.button("Regenerate Region").clicked() {
+                let seed = if self.config.use_random_seed {
+                    let new_seed = rand::random::<u32>();
+                    self.config.seed = new_seed;
+                    new_seed
+                } else {
+                    self.config.seed
+                };
+                let config = self.config.clone();
+                let previous = self.heightmap_data.clone();
+                let region = self.region;
+                self.region_job = Some(Job::spawn("Regenerating Region", move |progress| {
+                    generate_heightmap(&config, seed, &previous, Some(region), progress)
+                        .map(|heightmap| (heightmap, region))
+                }));
+            }
+
+            if let Some(job) = &self.region_job {
+                job_status(ui, job);
+            }
+        });
+    }
+
+    // asks for a grayscale image for the refiner, scaled to [0,1] and to the map size
+    fn load_grayscale_image(&self, title: &str) -> Option<Heightmap> {
+        let path = rfd::FileDialog::new()
+            .add_filter("Image", &["png", "jpg", "jpeg", "bmp", "tif", "tiff"])
+            .set_title(title)
+            .pick_file()?;
+        let gray = match image::open(&path) {
+            Ok(img) => img.to_luma16(),
+            Err(e) => {
+                eprintln!("Error loading image: {}", e);
+                return None;
+            }
+        };
+        let (w, h) = gray.dimensions();
+        let mut overlay = Heightmap::from_vec(
+            gray.pixels()
+                .map(|p| p[0] as f32 / u16::MAX as f32)
+                .collect(),
+            w,
+            h,
+        );
+        if let Some(heightmap) = &self.heightmap_data
+            && (w, h) != (heightmap.width(), heightmap.height())
+        {
+            overlay = resample_heightmap(
+                &overlay,
+                heightmap.width(),
+                heightmap.height(),
+                ResampleFilter::Bilinear,
+            );
+        }
+        Some(overlay)
+    }
+
+    fn render_sculpt_settings(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Sculpt", |ui| {
+            ui.checkbox(&mut self.sculpting, "Sculpt on Preview");
+            egui::ComboBox::from_label("Tool")
+                .selected_text(match self.brush.tool {
+                    BrushTool::RaiseLower => "Raise / Lower",
+                    BrushTool::Flatten => "Flatten",
+                    BrushTool::Smooth => "Smooth",
+                })
+                .show_ui(ui, |ui| {
+                    let tool = &mut self.brush.tool;
+                    ui.selectable_value(tool, BrushTool::RaiseLower, "Raise / Lower");
+                    ui.selectable_value(tool, BrushTool::Flatten, "Flatten");
+                    ui.selectable_value(tool, BrushTool::Smooth, "Smooth");
+                });
+            match self.brush.tool {
+                BrushTool::RaiseLower => {
+                    ui.label("Drag on the preview to raise, hold Shift to lower.");
+                }
+                BrushTool::Flatten => {
+                    ui.label("Alt-click the preview to pick the height, then drag to flatten.");
+                    ui.horizontal(|ui| {
+                        ui.label("Target Height:");
+                        ui.add(
+                            egui::DragValue::new(&mut self.brush.flatten_target)
+                                .speed(0.001)
+                                .clamp_range(0.0..=1.0),
+                        );
+                    });
+                    ui.checkbox(&mut self.brush.flatten_keep_coast, "Keep Coastline")
+                        .on_hover_text("Don't flatten across the sea level");
+                }
+                BrushTool::Smooth => {
+                    ui.label("Drag on the preview to soften the terrain.");
+                }
+            }
+            ui.add(
+                egui::Slider::new(&mut self.brush.radius, 1.0..=500.0)
+                    .text("Radius (px)")
+                    .logarithmic(true),
+            );
+            ui.add(egui::Slider::new(&mut self.brush.strength, 0.01..=1.0).text("Strength"));
+            egui::ComboBox::from_label("Falloff")
+                .selected_text(format!("{:?}", self.brush.falloff))
+                .show_ui(ui, |ui| {
+                    let falloff = &mut self.brush.falloff;
+                    ui.selectable_value(falloff, BrushFalloff::Smooth, "Smooth");
+                    ui.selectable_value(falloff, BrushFalloff::Linear, "Linear");
+                    ui.selectable_value(falloff, BrushFalloff::Constant, "Constant");
+                });
+        });
+    }
+
+    fn render_paint_overlay_settings(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Paint Overlay", |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Load Overlay").clicked()
+                    && let Some(overlay) = self.load_grayscale_image("Select an overlay image")
+                {
+                    self.refiner_config.paint_map_overlay = Some(Arc::new(overlay));
+                }
+                if let Some(overlay) = &self.refiner_config.paint_map_overlay {
+                    ui.label(format!("{}x{}", overlay.width(), overlay.height()));
+                    if ui.button("Clear").clicked() {
+                        self.refiner_config.paint_map_overlay = None;
                     }
                 }
+            });
+            egui::ComboBox::from_label("Blend")
+                .selected_text(format!("{:?}", self.refiner_config.paint_blend))
+                .show_ui(ui, |ui| {
+                    let blend = &mut self.refiner_config.paint_blend;
+                    ui.selectable_value(blend, PaintBlend::Add, "Add");
+                    ui.selectable_value(blend, PaintBlend::Subtract, "Subtract");
+                    ui.selectable_value(blend, PaintBlend::Multiply, "Multiply");
+                    ui.selectable_value(blend, PaintBlend::Lerp, "Lerp");
+                });
+            ui.add(
+                egui::Slider::new(&mut self.refiner_config.paint_strength, 0.0..=1.0)
+                    .text("Strength"),
+            );
+        });
+    }
+
+    fn render_detail_noise_settings(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Add Noise", |ui| {
+            let sea_level = self.config.sea_level as f32;
+            let noise = &mut self.refiner_config.detail_noise;
+            ui.checkbox(&mut noise.enabled, "Enabled");
+            ui.add(
+                egui::Slider::new(&mut noise.scale, 1.0..=256.0)
+                    .text("Scale (px)")
+                    .logarithmic(true),
+            );
+            ui.add(egui::Slider::new(&mut noise.amplitude, 0.0..=0.1).text("Amplitude"));
+            ui.horizontal(|ui| {
+                ui.label("Seed:");
+                ui.add(egui::DragValue::new(&mut noise.seed).speed(1));
+            });
+
+            ui.label("Height Band:");
+            ui.add(egui::Slider::new(&mut noise.min_height, 0.0..=1.0).text("Min Height"));
+            ui.add(egui::Slider::new(&mut noise.max_height, 0.0..=1.0).text("Max Height"));
+            ui.horizontal(|ui| {
+                if ui.button("Land Only").clicked() {
+                    (noise.min_height, noise.max_height) = (sea_level, 1.0);
+                }
+                if ui.button("Water Only").clicked() {
+                    (noise.min_height, noise.max_height) = (0.0, sea_level);
+                }
+                if ui.button("Everywhere").clicked() {
+                    (noise.min_height, noise.max_height) = (0.0, 1.0);
+                }
+            });
+
+            let mut limit_slope = noise.max_slope.is_some();
+            if ui.checkbox(&mut limit_slope, "Limit Slope").changed() {
+                noise.max_slope = limit_slope.then_some(0.005);
+            }
+            if let Some(max_slope) = &mut noise.max_slope {
+                ui.add(
+                    egui::Slider::new(max_slope, 0.0005..=0.05)
+                        .text("Max Slope")
+                        .logarithmic(true),
+                )
+                .on_hover_text("Height change per pixel above which no noise is added");
             }
         });
     }
 
+    // distribution of the heights with the sea level marked, the refinement preview is drawn
+    // on top while it is shown
+    fn render_height_histogram(&mut self, ui: &mut egui::Ui) {
+        let Some(heightmap) = &self.heightmap_data else {
+            ui.label("Generate a map to see its height distribution.");
+            return;
+        };
+        let current = self
+            .height_histogram
+            .get_or_insert_with(|| height_histogram(heightmap, HISTOGRAM_BUCKETS));
+        let bucket_width = 1.0 / HISTOGRAM_BUCKETS as f64;
+        let bars = |histogram: &[f32], color: egui::Color32| {
+            histogram
+                .iter()
+                .enumerate()
+                .map(|(i, &share)| {
+                    egui::plot::Bar::new((i as f64 + 0.5) * bucket_width, share as f64)
+                        .width(bucket_width)
+                        .fill(color)
+                })
+                .collect::<Vec<_>>()
+        };
+        let current = egui::plot::BarChart::new(bars(current, egui::Color32::GRAY))
+            .name("Current")
+            .color(egui::Color32::GRAY);
+        let refined = self
+            .refine_histogram
+            .as_ref()
+            .filter(|_| self.refine_live && self.refine_preview_texture.is_some())
+            .map(|histogram| {
+                let color = egui::Color32::from_rgba_unmultiplied(255, 160, 0, 120);
+                egui::plot::BarChart::new(bars(histogram, color))
+                    .name("Refined")
+                    .color(color)
+            });
+        let sea_level = egui::plot::VLine::new(self.config.sea_level)
+            .name("Sea Level")
+            .color(egui::Color32::LIGHT_BLUE);
+        let config = &self.refiner_config;
+        let clip_lines = [
+            (config.clip_min > 0.0).then_some(config.clip_min),
+            (config.clip_max < 1.0).then_some(config.clip_max),
+        ]
+        .map(|clip| {
+            clip.map(|value| {
+                egui::plot::VLine::new(value)
+                    .name("Clip")
+                    .color(egui::Color32::RED)
+            })
+        });
+
+        egui::plot::Plot::new("height_histogram")
+            .height(120.0)
+            .include_x(0.0)
+            .include_x(1.0)
+            .include_y(0.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .show_y(false)
+            .legend(egui::plot::Legend::default())
+            .show(ui, |plot_ui| {
+                plot_ui.bar_chart(current);
+                if let Some(refined) = refined {
+                    plot_ui.bar_chart(refined);
+                }
+                plot_ui.vline(sea_level);
+                for line in clip_lines.into_iter().flatten() {
+                    plot_ui.vline(line);
+                }
+            });
+    }
+
+    fn render_refine_mask_settings(&mut self, ui: &mut egui::Ui) {
+        egui::ComboBox::from_label("Apply To")
+            .selected_text(match self.refiner_config.mask {
+                RefineMask::All => "Whole Map",
+                RefineMask::LandOnly => "Land Only",
+                RefineMask::WaterOnly => "Water Only",
+                RefineMask::Custom => "Custom Mask",
+            })
+            .show_ui(ui, |ui| {
+                let mask = &mut self.refiner_config.mask;
+                ui.selectable_value(mask, RefineMask::All, "Whole Map");
+                ui.selectable_value(mask, RefineMask::LandOnly, "Land Only");
+                ui.selectable_value(mask, RefineMask::WaterOnly, "Water Only");
+                ui.selectable_value(mask, RefineMask::Custom, "Custom Mask");
+            })
+            .response
+            .on_hover_text("The rest of the map keeps its heights, the coastline is feathered");
+        if self.refiner_config.mask == RefineMask::Custom {
+            ui.horizontal(|ui| {
+                if ui.button("Load Mask").clicked()
+                    && let Some(mask) = self.load_grayscale_image("Select a mask image")
+                {
+                    self.refiner_config.custom_mask = Some(Arc::new(mask));
+                }
+                match &self.refiner_config.custom_mask {
+                    Some(mask) => {
+                        ui.label(format!("{}x{}", mask.width(), mask.height()));
+                        if ui.button("Clear").clicked() {
+                            self.refiner_config.custom_mask = None;
+                        }
+                    }
+                    None => {
+                        ui.label("No mask loaded, the whole map is refined");
+                    }
+                }
+            });
+        }
+    }
+
+    fn render_refine_pipeline(&mut self, ui: &mut egui::Ui) {
+        ui.label("Steps run from top to bottom, unchecked steps are skipped");
+        let mut moved = None;
+        let pipeline = &mut self.refiner_config.pipeline;
+        let count = pipeline.len();
+        for (i, step) in pipeline.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.add_enabled(i > 0, egui::Button::new("Up")).clicked() {
+                    moved = Some((i - 1, i));
+                }
+                if ui
+                    .add_enabled(i + 1 < count, egui::Button::new("Down"))
+                    .clicked()
+                {
+                    moved = Some((i, i + 1));
+                }
+                ui.checkbox(&mut step.enabled, refine_op_name(step.op));
+            });
+        }
+        if let Some((a, b)) = moved {
+            pipeline.swap(a, b);
+        }
+        if ui.button("Reset Order").clicked() {
+            *pipeline = default_refine_pipeline();
+        }
+    }
+
+    // tells the later steps which heightmap they work on
+    fn render_heightmap_source(&self, ui: &mut egui::Ui) {
+        if self.refine_base.is_some() {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                "Using the refined map, the refinement is not baked yet",
+            );
+            ui.separator();
+        }
+    }
+
+    // the settings are locked while a refinement runs, it works on a snapshot of them
     fn render_refine_settings(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        if let Some(job) = &self.refine_job {
+            job_status(ui, job);
+            ui.separator();
+        }
+        let idle = self.refine_job.is_none();
+        ui.add_enabled_ui(idle, |ui| self.render_refine_controls(ui, ctx));
+    }
+
+    fn render_refine_controls(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        ui.collapsing("Height Histogram", |ui| self.render_height_histogram(ui));
+        self.render_refine_mask_settings(ui);
+        ui.collapsing("Pipeline", |ui| self.render_refine_pipeline(ui));
+
         // Curve controls
         ui.collapsing("Height Curve", |ui| {
-            // Control points UI here
-            // Presets: Linear, Steep Peaks, Flatlands, etc.
+            let mut enabled = self.refiner_config.curve_points.is_some();
+            if ui.checkbox(&mut enabled, "Apply Curve").changed() {
+                self.refiner_config.curve_points = enabled.then(|| vec![(0.0, 0.0), (1.0, 1.0)]);
+                self.refiner_config.inland_curve_points = None;
+            }
+            let mut chosen = None;
+            egui::ComboBox::from_label("Preset")
+                .selected_text("Choose...")
+                .show_ui(ui, |ui| {
+                    for (i, (name, _)) in CURVE_PRESETS.iter().enumerate() {
+                        if ui.selectable_label(false, *name).clicked() {
+                            chosen = Some(i);
+                        }
+                    }
+                });
+            if let Some(i) = chosen {
+                // a curve that matches a preset can be replaced without losing anything
+                let custom = self
+                    .refiner_config
+                    .curve_points
+                    .as_ref()
+                    .is_some_and(|points| {
+                        !CURVE_PRESETS
+                            .iter()
+                            .any(|(_, preset)| points.as_slice() == *preset)
+                    });
+                if custom {
+                    self.pending_curve_preset = Some(i);
+                } else {
+                    self.refiner_config.curve_points = Some(CURVE_PRESETS[i].1.to_vec());
+                }
+            }
+            if let Some(i) = self.pending_curve_preset {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "Replace the current curve with '{}'?",
+                        CURVE_PRESETS[i].0
+                    ));
+                    if ui.button("Replace").clicked() {
+                        self.refiner_config.curve_points = Some(CURVE_PRESETS[i].1.to_vec());
+                        self.pending_curve_preset = None;
+                    }
+                    if ui.button("Keep").clicked() {
+                        self.pending_curve_preset = None;
+                    }
+                });
+            }
+
+            let config = &mut self.refiner_config;
+            if let Some(points) = &mut config.curve_points {
+                let mut separate = config.inland_curve_points.is_some();
+                if ui
+                    .checkbox(&mut separate, "Separate Inland Curve")
+                    .on_hover_text("Use the curve above near the coast and a second one inland")
+                    .changed()
+                {
+                    config.inland_curve_points = separate.then(|| points.clone());
+                }
+                if let Some(inland) = &mut config.inland_curve_points {
+                    ui.columns(2, |columns| {
+                        columns[0].label("Coast");
+                        curve_editor(&mut columns[0], points, &mut self.curve_drag);
+                        columns[1].label("Inland");
+                        curve_editor(&mut columns[1], inland, &mut self.inland_curve_drag);
+                    });
+                    ui.add(
+                        egui::Slider::new(&mut config.coast_transition_px, 1.0..=1000.0)
+                            .text("Transition (px)")
+                            .logarithmic(true),
+                    )
+                    .on_hover_text("Distance from the coastline over which the curves blend");
+                } else {
+                    curve_editor(ui, points, &mut self.curve_drag);
+                }
+                ui.label("Drag to move, click to add, right click to remove a point.");
+                if ui.button("Reset Curve").clicked() {
+                    *points = vec![(0.0, 0.0), (1.0, 1.0)];
+                    config.inland_curve_points = None;
+                }
+            }
         });
 
-        ui.label("Sea Level:");
-        ui.add(egui::Slider::new(&mut self.config.sea_level, 0.0..=1.0).text("Sea Level"));
+        ui.collapsing("Clip Heights", |ui| {
+            let config = &mut self.refiner_config;
+            ui.add(egui::Slider::new(&mut config.clip_min, 0.0..=1.0).text("Min Height"));
+            ui.add(egui::Slider::new(&mut config.clip_max, 0.0..=1.0).text("Max Height"));
+            ui.add(egui::Slider::new(&mut config.clip_knee, 0.0..=0.2).text("Knee"))
+                .on_hover_text("Ease into the limits over this height, 0 cuts off flat");
+            if config.clip_min > config.clip_max {
+                config.clip_max = config.clip_min;
+            }
+        });
+
+        ui.collapsing("Slope Limit", |ui| {
+            let config = &mut self.refiner_config;
+            let mut enabled = config.max_slope_step.is_some();
+            if ui.checkbox(&mut enabled, "Enabled").changed() {
+                config.max_slope_step = enabled.then_some(0.01);
+            }
+            if let Some(max_step) = &mut config.max_slope_step {
+                ui.add(
+                    egui::Slider::new(max_step, 0.0005..=0.1)
+                        .text("Max Step")
+                        .logarithmic(true),
+                )
+                .on_hover_text("Largest height difference allowed between neighboring cells");
+                ui.add(
+                    egui::Slider::new(&mut config.slope_limit_iterations, 1..=2000)
+                        .text("Iterations")
+                        .logarithmic(true),
+                );
+                if let Some((changed, cells, preview)) = self.slope_limit_report {
+                    ui.label(format!(
+                        "{} cells changed ({:.2}%){}",
+                        changed,
+                        changed as f64 / cells.max(1) as f64 * 100.0,
+                        if preview { " in the preview" } else { "" }
+                    ));
+                }
+            }
+        });
+
+        self.render_sea_level(ui, ctx);
 
         ui.label("Height Offset:");
         ui.add(
@@ -269,49 +2468,382 @@ impl DayZMapApp {
 
         // smoothness of the heightmap (0.0 = no smoothing, 1.0 = full smoothing)
         ui.label("Smoothing Factor:");
-        ui.add(
-            egui::Slider::new(&mut self.refiner_config.smoothness, 0.0..=1.0)
-                .text("Smoothing Factor"),
-        );
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::Slider::new(&mut self.refiner_config.smoothness, 0.0..=1.0)
+                    .text("Smoothing Factor"),
+            );
+            egui::ComboBox::from_id_source("smoothing_mode")
+                .selected_text(match self.refiner_config.smoothing_mode {
+                    SmoothingMode::Gaussian => "Gaussian",
+                    SmoothingMode::Bilateral => "Edge Preserving",
+                    SmoothingMode::SlopeLimited => "Steep Slopes Only",
+                    SmoothingMode::Median3 => "Median 3x3",
+                    SmoothingMode::Median5 => "Median 5x5",
+                })
+                .show_ui(ui, |ui| {
+                    let mode = &mut self.refiner_config.smoothing_mode;
+                    ui.selectable_value(mode, SmoothingMode::Gaussian, "Gaussian");
+                    ui.selectable_value(mode, SmoothingMode::Bilateral, "Edge Preserving");
+                    ui.selectable_value(mode, SmoothingMode::SlopeLimited, "Steep Slopes Only");
+                    ui.selectable_value(mode, SmoothingMode::Median3, "Median 3x3")
+                        .on_hover_text("Removes speckles, the smoothing factor sets the mix");
+                    ui.selectable_value(mode, SmoothingMode::Median5, "Median 5x5")
+                        .on_hover_text("Removes speckles, the smoothing factor sets the mix");
+                });
+        });
+        if self.refiner_config.smoothing_mode == SmoothingMode::Bilateral {
+            ui.add(
+                egui::Slider::new(&mut self.refiner_config.edge_threshold, 0.001..=0.5)
+                    .text("Edge Threshold")
+                    .logarithmic(true),
+            )
+            .on_hover_text("Height differences above this are treated as edges and kept");
+        }
+        if self.refiner_config.smoothing_mode == SmoothingMode::SlopeLimited {
+            ui.add(
+                egui::Slider::new(&mut self.refiner_config.slope_threshold, 0.0005..=0.05)
+                    .text("Slope Threshold")
+                    .logarithmic(true),
+            )
+            .on_hover_text(
+                "Height change per pixel where smoothing starts, it is fully applied at twice this",
+            );
+        }
+
+        ui.label("Terraces:");
+        ui.add(egui::Slider::new(&mut self.refiner_config.terrace_levels, 0..=64).text("Levels"))
+            .on_hover_text("Number of flat steps, 0 or 1 turns terracing off");
+        if self.refiner_config.terrace_levels > 1 {
+            ui.add(
+                egui::Slider::new(&mut self.refiner_config.terrace_blend, 0.0..=1.0)
+                    .text("Step Blend"),
+            )
+            .on_hover_text("0 gives hard steps, 1 an almost continuous slope");
+        }
+
+        egui::ComboBox::from_label("Normalization")
+            .selected_text(match self.refiner_config.normalization {
+                Normalization::Off => "Off",
+                Normalization::Clamp => "Clamp",
+                Normalization::Rescale => "Rescale",
+                Normalization::PreserveSeaLevel => "Keep Sea Level",
+            })
+            .show_ui(ui, |ui| {
+                let mode = &mut self.refiner_config.normalization;
+                ui.selectable_value(mode, Normalization::Off, "Off")
+                    .on_hover_text("Keep the refined heights as they are");
+                ui.selectable_value(mode, Normalization::Clamp, "Clamp")
+                    .on_hover_text("Cut off heights outside 0 to 1");
+                ui.selectable_value(mode, Normalization::Rescale, "Rescale")
+                    .on_hover_text("Stretch the heights to span 0 to 1");
+                ui.selectable_value(mode, Normalization::PreserveSeaLevel, "Keep Sea Level")
+                    .on_hover_text("Stretch land and water separately so the coastline stays put");
+            });
 
-        // TODO: connect this and add following features:
-        // - smoothing factor (taking into account cliffs and other features)
-        // - Curve points (add/remove points, adjust curve shape, similar to photoshop/gimp curves)
-        // - Paint map overlay (load a texture and use it to modify the heightmap using "sculpting" tools like "raise/lower, smooth, etc.)"
-        // - "live" preview using smaller texture (512x512) and a "preview" button to generate the full heightmap
-        // - "Apply" button to apply the changes to the heightmap and update the preview
+        self.render_paint_overlay_settings(ui);
+        self.render_detail_noise_settings(ui);
+        self.render_sculpt_settings(ui);
 
-        if ui.button("Apply Refinement").clicked() {
-            let refined_heightmap = refine_heightmap(
-                self.heightmap_data.as_ref().unwrap(),
-                &self.refiner_config,
-                &self.config,
+        ui.checkbox(&mut self.refine_live, "Live Preview")
+            .on_hover_text(format!(
+                "Show the refinement on a {} px copy of the map until it is applied",
+                REFINE_PREVIEW_SIZE
+            ));
+
+        let can_apply = self.heightmap_data.is_some() && !self.any_job_running();
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(can_apply, egui::Button::new("Apply Refinement"))
+                .on_hover_text("Refine the base map with the current settings")
+                .clicked()
+            {
+                if self.refine_base.is_none() {
+                    self.refine_base = self.heightmap_data.clone();
+                }
+                if let Some(heightmap) = &self.refine_base {
+                    let heightmap = heightmap.clone();
+                    let config = self.refiner_config.clone();
+                    let sea_level = self.config.sea_level as f32;
+                    let coast = self.refine_coast.clone();
+                    self.refine_job = Some(Job::spawn("Refining", move |progress| {
+                        refine_heightmap(&heightmap, &config, sea_level, 1.0, coast, progress)
+                    }));
+                }
+            }
+            let can_bake = self.refine_base.is_some() && !self.any_job_running();
+            if ui
+                .add_enabled(can_bake, egui::Button::new("Bake"))
+                .on_hover_text("Keep the refined map as the new base, later applies refine it")
+                .clicked()
+            {
+                self.refine_base = None;
+                self.refine_coast = None;
+                self.refine_source = None;
+                self.refine_source_coast = None;
+                self.refine_changed_at = Some(f64::NEG_INFINITY);
+            }
+        });
+        if self.refine_base.is_some() {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                "Refinement applied but not baked, Apply starts over from the base map",
             );
-            let (w, h) = (self.config.width, self.config.height);
-            let mut preview = ImageBuffer::new(w, h);
-            for y in 0..h {
-                for x in 0..w {
-                    let i = (y * w + x) as usize;
-                    let h = refined_heightmap[i];
-                    let (r, g, b) = get_color_for_height(h as f64, self.config.sea_level);
-                    preview.put_pixel(x, y, Rgba([r, g, b, 255]));
-                }
-            }
-            self.preview_image = Some(preview.clone());
-            let color_image = egui::ColorImage {
-                size: [w as usize, h as usize],
-                pixels: preview
-                    .pixels()
-                    .map(|p| egui::Color32::from_rgb(p[0], p[1], p[2]))
-                    .collect(),
-            };
-            self.preview_texture =
-                Some(ctx.load_texture("preview", color_image, egui::TextureOptions::default()));
-            self.heightmap_data = Some(refined_heightmap);
+        } else {
+            ui.label("Apply refines the current map");
+        }
+
+        ui.separator();
+        self.render_erosion_settings(ui);
+
+        ui.separator();
+        self.render_stamp_settings(ui, ctx);
+    }
+
+    fn render_stamp_settings(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        ui.collapsing("Stamp Features", |ui| {
+            egui::ComboBox::from_label("Feature")
+                .selected_text(match self.stamp.kind {
+                    StampKind::Volcano => "Volcano",
+                    StampKind::Crater => "Crater",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.stamp.kind, StampKind::Volcano, "Volcano");
+                    ui.selectable_value(&mut self.stamp.kind, StampKind::Crater, "Crater");
+                });
+            egui::ComboBox::from_label("Blend")
+                .selected_text(match self.stamp.blend {
+                    StampBlend::Add => "Add",
+                    StampBlend::Max => "Max",
+                    StampBlend::Replace => "Replace",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.stamp.blend, StampBlend::Add, "Add");
+                    ui.selectable_value(&mut self.stamp.blend, StampBlend::Max, "Max");
+                    ui.selectable_value(&mut self.stamp.blend, StampBlend::Replace, "Replace");
+                });
+
+            ui.horizontal(|ui| {
+                ui.label("Center:");
+                ui.add(egui::DragValue::new(&mut self.stamp.x).prefix("x: "));
+                ui.add(egui::DragValue::new(&mut self.stamp.y).prefix("y: "));
+            });
+            ui.add(
+                egui::Slider::new(&mut self.stamp.radius, 1.0..=1000.0)
+                    .text("Radius (px)")
+                    .clamp_to_range(false),
+            );
+            ui.add(egui::Slider::new(&mut self.stamp.height, 0.0..=1.0).text("Height"));
+            ui.add(egui::Slider::new(&mut self.stamp.rim_width, 0.01..=0.9).text("Rim Width"));
+
+            // a running job would overwrite the stamp with its result
+            let idle = !self.any_job_running();
+            if ui
+                .add_enabled(idle, egui::Button::new("Stamp Feature"))
+                .clicked()
+                && let Some(heightmap) = &self.heightmap_data
+            {
+                let mut stamped = heightmap.clone();
+                stamp_feature(&mut stamped, &self.stamp);
+                self.replace_heightmap("Stamp Feature", stamped);
+                self.update_heightmap_preview(ctx);
+            }
+        });
+    }
+
+    fn render_erosion_settings(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Hydraulic Erosion", |ui| {
+            ui.checkbox(&mut self.erosion_config.use_random_seed, "Use Random Seed");
+            if !self.erosion_config.use_random_seed {
+                ui.label("Seed:");
+                ui.add(egui::DragValue::new(&mut self.erosion_config.seed).speed(1));
+            } else {
+                ui.label(format!("Random Seed: {}", self.erosion_config.seed));
+            }
+
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.droplet_count, 0..=1000000)
+                    .text("Droplets")
+                    .logarithmic(true),
+            );
+            ui.add(egui::Slider::new(&mut self.erosion_config.inertia, 0.0..=1.0).text("Inertia"));
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.erosion_rate, 0.0..=1.0)
+                    .text("Erosion Rate"),
+            );
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.deposition_rate, 0.0..=1.0)
+                    .text("Deposition Rate"),
+            );
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.max_lifetime, 1..=200)
+                    .text("Max Lifetime"),
+            );
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.sediment_capacity, 0.0..=20.0)
+                    .text("Sediment Capacity"),
+            );
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.evaporation, 0.0..=0.5)
+                    .text("Evaporation"),
+            );
+            ui.add(egui::Slider::new(&mut self.erosion_config.radius, 1..=8).text("Brush Radius"));
+
+            // a job still running on the old map would bring it back when it finishes
+            if !self.any_job_running()
+                && ui.button("Run Erosion").clicked()
+                && let Some(heightmap) = &self.heightmap_data
+            {
+                let mut seed = self.erosion_config.seed;
+                if self.erosion_config.use_random_seed {
+                    seed = rand::random::<u32>();
+                    self.erosion_config.seed = seed;
+                }
+                let heightmap = heightmap.clone();
+                let config = self.erosion_config.clone();
+                self.erosion_job = Some(Job::spawn("Hydraulic Erosion", move |progress| {
+                    hydraulic_erosion(&heightmap, &config, seed, progress)
+                }));
+            }
+        });
+
+        ui.collapsing("Thermal Erosion", |ui| {
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.talus_angle, 1.0..=89.0)
+                    .text("Talus Angle (deg)"),
+            );
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.thermal_iterations, 1..=500)
+                    .text("Iterations"),
+            );
+            ui.add(
+                egui::Slider::new(&mut self.erosion_config.thermal_rate, 0.0..=1.0)
+                    .text("Rate"),
+            );
+
+            if !self.any_job_running()
+                && ui.button("Run Thermal Erosion").clicked()
+                && let Some(heightmap) = &self.heightmap_data
+            {
+                let heightmap = heightmap.clone();
+                let config = self.erosion_config.clone();
+                self.erosion_job = Some(Job::spawn("Thermal Erosion", move |progress| {
+                    thermal_erosion(&heightmap, &config, progress)
+                }));
+            }
+        });
+
+        if let Some(job) = &self.erosion_job {
+            job_status(ui, job);
+        }
+    }
+
+    // table the biomes are classified by, each rule can be edited in place
+    fn render_biome_rules(&mut self, ui: &mut egui::Ui) {
+        ui.label("The highest priority rule a cell matches sets its biome.");
+        ui.label("Elevation is the height over the sea level (1 is the coast), slope in °.");
+        ui.label("Ranges include the min but not the max, inf leaves an end open.");
+        let rules = &mut self.biome_config.biome_rules;
+        let mut removed = None;
+        egui::ScrollArea::horizontal().show(ui, |ui| {
+            egui::Grid::new("biome_rules").striped(true).show(ui, |ui| {
+                let columns = [
+                    "Biome",
+                    "Priority",
+                    "Elevation",
+                    "Temperature",
+                    "Humidity",
+                    "Slope",
+                    "",
+                ];
+                for column in columns {
+                    ui.label(column);
+                }
+                ui.end_row();
+                for (i, rule) in rules.iter_mut().enumerate() {
+                    biome_combo(ui, &format!("biome_rule_{}", i), &mut rule.biome);
+                    ui.add(egui::DragValue::new(&mut rule.priority));
+                    let ranges = [
+                        (&mut rule.elevation, 0.01),
+                        (&mut rule.temperature, 0.01),
+                        (&mut rule.humidity, 0.01),
+                        (&mut rule.slope, 0.5),
+                    ];
+                    for ((min, max), speed) in ranges {
+                        ui.horizontal(|ui| {
+                            ui.add(egui::DragValue::new(min).speed(speed));
+                            ui.label("to");
+                            ui.add(egui::DragValue::new(max).speed(speed));
+                        });
+                    }
+                    if ui.small_button("x").clicked() {
+                        removed = Some(i);
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+        if let Some(i) = removed {
+            rules.remove(i);
         }
+
+        ui.horizontal(|ui| {
+            ui.label("Fallback:");
+            biome_combo(ui, "fallback_biome", &mut self.biome_config.fallback_biome);
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Add Rule").clicked() {
+                let any = (f32::NEG_INFINITY, f32::INFINITY);
+                self.biome_config.biome_rules.push(BiomeRule {
+                    biome: Biome::Plains,
+                    priority: 0,
+                    elevation: any,
+                    temperature: any,
+                    humidity: any,
+                    slope: any,
+                });
+            }
+            if ui.button("Reset Rules").clicked() {
+                self.biome_config.biome_rules = default_biome_rules();
+                self.biome_config.fallback_biome = Biome::Plains;
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Save Rules").clicked() {
+                let path = rfd::FileDialog::new()
+                    .add_filter("CSV", &["csv"])
+                    .set_title("Save Biome Rules")
+                    .set_file_name("biome_rules.csv")
+                    .save_file();
+                if let Some(path) = path {
+                    let config = &self.biome_config;
+                    match save_biome_rules(&path, &config.biome_rules, config.fallback_biome) {
+                        Ok(()) => println!("Biome rules saved to {}", path.display()),
+                        Err(e) => eprintln!("Error saving biome rules: {}", e),
+                    }
+                }
+            }
+            if ui.button("Load Rules").clicked() {
+                let path = rfd::FileDialog::new()
+                    .add_filter("CSV", &["csv"])
+                    .set_title("Load Biome Rules")
+                    .pick_file();
+                if let Some(path) = path {
+                    match load_biome_rules(&path) {
+                        Ok((rules, fallback)) => {
+                            println!("Loaded {} biome rules from {}", rules.len(), path.display());
+                            self.biome_config.biome_rules = rules;
+                            self.biome_config.fallback_biome = fallback;
+                        }
+                        Err(e) => eprintln!("Error loading biome rules: {}", e),
+                    }
+                }
+            }
+        });
     }
 
-    fn render_biome_settings(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+    fn render_biome_settings(&mut self, ui: &mut egui::Ui, _ctx: &egui::Context) {
+        self.render_heightmap_source(ui);
         /* biome slider ranges */
 
         ui.checkbox(&mut self.biome_config.use_random_seed, "Use Random Seed");
@@ -343,6 +2875,27 @@ impl DayZMapApp {
                 .text("Temperature Variation"),
         );
 
+        ui.label("Latitude Gradient (°C):");
+        ui.add(
+            egui::Slider::new(&mut self.biome_config.latitude_gradient, -50.0..=50.0)
+                .text("Latitude Gradient"),
+        )
+        .on_hover_text(
+            "How much warmer the bottom edge is than the top, or the equator than the edges",
+        );
+        ui.checkbox(&mut self.biome_config.use_equator, "Equator");
+        if self.biome_config.use_equator {
+            ui.add(
+                egui::Slider::new(&mut self.biome_config.equator_position, 0.0..=1.0)
+                    .text("Equator Position"),
+            )
+            .on_hover_text("0 is the top edge of the map, 1 the bottom");
+        }
+
+        ui.label("Lapse Rate (°C per 1000 m):");
+        ui.add(egui::Slider::new(&mut self.biome_config.lapse_rate, 0.0..=20.0).text("Lapse Rate"))
+            .on_hover_text("High ground gets colder, the real atmosphere averages 6.5");
+
         ui.label("Base Humidity:");
         ui.add(
             egui::Slider::new(&mut self.biome_config.base_humidity, 0.0..=100.0)
@@ -355,33 +2908,302 @@ impl DayZMapApp {
                 .text("Humidity Variation"),
         );
 
+        ui.label("Coastal Humidity Boost (%):");
+        ui.add(
+            egui::Slider::new(&mut self.biome_config.coastal_humidity_boost, 0.0..=100.0)
+                .text("Coastal Humidity Boost"),
+        )
+        .on_hover_text("Moisture the sea adds at the coast, 0 leaves the humidity to the noise");
+        ui.label("Coastal Humidity Range (m):");
+        ui.add(
+            egui::Slider::new(
+                &mut self.biome_config.coastal_humidity_range,
+                100.0..=20000.0,
+            )
+            .text("Coastal Humidity Range")
+            .logarithmic(true),
+        )
+        .on_hover_text("Distance from the sea over which the boost fades out");
+
         ui.label("Biome Blend Factor:");
         ui.add(
             egui::Slider::new(&mut self.biome_config.biome_blend_factor, 0.0..=100.0)
                 .text("Biome Blend Factor"),
+        )
+        .on_hover_text(
+            "Dithers the biomes into each other along their borders, 0 keeps hard edges",
         );
 
-        if ui.button("Generate Biome Map").clicked() {
-            if let Some(heightmap) = &self.heightmap_data {
-                let mut seed = self.biome_config.seed;
-                if self.biome_config.use_random_seed {
-                    seed = rand::random::<u32>();
-                    self.biome_config.seed = seed;
-                }
+        ui.collapsing("Biome Rules", |ui| self.render_biome_rules(ui));
 
-                let (color_image, preview, biome) =
-                    generate_biome_map(&self.config, &self.biome_config, heightmap, seed);
-                self.biome_map = Some(biome);
-                self.preview_texture =
-                    Some(ctx.load_texture("preview", color_image, egui::TextureOptions::default()));
-                self.preview_image = Some(preview);
+        if self.wetness.is_some() {
+            ui.label("Swamps follow the wetlands of the generated water.");
+        }
+        if self.biome_job.is_none() && ui.button("Generate Biome Map").clicked() {
+            if self.heightmap_data.is_some() {
+                self.start_biome_job();
             } else {
                 ui.label("Please load a heightmap first.");
             }
         }
+
+        if let Some(job) = &self.biome_job {
+            job_status(ui, job);
+        }
+    }
+
+    fn start_biome_job(&mut self) {
+        let ocean_mask = self.ocean_mask().map(<[bool]>::to_vec).unwrap_or_default();
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let mut seed = self.biome_config.seed;
+        if self.biome_config.use_random_seed {
+            seed = rand::random::<u32>();
+            self.biome_config.seed = seed;
+        }
+
+        let heightmap = heightmap.clone();
+        let elevation_range = (self.max_elevation - self.min_elevation).max(1.0);
+        let slope_map = self.slope_map.clone().unwrap_or_else(|| {
+            compute_slope_map(&heightmap, self.config.cell_size_m, elevation_range)
+        });
+        let wetness = self.wetness.clone().unwrap_or_default();
+        let config = self.config.clone();
+        let biome_config = self.biome_config.clone();
+        self.biome_job = Some(Job::spawn("Generating Biomes", move |progress| {
+            let terrain = BiomeTerrain {
+                heightmap: &heightmap,
+                slope_map: &slope_map,
+                wetness: &wetness,
+                ocean_mask: &ocean_mask,
+            };
+            generate_biome_map(
+                &config,
+                &biome_config,
+                &terrain,
+                elevation_range,
+                seed,
+                progress,
+            )
+        }));
+    }
+
+    // lakes and rivers for the current heightmap, made from the uncarved map if an earlier
+    // run carved it
+    fn start_water_job(&mut self) {
+        let Some(heightmap) = self.water_base.as_ref().or(self.heightmap_data.as_ref()) else {
+            return;
+        };
+        let mut seed = self.water_config.seed;
+        if self.water_config.use_random_seed {
+            seed = rand::random::<u32>();
+            self.water_config.seed = seed;
+        }
+        let heightmap = heightmap.clone();
+        let elevation_range = (self.max_elevation - self.min_elevation).max(1.0);
+        let biome_map = self.biome_map.clone().unwrap_or_default();
+        let config = self.config.clone();
+        let water_config = self.water_config.clone();
+        self.water_job = Some(Job::spawn("Generating Water", move |progress| {
+            generate_water_map(
+                &config,
+                &water_config,
+                &heightmap,
+                &biome_map,
+                elevation_range,
+                seed,
+                progress,
+            )
+        }));
+    }
+
+    // takes over the maps of a finished water run, its channels and beds replace the
+    // heightmap
+    fn finish_water(&mut self, water: WaterMap, ctx: &egui::Context) {
+        self.lake_map = Some(water.lake_map);
+        self.lake_depths = Some(water.lake_depths);
+        self.river_depths = Some(water.river_depths);
+        self.river_surface = Some(water.river_surface);
+        self.river_widths = Some(water.river_widths);
+        self.lake_outlets = water.outlets;
+        self.waterfalls = water.waterfalls;
+        self.river_junctions = water.junctions;
+        self.river_lines = water.rivers;
+        self.water_stats = None;
+        self.river_crossings.clear();
+        self.wetness = Some(water.wetness);
+        self.lake_counts = Some((water.lakes_accepted, water.lakes_rejected));
+        self.unplaced_lakes = water.unplaced_lakes;
+        self.small_source_band = water.small_source_band;
+        self.shore_change = (self.water_config.shore_smoothing > 0).then_some(water.shore_change);
+        self.dead_end_rivers = self
+            .water_config
+            .enforce_outlet
+            .then_some((water.ponded_rivers, water.removed_rivers));
+        // the river channels and lake beds are part of the terrain from now on
+        let base = self.water_base.take();
+        if self.water_config.carve_terrain {
+            let base = base.or_else(|| self.heightmap_data.clone());
+            self.replace_heightmap("Water", water.heightmap);
+            self.water_base = base;
+        } else if let Some(base) = base {
+            // the channels of an earlier run don't belong to this water
+            self.replace_heightmap("Water", base);
+        }
+        self.update_heightmap_preview(ctx);
+    }
+
+    // placement mode for the preview and the lists of what was placed so far
+    fn render_water_placement(&mut self, ui: &mut egui::Ui) {
+        ui.label("Place by Clicking the Preview:");
+        ui.horizontal(|ui| {
+            let modes = [
+                (WaterPlacement::RiverSource, "Place River Source"),
+                (WaterPlacement::Lake, "Place Lake"),
+                (WaterPlacement::Canal, "Draw Canal"),
+            ];
+            for (mode, label) in modes {
+                let active = self.water_placement == Some(mode);
+                if ui.selectable_label(active, label).clicked() {
+                    self.water_placement = (!active).then_some(mode);
+                }
+            }
+        });
+        let lists = [
+            ("River Source", &mut self.water_config.river_sources),
+            ("Lake", &mut self.water_config.lake_seeds),
+        ];
+        for (name, points) in lists {
+            let mut removed = None;
+            for (i, &(x, y)) in points.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{} at {}, {}", name, x, y));
+                    if ui.small_button("x").clicked() {
+                        removed = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = removed {
+                points.remove(i);
+            }
+        }
+    }
+
+    // points, width and depth of the canal being drawn and the canals dug so far
+    fn render_canal_tool(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        let drawing = self.water_placement == Some(WaterPlacement::Canal);
+        if drawing || !self.canal_draft.points.is_empty() {
+            ui.label("Canal Width (m):");
+            ui.add(egui::Slider::new(&mut self.canal_draft.width, 1.0..=100.0));
+            ui.label("Canal Depth (m):");
+            ui.add(egui::Slider::new(&mut self.canal_draft.depth, 0.5..=20.0));
+            ui.checkbox(&mut self.canal_draft.smooth, "Smooth Curve")
+                .on_hover_text("Curve through the points instead of straight segments");
+            let limit = |size: u32| size.saturating_sub(1);
+            let (max_x, max_y) = (limit(self.config.width), limit(self.config.height));
+            let mut removed = None;
+            for (i, (x, y)) in self.canal_draft.points.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("Point {}:", i + 1));
+                    ui.add(egui::DragValue::new(x).clamp_range(0..=max_x));
+                    ui.add(egui::DragValue::new(y).clamp_range(0..=max_y));
+                    if ui.small_button("x").clicked() {
+                        removed = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = removed {
+                self.canal_draft.points.remove(i);
+            }
+            ui.horizontal(|ui| {
+                let ready = self.canal_draft.points.len() >= 2 && !self.any_job_running();
+                if ui
+                    .add_enabled(ready, egui::Button::new("Dig Canal"))
+                    .on_hover_text("Water runs towards the lower end")
+                    .clicked()
+                {
+                    self.dig_canal(ctx);
+                }
+                if ui.button("Clear Points").clicked() {
+                    self.canal_draft.points.clear();
+                }
+            });
+        }
+
+        let mut removed = None;
+        for (i, canal) in self.water_config.canals.iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "Canal {}: {} points, {:.0} m wide, {:.1} m deep",
+                    i + 1,
+                    canal.points.len(),
+                    canal.width,
+                    canal.depth
+                ));
+                if ui
+                    .small_button("x")
+                    .on_hover_text("Removed with the next water generation")
+                    .clicked()
+                {
+                    removed = Some(i);
+                }
+            });
+        }
+        if let Some(i) = removed {
+            self.water_config.canals.remove(i);
+        }
+    }
+
+    // keeps the drawn canal for later water runs and digs it into the current water right
+    // away. without any water yet it is dug once the water is generated.
+    fn dig_canal(&mut self, ctx: &egui::Context) {
+        let canal = self.canal_draft.clone();
+        self.canal_draft.points.clear();
+        self.water_config.canals.push(canal.clone());
+        let Some(ocean) = self.ocean_mask().map(<[bool]>::to_vec) else {
+            return;
+        };
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let fits = |map: &&mut Vec<f32>| map.len() == heightmap.len();
+        let (Some(lakes), Some(depths), Some(surface), Some(widths)) = (
+            self.lake_map.as_mut().filter(fits),
+            self.river_depths.as_mut().filter(fits),
+            self.river_surface.as_mut().filter(fits),
+            self.river_widths.as_mut().filter(fits),
+        ) else {
+            println!("Canal saved, it is dug with the next water map");
+            return;
+        };
+        let mut dug = heightmap.clone();
+        let elevation_range = (self.max_elevation - self.min_elevation).max(1.0);
+        let context = WaterContext::new(&self.config, &self.water_config, &ocean, elevation_range);
+        let path = dig_canal(&context, &canal, &mut dug, lakes, depths, surface, widths);
+        let width = dug.width();
+        self.river_lines
+            .push(river_line(path, None, depths, width, elevation_range));
+        self.water_stats = None;
+        self.river_crossings.clear();
+        println!("Dug canal through {} points", canal.points.len());
+        if self.water_config.carve_terrain {
+            // the canal is dug again whenever the water is generated from the uncarved map
+            let base = self.water_base.take();
+            self.replace_heightmap("Dig Canal", dug);
+            self.water_base = base;
+        }
+        self.update_heightmap_preview(ctx);
     }
 
     fn render_water_settings(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        self.render_heightmap_source(ui);
+        if self.water_preview_texture.is_none() {
+            self.update_water_preview(ctx);
+        }
+        // the water is drawn over the new coast without generating it again
+        self.render_sea_level(ui, ctx);
+        self.render_ocean_seed(ui);
         /* water slider ranges */
         ui.checkbox(&mut self.water_config.use_random_seed, "Use Random Seed");
 
@@ -411,36 +3233,60 @@ impl DayZMapApp {
         ui.label("Minimum Elevation:");
         ui.add(
             egui::Slider::new(&mut self.water_config.min_elevation, 0.0..=1.0)
-                .text("Minimum Elevation"),
+                .text("Minimum Elevation (normalized)"),
         );
         ui.label("Maximum Elevation:");
         ui.add(
             egui::Slider::new(&mut self.water_config.max_elevation, 0.0..=1.0)
-                .text("Maximum Elevation"),
+                .text("Maximum Elevation (normalized)"),
         );
         ui.label("Minimum Capacity:");
         ui.add(
             egui::Slider::new(&mut self.water_config.min_capacity, 0.0..=1000000.0)
-                .text("Minimum Capacity"),
+                .text("Minimum Capacity (m³)")
+                .logarithmic(true),
         );
         ui.label("Maximum Capacity:");
         ui.add(
             egui::Slider::new(&mut self.water_config.max_capacity, 0.0..=1000000.0)
-                .text("Maximum Capacity"),
+                .text("Maximum Capacity (m³)")
+                .logarithmic(true),
         );
         ui.label("Minimum Depth:");
         ui.add(
-            egui::Slider::new(&mut self.water_config.min_depth, 0.0..=100.0).text("Minimum Depth"),
+            egui::Slider::new(&mut self.water_config.min_depth, 0.0..=100.0)
+                .text("Minimum Depth (m)"),
+        );
+        ui.label("Maximum Radius:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.max_lake_radius, 1.0..=2000.0)
+                .text("Maximum Radius (px)")
+                .logarithmic(true),
+        );
+        ui.label("Minimum Spacing:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.min_lake_spacing, 0.0..=500.0)
+                .text("Minimum Spacing (px)"),
+        )
+        .on_hover_text("Free cells between the bounding circles of two lakes");
+        ui.label("Maximum Depth:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.max_lake_depth, 0.0..=200.0)
+                .text("Maximum Depth (m)"),
         );
         ui.label("Base Evaporation:");
         ui.add(
             egui::Slider::new(&mut self.water_config.base_evaporation, 0.0..=100.0)
                 .text("Base Evaporation"),
+        )
+        .on_hover_text(
+            "Water lost from the lake surface, lakes stop rising where it matches the inflow",
         );
         ui.label("Base Inflow:");
         ui.add(
             egui::Slider::new(&mut self.water_config.base_inflow, 0.0..=100.0).text("Base Inflow"),
-        );
+        )
+        .on_hover_text("Water reaching a lake from the ground that drains into its basin");
         ui.label("Base Drainage:");
         ui.add(
             egui::Slider::new(&mut self.water_config.base_drainage, 0.0..=100.0)
@@ -450,6 +3296,9 @@ impl DayZMapApp {
         ui.add(
             egui::Slider::new(&mut self.water_config.biome_influence, 0.0..=100.0)
                 .text("Biome Influence"),
+        )
+        .on_hover_text(
+            "How much the biomes shape the water, 0 ignores them. Needs the biomes generated first",
         );
         ui.label("Lake Terrain Modification:");
         ui.add(
@@ -459,15 +3308,113 @@ impl DayZMapApp {
             )
             .text("Lake Terrain Modification"),
         );
+        ui.label("Shore Smoothing:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.shore_smoothing, 0..=8).text("Radius (cells)"),
+        )
+        .on_hover_text("Rounds off ragged shorelines and eases the banks into the water, 0 is off");
+        ui.label("Wetlands:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.wetland_radius, 0.0..=1000.0)
+                .text("Wetland Radius (m)"),
+        )
+        .on_hover_text("How far the ground around lakes and slow rivers stays wet");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.wetland_strength, 0.0..=100.0)
+                .text("Wetland Strength"),
+        );
+        let weights = [
+            (
+                &mut self.water_config.wetness_water_weight,
+                "Near Lakes and Rivers",
+                "How wet the ground next to lakes and slow rivers gets",
+            ),
+            (
+                &mut self.water_config.wetness_ocean_weight,
+                "Near the Sea",
+                "How wet the ground along the coast gets",
+            ),
+            (
+                &mut self.water_config.wetness_flow_weight,
+                "Drained Water",
+                "How wet ground gets that much of the rain drains through",
+            ),
+            (
+                &mut self.water_config.wetness_slope_weight,
+                "Slope Drying",
+                "How much steep ground sheds its water",
+            ),
+        ];
+        for (weight, name, hover) in weights {
+            ui.add(egui::Slider::new(weight, 0.0..=100.0).text(name))
+                .on_hover_text(hover);
+        }
 
         ui.separator();
         ui.heading("River Generation");
+        egui::ComboBox::from_label("River Mode")
+            .selected_text(match self.water_config.river_mode {
+                RiverMode::Particles => "Particles",
+                RiverMode::Drainage => "Drainage Network",
+            })
+            .show_ui(ui, |ui| {
+                let mode = &mut self.water_config.river_mode;
+                ui.selectable_value(mode, RiverMode::Particles, "Particles")
+                    .on_hover_text("Trace single rivers downhill from random high points");
+                ui.selectable_value(mode, RiverMode::Drainage, "Drainage Network")
+                    .on_hover_text("Turn every cell that drains a large enough area into river");
+            });
+        if self.water_config.river_mode == RiverMode::Drainage {
+            ui.add(
+                egui::Slider::new(&mut self.water_config.flow_threshold, 10.0..=100000.0)
+                    .text("Flow Threshold (cells)")
+                    .logarithmic(true),
+            );
+        }
+        ui.checkbox(&mut self.water_config.fill_pits, "Fill Pits Before Routing")
+            .on_hover_text("Rivers find their way over small pits instead of ending in them");
+        ui.checkbox(
+            &mut self.water_config.apply_pit_fill,
+            "Apply Pit Fill to Terrain",
+        )
+        .on_hover_text("Keep the filled terrain, the lakes are placed before the filling");
+        let filling = self.water_config.fill_pits
+            || self.water_config.apply_pit_fill
+            || self.water_config.river_mode == RiverMode::Drainage;
+        if filling {
+            ui.add(
+                egui::Slider::new(&mut self.water_config.fill_epsilon, 0.0..=0.1)
+                    .text("Fill Slope (m per cell)")
+                    .logarithmic(true),
+            );
+        }
         ui.label("River Count:");
         ui.add(egui::Slider::new(&mut self.water_config.river_count, 0..=100).text("River Count"));
+        ui.label("River Sources:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.river_source_min_elevation, 0.0..=1.0)
+                .text("Min Elevation (normalized)"),
+        );
+        ui.add(
+            egui::Slider::new(&mut self.water_config.river_source_max_elevation, 0.0..=1.0)
+                .text("Max Elevation (normalized)"),
+        )
+        .on_hover_text("Random rivers rise between these heights, more often on higher ground");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.river_source_spacing, 0.0..=5000.0)
+                .text("Source Spacing (m)"),
+        );
         ui.label("River Width:");
         ui.add(
-            egui::Slider::new(&mut self.water_config.river_width, 0.0..=100.0).text("River Width"),
+            egui::Slider::new(&mut self.water_config.river_width, 0.0..=100.0)
+                .text("River Width (m)"),
         );
+        ui.label("River Min Width:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.river_min_width, 0.0..=100.0)
+                .text("River Min Width (m)"),
+        )
+        .on_hover_text("Width at the source, rivers widen up to the river width downstream");
         ui.label("River Momentum:");
         ui.add(
             egui::Slider::new(&mut self.water_config.river_momentum, 0.0..=100.0)
@@ -475,12 +3422,22 @@ impl DayZMapApp {
         );
         ui.label("River Direction Variation:");
         ui.add(
-            egui::Slider::new(
-                &mut self.water_config.river_direction_variation,
-                0.0..=100.0,
-            )
-            .text("River Direction Variation"),
+            egui::Slider::new(&mut self.water_config.river_direction_variation, 0.0..=90.0)
+                .text("River Direction Variation (deg)"),
         );
+        ui.label("Meander Length:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.meander_length, 10.0..=5000.0)
+                .text("Meander Length (m)")
+                .logarithmic(true),
+        )
+        .on_hover_text("Distance between two bends of a river");
+        ui.label("Uphill Tolerance:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.river_uphill_tolerance, 0.0..=20.0)
+                .text("Uphill Tolerance (m)"),
+        )
+        .on_hover_text("How far a river may climb over a bump, it is carved through later");
         ui.label("River Speed:");
         ui.add(
             egui::Slider::new(&mut self.water_config.river_speed, 0.0..=100.0).text("River Speed"),
@@ -492,52 +3449,519 @@ impl DayZMapApp {
         );
         ui.label("River Depth:");
         ui.add(
-            egui::Slider::new(&mut self.water_config.river_depth, 0.0..=100.0).text("River Depth"),
+            egui::Slider::new(&mut self.water_config.river_depth, 0.0..=100.0)
+                .text("River Depth (m)"),
+        );
+        ui.label("Valley Width:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.valley_width_mult, 1.0..=20.0)
+                .text("Valley Width (x river)"),
+        )
+        .on_hover_text("1 keeps the banks of the channel without a valley");
+        if self.water_config.valley_width_mult > 1.0 {
+            ui.add(
+                egui::Slider::new(&mut self.water_config.valley_profile, 0.2..=4.0)
+                    .text("Valley Profile"),
+            )
+            .on_hover_text("Below 1 the valley is V-shaped, above 1 it has a wide flat floor");
+        }
+        egui::ComboBox::from_label("River Profile")
+            .selected_text(match self.water_config.river_profile {
+                RiverProfile::Parabolic => "Parabolic",
+                RiverProfile::Trapezoid => "Trapezoid",
+            })
+            .show_ui(ui, |ui| {
+                let profile = &mut self.water_config.river_profile;
+                ui.selectable_value(profile, RiverProfile::Parabolic, "Parabolic");
+                ui.selectable_value(profile, RiverProfile::Trapezoid, "Trapezoid");
+            });
+        ui.label("Estuaries:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.estuary_length, 0.0..=2000.0)
+                .text("Estuary Length (m)"),
+        )
+        .on_hover_text("Stretch before the coast over which a river widens into its mouth");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.estuary_max_width, 0.0..=500.0)
+                .text("Estuary Max Width (m)"),
         );
+        ui.add(
+            egui::Slider::new(&mut self.water_config.estuary_branches, 1..=3)
+                .text("Estuary Branches"),
+        )
+        .on_hover_text("Split the mouth into distributaries with sandbars between them");
+        ui.label("Waterfall Drop:");
+        ui.add(
+            egui::Slider::new(&mut self.water_config.waterfall_drop, 0.5..=100.0)
+                .text("Waterfall Drop (m)")
+                .logarithmic(true),
+        )
+        .on_hover_text("Drop within one or two cells of a river that counts as a waterfall");
+        ui.checkbox(
+            &mut self.water_config.enforce_outlet,
+            "Rivers Must End in Water",
+        )
+        .on_hover_text("Rivers that don't reach the sea, a lake or another river get a remedy");
+        if self.water_config.enforce_outlet {
+            egui::ComboBox::from_label("Dead End Remedy")
+                .selected_text(match self.water_config.dead_end_remedy {
+                    DeadEndRemedy::Pond => "Dig Pond",
+                    DeadEndRemedy::Remove => "Remove River",
+                })
+                .show_ui(ui, |ui| {
+                    let remedy = &mut self.water_config.dead_end_remedy;
+                    ui.selectable_value(remedy, DeadEndRemedy::Pond, "Dig Pond");
+                    ui.selectable_value(remedy, DeadEndRemedy::Remove, "Remove River");
+                });
+        }
+        ui.checkbox(
+            &mut self.water_config.carve_terrain,
+            "Carve Water Into Heightmap",
+        )
+        .on_hover_text("Lower the ground under the water, reruns start from the uncarved map");
 
-        if ui.button("Generate Water Map").clicked() {
-            if let Some(heightmap) = &self.heightmap_data {
-                let mut seed = self.water_config.seed;
-                if self.water_config.use_random_seed {
-                    seed = rand::random::<u32>();
-                    self.water_config.seed = seed;
-                }
-                /*
-                let (color_image, preview, biome)  = generate_biome_map(&self.config, &self.biome_config, heightmap, seed);
-                self.biome_map = Some(biome);
-                self.preview_texture = Some(ctx.load_texture("preview", color_image, egui::TextureOptions::default()));
-                self.preview_image = Some(preview);
-                */
-            } else {
-                ui.label("Please load a heightmap first.");
+        self.render_water_placement(ui);
+        self.render_canal_tool(ui, ctx);
+
+        let has_map = self.heightmap_data.is_some();
+        if !has_map {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                "Generate or load a heightmap before adding water.",
+            );
+        }
+        let idle = !self.any_job_running();
+        if self.water_job.is_none()
+            && ui
+                .add_enabled(has_map && idle, egui::Button::new("Generate Water Map"))
+                .clicked()
+        {
+            self.start_water_job();
+        }
+        if let Some(job) = &self.water_job {
+            job_status(ui, job);
+        }
+        if !self.waterfalls.is_empty() {
+            ui.label(format!("{} waterfalls", self.waterfalls.len()));
+        }
+        if !self.river_junctions.is_empty() {
+            ui.label(format!("{} river junctions", self.river_junctions.len()));
+        }
+        if let Some((accepted, rejected)) = self.lake_counts {
+            ui.label(format!(
+                "Random lakes: {} made, {} attempts too close to a lake",
+                accepted, rejected
+            ));
+        }
+        for &((x, y), reason) in &self.unplaced_lakes {
+            let text = match reason {
+                NoLake::NoBasin => format!("No room for a lake at {}, {}", x, y),
+                NoLake::TooClose => format!("Lake at {}, {} runs into another lake", x, y),
+            };
+            ui.colored_label(egui::Color32::YELLOW, text);
+        }
+        if let Some(cells) = self.small_source_band {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                format!(
+                    "Only {} land cells between the river source elevations, the rivers rose \
+                     anywhere on land",
+                    cells
+                ),
+            );
+        }
+        if let Some((added, removed, area)) = self.shore_change {
+            let net = (added as f64 - removed as f64) / area.max(1) as f64 * 100.0;
+            ui.label(format!(
+                "Shore smoothing: {} cells added, {} removed ({:+.1}% lake area)",
+                added, removed, net
+            ));
+        }
+        if let Some((ponded, removed)) = self.dead_end_rivers {
+            ui.label(format!(
+                "Dead end rivers: {} given a pond, {} removed",
+                ponded, removed
+            ));
+        }
+        if self.lake_map.is_some() {
+            ui.collapsing("Statistics", |ui| self.render_water_statistics(ui));
+        }
+        if !self.river_lines.is_empty() {
+            ui.collapsing("Crossings", |ui| self.render_river_crossings(ui));
+        }
+        // the biomes are made before the water, this brings the swamps to the wet ground
+        if self.wetness.is_some()
+            && self.biome_job.is_none()
+            && ui.button("Update Biomes with Wetlands").clicked()
+        {
+            self.start_biome_job();
+        }
+        if let Some(job) = &self.biome_job {
+            job_status(ui, job);
+        }
+    }
+
+    fn render_object_settings(&mut self, ui: &mut egui::Ui) {
+        /* trees, building densities */
+        ui.collapsing("Flat Sites", |ui| {
+            let settings = &mut self.flat_site_config;
+            ui.add(egui::Slider::new(&mut settings.max_slope, 0.5..=30.0).text("Max Slope (deg)"));
+            let cell_area = self.config.cell_size_m * self.config.cell_size_m;
+            ui.add(
+                egui::Slider::new(&mut settings.min_area, 1..=100_000)
+                    .text("Min Area (px)")
+                    .logarithmic(true),
+            )
+            .on_hover_text(format!(
+                "{:.0} m² at the current cell size",
+                settings.min_area as f64 * cell_area
+            ));
+            ui.checkbox(&mut settings.show_on_preview, "Show on Preview");
+
+            if ui.button("Find Sites").clicked() {
+                if let (Some(heightmap), Some(slope)) = (&self.heightmap_data, &self.slope_map) {
+                    let start = std::time::Instant::now();
+                    self.flat_sites = find_flat_sites(
+                        heightmap,
+                        slope,
+                        self.config.sea_level as f32,
+                        settings.max_slope,
+                        settings.min_area,
+                    );
+                    println!(
+                        "Found {} flat sites in {:.2?}",
+                        self.flat_sites.len(),
+                        start.elapsed()
+                    );
+                } else {
+                    eprintln!("Error: no heightmap to search for flat sites");
+                }
+            }
+            if !self.flat_sites.is_empty() {
+                ui.label(format!("{} sites, largest first:", self.flat_sites.len()));
+                for site in self.flat_sites.iter().take(10) {
+                    ui.label(format!(
+                        "({:.0}, {:.0}): {:.0} m²",
+                        site.centroid.0,
+                        site.centroid.1,
+                        site.area as f64 * cell_area
+                    ));
+                }
+            }
+        });
+    }
+
+    // where the sea is flooded from. on the map border by default, a point in the sea for
+    // maps that are cut out of a larger coast
+    fn render_ocean_seed(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+        let mut from_point = self.config.ocean_seed.is_some();
+        if ui
+            .checkbox(&mut from_point, "Ocean From Point")
+            .on_hover_text("Below sea level counts as sea only where it connects to this point")
+            .changed()
+        {
+            let center = (self.config.width / 2, self.config.height / 2);
+            self.config.ocean_seed = from_point.then_some(center);
+            changed = true;
+        }
+        let max = (
+            self.config.width.saturating_sub(1),
+            self.config.height.saturating_sub(1),
+        );
+        if let Some((x, y)) = &mut self.config.ocean_seed {
+            ui.horizontal(|ui| {
+                ui.label("Ocean X:");
+                changed |= ui
+                    .add(egui::DragValue::new(x).clamp_range(0..=max.0))
+                    .changed();
+                ui.label("Y:");
+                changed |= ui
+                    .add(egui::DragValue::new(y).clamp_range(0..=max.1))
+                    .changed();
+            });
+        }
+        if changed {
+            self.config_changed(ui.ctx());
+        }
+    }
+
+    // searches the rivers for narrow spots with gentle banks, kept for bridges and fords
+    fn render_river_crossings(&mut self, ui: &mut egui::Ui) {
+        let settings = &mut self.crossing_config;
+        ui.add(egui::Slider::new(&mut settings.max_width, 1.0..=200.0).text("Max Width (m)"));
+        ui.add(
+            egui::Slider::new(&mut settings.max_bank_slope, 0.5..=30.0)
+                .text("Max Bank Slope (deg)"),
+        );
+        ui.add(egui::Slider::new(&mut settings.min_spacing, 1..=1000).text("Min Spacing (px)"))
+            .on_hover_text("Crossings closer than this to a better one are dropped");
+        ui.checkbox(&mut settings.show_on_preview, "Show on Preview");
+
+        // the rivers have to come from a water run on a map of the current size
+        let maps = self
+            .lake_map
+            .as_ref()
+            .zip(self.slope_map.as_ref())
+            .filter(|(lake_map, slope)| lake_map.len() == slope.len());
+        if ui
+            .add_enabled(maps.is_some(), egui::Button::new("Find Crossings"))
+            .clicked()
+            && let Some((lake_map, slope)) = maps
+        {
+            self.river_crossings = find_river_crossings(
+                &self.river_lines,
+                lake_map,
+                slope,
+                self.config.cell_size_m,
+                settings,
+            );
+            println!("Found {} river crossings", self.river_crossings.len());
+        }
+        if !self.river_crossings.is_empty() {
+            ui.label(format!(
+                "{} crossings, best first:",
+                self.river_crossings.len()
+            ));
+            for crossing in self.river_crossings.iter().take(10) {
+                ui.label(format!(
+                    "({}, {}): {:.0} m wide, banks {:.1}°",
+                    crossing.position.0, crossing.position.1, crossing.width, crossing.bank_slope
+                ));
             }
         }
     }
 
-    fn render_object_settings(&mut self, _ui: &mut egui::Ui) { /* trees, building densities */
+    // made on first view after a water run and whenever the distance to water changes
+    fn render_water_statistics(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Near Water (m):");
+            ui.add(egui::Slider::new(
+                &mut self.near_water_distance,
+                50.0..=5000.0,
+            ));
+        })
+        .response
+        .on_hover_text("Land within this distance of a lake or river counts as near water");
+        let distance = self.near_water_distance;
+        // a new sea level or ocean seed makes a new ocean mask, which drops the statistics
+        self.ocean_mask();
+        if self
+            .water_stats
+            .as_ref()
+            .is_none_or(|(made_for, _)| *made_for != distance)
+        {
+            let (Some(heightmap), Some((_, _, ocean))) = (&self.heightmap_data, &self.ocean_mask)
+            else {
+                return;
+            };
+            // water made for a map of another size is left out until it is generated again
+            let fits = |map: &&Vec<f32>| map.len() == heightmap.len();
+            let (Some(lake_map), Some(river_depths)) = (
+                self.lake_map.as_ref().filter(fits),
+                self.river_depths.as_ref().filter(fits),
+            ) else {
+                return;
+            };
+            let stats = water_statistics(
+                lake_map,
+                river_depths,
+                &self.river_lines,
+                ocean,
+                (heightmap.width(), heightmap.height()),
+                self.config.cell_size_m,
+                distance,
+            );
+            self.water_stats = Some((distance, stats));
+        }
+        let Some((_, stats)) = &self.water_stats else {
+            return;
+        };
+        ui.label(format!("Lakes: {}", stats.lakes));
+        ui.label(format!("Lake area: {:.3} km²", stats.lake_area));
+        ui.label(format!("Largest lake: {:.3} km²", stats.largest_lake));
+        ui.label(format!("River length: {:.2} km", stats.river_length));
+        ui.label(format!("Rivers reaching the sea: {}", stats.rivers_to_sea));
+        ui.label(format!(
+            "Land near water: {:.1}%",
+            stats.land_near_water * 100.0
+        ));
+    }
+
+    // the river beds are carved into the heightmap like the lake beds, the depth is the water
+    // on top and the surface its height above sea, with no data on dry cells
+    fn render_river_raster_export(&self, ui: &mut egui::Ui) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let fits = |map: &&Vec<f32>| map.len() == heightmap.len();
+        let (Some(depths), Some(surface)) = (
+            self.river_depths.as_ref().filter(fits),
+            self.river_surface.as_ref().filter(fits),
+        ) else {
+            return;
+        };
+        let (width, height) = (heightmap.width(), heightmap.height());
+        let save_dialog = |title: &str, name: String| save_dialog(title, "ASC", "asc", &name);
+        ui.horizontal(|ui| {
+            if ui.button("Export River Depth").clicked() {
+                let filename = format!("river_depth_{}x{}.asc", width, height);
+                if let Some(path) = save_dialog("Save River Depth", filename) {
+                    let path = path.to_string_lossy();
+                    let depth_map = Heightmap::from_vec(depths.clone(), width, height);
+                    if let Err(e) = export_heightmap_to_asc(
+                        &depth_map,
+                        &path,
+                        0.0,
+                        self.max_elevation - self.min_elevation,
+                        self.config.cell_size_m,
+                    ) {
+                        eprintln!("Error exporting river depth: {}", e);
+                    } else {
+                        println!("River depth exported to {}", path);
+                    }
+                }
+            }
+            if ui.button("Export River Surface").clicked() {
+                let filename = format!("river_surface_{}x{}.asc", width, height);
+                if let Some(path) = save_dialog("Save River Surface", filename) {
+                    let path = path.to_string_lossy();
+                    let surface_map = Heightmap::from_vec(surface.clone(), width, height);
+                    if let Err(e) = export_water_surface_to_asc(
+                        &surface_map,
+                        &path,
+                        self.min_elevation,
+                        self.max_elevation,
+                        self.config.cell_size_m,
+                    ) {
+                        eprintln!("Error exporting river surface: {}", e);
+                    } else {
+                        println!("River surface exported to {}", path);
+                    }
+                }
+            }
+        });
+    }
+
+    // depth masks for surface painting, 16 bit and scaled like the heightmap so the depth in
+    // meters comes back as value / 65535 * elevation range
+    fn render_water_mask_export(&self, ui: &mut egui::Ui) {
+        let Some(heightmap) = &self.heightmap_data else {
+            return;
+        };
+        let (width, height) = (heightmap.width(), heightmap.height());
+        let fits = |map: &&Vec<f32>| map.len() == heightmap.len();
+        let lakes = self.lake_depths.as_ref().filter(fits);
+        let rivers = self.river_depths.as_ref().filter(fits);
+        if lakes.is_none() && rivers.is_none() {
+            return;
+        }
+        let range = self.max_elevation - self.min_elevation;
+        let save_dialog = |title: &str, name: String| save_dialog(title, "PNG", "png", &name);
+
+        ui.label(format!(
+            "Water masks: depth (m) = value / 65535 * {:.0}",
+            range
+        ));
+        ui.horizontal(|ui| {
+            let masks = [("Lake", lakes), ("River", rivers)];
+            for (name, depths) in masks {
+                let Some(depths) = depths else {
+                    continue;
+                };
+                if !ui.button(format!("Export {} Mask", name)).clicked() {
+                    continue;
+                }
+                let filename = format!("{}_mask_{}x{}.png", name.to_lowercase(), width, height);
+                let Some(path) = save_dialog(&format!("Save {} Mask", name), filename) else {
+                    continue;
+                };
+                let path = path.to_string_lossy();
+                if let Err(e) = export_depth_mask_to_png(depths, width, height, &path) {
+                    eprintln!("Error exporting {} mask: {}", name.to_lowercase(), e);
+                } else {
+                    println!("{} mask exported to {}", name, path);
+                }
+            }
+
+            if let Some(wetness) = self.wetness.as_ref().filter(fits)
+                && ui.button("Export Wetness").clicked()
+            {
+                let filename = format!("wetness_{}x{}.png", width, height);
+                if let Some(path) = save_dialog("Save Wetness", filename) {
+                    let path = path.to_string_lossy();
+                    if let Err(e) = export_wetness_to_png(wetness, width, height, &path) {
+                        eprintln!("Error exporting wetness: {}", e);
+                    } else {
+                        println!("Wetness exported to {}", path);
+                    }
+                }
+            }
+
+            if let (Some(lakes), Some(rivers)) = (lakes, rivers)
+                && ui.button("Export Combined Mask").clicked()
+            {
+                let filename = format!("water_mask_{}x{}.png", width, height);
+                if let Some(path) = save_dialog("Save Combined Water Mask", filename) {
+                    let path = path.to_string_lossy();
+                    let result = export_water_masks_to_png(lakes, rivers, width, height, &path);
+                    if let Err(e) = result {
+                        eprintln!("Error exporting water mask: {}", e);
+                    } else {
+                        println!("Water mask exported to {} (lakes red, rivers green)", path);
+                    }
+                }
+            }
+        });
     }
 
     fn render_export_panel(&mut self, ui: &mut egui::Ui) {
         ui.label("Export Options");
 
-        if ui.button("Export Preview").clicked() {
-            if let Some(preview) = &self.preview_image {
-                let _ = preview.save("export_preview.png");
+        if ui.button("Export Preview").clicked()
+            && let Some(preview) = &self.preview_image
+        {
+            let _ = preview.save("export_preview.png");
+        }
+
+        let biomes = self.biome_map.as_ref().zip(self.heightmap_data.as_ref());
+        if let Some((ids, heightmap)) = biomes.filter(|(b, h)| b.len() == h.len())
+            && ui
+                .button("Export Biome Map")
+                .on_hover_text("Biome id per pixel, blended borders are dithered between ids")
+                .clicked()
+        {
+            let (width, height) = (heightmap.width(), heightmap.height());
+            let filename = format!("biomes_{}x{}.png", width, height);
+            if let Some(path) = save_dialog("Save Biome Map", "PNG", "png", &filename) {
+                let path = path.to_string_lossy();
+                if let Err(e) = export_biome_ids_to_png(ids, width, height, &path) {
+                    eprintln!("Error exporting biome map: {}", e);
+                } else {
+                    println!("Biome map exported to {}", path);
+                }
             }
         }
 
+        ui.horizontal(|ui| {
+            ui.label("Elevation (m):");
+            let min = ui.add(egui::DragValue::new(&mut self.min_elevation).speed(1.0));
+            ui.label("to");
+            let max = ui.add(egui::DragValue::new(&mut self.max_elevation).speed(1.0));
+            if min.changed() || max.changed() {
+                self.heightmap_changed();
+            }
+        });
+
         if ui.button("Export Heightmap").clicked() {
-            if let (Some(data), w, h) =
-                (&self.heightmap_data, self.config.width, self.config.height)
-            {
-                let filename = format!("heightmap_{}x{}.asc", w, h);
+            if let Some(data) = &self.heightmap_data {
+                let filename = format!("heightmap_{}x{}.asc", data.width(), data.height());
                 if let Err(e) = export_heightmap_to_asc(
                     data,
-                    w,
-                    h,
                     &filename,
-                    0.0,
-                    1000.0,
+                    self.min_elevation,
+                    self.max_elevation,
+                    self.config.cell_size_m,
                 ) {
                     eprintln!("Error exporting heightmap: {}", e);
                 } else {
@@ -547,11 +3971,106 @@ impl DayZMapApp {
                 ui.label("Please generate a heightmap first.");
             }
         }
+
+        // the lake beds are already lowered in the heightmap, this is the water on top
+        let lake_depths = self.lake_depths.as_ref().zip(self.heightmap_data.as_ref());
+        if let Some((depths, heightmap)) = lake_depths.filter(|(d, h)| d.len() == h.len())
+            && ui.button("Export Lake Depth").clicked()
+        {
+            let (width, height) = (heightmap.width(), heightmap.height());
+            let depth_map = Heightmap::from_vec(depths.clone(), width, height);
+            let filename = format!("lake_depth_{}x{}.asc", width, height);
+            if let Err(e) = export_heightmap_to_asc(
+                &depth_map,
+                &filename,
+                0.0,
+                self.max_elevation - self.min_elevation,
+                self.config.cell_size_m,
+            ) {
+                eprintln!("Error exporting lake depth: {}", e);
+            } else {
+                println!("Lake depth exported to {}", filename);
+            }
+        }
+
+        self.render_river_raster_export(ui);
+        self.render_water_mask_export(ui);
+
+        if !self.waterfalls.is_empty()
+            && ui.button("Export Waterfalls").clicked()
+            && let Some(path) = save_dialog("Save Waterfalls", "CSV", "csv", "waterfalls.csv")
+        {
+            let path = path.to_string_lossy();
+            if let Err(e) = export_waterfalls_to_csv(
+                &self.waterfalls,
+                &path,
+                self.config.height,
+                self.config.cell_size_m,
+            ) {
+                eprintln!("Error exporting waterfalls: {}", e);
+            } else {
+                println!("Waterfalls exported to {}", path);
+            }
+        }
+
+        if !self.river_lines.is_empty() {
+            ui.horizontal(|ui| {
+                let (map_height, cell_size) = (self.config.height, self.config.cell_size_m);
+                if ui.button("Export Rivers (GeoJSON)").clicked() {
+                    let path = save_dialog("Save Rivers", "GeoJSON", "geojson", "rivers.geojson");
+                    if let Some(path) = path {
+                        let path = path.to_string_lossy();
+                        let lines = &self.river_lines;
+                        let result = export_rivers_to_geojson(lines, &path, map_height, cell_size);
+                        if let Err(e) = result {
+                            eprintln!("Error exporting rivers: {}", e);
+                        } else {
+                            println!("Rivers exported to {}", path);
+                        }
+                    }
+                }
+                if ui.button("Export Rivers (CSV)").clicked()
+                    && let Some(path) = save_dialog("Save Rivers", "CSV", "csv", "rivers.csv")
+                {
+                    let path = path.to_string_lossy();
+                    let lines = &self.river_lines;
+                    if let Err(e) = export_rivers_to_csv(lines, &path, map_height, cell_size) {
+                        eprintln!("Error exporting rivers: {}", e);
+                    } else {
+                        println!("Rivers exported to {}", path);
+                    }
+                }
+            });
+        }
     }
 }
 
 impl eframe::App for DayZMapApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_jobs(ctx);
+        if self.live_preview && matches!(self.current_step, GenerationStep::Terrain) {
+            self.update_live_preview(ctx);
+        }
+        if self.refine_live && matches!(self.current_step, GenerationStep::Refinement) {
+            self.update_refine_preview(ctx);
+        }
+        if self.any_job_running() {
+            ctx.request_repaint();
+        }
+        if !ctx.wants_keyboard_input() {
+            let (undo, redo) = ctx.input_mut(|i| {
+                let redo_z = egui::Modifiers::COMMAND | egui::Modifiers::SHIFT;
+                (
+                    i.consume_key(egui::Modifiers::COMMAND, egui::Key::Z),
+                    i.consume_key(egui::Modifiers::COMMAND, egui::Key::Y)
+                        || i.consume_key(redo_z, egui::Key::Z),
+                )
+            });
+            if undo || redo {
+                self.step_history(ctx, redo);
+            }
+        }
+
         egui::SidePanel::left("sidebar")
             .resizable(false)
             .show(ctx, |ui| {
@@ -624,14 +4143,95 @@ impl eframe::App for DayZMapApp {
                                 };
                             }
                         }
+
+                        ui.separator();
+                        self.render_history_buttons(ui, ctx);
                     });
                 });
             });
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            if let Some(texture) = &self.preview_texture {
+            if self.heightmap_data.is_some() {
+                ui.horizontal(|ui| {
+                    ui.label("Preview:");
+                    let height =
+                        ui.selectable_value(&mut self.preview_mode, PreviewMode::Height, "Height");
+                    let slope =
+                        ui.selectable_value(&mut self.preview_mode, PreviewMode::Slope, "Slope");
+                    let wetness = self.wetness.is_some()
+                        && ui
+                            .selectable_value(
+                                &mut self.preview_mode,
+                                PreviewMode::Wetness,
+                                "Wetness",
+                            )
+                            .clicked();
+                    if height.clicked() || slope.clicked() || wetness {
+                        self.update_heightmap_preview(ctx);
+                    }
+                    if self.refine_live && matches!(self.current_step, GenerationStep::Refinement) {
+                        ui.separator();
+                        ui.checkbox(&mut self.split_view, "Split View")
+                            .on_hover_text("Compare the map before and after the refinement");
+                    }
+                });
+            }
+            let live = self.live_preview_texture.as_ref().filter(|_| {
+                self.live_preview && matches!(self.current_step, GenerationStep::Terrain)
+            });
+            let refine = self.refine_preview_texture.as_ref().filter(|_| {
+                self.refine_live && matches!(self.current_step, GenerationStep::Refinement)
+            });
+            // the map as it is on the left of the divider, the refinement on the right
+            let split = refine
+                .filter(|_| self.split_view)
+                .zip(self.preview_texture.as_ref());
+            let split_after = split.map(|(after, _)| after.id());
+            let live = live.or(refine);
+            let sea_level = self
+                .sea_level_texture
+                .as_ref()
+                .filter(|_| self.preview_mode == PreviewMode::Height);
+            let water = self
+                .water_preview_texture
+                .as_ref()
+                .filter(|_| matches!(self.current_step, GenerationStep::Water));
+            let texture = match split {
+                Some((_, before)) => Some(before),
+                None => live
+                    .or(sea_level)
+                    .or(water)
+                    .or(self.preview_texture.as_ref()),
+            };
+            if let Some(texture) = texture {
+                if split.is_some() {
+                    ui.label(format!(
+                        "Before | after refinement ({} px), drag to move the divider",
+                        REFINE_PREVIEW_SIZE
+                    ));
+                } else if refine.is_some() {
+                    ui.label(format!(
+                        "Refinement preview ({} px), press 'Apply Refinement' to keep it",
+                        REFINE_PREVIEW_SIZE
+                    ));
+                } else if live.is_some() {
+                    ui.label(format!(
+                        "Live preview ({} px), press 'Generate Map' for full resolution",
+                        LIVE_PREVIEW_SIZE
+                    ));
+                }
                 let available_size = ui.available_size();
-                let image_size = texture.size_vec2();
+                // the live preview is drawn at the size of the full map it approximates
+                let image_size = if live.is_some() {
+                    egui::vec2(self.config.width as f32, self.config.height as f32)
+                } else if let (Some(_), None, Some(full)) =
+                    (sea_level, split, &self.preview_texture)
+                {
+                    // the small copy stands in for the full preview
+                    full.size_vec2()
+                } else {
+                    texture.size_vec2()
+                };
                 let scale = {
                     let w_ratio = available_size.x / image_size.x;
                     let h_ratio = available_size.y / image_size.y;
@@ -639,13 +4239,43 @@ impl eframe::App for DayZMapApp {
                 };
                 let scaled_size = image_size * scale;
 
+                // the full preview reports the pointer so it can be sculpted on
+                let sense = if live.is_some() && split.is_none() {
+                    egui::Sense::hover()
+                } else {
+                    egui::Sense::click_and_drag()
+                };
+                let mut image_response = None;
+
                 // Center the image using manual layout
                 ui.vertical_centered(|ui| {
                     ui.add_space((available_size.y - scaled_size.y).max(0.0) / 2.0); // vertical centering
                     ui.horizontal_centered(|ui| {
-                        ui.image(texture, scaled_size);
+                        image_response =
+                            Some(ui.add(egui::Image::new(texture, scaled_size).sense(sense)));
                     });
                 });
+                let full_preview = live.is_none();
+                if let (Some(after), Some(response)) = (split_after, &image_response) {
+                    self.draw_split_view(ui, response, after);
+                }
+                if let Some(response) = image_response.filter(|_| full_preview) {
+                    self.sculpt_on_preview(ctx, ui, &response);
+                    if self.flat_site_config.show_on_preview
+                        && matches!(self.current_step, GenerationStep::Objects)
+                    {
+                        self.draw_flat_sites(ui, response.rect);
+                    }
+                    if matches!(self.current_step, GenerationStep::Water) {
+                        self.place_water_on_preview(&response);
+                        self.draw_lake_outlets(ui, response.rect);
+                        self.draw_waterfalls(ui, response.rect);
+                        if self.crossing_config.show_on_preview {
+                            self.draw_river_crossings(ui, response.rect);
+                        }
+                        self.draw_water_seeds(ui, response.rect);
+                    }
+                }
             } else {
                 ui.label("Press 'Generate Map' to create a new map preview.");
             }
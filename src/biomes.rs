@@ -1,8 +1,15 @@
-use crate::config::{BiomeConfig, MapConfig};
+use crate::config::{BiomeConfig, BiomeRule, MapConfig};
+use crate::heightmap::Heightmap;
+use crate::jobs::Progress;
+use crate::preview::build_grid_preview;
+use crate::utils::distance_transform;
 use eframe::egui;
 use image::{ImageBuffer, Rgba};
 use noise::{NoiseFn, Perlin, Seedable};
 use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Biome {
@@ -33,77 +40,298 @@ pub fn get_biome_color(biome: Biome) -> (u8, u8, u8) {
     }
 }
 
-pub fn choose_biome(temp: f64, humidity: f64, elev: f32, sea_level: f32, slope: f32) -> Biome {
-    // TODO: this is so messy, please fix ^^
-    if elev < sea_level * 0.8 {
-        Biome::Ocean
-    } else if elev < sea_level {
-        Biome::Beach
-    } else if humidity > 0.7 && temp > 0.7 {
-        if elev > 0.8 {
-            Biome::Mountain
-        } else {
-            Biome::Jungle
+impl Biome {
+    // every biome in the order of their ids
+    pub const ALL: [Biome; 10] = [
+        Biome::Ocean,
+        Biome::Beach,
+        Biome::Plains,
+        Biome::Forest,
+        Biome::Mountain,
+        Biome::Snow,
+        Biome::Desert,
+        Biome::Swamp,
+        Biome::Tundra,
+        Biome::Jungle,
+    ];
+
+    // the biome stored as `id` in the biome map
+    pub fn from_id(id: u8) -> Option<Biome> {
+        Self::ALL.get(id as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Biome::Ocean => "Ocean",
+            Biome::Beach => "Beach",
+            Biome::Plains => "Plains",
+            Biome::Forest => "Forest",
+            Biome::Mountain => "Mountain",
+            Biome::Snow => "Snow",
+            Biome::Desert => "Desert",
+            Biome::Swamp => "Swamp",
+            Biome::Tundra => "Tundra",
+            Biome::Jungle => "Jungle",
         }
-    } else if temp < 0.2 {
-        Biome::Snow
-    } else if slope > 0.5 {
-        Biome::Mountain
-    } else if elev < sea_level * 1.2 {
-        if humidity > 0.7 {
-            if temp > 0.5 {
-                Biome::Jungle
-            } else {
-                Biome::Swamp
-            }
-        } else if humidity > 0.4 {
-            if temp > 0.5 {
-                Biome::Forest
-            } else {
-                Biome::Plains
-            }
-        } else {
-            if temp > 0.7 {
-                Biome::Desert
-            } else {
-                Biome::Plains
-            }
+    }
+
+    pub fn from_name(name: &str) -> Option<Biome> {
+        Self::ALL
+            .into_iter()
+            .find(|b| b.name().eq_ignore_ascii_case(name))
+    }
+}
+
+// rough temperature and humidity of a biome, from 0 to 1
+pub fn biome_climate(biome: Biome) -> (f32, f32) {
+    match biome {
+        Biome::Ocean => (0.5, 1.0),
+        Biome::Beach => (0.6, 0.5),
+        Biome::Plains => (0.5, 0.5),
+        Biome::Forest => (0.45, 0.7),
+        Biome::Mountain => (0.3, 0.5),
+        Biome::Snow => (0.05, 0.4),
+        Biome::Desert => (0.9, 0.05),
+        Biome::Swamp => (0.6, 0.9),
+        Biome::Tundra => (0.15, 0.3),
+        Biome::Jungle => (0.85, 0.95),
+    }
+}
+
+// chance a lake forms in a basin of the biome, wet ground holds water and sand doesn't
+pub fn biome_lake_chance(biome: Biome) -> f32 {
+    match biome {
+        Biome::Swamp | Biome::Jungle => 1.0,
+        Biome::Forest => 0.8,
+        Biome::Plains => 0.5,
+        Biome::Mountain | Biome::Tundra => 0.4,
+        Biome::Ocean | Biome::Beach | Biome::Snow => 0.3,
+        Biome::Desert => 0.02,
+    }
+}
+
+// row of the default table in the order of BiomeRule: biome, priority and the elevation,
+// temperature, humidity and slope ranges
+type Row = (Biome, i32, (f32, f32), (f32, f32), (f32, f32), (f32, f32));
+
+// the classification the generator always had. the first rules cover the sea, the coast, the
+// cold and the cliffs, the rest splits the lowlands, the hills and the highlands by climate.
+// elevations are relative to the sea level, so the one absolute cutoff of the old code (the
+// hot and humid mountains above 0.8) only matches it at the default sea level of 0.4.
+pub fn default_biome_rules() -> Vec<BiomeRule> {
+    const ANY: (f32, f32) = (f32::NEG_INFINITY, f32::INFINITY);
+    const fn below(max: f32) -> (f32, f32) {
+        (f32::NEG_INFINITY, max)
+    }
+    const fn above(min: f32) -> (f32, f32) {
+        (min, f32::INFINITY)
+    }
+    let rows: [Row; 20] = [
+        (Biome::Ocean, 100, below(0.8), ANY, ANY, ANY),
+        (Biome::Beach, 90, below(1.0), ANY, ANY, ANY),
+        (Biome::Snow, 80, ANY, below(0.2), ANY, ANY),
+        (Biome::Mountain, 70, ANY, ANY, ANY, above(35.0)),
+        // hot and humid, mountain from twice the sea level up (0.8 at the default sea level)
+        (Biome::Mountain, 61, above(2.0), above(0.7), above(0.7), ANY),
+        (Biome::Jungle, 60, ANY, above(0.7), above(0.7), ANY),
+        // lowlands
+        (Biome::Jungle, 50, below(1.2), above(0.5), above(0.7), ANY),
+        (Biome::Swamp, 50, below(1.2), below(0.5), above(0.7), ANY),
+        (Biome::Forest, 50, below(1.2), above(0.5), (0.4, 0.7), ANY),
+        (Biome::Plains, 50, below(1.2), below(0.5), (0.4, 0.7), ANY),
+        (Biome::Desert, 50, below(1.2), above(0.7), below(0.4), ANY),
+        (Biome::Plains, 50, below(1.2), below(0.7), below(0.4), ANY),
+        // hills
+        (Biome::Mountain, 40, below(1.5), above(0.5), above(0.5), ANY),
+        (Biome::Tundra, 40, below(1.5), below(0.5), above(0.5), ANY),
+        (Biome::Desert, 40, below(1.5), above(0.7), below(0.5), ANY),
+        (Biome::Forest, 40, below(1.5), below(0.7), below(0.5), ANY),
+        // highlands
+        (Biome::Snow, 30, ANY, below(0.3), ANY, ANY),
+        (Biome::Mountain, 30, ANY, (0.3, 0.5), ANY, ANY),
+        (Biome::Forest, 30, ANY, (0.5, 0.7), ANY, ANY),
+        (Biome::Desert, 30, ANY, above(0.7), ANY, ANY),
+    ];
+    rows.into_iter()
+        .map(
+            |(biome, priority, elevation, temperature, humidity, slope)| BiomeRule {
+                biome,
+                priority,
+                elevation,
+                temperature,
+                humidity,
+                slope,
+            },
+        )
+        .collect()
+}
+
+// biome of the highest priority rule matching the cell, see BiomeRule. `slope` is in degrees.
+pub fn choose_biome(
+    config: &BiomeConfig,
+    temp: f64,
+    humidity: f64,
+    elev: f32,
+    sea_level: f32,
+    slope: f32,
+) -> Biome {
+    let elevation = elev / sea_level.max(1e-6);
+    let (temp, humidity) = (temp as f32, humidity as f32);
+    let within = |(min, max): (f32, f32), value: f32| value >= min && value < max;
+    config
+        .biome_rules
+        .iter()
+        .filter(|rule| {
+            within(rule.elevation, elevation)
+                && within(rule.temperature, temp)
+                && within(rule.humidity, humidity)
+                && within(rule.slope, slope)
+        })
+        .min_by_key(|rule| std::cmp::Reverse(rule.priority))
+        .map_or(config.fallback_biome, |rule| rule.biome)
+}
+
+const RULES_HEADER: &str = "biome,priority,elevation_min,elevation_max,temperature_min,\
+temperature_max,humidity_min,humidity_max,slope_min,slope_max";
+
+// the table as csv with one rule per line. unbounded ends are written as inf and -inf.
+pub fn save_biome_rules(path: &Path, rules: &[BiomeRule], fallback: Biome) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "fallback,{}", fallback.name())?;
+    writeln!(writer, "{}", RULES_HEADER)?;
+    for rule in rules {
+        let ranges = [rule.elevation, rule.temperature, rule.humidity, rule.slope];
+        write!(writer, "{},{}", rule.biome.name(), rule.priority)?;
+        for (min, max) in ranges {
+            write!(writer, ",{},{}", min, max)?;
         }
-    } else if elev < sea_level * 1.5 {
-        if humidity > 0.5 {
-            if temp > 0.5 {
-                Biome::Mountain
-            } else {
-                Biome::Tundra
-            }
-        } else {
-            if temp > 0.7 {
-                Biome::Desert
-            } else {
-                Biome::Forest
-            }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+// reads a table written by save_biome_rules, returns the rules and the fallback biome
+pub fn load_biome_rules(path: &Path) -> io::Result<(Vec<BiomeRule>, Biome)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let biome = |name: &str| {
+        Biome::from_name(name.trim()).ok_or_else(|| invalid(format!("unknown biome '{}'", name)))
+    };
+    let mut rules = Vec::new();
+    let mut fallback = Biome::Plains;
+    let text = std::fs::read_to_string(path)?;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line == RULES_HEADER {
+            continue;
         }
-    } else {
-        if temp < 0.3 {
-            Biome::Snow
-        } else if temp < 0.5 {
-            Biome::Mountain
-        } else if temp < 0.7 {
-            Biome::Forest
-        } else {
-            Biome::Desert
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if let ["fallback", name] = fields[..] {
+            fallback = biome(name)?;
+            continue;
+        }
+        if fields.len() != 10 {
+            let found = fields.len();
+            return Err(invalid(format!(
+                "line {}: expected 10 fields, found {}",
+                number + 1,
+                found
+            )));
         }
+        let value = |k: usize| {
+            fields[k].parse::<f32>().map_err(|_| {
+                invalid(format!(
+                    "line {}: invalid number '{}'",
+                    number + 1,
+                    fields[k]
+                ))
+            })
+        };
+        let priority = fields[1].parse::<i32>().map_err(|_| {
+            invalid(format!(
+                "line {}: invalid priority '{}'",
+                number + 1,
+                fields[1]
+            ))
+        })?;
+        rules.push(BiomeRule {
+            biome: biome(fields[0])?,
+            priority,
+            elevation: (value(2)?, value(3)?),
+            temperature: (value(4)?, value(5)?),
+            humidity: (value(6)?, value(7)?),
+            slope: (value(8)?, value(9)?),
+        });
     }
+    Ok((rules, fallback))
 }
 
+// degrees celsius covered by the temperature scale from 0 to 1
+const TEMPERATURE_SPAN: f32 = 50.0;
+
+// shift of the temperature (0 to 1 scale) at the relative row `t` from the top edge, from the
+// latitude gradient. it averages out to 0 over the map without an equator.
+fn latitude_offset(biome_config: &BiomeConfig, t: f32) -> f64 {
+    let gradient = biome_config.latitude_gradient / TEMPERATURE_SPAN;
+    let offset = if biome_config.use_equator {
+        let equator = biome_config.equator_position.clamp(0.0, 1.0);
+        let furthest = equator.max(1.0 - equator).max(1e-6);
+        gradient * (0.5 - (t - equator).abs() / furthest)
+    } else {
+        gradient * (t - 0.5)
+    };
+    offset as f64
+}
+
+// wetness from the water step (0 to 1) above which land turns into swamp
+const SWAMP_WETNESS: f32 = 0.3;
+// how far the temperature and humidity (0 to 1) and the height of land cells are shifted
+// per cell at full blend, which dithers the biomes across their borders
+const BLEND_CLIMATE_SHIFT: f64 = 0.15;
+const BLEND_HEIGHT_SHIFT: f32 = 0.05;
+
+// white noise between -1 and 1, the same for the same cell, seed and channel
+fn cell_noise(x: u32, y: u32, seed: u32, channel: u32) -> f64 {
+    let mut h = x.wrapping_mul(0x9E37_79B1)
+        ^ y.wrapping_mul(0x85EB_CA77)
+        ^ seed.wrapping_mul(0xC2B2_AE3D)
+        ^ channel.wrapping_mul(0x27D4_EB2F);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2C1B_3C6D);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297A_2D39);
+    h ^= h >> 15;
+    h as f64 / u32::MAX as f64 * 2.0 - 1.0
+}
+
+// preview texture, preview image and the biome id per pixel. with a blend factor the cells
+// along a border are dithered, each one still holds a single biome.
+pub type BiomeMap = (egui::ColorImage, ImageBuffer<Rgba<u8>, Vec<u8>>, Vec<u8>);
+
+// the maps the biomes are picked from. `wetness` may be empty before any water is made.
+pub struct BiomeTerrain<'a> {
+    pub heightmap: &'a Heightmap,
+    pub slope_map: &'a Heightmap,
+    pub wetness: &'a [f32],
+    pub ocean_mask: &'a [bool],
+}
+
+// `elevation_range` is the height of the heightmap in meters, which the lapse rate cools
 pub fn generate_biome_map(
     map_config: &MapConfig,
     biome_config: &BiomeConfig,
-    heightmap: &[f32],
+    terrain: &BiomeTerrain,
+    elevation_range: f32,
     seed: u32,
-) -> (egui::ColorImage, ImageBuffer<Rgba<u8>, Vec<u8>>, Vec<u8>) {
-    let width = map_config.width;
-    let height = map_config.height;
+    progress: &Progress,
+) -> Option<BiomeMap> {
+    let BiomeTerrain {
+        heightmap,
+        slope_map,
+        wetness,
+        ocean_mask,
+    } = *terrain;
+    let width = heightmap.width();
+    let height = heightmap.height();
     let size = (width * height) as usize;
 
     let sea_level = map_config.sea_level.clamp(0.0, 1.0) as f32;
@@ -111,7 +339,7 @@ pub fn generate_biome_map(
     let perlin_temp: Perlin = Perlin::new().set_seed(seed);
     let perlin_hum: Perlin = Perlin::new().set_seed(seed + 2000);
 
-    let avg_temp = ((biome_config.base_temperature + 10.0) / 50.0).clamp(0.0, 1.0);
+    let avg_temp = ((biome_config.base_temperature + 10.0) / TEMPERATURE_SPAN).clamp(0.0, 1.0);
     let avg_hum = (biome_config.base_humidity / 100.0).clamp(0.0, 1.0);
     let temp_variation = (biome_config.temperature_variation / 100.0).clamp(0.0, 1.0);
     let hum_variation = (biome_config.humidity_variation / 100.0).clamp(0.0, 1.0);
@@ -120,88 +348,256 @@ pub fn generate_biome_map(
     let max_temp = (avg_temp + temp_variation) as f64;
     let min_hum = (avg_hum - hum_variation) as f64;
     let max_hum = (avg_hum + hum_variation) as f64;
+    let blend = (biome_config.biome_blend_factor / 100.0).clamp(0.0, 1.0) as f64;
+    // cooling per normalized height above the sea, on the 0 to 1 temperature scale
+    let lapse = (biome_config.lapse_rate / 1000.0 * elevation_range / TEMPERATURE_SPAN) as f64;
+    // distance to the open sea in meters, only needed when the sea makes the air moist
+    let coast_boost = (biome_config.coastal_humidity_boost / 100.0).max(0.0);
+    let coast_range = biome_config.coastal_humidity_range.max(1.0);
+    let coast_distance = if coast_boost > 0.0 && ocean_mask.len() == size {
+        let cell_size = map_config.cell_size_m as f32;
+        let distance = distance_transform(ocean_mask, width, height);
+        distance.into_iter().map(|d| d * cell_size).collect()
+    } else {
+        Vec::new()
+    };
+
+    let start = std::time::Instant::now();
+    let mut biome_ids = vec![0u8; size];
+
+    progress.set_total(height as usize);
 
-    // Move ownership of the preview image and biome IDs into the mutex.
-    let preview_buf = std::sync::Mutex::new(ImageBuffer::new(width, height));
-    let biome_ids_buf = std::sync::Mutex::new(vec![0u8; size]);
-
-    (0..height).into_par_iter().for_each(|y| {
-        let mut row_biomes = Vec::with_capacity(width as usize);
-        let mut row_colors = Vec::with_capacity(width as usize);
-        let ny = y as f64;
-
-        for x in 0..width {
-            let idx = (y * width + x) as usize;
-            let h = heightmap[idx];
-            let nx = x as f64;
-
-            // Calculate slope with neighboring pixels.
-            let mut slope = 0.0;
-            if false && x > 0 && y > 0 && x < width - 1 && y < height - 1 {
-                let left = heightmap[idx - 1];
-                let right = heightmap[idx + 1];
-                let up = heightmap[idx - width as usize];
-                let down = heightmap[idx + width as usize];
-
-                slope = ((left - h).abs() + (right - h).abs() + (up - h).abs() + (down - h).abs())
-                    / 4.0;
-                slope *= 1000.0;
-
-                let angle_rad = slope.atan2(1.0);
-                slope = (angle_rad / std::f32::consts::PI * 2.0).clamp(0.0, 1.0);
+    // every row writes its own slice of the ids, so the workers never wait on each other
+    let w = width.max(1) as usize;
+    biome_ids
+        .par_chunks_mut(w)
+        .enumerate()
+        .for_each(|(y, row_ids)| {
+            if progress.is_cancelled() {
+                return;
             }
+            let y = y as u32;
+            let ny = y as f64;
+            let latitude = latitude_offset(biome_config, y as f32 / (height - 1).max(1) as f32);
 
-            // Generate temperature and humidity based on perlin noise.
-            let mut temp =
-                (perlin_temp.get([nx / biome_config.scale, ny / biome_config.scale]) + 1.0) / 2.0;
-            let mut humidity =
-                (perlin_hum.get([nx / biome_config.scale, ny / biome_config.scale]) + 1.0) / 2.0;
+            for x in 0..width {
+                let h = heightmap.get(x, y);
+                let nx = x as f64;
 
-            temp = temp * (max_temp - min_temp) + min_temp;
-            humidity = humidity * (max_hum - min_hum) + min_hum;
+                // Generate temperature and humidity based on perlin noise.
+                let mut temp =
+                    (perlin_temp.get([nx / biome_config.scale, ny / biome_config.scale]) + 1.0)
+                        / 2.0;
+                let mut humidity =
+                    (perlin_hum.get([nx / biome_config.scale, ny / biome_config.scale]) + 1.0)
+                        / 2.0;
 
-            let biome = choose_biome(temp, humidity, h, sea_level, slope);
-            let color = get_biome_color(biome); // Returns (u8, u8, u8)
+                temp = temp * (max_temp - min_temp) + min_temp + latitude;
+                temp -= (h - sea_level).max(0.0) as f64 * lapse;
+                humidity = humidity * (max_hum - min_hum) + min_hum;
+                let i = (y * width + x) as usize;
+                if let Some(&distance) = coast_distance.get(i) {
+                    humidity += (coast_boost * (1.0 - distance / coast_range).max(0.0)) as f64;
+                }
 
-            row_biomes.push(biome);
-            row_colors.push(color);
-        }
+                // ground soaked by the lakes and rivers, empty until the water is generated
+                let wet = wetness.get(i).copied().unwrap_or(0.0);
+                humidity = (humidity + wet as f64).min(1.0);
 
-        // Lock and update the preview image and biome IDs.
-        let mut preview_lock = preview_buf.lock().unwrap();
-        let mut biome_ids_lock = biome_ids_buf.lock().unwrap();
-        for x in 0..width {
-            let i = (y * width + x) as usize;
-            biome_ids_lock[i] = row_biomes[x as usize] as u8;
-            preview_lock.put_pixel(
-                x,
-                y,
-                Rgba([
-                    row_colors[x as usize].0,
-                    row_colors[x as usize].1,
-                    row_colors[x as usize].2,
-                    255,
-                ]),
-            );
-        }
+                // near a border a cell may fall on either side of it, the wider the blend the
+                // further from the border. the coast stays where it is.
+                let mut elev = h;
+                if blend > 0.0 {
+                    temp += cell_noise(x, y, seed, 0) * blend * BLEND_CLIMATE_SHIFT;
+                    humidity += cell_noise(x, y, seed, 1) * blend * BLEND_CLIMATE_SHIFT;
+                    if h >= sea_level {
+                        let shift = cell_noise(x, y, seed, 2) * blend;
+                        elev = (h + shift as f32 * BLEND_HEIGHT_SHIFT).max(sea_level);
+                    }
+                }
+
+                // slope in degrees from the analysis step
+                let slope = slope_map.get(x, y);
+                let mut biome = choose_biome(biome_config, temp, humidity, elev, sea_level, slope);
+                if wet > SWAMP_WETNESS
+                    && !matches!(
+                        biome,
+                        Biome::Ocean | Biome::Beach | Biome::Snow | Biome::Mountain
+                    )
+                {
+                    biome = Biome::Swamp;
+                }
+                // below the sea level but cut off from the sea, a basin that holds fresh
+                // water instead
+                if h < sea_level && !ocean_mask.get(i).copied().unwrap_or(true) {
+                    biome = Biome::Swamp;
+                }
+                row_ids[x as usize] = biome as u8;
+            }
+            progress.add(1);
+        });
+
+    if progress.is_cancelled() {
+        return None;
+    }
+
+    println!(
+        "Biome map {}x{} generated in {:.2?} on {} threads",
+        width,
+        height,
+        start.elapsed(),
+        rayon::current_num_threads()
+    );
+
+    // the preview is sampled down like the heightmap preview, the ids keep every pixel
+    let (color_image, preview) = build_grid_preview(width, height, |x, y| {
+        let id = biome_ids[(y * width + x) as usize];
+        Biome::from_id(id).map_or((0, 0, 0), get_biome_color)
     });
+    Some((color_image, preview, biome_ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::compute_slope_map;
+
+    // biome ids and slope of `heightmap` on the default map without any water
+    fn classify(
+        heightmap: &Heightmap,
+        biome_config: &BiomeConfig,
+        elevation_range: f32,
+    ) -> (Vec<u8>, Heightmap) {
+        let map_config = MapConfig::default();
+        let slope = compute_slope_map(heightmap, map_config.cell_size_m, elevation_range);
+        let ocean = vec![false; heightmap.len()];
+        let terrain = BiomeTerrain {
+            heightmap,
+            slope_map: &slope,
+            wetness: &[],
+            ocean_mask: &ocean,
+        };
+        let (_, _, ids) = generate_biome_map(
+            &map_config,
+            biome_config,
+            &terrain,
+            elevation_range,
+            7,
+            &Progress::default(),
+        )
+        .unwrap();
+        (ids, slope)
+    }
 
-    // Extract the values from the mutexes.
-    let preview = preview_buf.into_inner().unwrap();
-    let biome_ids = biome_ids_buf.into_inner().unwrap();
-
-    let pixels = preview
-        .pixels()
-        .map(|p| egui::Color32::from_rgb(p[0], p[1], p[2]))
-        .collect();
-
-    let size_arr = [width as usize, height as usize];
-    (
-        egui::ColorImage {
-            size: size_arr,
-            pixels,
-        },
-        preview,
-        biome_ids,
-    )
+    #[test]
+    fn steep_cells_of_a_ramp_are_mountain() {
+        // a gentle ramp of about 6 degrees that turns into a cliff halfway across
+        let (width, height) = (64, 8);
+        let data = (0..width * height)
+            .map(|i| {
+                let x = (i % width) as f32;
+                0.5 + x.min(32.0) * 0.0001 + (x - 32.0).max(0.0) * 0.01
+            })
+            .collect();
+        let heightmap = Heightmap::from_vec(data, width, height);
+        // dry enough that the hills are forest
+        let biome_config = BiomeConfig {
+            base_humidity: 30.0,
+            biome_blend_factor: 0.0,
+            ..BiomeConfig::default()
+        };
+        let (ids, slope) = classify(&heightmap, &biome_config, 1000.0);
+
+        let threshold = biome_config
+            .biome_rules
+            .iter()
+            .find(|rule| rule.biome == Biome::Mountain && rule.slope.0 > 0.0)
+            .unwrap()
+            .slope
+            .0;
+        let (steep, gentle): (Vec<usize>, Vec<usize>) =
+            (0..ids.len()).partition(|&i| slope.data()[i] >= threshold);
+        assert!(steep.len() > ids.len() / 3);
+        assert!(steep.iter().all(|&i| ids[i] == Biome::Mountain as u8));
+        assert!(gentle.iter().all(|&i| ids[i] != Biome::Mountain as u8));
+    }
+
+    #[test]
+    fn lapse_rate_snows_over_a_warm_peak() {
+        // a cone from the sea level up to 1800 m above it
+        let size = 65;
+        let data = (0..size * size)
+            .map(|i| {
+                let (x, y) = ((i % size) as f32 - 32.0, (i / size) as f32 - 32.0);
+                (1.0 - x.hypot(y) * 0.02).max(0.4)
+            })
+            .collect();
+        let heightmap = Heightmap::from_vec(data, size, size);
+        let summit = (32 * size + 32) as usize;
+        let foot = 0;
+        let warm = BiomeConfig {
+            base_temperature: 25.0,
+            temperature_variation: 0.0,
+            biome_blend_factor: 0.0,
+            ..BiomeConfig::default()
+        };
+
+        let (flat, _) = classify(&heightmap, &warm, 3000.0);
+        assert_ne!(flat[summit], Biome::Snow as u8);
+
+        let cooled = BiomeConfig {
+            lapse_rate: 15.0,
+            ..warm
+        };
+        let (ids, _) = classify(&heightmap, &cooled, 3000.0);
+        assert_eq!(ids[summit], Biome::Snow as u8);
+        assert_ne!(ids[foot], Biome::Snow as u8);
+    }
+
+    // a benchmark rather than a test, run it with
+    // cargo test --release biome_map_scales -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn biome_map_scales_with_the_threads() {
+        let size = 4096;
+        let data = (0..size * size)
+            .map(|i| ((i % size) as f32 * 0.01).sin() * ((i / size) as f32 * 0.013).cos())
+            .map(|h| 0.5 + 0.4 * h)
+            .collect();
+        let heightmap = Heightmap::from_vec(data, size, size);
+        let map_config = MapConfig::default();
+        let biome_config = BiomeConfig::default();
+        let slope = compute_slope_map(&heightmap, map_config.cell_size_m, 1000.0);
+        let ocean = vec![false; heightmap.len()];
+        let time = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let start = std::time::Instant::now();
+            pool.install(|| {
+                let terrain = BiomeTerrain {
+                    heightmap: &heightmap,
+                    slope_map: &slope,
+                    wetness: &[],
+                    ocean_mask: &ocean,
+                };
+                generate_biome_map(
+                    &map_config,
+                    &biome_config,
+                    &terrain,
+                    1000.0,
+                    7,
+                    &Progress::default(),
+                )
+            });
+            start.elapsed()
+        };
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let (one, all) = (time(1), time(threads));
+        println!(
+            "biome map {size}x{size}: {one:.2?} on 1 thread, {all:.2?} on {threads}, {:.1}x",
+            one.as_secs_f64() / all.as_secs_f64()
+        );
+    }
 }
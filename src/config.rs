@@ -1,21 +1,207 @@
-#[derive(Debug, Clone)]
+use crate::biomes::{Biome, default_biome_rules};
+use crate::heightmap::Heightmap;
+use std::sync::Arc;
+
+// elevation in meters that a normalized height of 1.0 corresponds to on export
+pub const DEFAULT_MAX_ELEVATION: f32 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseStack {
+    // the original base/mid/detail layers
+    Classic,
+    // fractional brownian motion over configurable octaves
+    Fbm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseMode {
+    Standard,
+    // 1 - |noise|, sharp connected ridgelines
+    Ridged,
+    // |noise|, rounded hills with creased valleys
+    Billow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseAlgorithm {
+    Perlin,
+    OpenSimplex,
+    Value,
+    // cellular noise, every cell has a constant height which gives mesa-like plateaus
+    Worley,
+}
+
+// one layer of the classic noise stack
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoiseLayer {
+    pub scale: f64,
+    pub amplitude: f64,
+    // added to the master seed when the layers are seeded independently
+    pub seed_offset: u32,
+    pub algorithm: NoiseAlgorithm,
+    pub enabled: bool,
+}
+
+impl NoiseLayer {
+    pub fn new(scale: f64, amplitude: f64, seed_offset: u32) -> Self {
+        Self {
+            scale,
+            amplitude,
+            seed_offset,
+            algorithm: NoiseAlgorithm::Perlin,
+            enabled: true,
+        }
+    }
+}
+
+// the original base/mid/detail layers
+pub fn default_noise_layers() -> Vec<NoiseLayer> {
+    vec![
+        NoiseLayer::new(400.0, 1.0, 0),
+        NoiseLayer::new(100.0, 0.5, 1),
+        NoiseLayer::new(25.0, 0.15, 2),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IslandShape {
+    Square,
+    // the original square falloff that adds up both edges, gives sharp cut corners
+    SquareAdditive,
+    Radial,
+    Superellipse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverlayMode {
+    Lerp,
+    Add,
+    Multiply,
+    Screen,
+    Max,
+    Min,
+}
+
+// mirrors the generated half or quadrant onto the rest of the map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Symmetry {
+    None,
+    MirrorX,
+    MirrorY,
+    Quad,
+    Rotational180,
+}
+
+// rectangle of the map that gets regenerated, the rest keeps its heights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    // distance in pixels over which the new terrain fades into the old one
+    pub feather: u32,
+}
+
+impl Default for Region {
+    fn default() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: 256,
+            height: 256,
+            feather: 32,
+        }
+    }
+}
+
+// part of the map that is kept when cropping
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    // fade the new borders into the sea with the island falloff
+    pub taper_edges: bool,
+}
+
+impl Default for Crop {
+    fn default() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: 256,
+            height: 256,
+            taper_edges: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MapConfig {
     pub width: u32,
     pub height: u32,
-    pub scale_base: f64,
-    pub amp_base: f64,
-    pub scale_mid: f64,
-    pub amp_mid: f64,
-    pub scale_detail: f64,
-    pub amp_detail: f64,
+    // grid spacing in meters, real DayZ terrains often use e.g. 4096 cells at 7.5m
+    pub cell_size_m: f64,
+    // interpret noise scales (and other horizontal lengths) in meters instead of pixels
+    pub scales_in_meters: bool,
+    // layers of the classic stack, the first enabled one gets the mountainous shaping
+    pub layers: Vec<NoiseLayer>,
+    pub noise_stack: NoiseStack,
+    pub noise_mode: NoiseMode,
+    pub fbm_algorithm: NoiseAlgorithm,
+    // derive a separate seed per noise layer instead of sampling one pattern at all scales
+    pub independent_layer_seeds: bool,
+    pub tectonic_strength: f64,
+    pub plate_count: u32,
+    pub plate_elevation_variance: f64,
+    pub terrace_steps: u32,
+    pub terrace_sharpness: f64,
+    pub warp_strength: f64,
+    pub warp_scale: f64,
+    pub octaves: u32,
+    pub lacunarity: f64,
+    pub persistence: f64,
+    pub base_frequency: f64,
     pub seed: u32,
     pub use_random_seed: bool,
+    pub tileable: bool,
+    pub symmetry: Symmetry,
     pub island_mode: bool,
     pub island_border: f64,
     pub island_curve: f64,
+    pub island_shape: IslandShape,
+    pub island_exponent: f64,
+    pub coast_noise_scale: f64,
+    pub coast_noise_strength: f64,
+    // grayscale image used to shape the landmass, kept at its original resolution. shared
+    // so cloning the config for previews and jobs doesn't copy the image.
+    pub shape_mask: Option<Arc<Heightmap>>,
+    pub shape_mask_blend: f64,
     pub sea_level: f64,
+    // pixel in the sea the ocean is flooded from, for maps that don't have sea all around.
+    // the ocean starts at the map border when none is set.
+    pub ocean_seed: Option<(u32, u32)>,
+    // continental shelf, widths in pixels and depths below sea level
+    pub continental_shelf: bool,
+    pub shelf_width: f64,
+    pub shelf_depth: f64,
+    pub abyss_depth: f64,
+    // pulls the land near the coast down onto a gentle walkable gradient (in percent)
+    pub beach_width_px: f64,
+    pub beach_strength: f64,
+    pub beach_slope: f64,
+    // extra high frequency noise on the sea floor, fading out towards the shore
+    pub sea_floor_detail: f64,
+    pub sea_floor_scale: f64,
+    pub sea_floor_fade_px: f64,
     pub mountainous: f64,
+    // flattening of low terrain between sea level and the threshold into buildable plains
+    pub plains_threshold: f64,
+    pub plains_strength: f64,
     pub overlay: f64,
+    pub overlay_mode: OverlayMode,
 }
 
 impl Default for MapConfig {
@@ -23,52 +209,365 @@ impl Default for MapConfig {
         Self {
             width: 512,
             height: 512,
+            cell_size_m: 1.0,
+            scales_in_meters: false,
             seed: 12345,
             use_random_seed: true,
+            tileable: false,
+            symmetry: Symmetry::None,
             island_mode: true,
             island_border: 0.1,
             island_curve: 2.0,
+            island_shape: IslandShape::Square,
+            island_exponent: 4.0,
+            coast_noise_scale: 150.0,
+            coast_noise_strength: 0.0,
+            shape_mask: None,
+            shape_mask_blend: 1.0,
             sea_level: 0.4,
-            scale_base: 400.0,
-            amp_base: 1.0,
-            scale_mid: 100.0,
-            amp_mid: 0.5,
-            scale_detail: 25.0,
-            amp_detail: 0.15,
+            ocean_seed: None,
+            continental_shelf: false,
+            shelf_width: 40.0,
+            shelf_depth: 0.05,
+            abyss_depth: 0.3,
+            beach_width_px: 30.0,
+            beach_strength: 0.0,
+            beach_slope: 2.0,
+            sea_floor_detail: 0.0,
+            sea_floor_scale: 20.0,
+            sea_floor_fade_px: 20.0,
+            layers: default_noise_layers(),
+            // the classic layers stay the default so existing seeds still reproduce
+            noise_stack: NoiseStack::Classic,
+            noise_mode: NoiseMode::Standard,
+            fbm_algorithm: NoiseAlgorithm::Perlin,
+            independent_layer_seeds: true,
+            tectonic_strength: 0.0,
+            plate_count: 12,
+            plate_elevation_variance: 0.3,
+            terrace_steps: 8,
+            terrace_sharpness: 0.0,
+            warp_strength: 0.0,
+            warp_scale: 200.0,
+            octaves: 5,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            base_frequency: 1.0 / 400.0,
             mountainous: 1.0,
+            plains_threshold: 0.55,
+            plains_strength: 0.0,
             overlay: 100.0,
+            overlay_mode: OverlayMode::Lerp,
+        }
+    }
+}
+
+impl MapConfig {
+    // restores the original three-layer noise setup
+    pub fn apply_classic_preset(&mut self) {
+        self.noise_stack = NoiseStack::Classic;
+        self.layers = default_noise_layers();
+    }
+
+    pub fn apply_fbm_preset(&mut self) {
+        let defaults = MapConfig::default();
+        self.noise_stack = NoiseStack::Fbm;
+        self.octaves = defaults.octaves;
+        self.lacunarity = defaults.lacunarity;
+        self.persistence = defaults.persistence;
+        self.base_frequency = defaults.base_frequency;
+    }
+
+    // changes the resolution by a factor while keeping the map looking the same
+    pub fn resize(&mut self, factor: f64) {
+        self.width = ((self.width as f64 * factor) as u32).max(1);
+        self.height = ((self.height as f64 * factor) as u32).max(1);
+        self.shelf_width *= factor;
+        self.beach_width_px *= factor;
+        self.sea_floor_fade_px *= factor;
+        if self.scales_in_meters {
+            // same world, finer or coarser grid
+            self.cell_size_m /= factor;
+            return;
+        }
+        for layer in &mut self.layers {
+            layer.scale *= factor;
+        }
+        self.base_frequency /= factor;
+        self.warp_strength *= factor;
+        self.warp_scale *= factor;
+        self.coast_noise_scale *= factor;
+        self.sea_floor_scale *= factor;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmoothingMode {
+    Gaussian,
+    // only averages neighbors of similar height, so cliffs and ridgelines stay sharp
+    Bilateral,
+    // the opposite, only smooths where the terrain is steeper than a threshold
+    SlopeLimited,
+    // median of a 3x3 or 5x5 neighborhood, for despeckling imported maps
+    Median3,
+    Median5,
+}
+
+// how a painted overlay image changes the heights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaintBlend {
+    Add,
+    Subtract,
+    Multiply,
+    Lerp,
+}
+
+// how the refined heights are brought back into [0,1]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    Off,
+    Clamp,
+    Rescale,
+    // rescales land and water separately so nothing crosses the sea level
+    PreserveSeaLevel,
+}
+
+// fine noise added back on in the refiner, optionally limited to a height band and to
+// gentle slopes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetailNoise {
+    pub enabled: bool,
+    // feature size in pixels of the full map
+    pub scale: f32,
+    pub amplitude: f32,
+    pub seed: u32,
+    pub min_height: f32,
+    pub max_height: f32,
+    // height change per pixel above which no noise is added
+    pub max_slope: Option<f32>,
+}
+
+impl Default for DetailNoise {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            scale: 8.0,
+            amplitude: 0.01,
+            seed: 0,
+            min_height: 0.0,
+            max_height: 1.0,
+            max_slope: None,
         }
     }
 }
 
+// part of the map the refinement is applied to, the rest keeps its heights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefineMask {
+    All,
+    LandOnly,
+    WaterOnly,
+    // grayscale image, white is fully refined
+    Custom,
+}
+
+// stages of the refinement, they run in the order of `RefinerConfig::pipeline`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefineOp {
+    Offset,
+    Coeff,
+    Exponent,
+    Smooth,
+    Curve,
+    Clip,
+    Paint,
+    Noise,
+    Terrace,
+    Normalize,
+    SlopeLimit,
+}
+
+impl RefineOp {
+    // the order the stages ran in before they could be rearranged
+    pub const DEFAULT_ORDER: [RefineOp; 11] = [
+        RefineOp::Offset,
+        RefineOp::Coeff,
+        RefineOp::Exponent,
+        RefineOp::Smooth,
+        RefineOp::Curve,
+        RefineOp::Clip,
+        RefineOp::Paint,
+        RefineOp::Noise,
+        RefineOp::Terrace,
+        RefineOp::Normalize,
+        RefineOp::SlopeLimit,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RefineStep {
+    pub op: RefineOp,
+    // a disabled step is skipped but keeps its place and settings
+    pub enabled: bool,
+}
+
+pub fn default_refine_pipeline() -> Vec<RefineStep> {
+    RefineOp::DEFAULT_ORDER
+        .iter()
+        .map(|&op| RefineStep { op, enabled: true })
+        .collect()
+}
+
+#[derive(Clone)]
 pub struct RefinerConfig {
+    // order of the stages, every op appears exactly once
+    pub pipeline: Vec<RefineStep>,
     pub height_offset: f32,
     pub height_coeff: f32,
     pub height_exponent: f32,
     pub smoothness: f32,
+    pub smoothing_mode: SmoothingMode,
+    // height difference at which neighbors stop counting in bilateral mode
+    pub edge_threshold: f32,
+    // height change per pixel of the full map above which slope limited smoothing kicks in
+    pub slope_threshold: f32,
     pub curve_points: Option<Vec<(f32, f32)>>,
-    pub paint_map_overlay: Option<Vec<f32>>,
+    // second curve for terrain away from the coast, `curve_points` then only applies near it
+    pub inland_curve_points: Option<Vec<(f32, f32)>>,
+    // distance in pixels from the coastline over which the coastal curve turns into the
+    // inland one
+    pub coast_transition_px: f32,
+    // heights are clamped to [clip_min, clip_max] after the curve. with a knee the clamp
+    // eases in over that height on both sides of the limit instead of cutting off flat.
+    pub clip_min: f32,
+    pub clip_max: f32,
+    pub clip_knee: f32,
+    // largest height difference between neighboring cells of the full map, cliffs above it
+    // are relaxed after normalizing
+    pub max_slope_step: Option<f32>,
+    pub slope_limit_iterations: u32,
+    // grayscale image in [0,1], resampled to the heightmap size when it's applied. shared
+    // so the live preview can snapshot the config cheaply.
+    pub paint_map_overlay: Option<Arc<Heightmap>>,
+    pub paint_blend: PaintBlend,
+    pub paint_strength: f32,
+    pub detail_noise: DetailNoise,
+    // number of flat steps the heights are quantized into, 0 and 1 leave them alone
+    pub terrace_levels: u32,
+    // 0 gives hard steps, 1 an almost continuous slope between them
+    pub terrace_blend: f32,
+    pub normalization: Normalization,
+    pub mask: RefineMask,
+    // image for the custom mask, resampled to the heightmap size like the paint overlay
+    pub custom_mask: Option<Arc<Heightmap>>,
 }
 
 impl Default for RefinerConfig {
     fn default() -> Self {
         Self {
+            pipeline: default_refine_pipeline(),
             height_offset: 0.0,
             height_coeff: 1.0,
             height_exponent: 1.0,
             smoothness: 0.0,
+            smoothing_mode: SmoothingMode::Gaussian,
+            edge_threshold: 0.05,
+            slope_threshold: 0.005,
             curve_points: None,
+            inland_curve_points: None,
+            coast_transition_px: 64.0,
+            clip_min: 0.0,
+            clip_max: 1.0,
+            clip_knee: 0.0,
+            max_slope_step: None,
+            slope_limit_iterations: 200,
             paint_map_overlay: None,
+            paint_blend: PaintBlend::Add,
+            paint_strength: 0.5,
+            detail_noise: DetailNoise::default(),
+            terrace_levels: 0,
+            terrace_blend: 0.2,
+            normalization: Normalization::Rescale,
+            mask: RefineMask::All,
+            custom_mask: None,
         }
     }
 }
 
+// images are compared by identity, loading one always creates a new one
+fn same_image(a: &Option<Arc<Heightmap>>, b: &Option<Arc<Heightmap>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+impl PartialEq for RefinerConfig {
+    fn eq(&self, other: &Self) -> bool {
+        same_image(&self.paint_map_overlay, &other.paint_map_overlay)
+            && same_image(&self.custom_mask, &other.custom_mask)
+            && self.pipeline == other.pipeline
+            && self.height_offset == other.height_offset
+            && self.height_coeff == other.height_coeff
+            && self.height_exponent == other.height_exponent
+            && self.smoothness == other.smoothness
+            && self.smoothing_mode == other.smoothing_mode
+            && self.edge_threshold == other.edge_threshold
+            && self.slope_threshold == other.slope_threshold
+            && self.curve_points == other.curve_points
+            && self.inland_curve_points == other.inland_curve_points
+            && self.coast_transition_px == other.coast_transition_px
+            && self.clip_min == other.clip_min
+            && self.clip_max == other.clip_max
+            && self.clip_knee == other.clip_knee
+            && self.max_slope_step == other.max_slope_step
+            && self.slope_limit_iterations == other.slope_limit_iterations
+            && self.paint_blend == other.paint_blend
+            && self.paint_strength == other.paint_strength
+            && self.detail_noise == other.detail_noise
+            && self.terrace_levels == other.terrace_levels
+            && self.terrace_blend == other.terrace_blend
+            && self.normalization == other.normalization
+            && self.mask == other.mask
+    }
+}
+
+// one row of the biome table. a cell gets the biome of the matching rule with the highest
+// priority, the earlier rule wins a tie. the elevation is the height divided by the sea
+// level, so 1 is the coast, temperature and humidity run from 0 to 1 and the slope is in
+// degrees. every range holds its min but not its max.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BiomeRule {
+    pub biome: Biome,
+    pub priority: i32,
+    pub elevation: (f32, f32),
+    pub temperature: (f32, f32),
+    pub humidity: (f32, f32),
+    pub slope: (f32, f32),
+}
+
+#[derive(Clone)]
 pub struct BiomeConfig {
     pub base_temperature: f32,
     pub base_humidity: f32,
     pub temperature_variation: f32,
     pub humidity_variation: f32,
+    // degrees the bottom edge of the map is warmer than the top one. with an equator at
+    // `equator_position` (0 is the top edge, 1 the bottom) it is instead the difference
+    // between the equator and the edge furthest from it.
+    pub latitude_gradient: f32,
+    pub use_equator: bool,
+    pub equator_position: f32,
+    // degrees the air cools per 1000 meters above the sea level
+    pub lapse_rate: f32,
+    // humidity in percent the sea adds at the coast, fading out over the range in meters
+    pub coastal_humidity_boost: f32,
+    pub coastal_humidity_range: f32,
+    // width of the dithered band along the biome borders in percent, 0 keeps hard edges
     pub biome_blend_factor: f32,
+    // classification table, cells no rule matches get the fallback
+    pub biome_rules: Vec<BiomeRule>,
+    pub fallback_biome: Biome,
     pub scale: f64,
     pub seed: u32,
     pub use_random_seed: bool,
@@ -81,7 +580,15 @@ impl Default for BiomeConfig {
             base_humidity: 50.0,
             temperature_variation: 20.0,
             humidity_variation: 20.0,
+            latitude_gradient: 0.0,
+            use_equator: false,
+            equator_position: 0.5,
+            lapse_rate: 0.0,
+            coastal_humidity_boost: 0.0,
+            coastal_humidity_range: 2000.0,
             biome_blend_factor: 0.5,
+            biome_rules: default_biome_rules(),
+            fallback_biome: Biome::Plains,
             scale: 10000.0,
             seed: 12345,
             use_random_seed: true,
@@ -89,6 +596,54 @@ impl Default for BiomeConfig {
     }
 }
 
+// cross section of a carved river channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RiverProfile {
+    Parabolic,
+    // flat bed with sloped sides
+    Trapezoid,
+}
+
+// how rivers are found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RiverMode {
+    // single rivers traced downhill from random sources
+    Particles,
+    // every cell that drains a large enough area, gives branching networks
+    Drainage,
+}
+
+// what happens to a river that ends without reaching any water
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeadEndRemedy {
+    // dig a small pond where it ends
+    Pond,
+    Remove,
+}
+
+// channel drawn by hand on the preview, in heightmap pixels. the water runs towards the
+// lower of its two ends. width and depth are in meters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Canal {
+    pub points: Vec<(u32, u32)>,
+    pub width: f32,
+    pub depth: f32,
+    // curve through the points instead of straight segments between them
+    pub smooth: bool,
+}
+
+impl Default for Canal {
+    fn default() -> Self {
+        Self {
+            points: Vec::new(),
+            width: 10.0,
+            depth: 2.0,
+            smooth: false,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct WaterConfig {
     pub seed: u32,
     pub use_random_seed: bool,
@@ -101,19 +656,85 @@ pub struct WaterConfig {
     pub min_capacity: f32,
     pub max_capacity: f32,
     pub min_depth: f32,
+    // limits the flood fill of a basin stops at, in cells from the lowest point and meters
+    pub max_lake_radius: f32,
+    pub max_lake_depth: f32,
+    // cells kept free between the bounding circles of two lakes
+    pub min_lake_spacing: f32,
     pub base_evaporation: f32,
     pub base_inflow: f32,
     pub base_drainage: f32,
     pub biome_influence: f32,
     pub lake_terrain_modification: f32,
-    // river generation
+    // radius in cells of the opening and closing that cleans up the shoreline of a lake, 0
+    // leaves it as flooded. the banks ease into the water over as many cells.
+    pub shore_smoothing: u32,
+    // wet ground around the water, which the biomes turn into swamp. the radius is in meters
+    // and the strength in percent.
+    pub wetland_radius: f32,
+    pub wetland_strength: f32,
+    // how much the closeness of lakes and rivers, of the sea and the water draining through a
+    // cell make the ground wet, and how much steep ground dries it out again, in percent
+    pub wetness_water_weight: f32,
+    pub wetness_ocean_weight: f32,
+    pub wetness_flow_weight: f32,
+    pub wetness_slope_weight: f32,
+    // river generation. widths and depths are in meters, the momentum is the share of its
+    // heading a river keeps each step and the direction variation how far in degrees it
+    // swings away from the way downhill in its bends. rivers start at the min width and
+    // widen downstream with the water they gather up to `river_width`, the speed sets how
+    // quickly.
     pub river_count: u32,
+    // band of normalized heights the random rivers rise in, higher ground is picked more
+    // often. sources stay `river_source_spacing` meters apart.
+    pub river_source_min_elevation: f32,
+    pub river_source_max_elevation: f32,
+    pub river_source_spacing: f32,
     pub river_width: f32,
+    pub river_min_width: f32,
     pub river_momentum: f32,
     pub river_direction_variation: f32,
+    // distance between two bends of a meandering river and how far it may climb over bumps
+    // on its way, both in meters
+    pub meander_length: f32,
+    pub river_uphill_tolerance: f32,
+    // drop in meters within one or two cells that makes a waterfall
+    pub waterfall_drop: f32,
     pub river_speed: f32,
     pub river_spread: f32,
+    // water in the channel in meters. it used to be an unread 0 to 100 setting defaulting to
+    // 50, taken as meters that is a canyon, so it starts at a river's 5 m instead.
     pub river_depth: f32,
+    // valley around a river, `valley_width_mult` times as wide as the river. its walls rise
+    // with the distance to the power of `valley_profile`, below 1 is a v and above a u.
+    pub valley_width_mult: f32,
+    pub valley_profile: f32,
+    pub river_profile: RiverProfile,
+    pub river_mode: RiverMode,
+    // rivers widen towards their mouth over the last `estuary_length` meters, up to
+    // `estuary_max_width` meters, and may split into up to 3 branches there
+    pub estuary_length: f32,
+    pub estuary_max_width: f32,
+    pub estuary_branches: u32,
+    // cells that have to drain into a cell before it counts as river in drainage mode
+    pub flow_threshold: f32,
+    // rivers are routed over a copy of the terrain with its pits filled, flats in it fall
+    // by `fill_epsilon` meters per cell towards their outlet. drainage mode always fills.
+    pub fill_pits: bool,
+    pub fill_epsilon: f32,
+    // fill the pits of the terrain itself once the lakes are placed
+    pub apply_pit_fill: bool,
+    // every river has to end in the sea, a lake, another river or at the map edge
+    pub enforce_outlet: bool,
+    pub dead_end_remedy: DeadEndRemedy,
+    // lower the terrain under the lakes and rivers, otherwise only the water maps are kept
+    pub carve_terrain: bool,
+    // lakes and river sources placed by hand on the preview, in heightmap pixels. they are
+    // generated before any of the random ones.
+    pub lake_seeds: Vec<(u32, u32)>,
+    pub river_sources: Vec<(u32, u32)>,
+    // dug after all the rivers
+    pub canals: Vec<Canal>,
 }
 
 impl Default for WaterConfig {
@@ -129,19 +750,216 @@ impl Default for WaterConfig {
             min_capacity: 10.0,
             max_capacity: 1000000.0,
             min_depth: 1.0,
+            max_lake_radius: 200.0,
+            min_lake_spacing: 0.0,
+            max_lake_depth: 30.0,
             base_evaporation: 50.0,
             base_inflow: 50.0,
             base_drainage: 50.0,
             biome_influence: 50.0,
             lake_terrain_modification: 10.0,
+            shore_smoothing: 0,
+            wetland_radius: 100.0,
+            wetland_strength: 50.0,
+            wetness_water_weight: 100.0,
+            wetness_ocean_weight: 20.0,
+            wetness_flow_weight: 30.0,
+            wetness_slope_weight: 50.0,
             river_count: 10,
+            river_source_min_elevation: 0.0,
+            river_source_max_elevation: 1.0,
+            river_source_spacing: 500.0,
             river_width: 50.0,
+            river_min_width: 5.0,
             river_momentum: 50.0,
-            river_direction_variation: 10.0,
+            river_direction_variation: 30.0,
+            meander_length: 300.0,
+            river_uphill_tolerance: 1.0,
+            waterfall_drop: 5.0,
             river_speed: 50.0,
             river_spread: 50.0,
-            river_depth: 50.0,
+            river_depth: 5.0,
+            valley_width_mult: 1.0,
+            valley_profile: 1.0,
+            river_profile: RiverProfile::Parabolic,
+            river_mode: RiverMode::Particles,
+            estuary_length: 300.0,
+            estuary_max_width: 120.0,
+            estuary_branches: 1,
+            flow_threshold: 2000.0,
+            fill_pits: false,
+            fill_epsilon: 0.001,
+            apply_pit_fill: false,
+            enforce_outlet: false,
+            dead_end_remedy: DeadEndRemedy::Pond,
+            carve_terrain: true,
+            lake_seeds: Vec::new(),
+            river_sources: Vec::new(),
+            canals: Vec::new(),
+
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ErosionConfig {
+    pub seed: u32,
+    pub use_random_seed: bool,
+    pub droplet_count: u32,
+    pub inertia: f32,
+    pub erosion_rate: f32,
+    pub deposition_rate: f32,
+    pub max_lifetime: u32,
+    pub sediment_capacity: f32,
+    pub evaporation: f32,
+    pub radius: u32,
+    // thermal erosion
+    pub talus_angle: f32,
+    pub thermal_iterations: u32,
+    pub thermal_rate: f32,
+}
+
+impl Default for ErosionConfig {
+    fn default() -> Self {
+        Self {
+            seed: 42345,
+            use_random_seed: true,
+            droplet_count: 50000,
+            inertia: 0.05,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            max_lifetime: 30,
+            sediment_capacity: 4.0,
+            evaporation: 0.01,
+            radius: 3,
+            talus_angle: 35.0,
+            thermal_iterations: 50,
+            thermal_rate: 0.5,
+        }
+    }
+}
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StampKind {
+    // cone with a crater depression at the summit
+    Volcano,
+    // raised rim ring around a bowl
+    Crater,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StampBlend {
+    Add,
+    Max,
+    Replace,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stamp {
+    pub kind: StampKind,
+    pub blend: StampBlend,
+    pub x: f32,
+    pub y: f32,
+    pub radius: f32,
+    pub height: f32,
+    // size of the crater (volcano) or rim (impact crater) relative to the radius
+    pub rim_width: f32,
+}
+
+impl Default for Stamp {
+    fn default() -> Self {
+        Self {
+            kind: StampKind::Volcano,
+            blend: StampBlend::Add,
+            x: 256.0,
+            y: 256.0,
+            radius: 80.0,
+            height: 0.3,
+            rim_width: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrushFalloff {
+    Smooth,
+    Linear,
+    Constant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrushTool {
+    RaiseLower,
+    // pulls the heights towards `flatten_target`
+    Flatten,
+    Smooth,
+}
+
+// sculpting brush used on the preview, radius in heightmap pixels
+#[derive(Debug, Clone)]
+pub struct Brush {
+    pub tool: BrushTool,
+    pub radius: f32,
+    // height change per second at the center of the brush
+    pub strength: f32,
+    pub falloff: BrushFalloff,
+    pub flatten_target: f32,
+    // flattening leaves the pixels on the other side of the sea level alone
+    pub flatten_keep_coast: bool,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            tool: BrushTool::RaiseLower,
+            radius: 40.0,
+            strength: 0.2,
+            falloff: BrushFalloff::Smooth,
+            flatten_target: 0.5,
+            flatten_keep_coast: true,
+        }
+    }
+}
+
+// search settings for flat building sites
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlatSiteConfig {
+    // steepest slope in degrees that still counts as flat
+    pub max_slope: f32,
+    // smallest site in pixels
+    pub min_area: u32,
+    pub show_on_preview: bool,
+}
+
+impl Default for FlatSiteConfig {
+    fn default() -> Self {
+        Self {
+            max_slope: 5.0,
+            min_area: 400,
+            show_on_preview: true,
+        }
+    }
+}
+
+// search settings for places where a road can bridge or ford a river
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CrossingConfig {
+    // widest river in meters that is still worth crossing
+    pub max_width: f32,
+    // steepest bank in degrees on either side of the river
+    pub max_bank_slope: f32,
+    // fewest pixels between two crossings
+    pub min_spacing: u32,
+    pub show_on_preview: bool,
+}
+
+impl Default for CrossingConfig {
+    fn default() -> Self {
+        Self {
+            max_width: 30.0,
+            max_bank_slope: 8.0,
+            min_spacing: 50,
+            show_on_preview: true,
         }
     }
 }
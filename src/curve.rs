@@ -0,0 +1,134 @@
+// number of entries in the lookup table the curve is baked into
+pub const CURVE_LUT_SIZE: usize = 1024;
+
+// named starting points for the curve editor
+pub const CURVE_PRESETS: &[(&str, &[(f32, f32)])] = &[
+    ("Linear", &[(0.0, 0.0), (1.0, 1.0)]),
+    (
+        "Steep Peaks",
+        &[(0.0, 0.0), (0.5, 0.3), (0.8, 0.55), (1.0, 1.0)],
+    ),
+    (
+        "Flatlands",
+        &[(0.0, 0.0), (0.3, 0.4), (0.7, 0.5), (1.0, 1.0)],
+    ),
+    (
+        "Coastal Shelf",
+        &[(0.0, 0.0), (0.2, 0.3), (0.4, 0.38), (0.5, 0.45), (1.0, 1.0)],
+    ),
+    (
+        "Plateau",
+        &[(0.0, 0.0), (0.4, 0.6), (0.6, 0.7), (0.8, 0.72), (1.0, 0.8)],
+    ),
+    (
+        "S-Curve",
+        &[(0.0, 0.0), (0.25, 0.1), (0.75, 0.9), (1.0, 1.0)],
+    ),
+];
+
+// control points sorted by input, clamped to the unit square. points closer together than
+// the lut resolution along x are merged so the curve never has to jump vertically.
+pub fn sanitize_curve_points(points: &[(f32, f32)]) -> Vec<(f32, f32)> {
+    let mut sorted: Vec<(f32, f32)> = points
+        .iter()
+        .filter(|(x, y)| x.is_finite() && y.is_finite())
+        .map(|&(x, y)| (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0)))
+        .collect();
+    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let min_gap = 1.0 / CURVE_LUT_SIZE as f32;
+    let mut merged: Vec<(f32, f32)> = Vec::with_capacity(sorted.len());
+    for (x, y) in sorted {
+        match merged.last_mut() {
+            Some(last) if x - last.0 < min_gap => last.1 = (last.1 + y) * 0.5,
+            _ => merged.push((x, y)),
+        }
+    }
+    merged
+}
+
+// tangents for a monotone cubic hermite spline (fritsch-carlson), so the curve never
+// overshoots between two control points
+fn monotone_tangents(points: &[(f32, f32)]) -> Vec<f32> {
+    let n = points.len();
+    let secants: Vec<f32> = points
+        .windows(2)
+        .map(|p| (p[1].1 - p[0].1) / (p[1].0 - p[0].0))
+        .collect();
+    let mut tangents = vec![0.0; n];
+    tangents[0] = secants[0];
+    tangents[n - 1] = secants[n - 2];
+    for i in 1..n - 1 {
+        tangents[i] = if secants[i - 1] * secants[i] <= 0.0 {
+            0.0
+        } else {
+            (secants[i - 1] + secants[i]) * 0.5
+        };
+    }
+    for (i, &secant) in secants.iter().enumerate() {
+        if secant == 0.0 {
+            tangents[i] = 0.0;
+            tangents[i + 1] = 0.0;
+            continue;
+        }
+        let a = tangents[i] / secant;
+        let b = tangents[i + 1] / secant;
+        let length = a.hypot(b);
+        if length > 3.0 {
+            let t = 3.0 / length;
+            tangents[i] = t * a * secant;
+            tangents[i + 1] = t * b * secant;
+        }
+    }
+    tangents
+}
+
+// evaluates the curve at CURVE_LUT_SIZE evenly spaced inputs in [0,1]. inputs before the
+// first or after the last point keep the value of that point.
+pub fn build_curve_lut(points: &[(f32, f32)]) -> Vec<f32> {
+    let points = sanitize_curve_points(points);
+    let input = |i: usize| i as f32 / (CURVE_LUT_SIZE - 1) as f32;
+    match points.len() {
+        0 => return (0..CURVE_LUT_SIZE).map(input).collect(),
+        1 => return vec![points[0].1; CURVE_LUT_SIZE],
+        _ => {}
+    }
+    let tangents = monotone_tangents(&points);
+
+    let mut segment = 0;
+    (0..CURVE_LUT_SIZE)
+        .map(|i| {
+            let x = input(i);
+            let (first, last) = (points[0], points[points.len() - 1]);
+            if x <= first.0 {
+                return first.1;
+            }
+            if x >= last.0 {
+                return last.1;
+            }
+            while x > points[segment + 1].0 {
+                segment += 1;
+            }
+            let (x0, y0) = points[segment];
+            let (x1, y1) = points[segment + 1];
+            let h = x1 - x0;
+            let t = (x - x0) / h;
+            let (t2, t3) = (t * t, t * t * t);
+            let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+            let h10 = t3 - 2.0 * t2 + t;
+            let h01 = -2.0 * t3 + 3.0 * t2;
+            let h11 = t3 - t2;
+            let y =
+                h00 * y0 + h10 * h * tangents[segment] + h01 * y1 + h11 * h * tangents[segment + 1];
+            y.clamp(0.0, 1.0)
+        })
+        .collect()
+}
+
+// linear lookup of an input in [0,1]
+pub fn sample_curve_lut(lut: &[f32], x: f32) -> f32 {
+    let f = x.clamp(0.0, 1.0) * (lut.len() - 1) as f32;
+    let i = (f as usize).min(lut.len() - 2);
+    let t = f - i as f32;
+    lut[i] + (lut[i + 1] - lut[i]) * t
+}
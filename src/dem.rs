@@ -0,0 +1,187 @@
+use crate::heightmap::Heightmap;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+// value SRTM tiles use for voids
+const HGT_NODATA: f32 = -32768.0;
+
+// elevation raster normalized to [0,1], with the real elevation range in meters so exports
+// can restore the true heights
+pub struct Dem {
+    pub heightmap: Heightmap,
+    pub min_elevation: f32,
+    pub max_elevation: f32,
+}
+
+pub fn is_dem_file(path: &Path) -> bool {
+    matches!(extension(path).as_str(), "hgt" | "asc" | "tif" | "tiff")
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// loads an SRTM .hgt tile, a single band GeoTIFF or an ESRI ASCII grid
+pub fn load_dem(path: &Path) -> io::Result<Dem> {
+    let (values, width, height, nodata) = match extension(path).as_str() {
+        "hgt" => read_hgt(path)?,
+        "asc" => read_ascii_grid(path)?,
+        "tif" | "tiff" => read_geotiff(path)?,
+        other => return Err(invalid_data(format!("unsupported DEM format '{}'", other))),
+    };
+    normalize(values, width, height, nodata)
+}
+
+fn read_hgt(path: &Path) -> io::Result<(Vec<f32>, u32, u32, Option<f32>)> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    // tiles are square grids of big endian i16, 1201 or 3601 samples per side
+    let side = ((bytes.len() / 2) as f64).sqrt() as usize;
+    if side == 0 || side * side * 2 != bytes.len() {
+        return Err(invalid_data(
+            "hgt file is not a square grid of 16 bit samples",
+        ));
+    }
+    let values = bytes
+        .chunks_exact(2)
+        .map(|b| i16::from_be_bytes([b[0], b[1]]) as f32)
+        .collect();
+    Ok((values, side as u32, side as u32, Some(HGT_NODATA)))
+}
+
+fn read_ascii_grid(path: &Path) -> io::Result<(Vec<f32>, u32, u32, Option<f32>)> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    let mut tokens = text.split_whitespace().peekable();
+
+    let (mut width, mut height, mut nodata) = (None, None, None);
+    // the header is a list of keyword/value pairs in front of the first number
+    while let Some(key) = tokens.next_if(|t| t.parse::<f64>().is_err()) {
+        let value = tokens
+            .next()
+            .ok_or_else(|| invalid_data(format!("missing value for '{}'", key)))?;
+        let bad_value = || invalid_data(format!("invalid value for '{}': {}", key, value));
+        match key.to_ascii_lowercase().as_str() {
+            "ncols" => width = Some(value.parse::<u32>().map_err(|_| bad_value())?),
+            "nrows" => height = Some(value.parse::<u32>().map_err(|_| bad_value())?),
+            "nodata_value" => nodata = Some(value.parse::<f32>().map_err(|_| bad_value())?),
+            _ => {}
+        }
+    }
+    let (Some(width), Some(height)) = (width, height) else {
+        return Err(invalid_data("ASCII grid header is missing ncols or nrows"));
+    };
+
+    let values = tokens
+        .map(|t| {
+            t.parse::<f32>()
+                .map_err(|_| invalid_data(format!("invalid sample '{}'", t)))
+        })
+        .collect::<io::Result<Vec<f32>>>()?;
+    if values.len() != (width * height) as usize {
+        return Err(invalid_data(format!(
+            "expected {}x{} samples, found {}",
+            width,
+            height,
+            values.len()
+        )));
+    }
+    Ok((values, width, height, nodata))
+}
+
+fn read_geotiff(path: &Path) -> io::Result<(Vec<f32>, u32, u32, Option<f32>)> {
+    let tiff_error = |e: tiff::TiffError| invalid_data(e.to_string());
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?)).map_err(tiff_error)?;
+    let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+    if !matches!(
+        decoder.colortype().map_err(tiff_error)?,
+        tiff::ColorType::Gray(_)
+    ) {
+        return Err(invalid_data("only single band GeoTIFFs are supported"));
+    }
+    // gdal stores the nodata value as text in its own tag
+    let nodata = decoder
+        .find_tag(Tag::GdalNodata)
+        .map_err(tiff_error)?
+        .and_then(|value| value.into_string().ok())
+        .and_then(|text| text.trim_matches(char::from(0)).trim().parse::<f32>().ok());
+
+    let values = match decoder.read_image().map_err(tiff_error)? {
+        DecodingResult::U8(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::U16(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::U32(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::U64(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::I8(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::I16(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::I32(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::I64(v) => v.into_iter().map(|x| x as f32).collect(),
+        DecodingResult::F32(v) => v,
+        DecodingResult::F64(v) => v.into_iter().map(|x| x as f32).collect(),
+    };
+    Ok((values, width, height, nodata))
+}
+
+// maps the valid samples onto [0,1]. nodata cells take the value of the closest valid
+// cell (breadth first, so the distance is measured in 4-connected steps).
+fn normalize(
+    mut values: Vec<f32>,
+    width: u32,
+    height: u32,
+    nodata: Option<f32>,
+) -> io::Result<Dem> {
+    let is_valid = |v: f32| v.is_finite() && nodata.is_none_or(|n| v != n);
+    let (w, h) = (width as usize, height as usize);
+
+    let mut filled: Vec<bool> = values.iter().map(|&v| is_valid(v)).collect();
+    let mut queue: VecDeque<usize> = (0..values.len()).filter(|&i| filled[i]).collect();
+    if queue.is_empty() {
+        return Err(invalid_data("the DEM does not contain any valid samples"));
+    }
+    let void_count = values.len() - queue.len();
+    while let Some(i) = queue.pop_front() {
+        let (x, y) = (i % w, i / w);
+        let neighbors = [
+            (x > 0).then(|| i - 1),
+            (x + 1 < w).then(|| i + 1),
+            (y > 0).then(|| i - w),
+            (y + 1 < h).then(|| i + w),
+        ];
+        for n in neighbors.into_iter().flatten() {
+            if !filled[n] {
+                filled[n] = true;
+                values[n] = values[i];
+                queue.push_back(n);
+            }
+        }
+    }
+    if void_count > 0 {
+        println!("Filled {} nodata cells", void_count);
+    }
+
+    let mut heightmap = Heightmap::from_vec(values, width, height);
+    let (min_elevation, max_elevation) = heightmap.min_max();
+    let range = max_elevation - min_elevation;
+    for value in heightmap.iter_mut() {
+        *value = if range > 0.0 {
+            (*value - min_elevation) / range
+        } else {
+            0.0
+        };
+    }
+    Ok(Dem {
+        heightmap,
+        min_elevation,
+        max_elevation,
+    })
+}
@@ -0,0 +1,298 @@
+use crate::config::ErosionConfig;
+use crate::heightmap::Heightmap;
+use crate::jobs::Progress;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use rayon::prelude::*;
+
+const GRAVITY: f32 = 4.0;
+const MIN_SLOPE: f32 = 0.01;
+
+// heights are stored normalized to [0,1] while cells are 1 unit wide, which makes every
+// slope tiny. the simulation runs on heights scaled so a full-height mountain spans a
+// quarter of the map, which keeps the droplet parameters independent of map size.
+fn height_scale(width: u32, height: u32) -> f32 {
+    width.max(height) as f32 * 0.25
+}
+
+// bilinear height and gradient at a floating point position
+fn height_and_gradient(map: &[f32], width: usize, x: f32, y: f32) -> (f32, f32, f32) {
+    let cx = x as usize;
+    let cy = y as usize;
+    let u = x - cx as f32;
+    let v = y - cy as f32;
+
+    let i = cy * width + cx;
+    let nw = map[i];
+    let ne = map[i + 1];
+    let sw = map[i + width];
+    let se = map[i + width + 1];
+
+    let gx = (ne - nw) * (1.0 - v) + (se - sw) * v;
+    let gy = (sw - nw) * (1.0 - u) + (se - ne) * u;
+    let h = nw * (1.0 - u) * (1.0 - v) + ne * u * (1.0 - v) + sw * (1.0 - u) * v + se * u * v;
+    (h, gx, gy)
+}
+
+// precomputed erosion brush: offsets around the droplet cell and their normalized weights
+fn build_brush(radius: u32) -> Vec<(i32, i32, f32)> {
+    let r = radius.max(1) as i32;
+    let mut brush = Vec::new();
+    let mut total = 0.0;
+    for dy in -r..=r {
+        for dx in -r..=r {
+            let dist = ((dx * dx + dy * dy) as f32).sqrt();
+            if dist < r as f32 {
+                let w = 1.0 - dist / r as f32;
+                total += w;
+                brush.push((dx, dy, w));
+            }
+        }
+    }
+    for b in brush.iter_mut() {
+        b.2 /= total;
+    }
+    brush
+}
+
+pub fn hydraulic_erosion(
+    heightmap: &Heightmap,
+    config: &ErosionConfig,
+    seed: u32,
+    progress: &Progress,
+) -> Option<Heightmap> {
+    let (width, height) = (heightmap.width(), heightmap.height());
+    let w = width as usize;
+    let h = height as usize;
+    if w < 3 || h < 3 {
+        return Some(heightmap.clone());
+    }
+
+    let scale = height_scale(width, height);
+    let mut map: Vec<f32> = heightmap.iter().map(|v| v * scale).collect();
+
+    let mut rng = StdRng::seed_from_u64(seed as u64);
+    let brush = build_brush(config.radius);
+    let inertia = config.inertia.clamp(0.0, 1.0);
+    let erosion_rate = config.erosion_rate.clamp(0.0, 1.0);
+    let deposition_rate = config.deposition_rate.clamp(0.0, 1.0);
+    let evaporation = config.evaporation.clamp(0.0, 1.0);
+
+    progress.set_total(config.droplet_count as usize);
+
+    for droplet in 0..config.droplet_count {
+        let mut px = rng.gen_range(0.0..(w - 1) as f32);
+        let mut py = rng.gen_range(0.0..(h - 1) as f32);
+        let mut dir_x = 0.0f32;
+        let mut dir_y = 0.0f32;
+        let mut speed = 1.0f32;
+        let mut water = 1.0f32;
+        let mut sediment = 0.0f32;
+
+        for _ in 0..config.max_lifetime {
+            let cx = px as usize;
+            let cy = py as usize;
+            let u = px - cx as f32;
+            let v = py - cy as f32;
+
+            let (old_h, gx, gy) = height_and_gradient(&map, w, px, py);
+
+            // blend previous direction with the downhill gradient
+            dir_x = dir_x * inertia - gx * (1.0 - inertia);
+            dir_y = dir_y * inertia - gy * (1.0 - inertia);
+            let len = (dir_x * dir_x + dir_y * dir_y).sqrt();
+            if len == 0.0 {
+                break;
+            }
+            dir_x /= len;
+            dir_y /= len;
+            px += dir_x;
+            py += dir_y;
+
+            if px < 0.0 || py < 0.0 || px >= (w - 1) as f32 || py >= (h - 1) as f32 {
+                break;
+            }
+
+            let (new_h, _, _) = height_and_gradient(&map, w, px, py);
+            let delta = new_h - old_h;
+
+            let capacity = (-delta).max(MIN_SLOPE) * speed * water * config.sediment_capacity;
+
+            if sediment > capacity || delta > 0.0 {
+                // fill the pit we moved out of, or drop the excess sediment
+                let amount = if delta > 0.0 {
+                    delta.min(sediment)
+                } else {
+                    (sediment - capacity) * deposition_rate
+                };
+                sediment -= amount;
+
+                let i = cy * w + cx;
+                map[i] += amount * (1.0 - u) * (1.0 - v);
+                map[i + 1] += amount * u * (1.0 - v);
+                map[i + w] += amount * (1.0 - u) * v;
+                map[i + w + 1] += amount * u * v;
+            } else {
+                // never erode more than the height difference, or we would dig holes
+                let amount = ((capacity - sediment) * erosion_rate).min(-delta);
+                for &(dx, dy, weight) in brush.iter() {
+                    let bx = cx as i32 + dx;
+                    let by = cy as i32 + dy;
+                    if bx < 0 || by < 0 || bx >= w as i32 || by >= h as i32 {
+                        continue;
+                    }
+                    let i = by as usize * w + bx as usize;
+                    let removed = (amount * weight).min(map[i]);
+                    map[i] -= removed;
+                    sediment += removed;
+                }
+            }
+
+            speed = (speed * speed - delta * GRAVITY).max(0.0).sqrt();
+            water *= 1.0 - evaporation;
+        }
+
+        if droplet % 1000 == 999 {
+            progress.add(1000);
+            if progress.is_cancelled() {
+                return None;
+            }
+        }
+    }
+    // the droplets after the last full thousand
+    progress.add(config.droplet_count as usize % 1000);
+
+    // keep the result in [0,1] without rescaling, so the sea level keeps its meaning
+    let map = map.iter().map(|v| (v / scale).clamp(0.0, 1.0)).collect();
+    Some(Heightmap::from_vec(map, width, height))
+}
+
+const NEIGHBORS: [(i32, i32); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+// one relaxation step: every cell sheds half of its largest excess over `talus` (times
+// `rate`) to the lower neighbors, in proportion to how far each of them is too low. the
+// step is computed from a copy of the map, so it parallelizes cleanly and does not depend
+// on the scan order. returns the largest amount that left a cell.
+fn relax_step(
+    map: &mut [f32],
+    outflow: &mut [(f32, f32)],
+    w: usize,
+    h: usize,
+    talus: f32,
+    rate: f32,
+) -> f32 {
+    // neighbors outside the map are ignored, so no material is lost over the border
+    // and the edges don't build up rims
+    let excess = |map: &[f32], x: usize, y: usize, dx: i32, dy: i32| -> f32 {
+        let nx = x as i32 + dx;
+        let ny = y as i32 + dy;
+        if nx < 0 || ny < 0 || nx >= w as i32 || ny >= h as i32 {
+            return 0.0;
+        }
+        (map[y * w + x] - map[ny as usize * w + nx as usize] - talus).max(0.0)
+    };
+
+    // amount leaving each cell and the total excess slope it is distributed over
+    outflow.par_chunks_mut(w).enumerate().for_each(|(y, row)| {
+        for (x, out) in row.iter_mut().enumerate() {
+            let mut total = 0.0;
+            let mut max_excess = 0.0f32;
+            for &(dx, dy) in NEIGHBORS.iter() {
+                let e = excess(map, x, y, dx, dy);
+                total += e;
+                max_excess = max_excess.max(e);
+            }
+            // moving half the excess would level the steepest pair exactly
+            *out = (max_excess * 0.5 * rate, total);
+        }
+    });
+    let largest = outflow
+        .par_iter()
+        .map(|&(moved, _)| moved)
+        .reduce(|| 0.0, f32::max);
+    if largest <= 0.0 {
+        return 0.0;
+    }
+
+    let previous = map.to_vec();
+    map.par_chunks_mut(w).enumerate().for_each(|(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            let mut v = previous[y * w + x] - outflow[y * w + x].0;
+            for &(dx, dy) in NEIGHBORS.iter() {
+                let nx = x as i32 + dx;
+                let ny = y as i32 + dy;
+                if nx < 0 || ny < 0 || nx >= w as i32 || ny >= h as i32 {
+                    continue;
+                }
+                let (nx, ny) = (nx as usize, ny as usize);
+                let (moved, total) = outflow[ny * w + nx];
+                if total > 0.0 {
+                    v += moved * excess(&previous, nx, ny, -dx, -dy) / total;
+                }
+            }
+            *value = v;
+        }
+    });
+    largest
+}
+
+// thermal weathering: material slides from cells whose slope to a neighbor exceeds the
+// talus angle
+pub fn thermal_erosion(
+    heightmap: &Heightmap,
+    config: &ErosionConfig,
+    progress: &Progress,
+) -> Option<Heightmap> {
+    let (width, height) = (heightmap.width(), heightmap.height());
+    let w = width as usize;
+    let h = height as usize;
+    let talus = config.talus_angle.to_radians().tan() / height_scale(width, height);
+    let rate = config.thermal_rate.clamp(0.0, 1.0);
+
+    let mut map = heightmap.data().to_vec();
+    let mut outflow = vec![(0.0f32, 0.0f32); w * h];
+
+    progress.set_total(config.thermal_iterations as usize);
+
+    for _ in 0..config.thermal_iterations {
+        if progress.is_cancelled() {
+            return None;
+        }
+        relax_step(&mut map, &mut outflow, w, h, talus, rate);
+        progress.add(1);
+    }
+
+    Some(Heightmap::from_vec(map, width, height))
+}
+
+// amount moved per step below which the slope limit counts as reached
+const SLOPE_LIMIT_TOLERANCE: f32 = 1e-5;
+
+// relaxes cliffs until no cell is more than `max_step` above any of its 4 neighbors, or
+// until `iterations` ran out. material only moves downhill to neighbors, so the total
+// volume and the height range are kept. returns how many cells changed.
+pub fn limit_slope(
+    heightmap: &mut Heightmap,
+    max_step: f32,
+    iterations: u32,
+    progress: &Progress,
+) -> Option<usize> {
+    let (w, h) = (heightmap.width() as usize, heightmap.height() as usize);
+    let original = heightmap.data().to_vec();
+    let map = heightmap.data_mut();
+    let mut outflow = vec![(0.0f32, 0.0f32); w * h];
+
+    for _ in 0..iterations {
+        if progress.is_cancelled() {
+            return None;
+        }
+        if relax_step(map, &mut outflow, w, h, max_step.max(0.0), 1.0) <= SLOPE_LIMIT_TOLERANCE {
+            break;
+        }
+    }
+    let changed = map
+        .iter()
+        .zip(original)
+        .filter(|&(&v, o)| (v - o).abs() > f32::EPSILON)
+        .count();
+    Some(changed)
+}
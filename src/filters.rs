@@ -0,0 +1,321 @@
+use crate::heightmap::Heightmap;
+use crate::jobs::Progress;
+use rayon::prelude::*;
+
+// largest blur sigma in pixels, reached at a smoothness of 1
+pub const MAX_SMOOTHING_SIGMA: f32 = 10.0;
+
+// normalized 1d gaussian weights from -radius to +radius
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil() as i64;
+    let weights: Vec<f32> = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = weights.iter().sum();
+    weights.into_iter().map(|w| w / sum).collect()
+}
+
+// separable gaussian blur, rows first and then columns. pixels outside the map repeat the
+// border pixel, so the edges keep their height instead of fading towards zero. the filters
+// check for a cancel on every row and return None then.
+pub fn gaussian_blur(heightmap: &Heightmap, sigma: f32, progress: &Progress) -> Option<Heightmap> {
+    if sigma <= 0.0 {
+        return Some(heightmap.clone());
+    }
+    let kernel = gaussian_kernel(sigma);
+    let radius = (kernel.len() / 2) as i64;
+    let (width, height) = (heightmap.width() as usize, heightmap.height() as usize);
+
+    let mut rows = vec![0.0; heightmap.len()];
+    rows.par_chunks_mut(width)
+        .zip(heightmap.data().par_chunks(width))
+        .for_each(|(out, src)| {
+            if progress.is_cancelled() {
+                return;
+            }
+            for (x, value) in out.iter_mut().enumerate() {
+                *value = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| {
+                        let sx = (x as i64 + k as i64 - radius).clamp(0, width as i64 - 1);
+                        w * src[sx as usize]
+                    })
+                    .sum();
+            }
+        });
+
+    let mut out = vec![0.0; heightmap.len()];
+    out.par_chunks_mut(width).enumerate().for_each(|(y, line)| {
+        if progress.is_cancelled() {
+            return;
+        }
+        for (k, w) in kernel.iter().enumerate() {
+            let sy = (y as i64 + k as i64 - radius).clamp(0, height as i64 - 1) as usize;
+            let src = &rows[sy * width..(sy + 1) * width];
+            for (value, s) in line.iter_mut().zip(src) {
+                *value += w * s;
+            }
+        }
+    });
+    if progress.is_cancelled() {
+        return None;
+    }
+    Some(Heightmap::from_vec(
+        out,
+        heightmap.width(),
+        heightmap.height(),
+    ))
+}
+
+// gaussian of the height difference, tabulated since exp() dominates the bilateral filter
+struct RangeWeights {
+    table: Vec<f32>,
+    // table entries per unit of height difference
+    resolution: f32,
+}
+
+impl RangeWeights {
+    // differences beyond this many thresholds get a weight of zero (exp(-8) before that)
+    const CUTOFF: f32 = 4.0;
+    const SIZE: usize = 1024;
+
+    fn new(edge_threshold: f32) -> Self {
+        let threshold = edge_threshold.max(1e-4);
+        let table = (0..=Self::SIZE)
+            .map(|i| {
+                let t = i as f32 / Self::SIZE as f32 * Self::CUTOFF;
+                (-0.5 * t * t).exp()
+            })
+            .collect();
+        Self {
+            table,
+            resolution: Self::SIZE as f32 / (Self::CUTOFF * threshold),
+        }
+    }
+
+    fn get(&self, difference: f32) -> f32 {
+        let i = (difference.abs() * self.resolution) as usize;
+        self.table.get(i).copied().unwrap_or(0.0)
+    }
+}
+
+// separable approximation of a bilateral filter. neighbors are weighted by distance like the
+// gaussian blur and additionally by how close their height is to the center pixel, so flat
+// areas get smoothed while steep transitions are preserved. running a row and a column pass
+// instead of the full 2d kernel keeps it at O(r) per pixel.
+pub fn bilateral_blur(
+    heightmap: &Heightmap,
+    sigma: f32,
+    edge_threshold: f32,
+    progress: &Progress,
+) -> Option<Heightmap> {
+    if sigma <= 0.0 {
+        return Some(heightmap.clone());
+    }
+    let kernel = gaussian_kernel(sigma);
+    let radius = (kernel.len() / 2) as i64;
+    let (width, height) = (heightmap.width() as usize, heightmap.height() as usize);
+    let range = RangeWeights::new(edge_threshold);
+
+    let mut rows = vec![0.0; heightmap.len()];
+    rows.par_chunks_mut(width)
+        .zip(heightmap.data().par_chunks(width))
+        .for_each(|(out, src)| {
+            if progress.is_cancelled() {
+                return;
+            }
+            for (x, value) in out.iter_mut().enumerate() {
+                let center = src[x];
+                let (mut sum, mut total) = (0.0, 0.0);
+                for (k, w) in kernel.iter().enumerate() {
+                    let sx = (x as i64 + k as i64 - radius).clamp(0, width as i64 - 1);
+                    let s = src[sx as usize];
+                    let weight = w * range.get(s - center);
+                    sum += weight * s;
+                    total += weight;
+                }
+                *value = sum / total;
+            }
+        });
+
+    let mut out = vec![0.0; heightmap.len()];
+    out.par_chunks_mut(width).enumerate().for_each(|(y, line)| {
+        if progress.is_cancelled() {
+            return;
+        }
+        let center = &rows[y * width..(y + 1) * width];
+        let mut total = vec![0.0; width];
+        for (k, w) in kernel.iter().enumerate() {
+            let sy = (y as i64 + k as i64 - radius).clamp(0, height as i64 - 1) as usize;
+            let src = &rows[sy * width..(sy + 1) * width];
+            for x in 0..width {
+                let weight = w * range.get(src[x] - center[x]);
+                line[x] += weight * src[x];
+                total[x] += weight;
+            }
+        }
+        for (value, total) in line.iter_mut().zip(total) {
+            *value /= total;
+        }
+    });
+    if progress.is_cancelled() {
+        return None;
+    }
+    Some(Heightmap::from_vec(
+        out,
+        heightmap.width(),
+        heightmap.height(),
+    ))
+}
+
+// gaussian blur that only applies where the terrain is steep. the blur is mixed in by how
+// far the local gradient (height change per pixel) exceeds `threshold`, reaching the full
+// blur at twice the threshold, so plains keep their detail.
+pub fn slope_limited_blur(
+    heightmap: &Heightmap,
+    sigma: f32,
+    threshold: f32,
+    progress: &Progress,
+) -> Option<Heightmap> {
+    if sigma <= 0.0 {
+        return Some(heightmap.clone());
+    }
+    let blurred = gaussian_blur(heightmap, sigma, progress)?;
+    let threshold = threshold.max(1e-6);
+    let (width, height) = (heightmap.width() as usize, heightmap.height() as usize);
+    let src = heightmap.data();
+
+    let mut out = blurred.into_data();
+    out.par_chunks_mut(width).enumerate().for_each(|(y, line)| {
+        if progress.is_cancelled() {
+            return;
+        }
+        let (y0, y1) = (y.saturating_sub(1), (y + 1).min(height - 1));
+        for (x, value) in line.iter_mut().enumerate() {
+            let (x0, x1) = (x.saturating_sub(1), (x + 1).min(width - 1));
+            let dx = (src[y * width + x1] - src[y * width + x0]) / (x1 - x0).max(1) as f32;
+            let dy = (src[y1 * width + x] - src[y0 * width + x]) / (y1 - y0).max(1) as f32;
+            let gradient = dx.hypot(dy);
+            let weight = ((gradient - threshold) / threshold).clamp(0.0, 1.0);
+            let original = src[y * width + x];
+            *value = original + (*value - original) * weight;
+        }
+    });
+    if progress.is_cancelled() {
+        return None;
+    }
+    Some(Heightmap::from_vec(
+        out,
+        heightmap.width(),
+        heightmap.height(),
+    ))
+}
+
+// median of the (2 * radius + 1)² neighborhood, removes single pixel spikes and pits
+// without smearing steps the way a blur does. pixels outside the map repeat the border.
+pub fn median_filter(
+    heightmap: &Heightmap,
+    radius: usize,
+    progress: &Progress,
+) -> Option<Heightmap> {
+    if radius == 0 {
+        return Some(heightmap.clone());
+    }
+    let (width, height) = (heightmap.width() as usize, heightmap.height() as usize);
+    let src = heightmap.data();
+    let r = radius as i64;
+
+    let mut out = vec![0.0; heightmap.len()];
+    out.par_chunks_mut(width).enumerate().for_each(|(y, line)| {
+        if progress.is_cancelled() {
+            return;
+        }
+        let mut window = Vec::with_capacity((2 * radius + 1).pow(2));
+        for (x, value) in line.iter_mut().enumerate() {
+            window.clear();
+            for dy in -r..=r {
+                let sy = (y as i64 + dy).clamp(0, height as i64 - 1) as usize;
+                for dx in -r..=r {
+                    let sx = (x as i64 + dx).clamp(0, width as i64 - 1) as usize;
+                    window.push(src[sy * width + sx]);
+                }
+            }
+            let middle = window.len() / 2;
+            *value = *window.select_nth_unstable_by(middle, f32::total_cmp).1;
+        }
+    });
+    if progress.is_cancelled() {
+        return None;
+    }
+    Some(Heightmap::from_vec(
+        out,
+        heightmap.width(),
+        heightmap.height(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blur(heightmap: &Heightmap, sigma: f32) -> Heightmap {
+        gaussian_blur(heightmap, sigma, &Progress::default()).unwrap()
+    }
+
+    #[test]
+    fn blurring_a_flat_map_changes_nothing() {
+        let heightmap = Heightmap::from_vec(vec![0.3; 40 * 30], 40, 30);
+        let blurred = blur(&heightmap, 3.0);
+        // the clamped border counts as the same height, so the edges don't darken either
+        assert!(blurred.iter().all(|&h| (h - 0.3).abs() < 1e-6));
+    }
+
+    #[test]
+    fn a_spike_spreads_the_same_way_in_every_direction() {
+        let size = 31;
+        let center = size / 2;
+        let mut data = vec![0.0; (size * size) as usize];
+        data[(center * size + center) as usize] = 1.0;
+        let blurred = blur(&Heightmap::from_vec(data, size, size), 2.0);
+        let at = |dx: i32, dy: i32| {
+            blurred.get((center as i32 + dx) as u32, (center as i32 + dy) as u32)
+        };
+        assert!(at(0, 0) < 1.0);
+        assert!(at(1, 0) > 0.0);
+        for (dx, dy) in [(1, 0), (3, 0), (2, 1), (4, 3)] {
+            let value = at(dx, dy);
+            for (x, y) in [(-dx, dy), (dx, -dy), (-dx, -dy), (dy, dx), (-dy, -dx)] {
+                assert!((at(x, y) - value).abs() < 1e-6, "{dx},{dy} against {x},{y}");
+            }
+        }
+        // nothing is lost, the spike is only spread out
+        let total: f32 = blurred.iter().sum();
+        assert!((total - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn median_removes_spikes_and_keeps_a_step() {
+        // a cliff down the middle with spikes and pits on both sides of it, in the corner
+        // and right next to the edge
+        let (width, height) = (32, 32);
+        let clean: Vec<f32> = (0..width * height)
+            .map(|i| if i % width < 16 { 0.2 } else { 0.8 })
+            .collect();
+        let mut noisy = clean.clone();
+        for (x, y, value) in [
+            (0, 0, 1.0),
+            (15, 10, 1.0),
+            (16, 20, 0.0),
+            (5, 31, 0.0),
+            (25, 7, 0.0),
+        ] {
+            noisy[(y * width + x) as usize] = value;
+        }
+        let noisy = Heightmap::from_vec(noisy, width, height);
+        for radius in [1, 2] {
+            let filtered = median_filter(&noisy, radius, &Progress::default()).unwrap();
+            assert_eq!(filtered.data(), &clean[..], "radius {radius}");
+        }
+    }
+}
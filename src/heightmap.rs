@@ -0,0 +1,143 @@
+use crate::utils::sample_bilinear;
+
+// a grid of f32 values stored row by row. used for the terrain heights, but also for
+// other per pixel maps like the slope, so the dimensions always travel with the data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    data: Vec<f32>,
+    width: u32,
+    height: u32,
+}
+
+impl Heightmap {
+    pub fn from_vec(data: Vec<f32>, width: u32, height: u32) -> Self {
+        assert_eq!(
+            data.len(),
+            (width * height) as usize,
+            "heightmap data does not match {}x{}",
+            width,
+            height
+        );
+        Self {
+            data,
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    // mutable access to the values, the length can't change through a slice
+    pub fn data_mut(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> Vec<f32> {
+        self.data
+    }
+
+    pub fn index(&self, x: u32, y: u32) -> usize {
+        debug_assert!(x < self.width && y < self.height);
+        (y * self.width + x) as usize
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> f32 {
+        self.data[self.index(x, y)]
+    }
+
+    // coordinates outside the map read the nearest border pixel
+    pub fn get_clamped(&self, x: i64, y: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as u32;
+        let y = y.clamp(0, self.height as i64 - 1) as u32;
+        self.get(x, y)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, f32> {
+        self.data.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, f32> {
+        self.data.iter_mut()
+    }
+
+    pub fn rows(&self) -> std::slice::Chunks<'_, f32> {
+        self.data.chunks(self.width.max(1) as usize)
+    }
+
+    pub fn rows_mut(&mut self) -> std::slice::ChunksMut<'_, f32> {
+        self.data.chunks_mut(self.width.max(1) as usize)
+    }
+
+    // bilinear sample at fractional pixel coordinates, clamped to the border
+    pub fn sample(&self, fx: f32, fy: f32) -> f32 {
+        sample_bilinear(&self.data, self.width, self.height, fx, fy)
+    }
+
+    // the range scan skips NaN and infinite values, so a single bad cell can't collapse
+    // the range that everything else is normalized with
+    pub fn min_max(&self) -> (f32, f32) {
+        self.data
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0 1 2
+    // 3 4 5
+    fn ramp() -> Heightmap {
+        Heightmap::from_vec(vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0], 3, 2)
+    }
+
+    #[test]
+    fn sample_hits_pixels_and_interpolates_between_them() {
+        let map = ramp();
+        assert_eq!(map.sample(2.0, 1.0), 5.0);
+        assert_eq!(map.sample(0.5, 0.0), 0.5);
+        assert_eq!(map.sample(1.0, 0.5), 2.5);
+        assert_eq!(map.sample(0.5, 0.5), 2.0);
+    }
+
+    #[test]
+    fn sample_clamps_to_the_border() {
+        let map = ramp();
+        assert_eq!(map.sample(-3.0, -3.0), 0.0);
+        assert_eq!(map.sample(10.0, 0.0), 2.0);
+        assert_eq!(map.sample(1.5, 7.0), 4.5);
+    }
+
+    #[test]
+    fn get_clamped_reads_the_nearest_border_pixel() {
+        let map = ramp();
+        assert_eq!(map.get_clamped(1, 1), 4.0);
+        assert_eq!(map.get_clamped(-1, 0), 0.0);
+        assert_eq!(map.get_clamped(5, -2), 2.0);
+        assert_eq!(map.get_clamped(i64::MAX, i64::MAX), 5.0);
+    }
+
+    #[test]
+    fn min_max_skips_non_finite_values() {
+        let map = Heightmap::from_vec(vec![f32::NAN, 0.25, f32::INFINITY, 0.75], 2, 2);
+        assert_eq!(map.min_max(), (0.25, 0.75));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::heightmap::Heightmap;
+use std::collections::VecDeque;
+use std::mem;
+
+// edge length of the blocks a change is compared and stored in
+const TILE_SIZE: u32 = 64;
+const DEFAULT_HISTORY_DEPTH: usize = 20;
+pub const MAX_HISTORY_DEPTH: usize = 200;
+
+// block of the heightmap, holds the values on the other side of the change
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    data: Vec<f32>,
+}
+
+enum Change {
+    // only the blocks that differ, so a brush stroke costs a few tiles instead of a full map
+    Tiles(Vec<Tile>),
+    // whole map, used when the size changed or most of the map is different anyway
+    Full(Heightmap),
+}
+
+struct Entry {
+    label: String,
+    change: Change,
+    // elevation range in meters, imports replace it together with the heightmap
+    elevation: (f32, f32),
+}
+
+impl Entry {
+    // swaps the stored state with the current one, the entry then describes the way back
+    fn swap(&mut self, heightmap: &mut Heightmap, elevation: &mut (f32, f32)) {
+        mem::swap(&mut self.elevation, elevation);
+        match &mut self.change {
+            Change::Full(stored) => mem::swap(stored, heightmap),
+            Change::Tiles(tiles) => {
+                let width = heightmap.width() as usize;
+                let data = heightmap.data_mut();
+                for tile in tiles {
+                    for (row, stored) in tile.data.chunks_mut(tile.width as usize).enumerate() {
+                        let start = (tile.y as usize + row) * width + tile.x as usize;
+                        data[start..start + stored.len()].swap_with_slice(stored);
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_bytes(&self) -> usize {
+        let values = match &self.change {
+            Change::Full(heightmap) => heightmap.len(),
+            Change::Tiles(tiles) => tiles.iter().map(|t| t.data.len()).sum(),
+        };
+        values * mem::size_of::<f32>()
+    }
+}
+
+// tiles of `before` where it differs from `after`, both have to be the same size
+fn changed_tiles(before: &Heightmap, after: &Heightmap) -> Vec<Tile> {
+    let (width, height) = (before.width(), before.height());
+    let mut tiles = Vec::new();
+    for y in (0..height).step_by(TILE_SIZE as usize) {
+        for x in (0..width).step_by(TILE_SIZE as usize) {
+            let tile_width = TILE_SIZE.min(width - x);
+            let tile_height = TILE_SIZE.min(height - y);
+            let rows = (y..y + tile_height).map(|row| {
+                let start = before.index(x, row);
+                start..start + tile_width as usize
+            });
+            let changed = rows
+                .clone()
+                .any(|range| before.data()[range.clone()] != after.data()[range]);
+            if changed {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: tile_width,
+                    data: rows
+                        .flat_map(|range| before.data()[range].to_vec())
+                        .collect(),
+                });
+            }
+        }
+    }
+    tiles
+}
+
+// undo and redo stacks for heightmap edits. the oldest entries are dropped once there are
+// more than `depth` of them.
+pub struct History {
+    undo: VecDeque<Entry>,
+    redo: Vec<Entry>,
+    depth: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            depth: DEFAULT_HISTORY_DEPTH,
+        }
+    }
+}
+
+impl History {
+    // remembers `before` as the state in front of an edit that produced `after`. a new edit
+    // makes the redo stack meaningless, so it is cleared.
+    pub fn record(
+        &mut self,
+        label: &str,
+        before: Heightmap,
+        after: &Heightmap,
+        elevation: (f32, f32),
+    ) {
+        let same_size = (before.width(), before.height()) == (after.width(), after.height());
+        let change = if same_size {
+            let tiles = changed_tiles(&before, after);
+            if tiles.is_empty() {
+                return;
+            }
+            let stored: usize = tiles.iter().map(|t| t.data.len()).sum();
+            // past half the map the tile bookkeeping saves little, keep the plain copy
+            if stored * 2 > before.len() {
+                Change::Full(before)
+            } else {
+                Change::Tiles(tiles)
+            }
+        } else {
+            Change::Full(before)
+        };
+        self.redo.clear();
+        self.undo.push_back(Entry {
+            label: label.to_string(),
+            change,
+            elevation,
+        });
+        self.evict();
+    }
+
+    // restores the state in front of the last edit, returns its label
+    pub fn undo(
+        &mut self,
+        heightmap: &mut Heightmap,
+        elevation: &mut (f32, f32),
+    ) -> Option<String> {
+        let mut entry = self.undo.pop_back()?;
+        entry.swap(heightmap, elevation);
+        let label = entry.label.clone();
+        self.redo.push(entry);
+        Some(label)
+    }
+
+    // repeats the last undone edit, returns its label
+    pub fn redo(
+        &mut self,
+        heightmap: &mut Heightmap,
+        elevation: &mut (f32, f32),
+    ) -> Option<String> {
+        let mut entry = self.redo.pop()?;
+        entry.swap(heightmap, elevation);
+        let label = entry.label.clone();
+        self.undo.push_back(entry);
+        Some(label)
+    }
+
+    pub fn undo_label(&self) -> Option<&str> {
+        self.undo.back().map(|e| e.label.as_str())
+    }
+
+    pub fn redo_label(&self) -> Option<&str> {
+        self.redo.last().map(|e| e.label.as_str())
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.clamp(1, MAX_HISTORY_DEPTH);
+        self.evict();
+    }
+
+    // memory held by both stacks
+    pub fn size_bytes(&self) -> usize {
+        self.undo
+            .iter()
+            .chain(&self.redo)
+            .map(Entry::size_bytes)
+            .sum()
+    }
+
+    fn evict(&mut self) {
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+        // the bottom of the redo stack is the furthest step ahead
+        let excess = self.redo.len().saturating_sub(self.depth);
+        self.redo.drain(..excess);
+    }
+}
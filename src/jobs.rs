@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+
+// shared progress counter and cancel flag between a worker thread and the ui
+#[derive(Default)]
+pub struct Progress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: usize) {
+        self.done.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn fraction(&self) -> f32 {
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+        (self.done.load(Ordering::Relaxed) as f32 / total as f32).clamp(0.0, 1.0)
+    }
+
+    // workers check this between rows or tiles and return None once it is set
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// a long running computation on a worker thread, polled from the ui every frame
+pub struct Job<T> {
+    pub label: String,
+    pub progress: Arc<Progress>,
+    handle: JoinHandle<T>,
+}
+
+impl<T: Send + 'static> Job<T> {
+    pub fn spawn<F>(label: &str, f: F) -> Self
+    where
+        F: FnOnce(&Progress) -> T + Send + 'static,
+    {
+        let progress = Arc::new(Progress::default());
+        let worker_progress = progress.clone();
+        let handle = std::thread::spawn(move || f(&worker_progress));
+        Self {
+            label: label.to_string(),
+            progress,
+            handle,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    // returns None if the worker panicked
+    pub fn join(self) -> Option<T> {
+        self.handle.join().ok()
+    }
+}
+
+// takes the result out of a finished job, None while it runs or if it was cancelled or failed
+pub fn take_finished<T: Send + 'static>(slot: &mut Option<Job<Option<T>>>) -> Option<T> {
+    if !slot.as_ref().is_some_and(|job| job.is_finished()) {
+        return None;
+    }
+    let job = slot.take().unwrap();
+    let label = job.label.clone();
+    match job.join() {
+        Some(Some(result)) => Some(result),
+        Some(None) => {
+            println!("{} cancelled", label);
+            None
+        }
+        None => {
+            eprintln!("Error: {} failed", label);
+            None
+        }
+    }
+}
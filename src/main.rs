@@ -6,6 +6,16 @@ mod refiner;
 mod biomes;
 mod water;
 mod utils;
+mod erosion;
+mod jobs;
+mod stamps;
+mod analysis;
+mod heightmap;
+mod dem;
+mod filters;
+mod curve;
+mod sculpt;
+mod history;
 
 fn main() -> eframe::Result<()> {
     let options = eframe::NativeOptions::default();
@@ -1,3 +1,8 @@
+use crate::heightmap::Heightmap;
+use eframe::egui;
+use image::{ImageBuffer, Rgba};
+use rayon::prelude::*;
+
 pub fn get_color_for_height(h: f64, sea_level: f64) -> (u8, u8, u8) {
     if h < sea_level * 0.6 {
         (0, 0, 100)
@@ -13,3 +18,131 @@ pub fn get_color_for_height(h: f64, sea_level: f64) -> (u8, u8, u8) {
         (255, 250, 250)
     }
 }
+
+// flat ground is green and turns red towards 45 degrees and steeper
+pub fn get_color_for_slope(slope_degrees: f32) -> (u8, u8, u8) {
+    let t = (slope_degrees / 45.0).clamp(0.0, 1.0);
+    let lerp = |a: f32, b: f32| (a + (b - a) * t) as u8;
+    (lerp(34.0, 200.0), lerp(139.0, 40.0), lerp(34.0, 30.0))
+}
+
+// dry ground is sand colored and turns blue the wetter it gets
+pub fn get_color_for_wetness(wetness: f32) -> (u8, u8, u8) {
+    let t = wetness.clamp(0.0, 1.0);
+    let lerp = |a: f32, b: f32| (a + (b - a) * t) as u8;
+    (lerp(210.0, 20.0), lerp(190.0, 80.0), lerp(140.0, 200.0))
+}
+
+// alternates the brightness of neighbouring terrace levels so the steps are visible
+pub fn shade_terrace(color: (u8, u8, u8), h: f64, steps: u32) -> (u8, u8, u8) {
+    let level = (h * steps as f64).floor() as i64;
+    if level % 2 == 0 {
+        return color;
+    }
+    let darken = |c: u8| (c as f64 * 0.85) as u8;
+    (darken(color.0), darken(color.1), darken(color.2))
+}
+
+// largest side of the preview image, bigger maps are point sampled down to this size
+pub const PREVIEW_MAX_SIZE: u32 = 2048;
+
+// every how many map pixels one preview pixel is sampled
+fn preview_step(width: u32, height: u32) -> u32 {
+    width.max(height).div_ceil(PREVIEW_MAX_SIZE).max(1)
+}
+
+// size of the preview build_preview makes for a map
+pub fn preview_size(width: u32, height: u32) -> [usize; 2] {
+    let step = preview_step(width, height);
+    [
+        width.div_ceil(step) as usize,
+        height.div_ceil(step) as usize,
+    ]
+}
+
+// builds the preview texture and image from a heightmap without ever allocating a full
+// resolution image
+pub fn build_preview<F>(
+    heightmap: &Heightmap,
+    color: F,
+) -> (egui::ColorImage, ImageBuffer<Rgba<u8>, Vec<u8>>)
+where
+    F: Fn(f32) -> (u8, u8, u8) + Sync,
+{
+    build_grid_preview(heightmap.width(), heightmap.height(), |x, y| {
+        color(heightmap.get(x, y))
+    })
+}
+
+// the same for any per pixel map, `color` gives the color of the map pixel at (x, y)
+pub fn build_grid_preview<F>(
+    width: u32,
+    height: u32,
+    color: F,
+) -> (egui::ColorImage, ImageBuffer<Rgba<u8>, Vec<u8>>)
+where
+    F: Fn(u32, u32) -> (u8, u8, u8) + Sync,
+{
+    let step = preview_step(width, height);
+    let (pw, ph) = (width.div_ceil(step), height.div_ceil(step));
+    let mut raw = vec![0u8; (pw * ph * 4) as usize];
+    raw.par_chunks_mut((pw * 4) as usize)
+        .enumerate()
+        .for_each(|(py, row)| {
+            let y = py as u32 * step;
+            for (px, pixel) in row.chunks_mut(4).enumerate() {
+                let x = px as u32 * step;
+                let (r, g, b) = color(x, y);
+                pixel.copy_from_slice(&[r, g, b, 255]);
+            }
+        });
+    let pixels = raw
+        .chunks(4)
+        .map(|p| egui::Color32::from_rgb(p[0], p[1], p[2]))
+        .collect();
+    let image = ImageBuffer::from_raw(pw, ph, raw).unwrap();
+    (
+        egui::ColorImage {
+            size: [pw as usize, ph as usize],
+            pixels,
+        },
+        image,
+    )
+}
+
+// rebuilds the part of a build_preview image that shows the map pixels x0..x1, y0..y1.
+// returns the patch and its position in the preview.
+pub fn build_preview_patch<F>(
+    heightmap: &Heightmap,
+    (x0, y0, x1, y1): (u32, u32, u32, u32),
+    color: F,
+) -> ([usize; 2], egui::ColorImage)
+where
+    F: Fn(f32) -> (u8, u8, u8) + Sync,
+{
+    let step = preview_step(heightmap.width(), heightmap.height());
+    let (x1, y1) = (x1.min(heightmap.width()), y1.min(heightmap.height()));
+    // preview pixels whose sample position falls inside the rectangle
+    let (px0, px1) = (x0.div_ceil(step), x1.div_ceil(step));
+    let (py0, py1) = (y0.div_ceil(step), y1.div_ceil(step));
+    let (pw, ph) = (px1.saturating_sub(px0), py1.saturating_sub(py0));
+    let mut pixels = vec![egui::Color32::BLACK; (pw * ph) as usize];
+    pixels
+        .par_chunks_mut(pw.max(1) as usize)
+        .enumerate()
+        .for_each(|(row, line)| {
+            let y = (py0 + row as u32) * step;
+            for (col, pixel) in line.iter_mut().enumerate() {
+                let x = (px0 + col as u32) * step;
+                let (r, g, b) = color(heightmap.get(x, y));
+                *pixel = egui::Color32::from_rgb(r, g, b);
+            }
+        });
+    (
+        [px0 as usize, py0 as usize],
+        egui::ColorImage {
+            size: [pw as usize, ph as usize],
+            pixels,
+        },
+    )
+}
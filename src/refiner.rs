@@ -1,47 +1,567 @@
-use crate::config::{MapConfig, RefinerConfig};
+use crate::config::{
+    DetailNoise, NoiseAlgorithm, Normalization, PaintBlend, RefineMask, RefineOp, RefinerConfig,
+    SmoothingMode,
+};
+use crate::curve::{build_curve_lut, sample_curve_lut};
+use crate::erosion::limit_slope;
+use crate::filters::{
+    MAX_SMOOTHING_SIGMA, bilateral_blur, gaussian_blur, median_filter, slope_limited_blur,
+};
+use crate::heightmap::Heightmap;
+use crate::jobs::Progress;
+use crate::terrain::NoiseSource;
+use crate::utils::{ResampleFilter, distance_transform, resample_heightmap};
+use std::sync::Arc;
 
-pub fn refine_heightmap(
-    heightmap: &Vec<f32>,
-    config: &RefinerConfig,
-    map_config: &MapConfig,
-) -> Vec<f32> {
-    let width = map_config.width as usize;
-    let height = map_config.height as usize;
-    let size = width * height;
+// height band around the sea level over which the land and water masks fade, so the
+// coastline doesn't get a seam
+const MASK_FEATHER: f32 = 0.01;
 
-    // clone the heightmap to avoid modifying the original
-    let mut heightmap = heightmap.clone();
+// per pixel weight of the refinement, taken from the heights before any stage ran. none
+// means the whole map is refined.
+struct Mask(Option<Vec<f32>>);
+
+impl Mask {
+    fn new(heightmap: &Heightmap, config: &RefinerConfig, sea_level: f32) -> Self {
+        let land = |h: f32| {
+            let t = ((h - sea_level + MASK_FEATHER) / (2.0 * MASK_FEATHER)).clamp(0.0, 1.0);
+            t * t * (3.0 - 2.0 * t)
+        };
+        let weights = match config.mask {
+            RefineMask::All => None,
+            RefineMask::LandOnly => Some(heightmap.iter().map(|&h| land(h)).collect()),
+            RefineMask::WaterOnly => Some(heightmap.iter().map(|&h| 1.0 - land(h)).collect()),
+            // without an image there is nothing to restrict the refinement to
+            RefineMask::Custom => config.custom_mask.as_deref().map(|mask| {
+                let resampled = resample_heightmap(
+                    mask,
+                    heightmap.width(),
+                    heightmap.height(),
+                    ResampleFilter::Bilinear,
+                );
+                resampled.iter().map(|m| m.clamp(0.0, 1.0)).collect()
+            }),
+        };
+        Self(weights)
+    }
+
+    // copy of the heights in front of a stage, only needed when there is a mask
+    fn snapshot(&self, heightmap: &Heightmap) -> Option<Vec<f32>> {
+        self.0.as_ref().map(|_| heightmap.data().to_vec())
+    }
+
+    // blends the result of a stage with the heights from the snapshot
+    fn restore(&self, heightmap: &mut Heightmap, before: Option<Vec<f32>>) {
+        let (Some(weights), Some(before)) = (&self.0, before) else {
+            return;
+        };
+        for ((value, b), w) in heightmap.iter_mut().zip(before).zip(weights) {
+            *value = b + (*value - b) * w;
+        }
+    }
+}
+
+// distance in pixels from every pixel to the coastline, land to the nearest water and water
+// to the nearest land. it only changes with the heightmap and the sea level, so the caller
+// keeps it for the next refinement of the same map.
+pub struct CoastDistance {
+    sea_level: f32,
+    distance: Vec<f32>,
+}
+
+impl CoastDistance {
+    pub fn new(heightmap: &Heightmap, sea_level: f32) -> Self {
+        let (width, height) = (heightmap.width(), heightmap.height());
+        let land: Vec<bool> = heightmap.iter().map(|&h| h >= sea_level).collect();
+        let water: Vec<bool> = land.iter().map(|&l| !l).collect();
+        let to_water = distance_transform(&water, width, height);
+        let to_land = distance_transform(&land, width, height);
+        let distance = land
+            .iter()
+            .zip(to_water.into_iter().zip(to_land))
+            .map(|(&is_land, (d_water, d_land))| if is_land { d_water } else { d_land })
+            .collect();
+        Self {
+            sea_level,
+            distance,
+        }
+    }
+
+    fn fits(&self, heightmap: &Heightmap, sea_level: f32) -> bool {
+        self.sea_level == sea_level && self.distance.len() == heightmap.len()
+    }
+
+    // how far inland every pixel is, 0 on the coastline and 1 from `transition` pixels on.
+    // water counts the same way by its distance to land, so the shallows follow the coast
+    // too.
+    fn inland_weights(&self, transition: f32) -> Vec<f32> {
+        let transition = transition.max(1.0);
+        self.distance
+            .iter()
+            .map(|&d| {
+                let t = (d / transition).clamp(0.0, 1.0);
+                t * t * (3.0 - 2.0 * t)
+            })
+            .collect()
+    }
+}
+
+// height band in which the detail noise fades out, so the band has no visible edge
+const NOISE_BAND_FEATHER: f32 = 0.02;
 
-    // Apply height offset, coefficient, and exponent
-    for i in 0..size {
-        heightmap[i] = (heightmap[i] as f32 + config.height_offset) * config.height_coeff;
-        heightmap[i] = heightmap[i].powf(config.height_exponent);
+// adds perlin noise where the height is inside the band and the slope is gentle enough.
+// the mask is taken from the heights before any noise was added.
+fn add_detail_noise(
+    heightmap: &mut Heightmap,
+    noise: &DetailNoise,
+    scale: f32,
+    progress: &Progress,
+) -> Option<()> {
+    let source = NoiseSource::new(NoiseAlgorithm::Perlin, noise.seed);
+    // noise coordinates are in pixels of the full map
+    let unit = 1.0 / (scale.max(1e-6) * noise.scale.max(0.01));
+    let (width, height) = (heightmap.width() as usize, heightmap.height() as usize);
+    let original = heightmap.data().to_vec();
+    let band = |h: f32| {
+        let above = ((h - noise.min_height) / NOISE_BAND_FEATHER + 0.5).clamp(0.0, 1.0);
+        let below = ((noise.max_height - h) / NOISE_BAND_FEATHER + 0.5).clamp(0.0, 1.0);
+        above * below
+    };
+    // a pixel of a downsampled map covers more height change
+    let max_slope = noise.max_slope.map(|s| s.max(1e-6) / scale.max(1e-6));
+
+    for (y, row) in heightmap.rows_mut().enumerate() {
+        if progress.is_cancelled() {
+            return None;
+        }
+        let (y0, y1) = (y.saturating_sub(1), (y + 1).min(height - 1));
+        for (x, value) in row.iter_mut().enumerate() {
+            let mut weight = band(original[y * width + x]);
+            if let Some(max_slope) = max_slope {
+                let (x0, x1) = (x.saturating_sub(1), (x + 1).min(width - 1));
+                let dx = (original[y * width + x1] - original[y * width + x0]) / 2.0;
+                let dy = (original[y1 * width + x] - original[y0 * width + x]) / 2.0;
+                // fades out over the last quarter below the limit
+                weight *= ((max_slope - dx.hypot(dy)) / (0.25 * max_slope)).clamp(0.0, 1.0);
+            }
+            if weight > 0.0 {
+                let n = source.sample(x as f64 * unit as f64, y as f64 * unit as f64, None);
+                *value += n as f32 * noise.amplitude * weight;
+            }
+        }
+        progress.add(1);
     }
+    Some(())
+}
 
-    // Smooth the heightmap
-    // TODO: Implement smoothing logic
-    // This could involve averaging neighboring pixels or applying a Gaussian blur
-    // probably something more advanced taking into account cliffs and other features
-    // would be nice to have a preview of the smoothed heightmap
+// clamps v to at most `max`. with a knee the slope falls off linearly from max - knee to
+// max + knee, so heights near the limit are compressed instead of cut off.
+fn soft_clamp_max(v: f32, max: f32, knee: f32) -> f32 {
+    if knee <= 0.0 {
+        return v.min(max);
+    }
+    let x = v - (max - knee);
+    if x <= 0.0 {
+        v
+    } else if x >= 2.0 * knee {
+        max
+    } else {
+        max - knee + x - x * x / (4.0 * knee)
+    }
+}
 
-    // Apply curve points if provided
-    // TODO: Implement curve points logic
+// quantizes t in [0,1] into `levels` steps. the step edges are smoothed over a share of the
+// step given by `blend`, so 1 leaves only a short flat at each level.
+fn terrace(t: f32, levels: u32, blend: f32) -> f32 {
+    let steps = (levels - 1) as f32;
+    let scaled = t * steps;
+    let level = scaled.floor().min(steps - 1.0);
+    let f = scaled - level;
+    let f = if blend <= 0.0 {
+        f.round()
+    } else {
+        let x = ((f - 0.5 * (1.0 - blend)) / blend).clamp(0.0, 1.0);
+        x * x * (3.0 - 2.0 * x)
+    };
+    (level + f) / steps
+}
 
-    // Apply paint map overlay if provided
-    // TODO: Implement paint map overlay logic
+// applies `f` to every height. like all steps it checks for a cancel on every row and
+// returns None then.
+fn map_heights(
+    heightmap: &mut Heightmap,
+    progress: &Progress,
+    f: impl Fn(f32) -> f32,
+) -> Option<()> {
+    for row in heightmap.rows_mut() {
+        if progress.is_cancelled() {
+            return None;
+        }
+        for value in row {
+            *value = f(*value);
+        }
+    }
+    Some(())
+}
 
-    // Normalize the heightmap to the range [0.0, 1.0]
-    let min_height = *heightmap.iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let max_height = *heightmap.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
+// smooths the heightmap with the configured filter, `smoothing` is in (0,1]
+fn smooth(
+    heightmap: &Heightmap,
+    config: &RefinerConfig,
+    smoothing: f32,
+    scale: f32,
+    progress: &Progress,
+) -> Option<Heightmap> {
+    let sigma = smoothing * MAX_SMOOTHING_SIGMA * scale;
+    match config.smoothing_mode {
+        SmoothingMode::Gaussian => gaussian_blur(heightmap, sigma, progress),
+        SmoothingMode::Bilateral => {
+            bilateral_blur(heightmap, sigma, config.edge_threshold, progress)
+        }
+        // a pixel of a downsampled map covers more height change
+        SmoothingMode::SlopeLimited => {
+            let threshold = config.slope_threshold / scale.max(1e-6);
+            slope_limited_blur(heightmap, sigma, threshold, progress)
+        }
+        // the kernel size is fixed, the smoothing factor mixes the result in instead
+        SmoothingMode::Median3 | SmoothingMode::Median5 => {
+            let radius = if config.smoothing_mode == SmoothingMode::Median3 {
+                1
+            } else {
+                2
+            };
+            let mut filtered = median_filter(heightmap, radius, progress)?;
+            for (value, &original) in filtered.iter_mut().zip(heightmap.iter()) {
+                *value = original + (*value - original) * smoothing;
+            }
+            Some(filtered)
+        }
+    }
+}
+
+// the curves work on [0,1], so the heights are mapped through them relative to their current
+// range. with an inland curve the two are blended by the distance from the coast, a missing
+// curve leaves the heights as they are.
+fn apply_curves(
+    heightmap: &mut Heightmap,
+    config: &RefinerConfig,
+    inland: Option<&[f32]>,
+    progress: &Progress,
+) -> Option<()> {
+    let identity = [(0.0, 0.0), (1.0, 1.0)];
+    let lut = build_curve_lut(config.curve_points.as_deref().unwrap_or(&identity));
+    let inland_lut = config.inland_curve_points.as_deref().map(build_curve_lut);
+    let width = heightmap.width() as usize;
+    let (min_height, max_height) = heightmap.min_max();
     let range = max_height - min_height;
-    if range > 0.0 {
-        for i in 0..size {
-            heightmap[i] = (heightmap[i] - min_height) / range;
+    if range <= 0.0 {
+        return Some(());
+    }
+    for (y, row) in heightmap.rows_mut().enumerate() {
+        if progress.is_cancelled() {
+            return None;
+        }
+        for (x, value) in row.iter_mut().enumerate() {
+            let t = (*value - min_height) / range;
+            let mut curved = sample_curve_lut(&lut, t);
+            if let (Some(inland_lut), Some(inland)) = (&inland_lut, inland) {
+                let w = inland[y * width + x];
+                curved += (sample_curve_lut(inland_lut, t) - curved) * w;
+            }
+            *value = min_height + curved * range;
+        }
+    }
+    Some(())
+}
+
+fn apply_paint(
+    heightmap: &mut Heightmap,
+    overlay: &Heightmap,
+    config: &RefinerConfig,
+    progress: &Progress,
+) -> Option<()> {
+    let same_size = (overlay.width(), overlay.height()) == (heightmap.width(), heightmap.height());
+    let resampled;
+    let overlay = if same_size {
+        overlay
+    } else {
+        resampled = resample_heightmap(
+            overlay,
+            heightmap.width(),
+            heightmap.height(),
+            ResampleFilter::Bilinear,
+        );
+        &resampled
+    };
+    let strength = config.paint_strength.clamp(0.0, 1.0);
+    for (row, paint) in heightmap.rows_mut().zip(overlay.rows()) {
+        if progress.is_cancelled() {
+            return None;
+        }
+        for (value, &p) in row.iter_mut().zip(paint) {
+            *value = match config.paint_blend {
+                PaintBlend::Add => *value + p * strength,
+                PaintBlend::Subtract => *value - p * strength,
+                PaintBlend::Multiply => *value * (1.0 + (p - 1.0) * strength),
+                PaintBlend::Lerp => *value + (p - *value) * strength,
+            };
+        }
+    }
+    Some(())
+}
+
+// the levels are spread evenly over the current range
+fn apply_terrace(
+    heightmap: &mut Heightmap,
+    levels: u32,
+    blend: f32,
+    progress: &Progress,
+) -> Option<()> {
+    let (min_height, max_height) = heightmap.min_max();
+    let range = max_height - min_height;
+    if range <= 0.0 {
+        return Some(());
+    }
+    map_heights(heightmap, progress, |v| {
+        min_height + terrace((v - min_height) / range, levels, blend) * range
+    })
+}
+
+// brings the heightmap back into the range [0.0, 1.0]
+fn normalize(
+    heightmap: &mut Heightmap,
+    normalization: Normalization,
+    sea_level: f32,
+    progress: &Progress,
+) -> Option<()> {
+    let (min_height, max_height) = heightmap.min_max();
+    let range = max_height - min_height;
+    map_heights(heightmap, progress, |v| match normalization {
+        Normalization::Off => v,
+        Normalization::Clamp => v.clamp(0.0, 1.0),
+        Normalization::Rescale if range > 0.0 => (v - min_height) / range,
+        Normalization::Rescale => v,
+        // water spans [min, sea level] and land [sea level, max], each is stretched to its
+        // side of the sea level on its own
+        Normalization::PreserveSeaLevel if v < sea_level => {
+            (v - min_height) / (sea_level - min_height) * sea_level
+        }
+        Normalization::PreserveSeaLevel if max_height > sea_level => {
+            sea_level + (v - sea_level) / (max_height - sea_level) * (1.0 - sea_level)
+        }
+        Normalization::PreserveSeaLevel => sea_level,
+    })
+}
+
+// whether a step has anything to do with the current settings
+fn op_active(op: RefineOp, config: &RefinerConfig) -> bool {
+    match op {
+        RefineOp::Offset | RefineOp::Coeff | RefineOp::Exponent | RefineOp::Normalize => true,
+        RefineOp::Smooth => config.smoothness > 0.0,
+        RefineOp::Curve => config.curve_points.is_some() || config.inland_curve_points.is_some(),
+        RefineOp::Clip => config.clip_min > 0.0 || config.clip_max < 1.0,
+        RefineOp::Paint => config.paint_map_overlay.is_some(),
+        RefineOp::Noise => config.detail_noise.enabled,
+        RefineOp::Terrace => config.terrace_levels > 1,
+        RefineOp::SlopeLimit => config.max_slope_step.is_some(),
+    }
+}
+
+pub struct Refined {
+    pub heightmap: Heightmap,
+    // cells changed by the slope limit, none when it is off
+    pub slope_limited_cells: Option<usize>,
+    // distance to the coast of the input, to hand in again with the next refinement of it
+    pub coast: Option<Arc<CoastDistance>>,
+}
+
+// runs the enabled steps of the pipeline in order. `scale` is the size of the given
+// heightmap relative to the map the settings are meant for, so a downsampled preview blurs
+// over the same part of the map. `coast` is the distance to the coast of the input from an
+// earlier refinement, it is worked out again if it is missing or made for another sea level.
+// `sea_level` is used by the land and water masks and the sea level preserving
+// normalization. every step is blended with its input by the mask, the normalization too,
+// so a masked refinement leaves the other side of the sea level as it was.
+pub fn refine_heightmap(
+    heightmap: &Heightmap,
+    config: &RefinerConfig,
+    sea_level: f32,
+    scale: f32,
+    coast: Option<Arc<CoastDistance>>,
+    progress: &Progress,
+) -> Option<Refined> {
+    let height = heightmap.height() as usize;
+
+    // clone the heightmap to avoid modifying the original
+    let mut heightmap = heightmap.clone();
+
+    let steps: Vec<RefineOp> = config
+        .pipeline
+        .iter()
+        .filter(|step| step.enabled && op_active(step.op, config))
+        .map(|step| step.op)
+        .collect();
+    progress.set_total(height * steps.len());
+    let sea_level = sea_level.clamp(0.0, 1.0);
+    let mask = Mask::new(&heightmap, config, sea_level);
+    // the coast is taken from the map as it came in, like the mask
+    let coast = match coast {
+        Some(coast) if coast.fits(&heightmap, sea_level) => Some(coast),
+        _ if config.inland_curve_points.is_some() => {
+            Some(Arc::new(CoastDistance::new(&heightmap, sea_level)))
+        }
+        _ => None,
+    };
+    let inland = config
+        .inland_curve_points
+        .as_ref()
+        .and(coast.as_ref())
+        .map(|coast| coast.inland_weights(config.coast_transition_px * scale));
+
+    // offset, coefficient and exponent next to each other make up one height transform, the
+    // mask blends it in once instead of after every part
+    let transform = |op: Option<&RefineOp>| {
+        matches!(
+            op,
+            Some(RefineOp::Offset | RefineOp::Coeff | RefineOp::Exponent)
+        )
+    };
+    let mut slope_limited_cells = None;
+    let mut before = None;
+    for (i, &op) in steps.iter().enumerate() {
+        if progress.is_cancelled() {
+            return None;
+        }
+        let continues = transform(Some(&op)) && i > 0 && transform(steps.get(i - 1));
+        if !continues {
+            before = mask.snapshot(&heightmap);
+        }
+        match op {
+            RefineOp::Offset => {
+                map_heights(&mut heightmap, progress, |v| v + config.height_offset)?
+            }
+            RefineOp::Coeff => map_heights(&mut heightmap, progress, |v| v * config.height_coeff)?,
+            // a fractional power of a negative number is NaN, so the exponent is applied to
+            // the magnitude and the sign is kept
+            RefineOp::Exponent => map_heights(&mut heightmap, progress, |v| {
+                v.signum() * v.abs().powf(config.height_exponent)
+            })?,
+            RefineOp::Smooth => {
+                let smoothing = config.smoothness.min(1.0);
+                heightmap = smooth(&heightmap, config, smoothing, scale, progress)?;
+            }
+            RefineOp::Curve => apply_curves(&mut heightmap, config, inland.as_deref(), progress)?,
+            RefineOp::Clip => {
+                let knee = config.clip_knee.max(0.0);
+                map_heights(&mut heightmap, progress, |v| {
+                    let v = soft_clamp_max(v, config.clip_max, knee);
+                    -soft_clamp_max(-v, -config.clip_min, knee)
+                })?;
+            }
+            RefineOp::Paint => {
+                if let Some(overlay) = config.paint_map_overlay.as_deref() {
+                    apply_paint(&mut heightmap, overlay, config, progress)?;
+                }
+            }
+            // reports its own progress
+            RefineOp::Noise => {
+                add_detail_noise(&mut heightmap, &config.detail_noise, scale, progress)?;
+            }
+            RefineOp::Terrace => {
+                let blend = config.terrace_blend.clamp(0.0, 1.0);
+                apply_terrace(&mut heightmap, config.terrace_levels, blend, progress)?;
+            }
+            RefineOp::Normalize => {
+                normalize(&mut heightmap, config.normalization, sea_level, progress)?
+            }
+            // relaxing never leaves the current height range, so with the default order the
+            // normalization can't steepen the cliffs again
+            RefineOp::SlopeLimit => {
+                // a pixel of a downsampled map covers more height change
+                let max_step = config.max_slope_step.unwrap_or(0.0) / scale.max(1e-6);
+                let iterations = config.slope_limit_iterations;
+                let changed = limit_slope(&mut heightmap, max_step, iterations, progress)?;
+                slope_limited_cells = Some(changed);
+            }
+        }
+        if !(transform(Some(&op)) && transform(steps.get(i + 1))) {
+            mask.restore(&mut heightmap, before.take());
+        }
+        if op != RefineOp::Noise {
+            progress.add(height);
         }
     }
 
     // Return the refined heightmap
-    heightmap
+    Some(Refined {
+        heightmap,
+        slope_limited_cells,
+        coast,
+    })
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // heights rising from `low` on the left to `high` on the right
+    fn ramp(width: u32, height: u32, low: f32, high: f32) -> Heightmap {
+        let data = (0..width * height)
+            .map(|i| low + (high - low) * (i % width) as f32 / (width - 1) as f32)
+            .collect();
+        Heightmap::from_vec(data, width, height)
+    }
+
+    fn refine(heightmap: &Heightmap, config: &RefinerConfig, sea_level: f32) -> Heightmap {
+        refine_heightmap(
+            heightmap,
+            config,
+            sea_level,
+            1.0,
+            None,
+            &Progress::default(),
+        )
+        .unwrap()
+        .heightmap
+    }
 
+    fn ocean_cells(heightmap: &Heightmap, sea_level: f32) -> usize {
+        heightmap.iter().filter(|&&h| h < sea_level).count()
+    }
+
+    #[test]
+    fn fractional_exponent_of_negative_heights_stays_finite() {
+        let config = RefinerConfig {
+            height_offset: -0.5,
+            height_exponent: 0.5,
+            ..RefinerConfig::default()
+        };
+        let refined = refine(&ramp(64, 4, 0.0, 1.0), &config, 0.3);
+        assert!(
+            refined
+                .iter()
+                .all(|h| h.is_finite() && (0.0..=1.0).contains(h))
+        );
+    }
+
+    #[test]
+    fn only_rescaling_moves_the_coast() {
+        // half of the ramp is below the sea level, it doesn't reach 0 or 1 so a rescale
+        // stretches it
+        let heightmap = ramp(101, 4, 0.1, 0.7);
+        let sea_level = 0.4;
+        let before = ocean_cells(&heightmap, sea_level);
+        let count = |normalization| {
+            let config = RefinerConfig {
+                normalization,
+                ..RefinerConfig::default()
+            };
+            ocean_cells(&refine(&heightmap, &config, sea_level), sea_level)
+        };
+        assert_eq!(count(Normalization::Off), before);
+        assert_eq!(count(Normalization::Clamp), before);
+        assert_eq!(count(Normalization::PreserveSeaLevel), before);
+        // the stretch lifts the sea level of the ramp to 0.5, so water turns into land
+        assert!(count(Normalization::Rescale) < before);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::config::{Brush, BrushFalloff, Region};
+use crate::heightmap::Heightmap;
+use rayon::prelude::*;
+
+// weight of the brush at a distance from its center, in units of the radius
+fn falloff(brush: &Brush, r: f32) -> f32 {
+    if r >= 1.0 {
+        return 0.0;
+    }
+    match brush.falloff {
+        BrushFalloff::Smooth => {
+            let t = 1.0 - r;
+            t * t * (3.0 - 2.0 * t)
+        }
+        BrushFalloff::Linear => 1.0 - r,
+        BrushFalloff::Constant => 1.0,
+    }
+}
+
+// the pixels a brush at (cx, cy) touches, None if it is completely outside the map
+fn brush_region(heightmap: &Heightmap, brush: &Brush, cx: f32, cy: f32) -> Option<Region> {
+    let radius = brush.radius.max(1.0);
+    let x0 = (cx - radius).floor().max(0.0) as u32;
+    let y0 = (cy - radius).floor().max(0.0) as u32;
+    let x1 = ((cx + radius).ceil().max(0.0) as u32).min(heightmap.width());
+    let y1 = ((cy + radius).ceil().max(0.0) as u32).min(heightmap.height());
+    (x0 < x1 && y0 < y1).then_some(Region {
+        x: x0,
+        y: y0,
+        width: x1 - x0,
+        height: y1 - y0,
+        feather: 0,
+    })
+}
+
+// how fast the smooth brush blends towards the local average, per unit of strength
+const SMOOTH_RATE: f32 = 10.0;
+
+// calls `apply(x, y, height, weight)` for every pixel under the brush and returns the region
+// that changed
+fn apply_brush<F>(
+    heightmap: &mut Heightmap,
+    brush: &Brush,
+    cx: f32,
+    cy: f32,
+    apply: F,
+) -> Option<Region>
+where
+    F: Fn(u32, u32, f32, f32) -> f32 + Sync,
+{
+    let region = brush_region(heightmap, brush, cx, cy)?;
+    let radius = brush.radius.max(1.0);
+    let w = heightmap.width() as usize;
+    let (y0, y1) = (region.y as usize, (region.y + region.height) as usize);
+    let (x0, x1) = (region.x as usize, (region.x + region.width) as usize);
+
+    heightmap.data_mut()[y0 * w..y1 * w]
+        .par_chunks_mut(w)
+        .enumerate()
+        .for_each(|(row, line)| {
+            let dy = (y0 + row) as f32 - cy;
+            for (x, h) in line.iter_mut().enumerate().take(x1).skip(x0) {
+                let dx = x as f32 - cx;
+                let weight = falloff(brush, (dx * dx + dy * dy).sqrt() / radius);
+                if weight > 0.0 {
+                    *h = apply(x as u32, (y0 + row) as u32, *h, weight).clamp(0.0, 1.0);
+                }
+            }
+        });
+    Some(region)
+}
+
+// raises (positive amount) or lowers the terrain under the brush
+pub fn raise_lower(
+    heightmap: &mut Heightmap,
+    brush: &Brush,
+    cx: f32,
+    cy: f32,
+    amount: f32,
+) -> Option<Region> {
+    apply_brush(heightmap, brush, cx, cy, |_, _, h, weight| {
+        h + amount * weight
+    })
+}
+
+// moves the terrain under the brush towards the target height by at most `amount`. with
+// a sea level, pixels on the other side of it than the target are left alone, so a brush
+// on land doesn't fill in bays.
+pub fn flatten(
+    heightmap: &mut Heightmap,
+    brush: &Brush,
+    cx: f32,
+    cy: f32,
+    amount: f32,
+    sea_level: Option<f32>,
+) -> Option<Region> {
+    let target = brush.flatten_target;
+    apply_brush(heightmap, brush, cx, cy, |_, _, h, weight| {
+        if sea_level.is_some_and(|sea| (h < sea) != (target < sea)) {
+            return h;
+        }
+        let step = amount * weight;
+        h + (target - h).clamp(-step, step)
+    })
+}
+
+// blends the terrain under the brush towards the 3x3 average around every pixel. the
+// averages are taken from a copy of the area, and the blend factor never exceeds 1, so
+// repeated strokes settle on flat ground instead of oscillating.
+pub fn smooth(
+    heightmap: &mut Heightmap,
+    brush: &Brush,
+    cx: f32,
+    cy: f32,
+    amount: f32,
+) -> Option<Region> {
+    let region = brush_region(heightmap, brush, cx, cy)?;
+    let (width, height) = (heightmap.width(), heightmap.height());
+    // the region plus a one pixel margin, cut at the map border
+    let x0 = region.x.saturating_sub(1);
+    let y0 = region.y.saturating_sub(1);
+    let x1 = (region.x + region.width + 1).min(width);
+    let y1 = (region.y + region.height + 1).min(height);
+    let copy_width = (x1 - x0) as usize;
+    let copy: Vec<f32> = (y0..y1)
+        .flat_map(|y| {
+            let start = heightmap.index(x0, y);
+            heightmap.data()[start..start + copy_width].iter().copied()
+        })
+        .collect();
+    // neighbors outside the map read the nearest border pixel
+    let at = |x: i64, y: i64| {
+        let x = x.clamp(x0 as i64, x1 as i64 - 1) - x0 as i64;
+        let y = y.clamp(y0 as i64, y1 as i64 - 1) - y0 as i64;
+        copy[y as usize * copy_width + x as usize]
+    };
+
+    apply_brush(heightmap, brush, cx, cy, |x, y, h, weight| {
+        let (x, y) = (x as i64, y as i64);
+        let mut sum = 0.0;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                sum += at(x + dx, y + dy);
+            }
+        }
+        let blend = (amount * SMOOTH_RATE * weight).min(1.0);
+        h + (sum / 9.0 - h) * blend
+    })
+}
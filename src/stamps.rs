@@ -0,0 +1,89 @@
+use crate::config::{Stamp, StampBlend, StampKind};
+use crate::heightmap::Heightmap;
+use rayon::prelude::*;
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// height of the landform relative to the surrounding terrain, r is the distance from the
+// center in units of the stamp radius
+fn profile(stamp: &Stamp, r: f32) -> f32 {
+    let rim = stamp.rim_width.clamp(0.01, 0.9);
+    match stamp.kind {
+        StampKind::Volcano => {
+            let cone = |r: f32| stamp.height * (1.0 - r).max(0.0).powf(1.5);
+            if r >= rim {
+                cone(r)
+            } else {
+                // bowl inside the crater, half as deep as the rim is high
+                let rim_height = cone(rim);
+                let t = r / rim;
+                rim_height - rim_height * 0.5 * (1.0 - t * t)
+            }
+        }
+        StampKind::Crater => {
+            let ring = 0.5 * stamp.height * (-((r - 1.0) / rim).powi(2)).exp();
+            if r < 1.0 {
+                ring - stamp.height * (1.0 - r * r)
+            } else {
+                ring
+            }
+        }
+    }
+}
+
+// how far from the center the stamp affects the terrain, in units of the radius
+fn extent(stamp: &Stamp) -> f32 {
+    match stamp.kind {
+        StampKind::Volcano => 1.0,
+        StampKind::Crater => 1.0 + 2.5 * stamp.rim_width.clamp(0.01, 0.9),
+    }
+}
+
+// stamps a parametric landform onto the heightmap, blending into the terrain at the edge
+pub fn stamp_feature(heightmap: &mut Heightmap, stamp: &Stamp) {
+    let (width, height) = (heightmap.width(), heightmap.height());
+    let w = width as usize;
+    let radius = stamp.radius.max(1.0);
+    let reach = radius * extent(stamp);
+
+    // the stamp sits on the terrain height at its center
+    let base = heightmap.get_clamped(stamp.x.round() as i64, stamp.y.round() as i64);
+
+    let y0 = (stamp.y - reach).floor().max(0.0) as usize;
+    let y1 = ((stamp.y + reach).ceil() as usize).min(height as usize);
+    let x0 = (stamp.x - reach).floor().max(0.0) as usize;
+    let x1 = ((stamp.x + reach).ceil() as usize).min(w);
+    if y0 >= y1 || x0 >= x1 {
+        return;
+    }
+
+    heightmap.data_mut()[y0 * w..y1 * w]
+        .par_chunks_mut(w)
+        .enumerate()
+        .for_each(|(row, line)| {
+            let y = (y0 + row) as f32;
+            for (x, cell) in line.iter_mut().enumerate().take(x1).skip(x0) {
+                let dx = x as f32 - stamp.x;
+                let dy = y - stamp.y;
+                let dist = (dx * dx + dy * dy).sqrt();
+                if dist >= reach {
+                    continue;
+                }
+                let r = dist / radius;
+                // fade out over the outer 30% so there is no visible edge
+                let falloff = smoothstep(0.0, 0.3, 1.0 - dist / reach);
+                let shape = profile(stamp, r);
+                let h = *cell;
+                let target = base + shape;
+                let new_h = match stamp.blend {
+                    StampBlend::Add => h + shape * falloff,
+                    StampBlend::Max => h.max(h + (target - h) * falloff),
+                    StampBlend::Replace => h + (target - h) * falloff,
+                };
+                *cell = new_h.clamp(0.0, 1.0);
+            }
+        });
+}
@@ -1,101 +1,1003 @@
 use image::{ImageBuffer, Rgba};
-use noise::{NoiseFn, Perlin, Seedable};
+use noise::{NoiseFn, OpenSimplex, Perlin, Seedable, Value, Worley};
 use rayon::prelude::*;
+use rand::{Rng, SeedableRng, rngs::StdRng};
 use eframe::egui;
-use crate::config::MapConfig;
-use crate::preview::get_color_for_height;
+use crate::config::{
+    DEFAULT_MAX_ELEVATION, IslandShape, MapConfig, NoiseAlgorithm, NoiseMode, NoiseStack,
+    OverlayMode, Region, Symmetry,
+};
+use crate::heightmap::Heightmap;
+use crate::jobs::Progress;
+use crate::preview::{build_preview, get_color_for_height, shade_terrace};
+use crate::utils::distance_transform;
 
-pub fn generate_map(config: &MapConfig, seed: u32, previous_map: &Option<Vec<f32>>) -> (egui::ColorImage, ImageBuffer<Rgba<u8>, Vec<u8>>, Vec<f32>) {
-    let perlin = Perlin::new().set_seed(seed);
+// OpenSimplex from the noise crate only reaches about +-0.54 in 2D
+const OPEN_SIMPLEX_SCALE: f64 = 1.0 / 0.544;
+
+// static dispatch over the supported algorithms, so the hot loop doesn't box per sample
+pub(crate) enum NoiseSource {
+    Perlin(Perlin),
+    OpenSimplex(OpenSimplex),
+    Value(Value),
+    Worley(Worley),
+}
+
+impl NoiseSource {
+    pub(crate) fn new(algorithm: NoiseAlgorithm, seed: u32) -> Self {
+        match algorithm {
+            NoiseAlgorithm::Perlin => NoiseSource::Perlin(Perlin::new().set_seed(seed)),
+            NoiseAlgorithm::OpenSimplex => {
+                NoiseSource::OpenSimplex(OpenSimplex::new().set_seed(seed))
+            }
+            NoiseAlgorithm::Value => NoiseSource::Value(Value::new().set_seed(seed)),
+            NoiseAlgorithm::Worley => NoiseSource::Worley(Worley::new().set_seed(seed)),
+        }
+    }
+
+    // noise value normalized to [-1,1] for every algorithm
+    fn get(&self, point: [f64; 2]) -> f64 {
+        match self {
+            NoiseSource::Perlin(n) => n.get(point),
+            NoiseSource::OpenSimplex(n) => (n.get(point) * OPEN_SIMPLEX_SCALE).clamp(-1.0, 1.0),
+            NoiseSource::Value(n) => n.get(point),
+            NoiseSource::Worley(n) => n.get(point),
+        }
+    }
+
+    // samples at (x, y) in noise units. with a period, four copies of the noise offset by
+    // one period are blended bilinearly, so the result repeats every period[0] along x and
+    // period[1] along y.
+    pub(crate) fn sample(&self, x: f64, y: f64, period: Option<[f64; 2]>) -> f64 {
+        let Some([pw, ph]) = period else {
+            return self.get([x, y]);
+        };
+        // wrap first, warped coordinates can leave the map
+        let x = x.rem_euclid(pw);
+        let y = y.rem_euclid(ph);
+        let u = x / pw;
+        let v = y / ph;
+        let weights = [(1.0 - u) * (1.0 - v), u * (1.0 - v), (1.0 - u) * v, u * v];
+        let samples = [
+            self.get([x, y]),
+            self.get([x - pw, y]),
+            self.get([x, y - ph]),
+            self.get([x - pw, y - ph]),
+        ];
+        let blended: f64 = weights.iter().zip(samples.iter()).map(|(w, s)| w * s).sum();
+        // blending independent samples lowers the contrast towards the middle of the map,
+        // dividing by the combined weight norm keeps the variance roughly constant
+        let norm = weights.iter().map(|w| w * w).sum::<f64>().sqrt();
+        (blended / norm).clamp(-1.0, 1.0)
+    }
+}
+
+// maximum value the noise stack can reach, used to normalize heights to [0,1]
+fn max_amplitude(config: &MapConfig) -> f64 {
+    let max_mountainous = 1.5_f64.powf(config.mountainous) - 0.5;
+    match config.noise_stack {
+        NoiseStack::Classic => {
+            let mut enabled = config.layers.iter().filter(|layer| layer.enabled);
+            let Some(first) = enabled.next() else {
+                return 1.0;
+            };
+            max_mountainous * first.amplitude + enabled.map(|layer| layer.amplitude).sum::<f64>()
+        }
+        NoiseStack::Fbm => {
+            let mut max_amp = max_mountainous;
+            let mut amp = 1.0;
+            for _ in 1..config.octaves.max(1) {
+                amp *= config.persistence;
+                max_amp += amp;
+            }
+            max_amp
+        }
+    }
+}
+
+// samples the noise at the given point and maps it to [0,1] according to the noise mode
+fn sample_noise(
+    mode: NoiseMode,
+    source: &NoiseSource,
+    x: f64,
+    y: f64,
+    period: Option<[f64; 2]>,
+) -> f64 {
+    let n = source.sample(x, y, period);
+    match mode {
+        NoiseMode::Standard => (n + 1.0) / 2.0,
+        NoiseMode::Ridged => {
+            // squaring sharpens the ridge crests and widens the valleys between them
+            let r = 1.0 - n.abs().min(1.0);
+            r * r
+        }
+        NoiseMode::Billow => n.abs().min(1.0),
+    }
+}
+
+// one noise source per layer/octave, seeded from the master seed
+fn build_layer_noise(config: &MapConfig, seed: u32) -> Vec<NoiseSource> {
+    let seeded = |offset: u32| {
+        if config.independent_layer_seeds { seed.wrapping_add(offset) } else { seed }
+    };
+    match config.noise_stack {
+        NoiseStack::Classic => config
+            .layers
+            .iter()
+            .map(|layer| NoiseSource::new(layer.algorithm, seeded(layer.seed_offset)))
+            .collect(),
+        NoiseStack::Fbm => (0..config.octaves.max(1))
+            .map(|i| NoiseSource::new(config.fbm_algorithm, seeded(i)))
+            .collect(),
+    }
+}
+
+// raw (unnormalized) height of the noise stack at the given pixel.
+// `tile` is the map size in pixels when generating a tileable map.
+fn sample_noise_stack(
+    config: &MapConfig,
+    layers: &[NoiseSource],
+    nx: f64,
+    ny: f64,
+    tile: Option<[f64; 2]>,
+) -> f64 {
+    match config.noise_stack {
+        NoiseStack::Classic => {
+            let mode = config.noise_mode;
+            let layer = |source: &NoiseSource, scale: f64| {
+                let period = tile.map(|[w, h]| [w / scale, h / scale]);
+                sample_noise(mode, source, nx / scale, ny / scale, period)
+            };
+            let mut h = 0.0;
+            let mut first = true;
+            for (settings, source) in config.layers.iter().zip(layers) {
+                if !settings.enabled {
+                    continue;
+                }
+                let n = layer(source, settings.scale);
+                if first {
+                    h += ((n + 0.5).powf(config.mountainous) - 0.5) * settings.amplitude;
+                    first = false;
+                } else {
+                    h += settings.amplitude * n;
+                }
+            }
+            h
+        }
+        NoiseStack::Fbm => {
+            let mode = config.noise_mode;
+            let mut freq = config.base_frequency;
+            let mut amp = 1.0;
+
+            // the first octave gets the mountainous shaping, like the classic base layer
+            let period = |freq: f64| tile.map(|[w, h]| [w * freq, h * freq]);
+            let base = sample_noise(mode, &layers[0], nx * freq, ny * freq, period(freq));
+            let mut h = (base + 0.5).powf(config.mountainous) - 0.5;
+
+            // in ridged mode each octave is weighted by the previous one (multifractal),
+            // so fine detail accumulates on the ridges instead of in the valleys. the
+            // detail fades to the mid value rather than to zero, which keeps the heights
+            // around the sea level where the preview colors and the classic stack put them
+            let mut weight = 1.0;
+            let mut prev = base;
+
+            for layer in layers.iter().skip(1) {
+                freq *= config.lacunarity;
+                amp *= config.persistence;
+                let n = sample_noise(mode, layer, nx * freq, ny * freq, period(freq));
+                if mode == NoiseMode::Ridged {
+                    weight = prev.clamp(0.0, 1.0);
+                    prev = n;
+                }
+                h += amp * (n * weight + 0.5 * (1.0 - weight));
+            }
+            h
+        }
+    }
+}
+
+struct Plate {
+    x: f64,
+    y: f64,
+    elevation: f64,
+    // tilt of the plate, change in elevation per map width along (dir_x, dir_y)
+    dir_x: f64,
+    dir_y: f64,
+    tilt: f64,
+}
+
+// continent-scale base layer: a seeded voronoi diagram where every cell is a tilted plate
+// with its own base elevation. values are in [0,1] and blended smoothly at plate borders.
+fn generate_plate_elevation(config: &MapConfig, seed: u32) -> Vec<f32> {
+    let width = config.width as usize;
+    let height = config.height as usize;
+    let size = width.max(height) as f64;
+    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(4000) as u64);
+    let variance = config.plate_elevation_variance.clamp(0.0, 0.5);
+
+    let plates: Vec<Plate> = (0..config.plate_count.max(1))
+        .map(|_| {
+            let angle = rng.gen_range(0.0..std::f64::consts::TAU);
+            Plate {
+                x: rng.gen_range(0.0..width as f64),
+                y: rng.gen_range(0.0..height as f64),
+                elevation: 0.5 + rng.gen_range(-1.0..=1.0) * variance,
+                dir_x: angle.cos(),
+                dir_y: angle.sin(),
+                tilt: rng.gen_range(0.0..=1.0) * variance,
+            }
+        })
+        .collect();
+
+    // width of the transition between two plates
+    let blend_width = size * 0.05;
+    let tileable = config.tileable;
+    let delta = |a: f64, b: f64, period: f64| {
+        let d = a - b;
+        if tileable {
+            d - period * (d / period).round()
+        } else {
+            d
+        }
+    };
+
+    let mut elevation = vec![0.0f32; width * height];
+    elevation.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            // nearest and second nearest plate
+            let mut best = (f64::MAX, 0.0);
+            let mut second = (f64::MAX, 0.0);
+            for plate in plates.iter() {
+                let dx = delta(x as f64, plate.x, width as f64);
+                let dy = delta(y as f64, plate.y, height as f64);
+                let dist = (dx * dx + dy * dy).sqrt();
+                let e = plate.elevation + (dx * plate.dir_x + dy * plate.dir_y) / size * plate.tilt;
+                if dist < best.0 {
+                    second = best;
+                    best = (dist, e);
+                } else if dist < second.0 {
+                    second = (dist, e);
+                }
+            }
+            let e = if second.0 == f64::MAX {
+                best.1
+            } else {
+                // 0.5 on the border itself, 1.0 once we are blend_width inside the plate
+                let t = ((second.0 - best.0) / blend_width).clamp(0.0, 1.0);
+                let t = 0.5 + 0.5 * t * t * (3.0 - 2.0 * t);
+                second.1 + (best.1 - second.1) * t
+            };
+            *value = e.clamp(0.0, 1.0) as f32;
+        }
+    });
+    elevation
+}
+
+// quantizes a normalized height into `steps` flat bands. sharpness 0 leaves the height
+// untouched, 1 gives hard steps with flat tops.
+fn terrace(h: f64, steps: u32, sharpness: f64) -> f64 {
+    if steps < 2 || sharpness <= 0.0 {
+        return h;
+    }
+    let scaled = h * steps as f64;
+    let level = scaled.floor();
+    let t = scaled - level;
+    // raising the position within the band to a power keeps the tread flat and moves
+    // the whole rise to the end of the band
+    let k = 1.0 + 40.0 * sharpness.clamp(0.0, 1.0).powi(2);
+    (level + t.powf(k)) / steps as f64
+}
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// blends a newly generated height onto the previous map, strength in [0,1]
+fn blend_overlay(mode: OverlayMode, old: f64, new: f64, strength: f64) -> f64 {
+    let blended = match mode {
+        OverlayMode::Lerp => return new * strength + old * (1.0 - strength),
+        OverlayMode::Add => old + new,
+        OverlayMode::Multiply => old * new,
+        OverlayMode::Screen => 1.0 - (1.0 - old) * (1.0 - new),
+        OverlayMode::Max => old.max(new),
+        OverlayMode::Min => old.min(new),
+    };
+    let blended = blended.clamp(0.0, 1.0);
+    (old + (blended - old) * strength).clamp(0.0, 1.0)
+}
+
+// pulls heights between sea level and the threshold towards a plains elevation. the weight
+// is zero at both ends of the band, so the coastline and the mountains don't move.
+fn flatten_plains(h: f64, sea_level: f64, threshold: f64, strength: f64) -> f64 {
+    if strength <= 0.0 || threshold <= sea_level || h <= sea_level || h >= threshold {
+        return h;
+    }
+    let t = (h - sea_level) / (threshold - sea_level);
+    let weight = smoothstep(0.0, 0.35, t) * (1.0 - smoothstep(0.65, 1.0, t));
+    let target = sea_level + (threshold - sea_level) * 0.3;
+    h + (target - h) * weight * strength.clamp(0.0, 1.0)
+}
+
+// multiplier applied to the height at the given pixel when island mode is enabled.
+// `coast` shifts the border inwards (positive) or outwards (negative), in map fractions.
+fn island_falloff(config: &MapConfig, x: u32, y: u32, width: u32, height: u32, coast: f64) -> f64 {
+    let border = config.island_border.clamp(0.01, 0.5);
+    let curve = config.island_curve.clamp(1.0, 10.0);
+    let xf = x as f64 / width as f64;
+    let yf = y as f64 / height as f64;
+
+    match config.island_shape {
+        IslandShape::Square | IslandShape::SquareAdditive => {
+            let mut edge_strength_x = 0.0;
+            let mut edge_strength_y = 0.0;
+            let (x_low, x_high) = (xf - coast, xf + coast);
+            let (y_low, y_high) = (yf - coast, yf + coast);
+            if x_low < border {
+                edge_strength_x = 1.0 - (x_low / border);
+            } else if x_high > 1.0 - border {
+                edge_strength_x = (x_high - (1.0 - border)) / border;
+            }
+            if y_low < border {
+                edge_strength_y = 1.0 - (y_low / border);
+            } else if y_high > 1.0 - border {
+                edge_strength_y = (y_high - (1.0 - border)) / border;
+            }
+            let edge_strength_x = edge_strength_x.clamp(0.0, 1.0);
+            let edge_strength_y = edge_strength_y.clamp(0.0, 1.0);
+
+            // the sum reaches 2 in the corners, which cuts hard diagonal corners into the sea.
+            // the euclidean distance rounds them off instead.
+            let edge_strength = match config.island_shape {
+                IslandShape::SquareAdditive => edge_strength_x + edge_strength_y,
+                _ => edge_strength_x.hypot(edge_strength_y).min(1.0),
+            };
+            (1.0 - edge_strength.powf(curve)).clamp(0.0, 1.0)
+        }
+        IslandShape::Radial | IslandShape::Superellipse => {
+            // distance from the center, 0 at the center and 1 at the middle of each edge
+            let dx = (xf - 0.5).abs() * 2.0;
+            let dy = (yf - 0.5).abs() * 2.0;
+            let mut dist = match config.island_shape {
+                IslandShape::Radial => (dx * dx + dy * dy).sqrt(),
+                _ => {
+                    // exponent 2 is a circle, large exponents approach a square
+                    let n = config.island_exponent.clamp(1.0, 50.0);
+                    (dx.powf(n) + dy.powf(n)).powf(1.0 / n)
+                }
+            };
+            dist += coast * 2.0;
+            let edge_strength = ((dist - (1.0 - 2.0 * border)) / (2.0 * border)).clamp(0.0, 1.0);
+            (1.0 - edge_strength.powf(curve)).clamp(0.0, 1.0)
+        }
+    }
+}
+
+// multiplies an existing heightmap with the island falloff of the config, e.g. to taper
+// the borders of a cropped map into the sea
+pub fn apply_island_falloff(heightmap: &mut Heightmap, config: &MapConfig) {
+    let (width, height) = (heightmap.width(), heightmap.height());
+    heightmap.data_mut().par_chunks_mut(width as usize).enumerate().for_each(|(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            let falloff = island_falloff(config, x as u32, y as u32, width, height, 0.0);
+            *value *= falloff.clamp(0.0, 1.0) as f32;
+        }
+    });
+}
+
+// remaps the sea floor onto a shelf profile: a gentle slope down to the shelf depth within
+// `shelf_width` of the coast, then a steeper drop to the abyss depth
+fn shape_continental_shelf(heightmap: &mut [f32], config: &MapConfig) {
+    let sea_level = config.sea_level as f32;
+    let land: Vec<bool> = heightmap.iter().map(|&h| h >= sea_level).collect();
+    let distance = distance_transform(&land, config.width, config.height);
+
+    let shelf_width = config.shelf_width.max(1.0) as f32;
+    let shelf_depth = config.shelf_depth.max(0.0) as f32;
+    let abyss_depth = (config.abyss_depth as f32).max(shelf_depth);
+    // the slope after the shelf edge covers half the shelf width
+    let drop_width = shelf_width * 0.5;
+
+    heightmap.par_iter_mut().zip(distance.par_iter()).for_each(|(h, &d)| {
+        if *h >= sea_level {
+            return;
+        }
+        let depth = if d <= shelf_width {
+            shelf_depth * d / shelf_width
+        } else {
+            let t = ((d - shelf_width) / drop_width).min(1.0);
+            let t = t * t * (3.0 - 2.0 * t);
+            shelf_depth + (abyss_depth - shelf_depth) * t
+        };
+        *h = (sea_level - depth).max(0.0);
+    });
+}
+
+// lowers the land within `beach_width_px` of the coast towards a slope of `beach_slope`
+// percent rising from the sea. the land is only ever lowered and never below sea level, so
+// the pass can't push the sea floor up or build a berm with a pond behind it.
+fn shape_beaches(heightmap: &mut [f32], config: &MapConfig) {
+    let sea_level = config.sea_level as f32;
+    let water: Vec<bool> = heightmap.iter().map(|&h| h < sea_level).collect();
+    let distance = distance_transform(&water, config.width, config.height);
+
+    let beach_width = config.beach_width_px.max(1.0);
+    let strength = config.beach_strength.clamp(0.0, 1.0) as f32;
+    // rise per pixel in normalized heights
+    let rise = (config.beach_slope.max(0.0) / 100.0 * config.cell_size_m
+        / DEFAULT_MAX_ELEVATION as f64) as f32;
+
+    heightmap.par_iter_mut().zip(distance.par_iter()).for_each(|(h, &d)| {
+        if *h < sea_level || d as f64 >= beach_width {
+            return;
+        }
+        let target = sea_level + rise * d;
+        if *h <= target {
+            return;
+        }
+        let weight = (1.0 - smoothstep(0.0, beach_width, d as f64)) as f32 * strength;
+        *h += (target - *h) * weight;
+    });
+}
+
+// adds fine noise to the cells below sea level. it fades in over `sea_floor_fade_px` from
+// the shore so the shallows stay smooth, and never lifts a cell above sea level.
+fn add_sea_floor_detail(heightmap: &mut [f32], config: &MapConfig, seed: u32) {
+    let (width, height) = (config.width, config.height);
+    let sea_level = config.sea_level as f32;
+    let land: Vec<bool> = heightmap.iter().map(|&h| h >= sea_level).collect();
+    let distance = distance_transform(&land, width, height);
+
+    let noise = NoiseSource::new(NoiseAlgorithm::Perlin, seed.wrapping_add(5000));
+    let unit = if config.scales_in_meters { config.cell_size_m.max(0.001) } else { 1.0 };
+    let scale = config.sea_floor_scale.max(0.001) / unit;
+    let period = config.tileable.then_some([width as f64 / scale, height as f64 / scale]);
+    let fade = config.sea_floor_fade_px.max(1.0);
+
+    heightmap.par_chunks_mut(width as usize).enumerate().for_each(|(y, row)| {
+        for (x, h) in row.iter_mut().enumerate() {
+            if *h >= sea_level {
+                continue;
+            }
+            let d = distance[y * width as usize + x] as f64;
+            // sample at the source pixel so mirrored maps get a mirrored sea floor
+            let (sx, sy) = symmetry_source(config.symmetry, x as u32, y as u32, width, height);
+            let n = noise.sample(sx as f64 / scale, sy as f64 / scale, period);
+            let detail = n * config.sea_floor_detail * smoothstep(0.0, fade, d);
+            *h = (*h + detail as f32).min(sea_level - f32::EPSILON).max(0.0);
+        }
+    });
+}
+
+// true for the pixels that are generated, the rest are mirror images of them
+fn in_symmetry_domain(symmetry: Symmetry, x: u32, y: u32, width: u32, height: u32) -> bool {
+    let left = 2 * x < width;
+    let top = 2 * y < height;
+    match symmetry {
+        Symmetry::None => true,
+        Symmetry::MirrorX => left,
+        Symmetry::MirrorY => top,
+        Symmetry::Quad => left && top,
+        // the middle row of odd heights is itself rotated around the center pixel
+        Symmetry::Rotational180 => 2 * y < height - 1 || (2 * y == height - 1 && left),
+    }
+}
+
+// generated pixel that a pixel is a mirror image of
+fn symmetry_source(symmetry: Symmetry, x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+    let (mx, my) = (width - 1 - x, height - 1 - y);
+    match symmetry {
+        Symmetry::None => (x, y),
+        Symmetry::MirrorX => (x.min(mx), y),
+        Symmetry::MirrorY => (x, y.min(my)),
+        Symmetry::Quad => (x.min(mx), y.min(my)),
+        Symmetry::Rotational180 => {
+            if in_symmetry_domain(symmetry, x, y, width, height) { (x, y) } else { (mx, my) }
+        }
+    }
+}
+
+// fills the mirrored part of the map from the generated part
+fn mirror_heightmap(heightmap: &mut [f32], width: u32, height: u32, symmetry: Symmetry) {
+    let w = width as usize;
+    let h = height as usize;
+    let mirror_rows = |rows: &mut [f32]| {
+        rows.par_chunks_mut(w).for_each(|row| {
+            for x in w.div_ceil(2)..w {
+                row[x] = row[w - 1 - x];
+            }
+        });
+    };
+    // copies the top rows onto the bottom rows upside down, optionally also flipped sideways
+    let mirror_columns = |heightmap: &mut [f32], flip_x: bool| {
+        let (top, bottom) = heightmap.split_at_mut(h.div_ceil(2) * w);
+        bottom.par_chunks_mut(w).enumerate().for_each(|(i, row)| {
+            let source_y = h - 1 - (h.div_ceil(2) + i);
+            let source = &top[source_y * w..(source_y + 1) * w];
+            if flip_x {
+                for (x, value) in row.iter_mut().enumerate() {
+                    *value = source[w - 1 - x];
+                }
+            } else {
+                row.copy_from_slice(source);
+            }
+        });
+    };
+
+    match symmetry {
+        Symmetry::None => {}
+        Symmetry::MirrorX => mirror_rows(heightmap),
+        Symmetry::MirrorY => mirror_columns(heightmap, false),
+        Symmetry::Quad => {
+            mirror_rows(&mut heightmap[..h.div_ceil(2) * w]);
+            mirror_columns(heightmap, false);
+        }
+        Symmetry::Rotational180 => {
+            if h % 2 == 1 {
+                let middle = h / 2 * w;
+                mirror_rows(&mut heightmap[middle..middle + w]);
+            }
+            mirror_columns(heightmap, true);
+        }
+    }
+}
+
+// preview texture, preview image and the full resolution heightmap
+pub type GeneratedMap = (egui::ColorImage, ImageBuffer<Rgba<u8>, Vec<u8>>, Heightmap);
+
+// maps are generated in square tiles so the progress can be reported while large maps build
+const TILE_SIZE: u32 = 512;
+
+// true if generating with this config reads the previously generated map
+pub fn uses_previous_map(config: &MapConfig) -> bool {
+    let overlay_strength = (config.overlay / 100.0).clamp(0.0, 1.0);
+    // lerp at full strength just replaces the old map, the other modes still combine both
+    overlay_strength < 0.999 || config.overlay_mode != OverlayMode::Lerp
+}
+
+pub fn generate_map(config: &MapConfig, seed: u32, previous_map: &Option<Heightmap>, progress: &Progress) -> Option<GeneratedMap> {
+    let heightmap = generate_heightmap(config, seed, previous_map, None, progress)?;
+    let (color_image, preview) = build_preview(&heightmap, |h| terrain_color(config, h));
+    Some((color_image, preview, heightmap))
+}
+
+// preview color of a generated height, with the terrace levels shaded when terracing is on
+pub fn terrain_color(config: &MapConfig, h: f32) -> (u8, u8, u8) {
+    let h = h as f64;
+    let color = get_color_for_height(h, config.sea_level);
+    let terrace_shading = config.terrace_sharpness > 0.0 && config.terrace_steps >= 2;
+    // the shelf reshapes the sea floor after terracing, so its steps aren't shaded
+    if terrace_shading && (!config.continental_shelf || h >= config.sea_level) {
+        return shade_terrace(color, h, config.terrace_steps);
+    }
+    color
+}
+
+// how much of the regenerated height is used at a pixel of the region, fading in over the
+// feather distance from every edge that doesn't touch the map border
+fn region_weight(region: &Region, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    if region.feather == 0 {
+        return 1.0;
+    }
+    let x1 = region.x + region.width;
+    let y1 = region.y + region.height;
+    let mut d = u32::MAX;
+    if region.x > 0 {
+        d = d.min(x - region.x);
+    }
+    if y1 < height {
+        d = d.min(y1 - 1 - y);
+    }
+    if region.y > 0 {
+        d = d.min(y - region.y);
+    }
+    if x1 < width {
+        d = d.min(x1 - 1 - x);
+    }
+    smoothstep(0.0, region.feather as f64, d as f64 + 1.0)
+}
+
+// generates the heightmap. with a region and a previous map of the same size, only the
+// pixels inside the region are regenerated and blended into the previous heights.
+pub fn generate_heightmap(
+    config: &MapConfig,
+    seed: u32,
+    previous_map: &Option<Heightmap>,
+    region: Option<Region>,
+    progress: &Progress,
+) -> Option<Heightmap> {
+    let start = std::time::Instant::now();
+    let layers = build_layer_noise(config, seed);
+    // two independent channels used to offset the sample coordinates (domain warping)
+    let warp_x = NoiseSource::new(NoiseAlgorithm::Perlin, seed.wrapping_add(1000));
+    let warp_y = NoiseSource::new(NoiseAlgorithm::Perlin, seed.wrapping_add(2000));
+    let warp = config.warp_strength > 0.0;
+    // low frequency channel that wiggles the island border into bays and peninsulas
+    let coast_noise = Perlin::new().set_seed(seed.wrapping_add(3000));
     let width = config.width;
     let height = config.height;
-    let mut preview = ImageBuffer::new(width, height);
-    let mut heightmap = vec![0.0f32; (width * height) as usize];
+    // noise coordinates are in meters when the scales are interpreted in meters, so the same
+    // world size produces the same landforms at any resolution
+    let unit = if config.scales_in_meters { config.cell_size_m.max(0.001) } else { 1.0 };
+    // the island falloff and shape mask would break the seams, so they are skipped
+    let tile = config.tileable.then_some([width as f64 * unit, height as f64 * unit]);
 
     let overlay_strength = (config.overlay / 100.0).clamp(0.0, 1.0);
-    let overlay_old = 1.0 - overlay_strength;
-    // check if strength is below 1 and if old map is provided and same size
-    let overlay: bool = overlay_strength < 0.999 && previous_map.is_some() && previous_map.as_ref().unwrap().len() == (width * height) as usize;
-    let previous_ref = previous_map.as_ref();
+    // check if old map is needed, provided and same size
+    let overlay = previous_map.as_ref().filter(|p| {
+        uses_previous_map(config) && p.width() == width && p.height() == height
+    });
 
+    let max_amp = max_amplitude(config);
+    let tectonic_strength = config.tectonic_strength.clamp(0.0, 1.0);
+    let plates = (tectonic_strength > 0.0).then(|| generate_plate_elevation(config, seed));
 
-    let max_mountainous = 1.5_f64.powf(config.mountainous) - 0.5;
-    let max_amp = max_mountainous * config.amp_base + config.amp_mid + config.amp_detail;
-
-    let preview_buf = std::sync::Mutex::new(&mut preview);
-    let heightmap_buf = std::sync::Mutex::new(&mut heightmap);
-
-    (0..height).into_par_iter().for_each(|y| {
-        let mut row_data = Vec::with_capacity(width as usize);
-        for x in 0..width {
-            let nx = x as f64;
-            let ny = y as f64;
-
-            let base = (perlin.get([nx / config.scale_base, ny / config.scale_base]) + 1.0) / 2.0;
-            let mid = (perlin.get([nx / config.scale_mid, ny / config.scale_mid]) + 1.0) / 2.0;
-            let detail = (perlin.get([nx / config.scale_detail, ny / config.scale_detail]) + 1.0) / 2.0;
-
-            let mut h = base;
-            h = (h + 0.5).powf(config.mountainous);
-            h = (h - 0.5) * config.amp_base;
-            h += config.amp_mid * mid;
-            h += config.amp_detail * detail;
-            h = (h / max_amp).clamp(0.0, 1.0);
-
-            if config.island_mode {
-                let border = config.island_border.clamp(0.01, 0.5);
-                let curve = config.island_curve.clamp(1.0, 10.0);
-                let xf = x as f64 / width as f64;
-                let yf = y as f64 / height as f64;
-
-                let mut edge_strength_x = 0.0;
-                let mut edge_strength_y = 0.0;
-                if xf < border {
-                    edge_strength_x = 1.0 - (xf / border);
-                } else if xf > 1.0 - border {
-                    edge_strength_x = (xf - (1.0 - border)) / border;
+    // terrain before the island shaping, for the pixel at the given coordinates
+    let base_height = |x: u32, y: u32| -> f64 {
+        let mut nx = x as f64 * unit;
+        let mut ny = y as f64 * unit;
+
+        if warp {
+            let (sx, sy) = (nx / config.warp_scale, ny / config.warp_scale);
+            let period = tile.map(|[w, h]| [w / config.warp_scale, h / config.warp_scale]);
+            let wx = warp_x.sample(sx, sy, period);
+            let wy = warp_y.sample(sx, sy, period);
+            nx += wx * config.warp_strength;
+            ny += wy * config.warp_strength;
+        }
+
+        let mut h = sample_noise_stack(config, &layers, nx, ny, tile);
+        h = (h / max_amp).clamp(0.0, 1.0);
+        if let Some(plates) = &plates {
+            let plate = plates[(y * width + x) as usize] as f64;
+            h = h * (1.0 - tectonic_strength) + plate * tectonic_strength;
+        }
+        h = terrace(h, config.terrace_steps, config.terrace_sharpness);
+        h
+    };
+
+    // the falloff, mask and overlay come after mirroring. the falloff is evaluated at the
+    // source pixel so it can't break the symmetry by being off by a pixel.
+    let finish_height = |mut h: f64, x: u32, y: u32| -> f64 {
+        let (sx, sy) = symmetry_source(config.symmetry, x, y, width, height);
+
+        let mut falloff = if config.island_mode && !config.tileable {
+            let coast = if config.coast_noise_strength > 0.0 {
+                let cx = sx as f64 * unit / config.coast_noise_scale;
+                let cy = sy as f64 * unit / config.coast_noise_scale;
+                coast_noise.get([cx, cy]) * config.coast_noise_strength
+            } else {
+                0.0
+            };
+            island_falloff(config, sx, sy, width, height, coast)
+        } else {
+            1.0
+        };
+        if let Some(mask) = config.shape_mask.as_ref().filter(|_| !config.tileable) {
+            // the mask can have any resolution, map pixel centers onto it
+            let mx = (x as f32 + 0.5) / width as f32 * mask.width() as f32 - 0.5;
+            let my = (y as f32 + 0.5) / height as f32 * mask.height() as f32 - 0.5;
+            let m = mask.sample(mx, my) as f64;
+            let blend = config.shape_mask_blend.clamp(0.0, 1.0);
+            falloff = falloff * (1.0 - blend) + m * blend;
+        }
+        h *= falloff;
+
+        if let Some(previous) = overlay {
+            let old_height = previous.get(x, y) as f64;
+            h = blend_overlay(config.overlay_mode, old_height, h, overlay_strength);
+        }
+
+        h = flatten_plains(h, config.sea_level, config.plains_threshold, config.plains_strength);
+        h
+    };
+
+    // the region is clipped to the map and needs a previous map of the same size
+    let region = region
+        .map(|r| {
+            let x = r.x.min(width);
+            let y = r.y.min(height);
+            Region { x, y, width: r.width.min(width - x), height: r.height.min(height - y), ..r }
+        })
+        .filter(|r| r.width > 0 && r.height > 0);
+    let region_source = previous_map.as_ref().filter(|p| p.width() == width && p.height() == height);
+    if let (Some(region), Some(previous)) = (region, region_source) {
+        let mut heightmap = previous.data().to_vec();
+        progress.set_total(region.height as usize);
+        let (x0, x1) = (region.x, region.x + region.width);
+        let band = (region.y * width) as usize..((region.y + region.height) * width) as usize;
+        heightmap[band].par_chunks_mut(width as usize).enumerate().for_each(|(row, line)| {
+            if progress.is_cancelled() {
+                return;
+            }
+            let y = region.y + row as u32;
+            for x in x0..x1 {
+                let (sx, sy) = symmetry_source(config.symmetry, x, y, width, height);
+                line[x as usize] = finish_height(base_height(sx, sy), x, y) as f32;
+            }
+            progress.add(1);
+        });
+        if progress.is_cancelled() {
+            return None;
+        }
+
+        if config.continental_shelf {
+            shape_continental_shelf(&mut heightmap, config);
+        }
+        if config.beach_strength > 0.0 {
+            shape_beaches(&mut heightmap, config);
+        }
+        if config.sea_floor_detail > 0.0 {
+            add_sea_floor_detail(&mut heightmap, config, seed);
+        }
+
+        // everything outside the region keeps its old height, the coast shaping only counts
+        // inside
+        heightmap.par_chunks_mut(width as usize).enumerate().for_each(|(y, line)| {
+            let y = y as u32;
+            for (x, value) in line.iter_mut().enumerate() {
+                let x = x as u32;
+                let old = previous.get(x, y);
+                let inside = x >= x0 && x < x1 && y >= region.y && y < region.y + region.height;
+                *value = if inside {
+                    let weight = region_weight(&region, x, y, width, height) as f32;
+                    old + (*value - old) * weight
+                } else {
+                    old
+                };
+            }
+        });
+
+        println!(
+            "Region {}x{} regenerated in {:.2?}",
+            region.width, region.height, start.elapsed()
+        );
+        return Some(Heightmap::from_vec(heightmap, width, height));
+    }
+
+    let mut heightmap = vec![0.0f32; (width * height) as usize];
+
+    // small maps use shorter tiles so every thread still gets a band of rows
+    let tile_rows = TILE_SIZE.min(height.div_ceil(rayon::current_num_threads() as u32)).max(1);
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    progress.set_total((height.div_ceil(tile_rows) * tiles_x) as usize);
+
+    heightmap.par_chunks_mut((width * tile_rows) as usize).enumerate().for_each(|(band, rows)| {
+        let y0 = band as u32 * tile_rows;
+        let band_height = rows.len() as u32 / width;
+        for tx in 0..tiles_x {
+            if progress.is_cancelled() {
+                return;
+            }
+            let x0 = tx * TILE_SIZE;
+            let x1 = (x0 + TILE_SIZE).min(width);
+            for y in y0..y0 + band_height {
+                for x in x0..x1 {
+                    // mirrored pixels are copied from their source afterwards
+                    if !in_symmetry_domain(config.symmetry, x, y, width, height) {
+                        continue;
+                    }
+                    rows[((y - y0) * width + x) as usize] = base_height(x, y) as f32;
                 }
-                if yf < border {
-                    edge_strength_y = 1.0 - (yf / border);
-                } else if yf > 1.0 - border {
-                    edge_strength_y = (yf - (1.0 - border)) / border;
+            }
+            progress.add(1);
+        }
+    });
+    if progress.is_cancelled() {
+        return None;
+    }
+
+    mirror_heightmap(&mut heightmap, width, height, config.symmetry);
+
+    heightmap.par_chunks_mut(width as usize).enumerate().for_each(|(y, row)| {
+        let y = y as u32;
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = finish_height(*value as f64, x as u32, y) as f32;
+        }
+    });
+
+    if config.continental_shelf {
+        shape_continental_shelf(&mut heightmap, config);
+    }
+    if config.beach_strength > 0.0 {
+        shape_beaches(&mut heightmap, config);
+    }
+    if config.sea_floor_detail > 0.0 {
+        add_sea_floor_detail(&mut heightmap, config, seed);
+    }
+
+    println!(
+        "Map {}x{} generated in {:.2?} on {} threads",
+        width, height, start.elapsed(), rayon::current_num_threads()
+    );
+    Some(Heightmap::from_vec(heightmap, width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn island_falloff_never_goes_negative() {
+        let (width, height) = (64, 48);
+        let corners = [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)];
+        for island_shape in [
+            IslandShape::Square,
+            IslandShape::SquareAdditive,
+            IslandShape::Radial,
+            IslandShape::Superellipse,
+        ] {
+            for (island_border, island_curve) in [(0.1, 1.0), (0.5, 2.0), (0.5, 7.5)] {
+                let config = MapConfig {
+                    island_shape,
+                    island_border,
+                    island_curve,
+                    ..MapConfig::default()
+                };
+                // the coast noise moves the border in and out
+                for coast in [-0.3, 0.0, 0.3] {
+                    for &(x, y) in &corners {
+                        let falloff = island_falloff(&config, x, y, width, height, coast);
+                        assert!((0.0..=1.0).contains(&falloff), "{island_shape:?} {x},{y}");
+                    }
                 }
+            }
+        }
+    }
 
-                let edge_strength = edge_strength_x + edge_strength_y;
-                let falloff = 1.0 - edge_strength.powf(curve);
-                h *= falloff;
+    #[test]
+    fn mirrored_maps_match_across_the_axis() {
+        for (width, height) in [(64, 48), (63, 47)] {
+            for symmetry in [
+                Symmetry::MirrorX,
+                Symmetry::MirrorY,
+                Symmetry::Quad,
+                Symmetry::Rotational180,
+            ] {
+                // a noisy coast, so the falloff has to be mirrored too
+                let config = MapConfig {
+                    width,
+                    height,
+                    symmetry,
+                    coast_noise_strength: 0.1,
+                    coast_noise_scale: 10.0,
+                    ..MapConfig::default()
+                };
+                let map = generate_heightmap(&config, 9, &None, None, &Progress::default())
+                    .unwrap();
+                for y in 0..height {
+                    for x in 0..width {
+                        let (mx, my) = (width - 1 - x, height - 1 - y);
+                        let mirrors: &[(u32, u32)] = match symmetry {
+                            Symmetry::MirrorX => &[(mx, y)],
+                            Symmetry::MirrorY => &[(x, my)],
+                            Symmetry::Quad => &[(mx, y), (x, my), (mx, my)],
+                            _ => &[(mx, my)],
+                        };
+                        for &(u, v) in mirrors {
+                            assert_eq!(map.get(x, y), map.get(u, v), "{symmetry:?} {x},{y}");
+                        }
+                    }
+                }
             }
+        }
+    }
 
-            if overlay {
-                let old_height = previous_ref.unwrap()[(y * width + x) as usize] as f64;
-                h = h * overlay_strength + old_height * overlay_old;
+    #[test]
+    fn tileable_maps_continue_across_the_edges() {
+        let (width, height) = (96, 64);
+        let config = MapConfig {
+            width,
+            height,
+            tileable: true,
+            noise_stack: NoiseStack::Fbm,
+            base_frequency: 1.0 / 40.0,
+            warp_strength: 8.0,
+            warp_scale: 30.0,
+            ..MapConfig::default()
+        };
+        let map = generate_heightmap(&config, 3, &None, None, &Progress::default()).unwrap();
+        // the step over the seam is no bigger than the steepest step inside the map
+        let mut epsilon = 0.0f32;
+        for y in 0..height - 1 {
+            for x in 0..width - 1 {
+                let h = map.get(x, y);
+                epsilon = epsilon.max((h - map.get(x + 1, y)).abs());
+                epsilon = epsilon.max((h - map.get(x, y + 1)).abs());
             }
+        }
+        assert!(epsilon > 0.0);
+        let data = map.data();
+        let w = width as usize;
+        for y in 0..height as usize {
+            assert!((data[y * w] - data[y * w + (w - 1)]).abs() <= epsilon, "row {y}");
+        }
+        let last_row = (height as usize - 1) * w;
+        for x in 0..w {
+            assert!((data[x] - data[last_row + x]).abs() <= epsilon, "column {x}");
+        }
+    }
 
-            row_data.push((h as f32, get_color_for_height(h as f64, config.sea_level)));
+    // pearson correlation of two layers sampled at the same points
+    fn correlation(a: &NoiseSource, b: &NoiseSource) -> f64 {
+        let points: Vec<[f64; 2]> =
+            (0..64 * 64).map(|i| [(i % 64) as f64 * 0.37, (i / 64) as f64 * 0.37]).collect();
+        let a: Vec<f64> = points.iter().map(|&p| a.get(p)).collect();
+        let b: Vec<f64> = points.iter().map(|&p| b.get(p)).collect();
+        let n = points.len() as f64;
+        let (mean_a, mean_b) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+        let mut cov = 0.0;
+        let (mut var_a, mut var_b) = (0.0, 0.0);
+        for (x, y) in a.iter().zip(&b) {
+            cov += (x - mean_a) * (y - mean_b);
+            var_a += (x - mean_a) * (x - mean_a);
+            var_b += (y - mean_b) * (y - mean_b);
         }
+        cov / (var_a * var_b).sqrt()
+    }
 
-        let mut preview_lock = preview_buf.lock().unwrap();
-        let mut heightmap_lock = heightmap_buf.lock().unwrap();
-        for x in 0..width {
-            let i = (y * width + x) as usize;
-            heightmap_lock[i] = row_data[x as usize].0;
-            preview_lock.put_pixel(
-                x, y,
-                Rgba([
-                    row_data[x as usize].1.0,
-                    row_data[x as usize].1.1,
-                    row_data[x as usize].1.2,
-                    255,
-                ])
-            );
+    #[test]
+    fn noise_layers_are_decorrelated() {
+        for noise_stack in [NoiseStack::Classic, NoiseStack::Fbm] {
+            for independent_layer_seeds in [true, false] {
+                let config = MapConfig {
+                    noise_stack,
+                    independent_layer_seeds,
+                    octaves: 3,
+                    ..MapConfig::default()
+                };
+                let layers = build_layer_noise(&config, 42);
+                assert!(layers.len() >= 3);
+                for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+                    let r = correlation(&layers[a], &layers[b]);
+                    if independent_layer_seeds {
+                        assert!(r.abs() < 0.2, "{noise_stack:?} layers {a} and {b}: {r}");
+                    } else {
+                        // the old behavior, every layer is the same pattern
+                        assert!(r > 0.99, "{noise_stack:?} layers {a} and {b}: {r}");
+                    }
+                }
+            }
         }
-    });
+    }
 
-    let pixels = preview
-        .pixels()
-        .map(|p| egui::Color32::from_rgb(p[0], p[1], p[2]))
-        .collect();
+    #[test]
+    fn ridged_stacks_sit_at_the_same_level() {
+        let median = |noise_stack| {
+            let config = MapConfig {
+                width: 256,
+                height: 256,
+                island_mode: false,
+                noise_stack,
+                noise_mode: NoiseMode::Ridged,
+                ..MapConfig::default()
+            };
+            let map = generate_heightmap(&config, 5, &None, None, &Progress::default()).unwrap();
+            let mut heights = map.data().to_vec();
+            heights.sort_by(f32::total_cmp);
+            heights[heights.len() / 2]
+        };
+        let (classic, fbm) = (median(NoiseStack::Classic), median(NoiseStack::Fbm));
+        assert!((classic - fbm).abs() < 0.05, "classic {classic}, fbm {fbm}");
+    }
 
-    let size = [width as usize, height as usize];
-    (egui::ColorImage { size, pixels }, preview, heightmap)
+    // a benchmark rather than a test, run it with
+    // cargo test --release heightmap_scales -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn heightmap_scales_with_the_threads() {
+        let config = MapConfig { width: 4096, height: 4096, ..MapConfig::default() };
+        let time = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let start = std::time::Instant::now();
+            pool.install(|| generate_heightmap(&config, 1, &None, None, &Progress::default()));
+            start.elapsed()
+        };
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let (one, all) = (time(1), time(threads));
+        println!(
+            "heightmap 4096x4096: {one:.2?} on 1 thread, {all:.2?} on {threads}, {:.1}x",
+            one.as_secs_f64() / all.as_secs_f64()
+        );
+    }
 }
@@ -1,28 +1,241 @@
+use crate::heightmap::Heightmap;
+use crate::water::{RiverLine, Waterfall};
+use image::{ImageBuffer, Luma, Rgba};
+use rayon::prelude::*;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 
-pub fn export_heightmap_to_asc(
-    heightmap: &[f32],
+const DT_INF: f32 = 1e20;
+
+// squared 1d distance transform of a sampled function (felzenszwalb & huttenlocher)
+fn distance_transform_1d(f: &[f32], out: &mut [f32], v: &mut [usize], z: &mut [f32]) {
+    let n = f.len();
+    let mut k = 0;
+    v[0] = 0;
+    z[0] = -DT_INF;
+    z[1] = DT_INF;
+    for q in 1..n {
+        let mut s;
+        loop {
+            let p = v[k];
+            s = ((f[q] + (q * q) as f32) - (f[p] + (p * p) as f32))
+                / (2.0 * q as f32 - 2.0 * p as f32);
+            if s <= z[k] && k > 0 {
+                k -= 1;
+            } else {
+                break;
+            }
+        }
+        if s <= z[k] {
+            // k == 0 and the new parabola dominates the first one
+            v[0] = q;
+            z[1] = DT_INF;
+            continue;
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = DT_INF;
+    }
+    k = 0;
+    for (q, value) in out.iter_mut().enumerate() {
+        while z[k + 1] < q as f32 {
+            k += 1;
+        }
+        let p = v[k];
+        let d = q as f32 - p as f32;
+        *value = d * d + f[p];
+    }
+}
+
+fn distance_transform_rows(grid: &mut [f32], width: usize) {
+    grid.par_chunks_mut(width).for_each_init(
+        || (vec![0.0; width], vec![0usize; width], vec![0.0; width + 1]),
+        |(out, v, z), row| {
+            distance_transform_1d(row, out, v, z);
+            row.copy_from_slice(out);
+        },
+    );
+}
+
+fn transpose(grid: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut out = vec![0.0; grid.len()];
+    out.par_chunks_mut(height).enumerate().for_each(|(x, column)| {
+        for (y, value) in column.iter_mut().enumerate() {
+            *value = grid[y * width + x];
+        }
+    });
+    out
+}
+
+// euclidean distance (in pixels) from every cell to the nearest cell where `mask` is true.
+// cells are very far away (>1e9) if the mask is empty.
+pub fn distance_transform(mask: &[bool], width: u32, height: u32) -> Vec<f32> {
+    let (w, h) = (width as usize, height as usize);
+    let mut grid: Vec<f32> = mask.iter().map(|&m| if m { 0.0 } else { DT_INF }).collect();
+    distance_transform_rows(&mut grid, w);
+    let mut columns = transpose(&grid, w, h);
+    distance_transform_rows(&mut columns, h);
+    let grid = transpose(&columns, h, w);
+    grid.par_iter().map(|d| d.sqrt()).collect()
+}
+
+// samples a row-major buffer at a fractional pixel position, clamping at the borders
+pub fn sample_bilinear(data: &[f32], width: u32, height: u32, fx: f32, fy: f32) -> f32 {
+    let max_x = (width - 1) as f32;
+    let max_y = (height - 1) as f32;
+    let fx = fx.clamp(0.0, max_x);
+    let fy = fy.clamp(0.0, max_y);
+    let x0 = fx.floor() as u32;
+    let y0 = fy.floor() as u32;
+    let x1 = (x0 + 1).min(width - 1);
+    let y1 = (y0 + 1).min(height - 1);
+    let tx = fx - x0 as f32;
+    let ty = fy - y0 as f32;
+
+    let w = width as usize;
+    let a = data[y0 as usize * w + x0 as usize];
+    let b = data[y0 as usize * w + x1 as usize];
+    let c = data[y1 as usize * w + x0 as usize];
+    let d = data[y1 as usize * w + x1 as usize];
+    let top = a + (b - a) * tx;
+    let bottom = c + (d - c) * tx;
+    top + (bottom - top) * ty
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResampleFilter {
+    Bilinear,
+    // catmull-rom, sharper than bilinear but can overshoot slightly at steep edges
+    Bicubic,
+}
+
+fn catmull_rom(p0: f64, p1: f64, p2: f64, p3: f64, t: f64) -> f64 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+// resamples one line. shrinking averages the source span every output pixel covers,
+// growing interpolates between the source pixel centers.
+fn resample_line(src: &[f32], dst: &mut [f32], filter: ResampleFilter) {
+    let (n, m) = (src.len(), dst.len());
+    if n == m {
+        dst.copy_from_slice(src);
+        return;
+    }
+    let scale = n as f64 / m as f64;
+    if m < n {
+        for (i, out) in dst.iter_mut().enumerate() {
+            let start = i as f64 * scale;
+            let end = start + scale;
+            let mut sum = 0.0;
+            let mut j = start.floor() as usize;
+            while j < n && (j as f64) < end {
+                let overlap = end.min(j as f64 + 1.0) - start.max(j as f64);
+                sum += src[j] as f64 * overlap.max(0.0);
+                j += 1;
+            }
+            *out = (sum / scale) as f32;
+        }
+        return;
+    }
+    let at = |i: i64| src[i.clamp(0, n as i64 - 1) as usize] as f64;
+    for (i, out) in dst.iter_mut().enumerate() {
+        let pos = (i as f64 + 0.5) * scale - 0.5;
+        let i0 = pos.floor() as i64;
+        let t = pos - pos.floor();
+        let value = match filter {
+            ResampleFilter::Bilinear => at(i0) + (at(i0 + 1) - at(i0)) * t,
+            ResampleFilter::Bicubic => catmull_rom(at(i0 - 1), at(i0), at(i0 + 1), at(i0 + 2), t),
+        };
+        *out = value as f32;
+    }
+}
+
+fn resample_rows(grid: &[f32], width: usize, new_width: usize, filter: ResampleFilter) -> Vec<f32> {
+    let height = grid.len() / width;
+    let mut out = vec![0.0; new_width * height];
+    out.par_chunks_mut(new_width)
+        .zip(grid.par_chunks(width))
+        .for_each(|(dst, src)| resample_line(src, dst, filter));
+    out
+}
+
+// resizes a heightmap, every axis is shrunk or grown on its own so mixed ratios work too
+pub fn resample_heightmap(
+    heightmap: &Heightmap,
+    width: u32,
+    height: u32,
+    filter: ResampleFilter,
+) -> Heightmap {
+    let (w, h) = (heightmap.width() as usize, heightmap.height() as usize);
+    let (nw, nh) = (width.max(1) as usize, height.max(1) as usize);
+    let rows = resample_rows(heightmap.data(), w, nw, filter);
+    let columns = resample_rows(&transpose(&rows, nw, h), h, nh, filter);
+    Heightmap::from_vec(transpose(&columns, nh, nw), nw as u32, nh as u32)
+}
+
+// copies the rectangle at (x, y) out of a row-major buffer, the rectangle has to fit
+pub fn crop_grid<T: Copy>(
+    data: &[T],
+    width: u32,
+    x: u32,
+    y: u32,
+    new_width: u32,
+    new_height: u32,
+) -> Vec<T> {
+    let mut out = Vec::with_capacity((new_width * new_height) as usize);
+    for row in y..y + new_height {
+        let start = (row * width + x) as usize;
+        out.extend_from_slice(&data[start..start + new_width as usize]);
+    }
+    out
+}
+
+// nearest neighbor resize for per pixel ids like the biome map, which can't be blended
+pub fn resample_nearest<T: Copy + Send + Sync>(
+    data: &[T],
     width: u32,
     height: u32,
+    new_width: u32,
+    new_height: u32,
+) -> Vec<T> {
+    let nearest = |i: u32, from: u32, to: u32| {
+        (((i as f64 + 0.5) * from as f64 / to as f64) as u32).min(from - 1)
+    };
+    (0..new_width * new_height)
+        .into_par_iter()
+        .map(|i| {
+            let x = nearest(i % new_width, width, new_width);
+            let y = nearest(i / new_width, height, new_height);
+            data[(y * width + x) as usize]
+        })
+        .collect()
+}
+
+pub fn export_heightmap_to_asc(
+    heightmap: &Heightmap,
     filename: &str,
     min_elevation: f32,
     max_elevation: f32,
+    cell_size: f64,
 ) -> std::io::Result<()> {
     let file = File::create(filename)?;
     let mut writer = BufWriter::new(file);
 
-    writeln!(writer, "ncols         {}", width)?;
-    writeln!(writer, "nrows         {}", height)?;
+    writeln!(writer, "ncols         {}", heightmap.width())?;
+    writeln!(writer, "nrows         {}", heightmap.height())?;
     writeln!(writer, "xllcorner     0.0")?;
     writeln!(writer, "yllcorner     0.0")?;
-    writeln!(writer, "cellsize      1.0")?;
+    writeln!(writer, "cellsize      {}", cell_size)?;
     writeln!(writer, "NODATA_value  -9999")?;
 
-    for y in 0..height {
-        for x in 0..width {
-            let i = (y * width + x) as usize;
-            let val = heightmap[i];
+    for row in heightmap.rows() {
+        for &val in row {
             let elevation = min_elevation + val * (max_elevation - min_elevation);
             write!(writer, "{:.2} ", elevation)?;
         }
@@ -30,4 +243,270 @@ pub fn export_heightmap_to_asc(
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// water surface like export_heightmap_to_asc, cells without water are written as no data
+pub fn export_water_surface_to_asc(
+    surface: &Heightmap,
+    filename: &str,
+    min_elevation: f32,
+    max_elevation: f32,
+    cell_size: f64,
+) -> std::io::Result<()> {
+    let file = File::create(filename)?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "ncols         {}", surface.width())?;
+    writeln!(writer, "nrows         {}", surface.height())?;
+    writeln!(writer, "xllcorner     0.0")?;
+    writeln!(writer, "yllcorner     0.0")?;
+    writeln!(writer, "cellsize      {}", cell_size)?;
+    writeln!(writer, "NODATA_value  -9999")?;
+
+    for row in surface.rows() {
+        for &val in row {
+            if val > 0.0 {
+                let elevation = min_elevation + val * (max_elevation - min_elevation);
+                write!(writer, "{:.2} ", elevation)?;
+            } else {
+                write!(writer, "-9999 ")?;
+            }
+        }
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+// waterfalls as csv in meters. like the asc export y grows northwards from the bottom edge
+// of the map, so the points line up with the terrain in terrain builder.
+pub fn export_waterfalls_to_csv(
+    waterfalls: &[Waterfall],
+    filename: &str,
+    map_height: u32,
+    cell_size: f64,
+) -> std::io::Result<()> {
+    let file = File::create(filename)?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "x,y,drop")?;
+    for waterfall in waterfalls {
+        let (east, north) = cell_to_meters(waterfall.position, map_height, cell_size);
+        writeln!(writer, "{:.2},{:.2},{:.2}", east, north, waterfall.drop)?;
+    }
+
+    Ok(())
+}
+
+// map cell to meters east and north, like the waterfall export
+fn cell_to_meters((x, y): (u32, u32), map_height: u32, cell_size: f64) -> (f64, f64) {
+    let east = (x as f64 + 0.5) * cell_size;
+    let north = (map_height as f64 - y as f64 - 0.5) * cell_size;
+    (east, north)
+}
+
+// rivers as geojson line strings in meters. the widths in meters and depths of the points are
+// kept as lists in the properties, `joins` is the index of the feature a tributary runs into.
+pub fn export_rivers_to_geojson(
+    rivers: &[RiverLine],
+    filename: &str,
+    map_height: u32,
+    cell_size: f64,
+) -> std::io::Result<()> {
+    let file = File::create(filename)?;
+    let mut writer = BufWriter::new(file);
+    let list = |values: Vec<String>| format!("[{}]", values.join(","));
+
+    writeln!(writer, "{{\"type\":\"FeatureCollection\",\"features\":[")?;
+    for (i, river) in rivers.iter().enumerate() {
+        let coordinates = river
+            .points
+            .iter()
+            .map(|p| {
+                let (east, north) = cell_to_meters(p.position, map_height, cell_size);
+                format!("[{:.2},{:.2}]", east, north)
+            })
+            .collect();
+        let widths = river
+            .points
+            .iter()
+            .map(|p| format!("{:.2}", p.width as f64 * cell_size))
+            .collect();
+        let depths = river.points.iter().map(|p| format!("{:.2}", p.depth)).collect();
+        let joins = river.joins.map_or("null".to_string(), |j| j.to_string());
+        writeln!(
+            writer,
+            "{{\"type\":\"Feature\",\"geometry\":{{\"type\":\"LineString\",\"coordinates\":{}}},\
+             \"properties\":{{\"id\":{},\"joins\":{},\"widths\":{},\"depths\":{}}}}}{}",
+            list(coordinates),
+            i,
+            joins,
+            list(widths),
+            list(depths),
+            if i + 1 < rivers.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(writer, "]}}")?;
+
+    Ok(())
+}
+
+// one row per river point in meters, in order from the source down. `joins` is empty for a
+// river that doesn't run into another one.
+pub fn export_rivers_to_csv(
+    rivers: &[RiverLine],
+    filename: &str,
+    map_height: u32,
+    cell_size: f64,
+) -> std::io::Result<()> {
+    let file = File::create(filename)?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "river,joins,x,y,width,depth")?;
+    for (i, river) in rivers.iter().enumerate() {
+        let joins = river.joins.map_or(String::new(), |j| j.to_string());
+        for point in &river.points {
+            let (east, north) = cell_to_meters(point.position, map_height, cell_size);
+            writeln!(
+                writer,
+                "{},{},{:.2},{:.2},{:.2},{:.2}",
+                i,
+                joins,
+                east,
+                north,
+                point.width as f64 * cell_size,
+                point.depth
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// 16 bit value of a depth scaled to the elevation range like the heightmap, so meters are
+// value / 65535 * (max elevation - min elevation)
+fn depth_to_u16(depth: f32) -> u16 {
+    (depth.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+}
+
+// grayscale png of a water depth map, dry cells are black
+pub fn export_depth_mask_to_png(
+    depths: &[f32],
+    width: u32,
+    height: u32,
+    filename: &str,
+) -> image::ImageResult<()> {
+    let pixels = depths.iter().map(|&d| depth_to_u16(d)).collect();
+    let image: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_raw(width, height, pixels)
+        .expect("depth map doesn't match the image size");
+    image.save(filename)
+}
+
+// grayscale png of the wetness from the water step, white is soaked ground
+pub fn export_wetness_to_png(
+    wetness: &[f32],
+    width: u32,
+    height: u32,
+    filename: &str,
+) -> image::ImageResult<()> {
+    export_depth_mask_to_png(wetness, width, height, filename)
+}
+
+// grayscale png holding the biome id of every cell, see Biome::from_id. blended borders are
+// dithered, a cell there has the id of one of the biomes meeting at it and never a mix.
+pub fn export_biome_ids_to_png(
+    ids: &[u8],
+    width: u32,
+    height: u32,
+    filename: &str,
+) -> image::ImageResult<()> {
+    let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, ids.to_vec())
+        .expect("biome map doesn't match the image size");
+    image.save(filename)
+}
+
+// lakes in red and rivers in green, both scaled like export_depth_mask_to_png. alpha marks
+// the cells that hold any water at all.
+pub fn export_water_masks_to_png(
+    lake_depths: &[f32],
+    river_depths: &[f32],
+    width: u32,
+    height: u32,
+    filename: &str,
+) -> image::ImageResult<()> {
+    let pixels = lake_depths
+        .iter()
+        .zip(river_depths)
+        .flat_map(|(&lake, &river)| {
+            let wet = if lake > 0.0 || river > 0.0 { u16::MAX } else { 0 };
+            [depth_to_u16(lake), depth_to_u16(river), 0, wet]
+        })
+        .collect();
+    let image: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::from_raw(width, height, pixels)
+        .expect("water maps don't match the image size");
+    image.save(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(width: u32, height: u32, f: impl Fn(f32, f32) -> f32) -> Heightmap {
+        let data = (0..width * height)
+            .map(|i| f((i % width) as f32, (i / width) as f32))
+            .collect();
+        Heightmap::from_vec(data, width, height)
+    }
+
+    fn mean(heightmap: &Heightmap) -> f32 {
+        heightmap.iter().sum::<f32>() / heightmap.len() as f32
+    }
+
+    #[test]
+    fn halving_averages_every_two_by_two_block() {
+        let map = grid(8, 6, |x, y| (x * 7.0 + y * 3.0) % 5.0);
+        let half = resample_heightmap(&map, 4, 3, ResampleFilter::Bilinear);
+        for y in 0..3 {
+            for x in 0..4 {
+                let (sx, sy) = (2 * x, 2 * y);
+                let block = map.get(sx, sy)
+                    + map.get(sx + 1, sy)
+                    + map.get(sx, sy + 1)
+                    + map.get(sx + 1, sy + 1);
+                assert!((half.get(x, y) - block / 4.0).abs() < 1e-5, "{x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn doubling_interpolates_between_the_pixel_centers() {
+        // both filters reproduce a plane exactly away from the clamped border
+        let map = grid(8, 6, |x, y| x + 10.0 * y);
+        for filter in [ResampleFilter::Bilinear, ResampleFilter::Bicubic] {
+            let double = resample_heightmap(&map, 16, 12, filter);
+            for y in 4..8 {
+                for x in 4..12 {
+                    let (sx, sy) = (x as f32 / 2.0 - 0.25, y as f32 / 2.0 - 0.25);
+                    let error = (double.get(x, y) - (sx + 10.0 * sy)).abs();
+                    assert!(error < 1e-4, "{filter:?} {x},{y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn non_integer_ratios_keep_flat_maps_flat_and_the_mean() {
+        let flat = grid(10, 7, |_, _| 0.25);
+        for (width, height) in [(23, 5), (4, 13), (7, 3)] {
+            for filter in [ResampleFilter::Bilinear, ResampleFilter::Bicubic] {
+                let resized = resample_heightmap(&flat, width, height, filter);
+                assert_eq!((resized.width(), resized.height()), (width, height));
+                assert!(resized.iter().all(|&h| (h - 0.25).abs() < 1e-6));
+            }
+        }
+        // shrinking by 2.5 and 1.75 averages over the covered area, nothing is lost
+        let map = grid(10, 7, |x, y| (x * 0.3 + y * 0.7).sin());
+        let shrunk = resample_heightmap(&map, 4, 4, ResampleFilter::Bilinear);
+        assert!((mean(&shrunk) - mean(&map)).abs() < 1e-5);
+    }
+}
@@ -1,109 +1,2668 @@
-use crate::config::{MapConfig, WaterConfig};
+use crate::analysis::{compute_ocean_mask, compute_slope_map};
+use crate::biomes::{Biome, biome_climate, biome_lake_chance};
+use crate::config::{Canal, DeadEndRemedy, MapConfig, RiverMode, RiverProfile, WaterConfig};
+use crate::heightmap::Heightmap;
+use crate::jobs::Progress;
+use crate::utils::distance_transform;
 use eframe::egui;
 use image::{ImageBuffer, Rgba};
+use noise::{NoiseFn, Perlin, Seedable};
+use rand::{Rng, SeedableRng, rngs::StdRng};
 use rayon::prelude::*;
-use rand::{
-    Rng,
-    SeedableRng,
-    rngs::StdRng,
-};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 
-pub fn get_color_for_water(value: f32) -> (u8, u8, u8) {
-    let blue = (value * 255.0).clamp(0.0, 255.0) as u8;
-    let green = ((1.0 - value) * 255.0).clamp(0.0, 255.0) as u8;
-    (blue, green, 255)
+// width in cells a drainage river gains per e-fold of its catchment above the threshold, at
+// a river speed of 100
+const LOG_WIDTH_GROWTH: f32 = 4.0;
+// share of the water falling on its catchment that reaches a lake at a base inflow of 100
+const RUNOFF_SHARE: f32 = 0.5;
+// the rainfall of a lake differs from the climate by up to this share either way
+const RAINFALL_VARIATION: f32 = 0.25;
+// further attempts per missing lake once `lake_attempts` didn't reach `min_lake_n`
+const EXTRA_ATTEMPTS_PER_LAKE: u32 = 50;
+// random cells tried for a river source before giving up on it
+const SOURCE_TRIES: usize = 1000;
+// land cells the source elevation band needs, with fewer rivers rise anywhere on land
+const MIN_SOURCE_BAND_CELLS: usize = 100;
+// steps without reaching a new lowest point after which a river counts as stuck in a pit
+const MAX_STALL_STEPS: usize = 32;
+// extra flow of a river rising in snow or tundra at full biome influence, fed by glaciers
+const GLACIER_FLOW: f32 = 150.0;
+// rivers shorter than this many cells are dropped
+const MIN_RIVER_LENGTH: usize = 8;
+// width in cells a river gains per cell of length at a river speed of 100
+const WIDTH_GROWTH: f32 = 0.02;
+// flow an outlet river starts with per cell of lake area, at full drainage
+const OUTLET_FLOW_PER_CELL: f32 = 0.1;
+// size of the pond dug at a dead end, relative to the river width there and at least
+const POND_RADIUS_PER_WIDTH: f32 = 1.5;
+const MIN_POND_RADIUS: f32 = 3.0;
+const RIVER_COLOR: (u8, u8, u8) = (70, 150, 225);
+// opacity of the water drawn over the terrain preview. rivers start at the minimum at
+// `river_min_width` and are opaque at `river_width`.
+const LAKE_ALPHA: f32 = 0.9;
+const MIN_RIVER_ALPHA: f32 = 0.4;
+// rivers on ground flatter than this, in degrees, are slow enough to soak their banks
+const SLOW_RIVER_SLOPE: f32 = 3.0;
+// slope in degrees at which the ground sheds all its water at full slope weight
+const DRY_SLOPE: f32 = 20.0;
+// cells draining through a cell that keep it soaked at full flow weight
+const WET_CATCHMENT: f32 = 10_000.0;
+// flat ground within this height above the sea is damp, even away from any water
+const LOWLAND_SLOPE: f32 = 1.0;
+const LOWLAND_HEIGHT: f32 = 0.05;
+// cells back from the mouth the direction the estuary runs out to sea is taken from
+const ESTUARY_HEADING_CELLS: usize = 5;
+// share of its part of the estuary a distributary is wide, the rest is sandbar
+const BRANCH_WIDTH_SHARE: f32 = 0.7;
+// depth of the sandbars between distributaries below the sea level in meters
+const SANDBAR_DEPTH: f32 = 0.5;
+// cells from its source over which a valley grows to its full width
+const VALLEY_TAPER_CELLS: f32 = 50.0;
+// cells below a junction over which the river widens to carry its tributary's flow
+const JUNCTION_BLEND_CELLS: usize = 10;
+// depth in meters the lake bed is cut to where the smoothed shoreline takes in dry ground
+const SHORE_CUT_DEPTH: f32 = 0.2;
+// random lake attempts planned side by side against the same maps before they are placed
+const LAKE_BATCH: u32 = 32;
+// random number streams, every lake attempt and river draws from one of its own
+const PLACED_LAKE_STREAM: u64 = 0;
+const LAKE_STREAM: u64 = 1;
+const OUTLET_STREAM: u64 = 2;
+const RIVER_STREAM: u64 = 3;
+// owner of a cell no traced river runs through
+const NO_RIVER: u32 = u32::MAX;
+// cells searched around a lake for the pass its outlet river crosses
+const MAX_OUTLET_SEARCH: usize = 100_000;
+
+// lake color from shallow (0) to as deep as lakes get (1)
+pub fn get_color_for_water(depth: f32) -> (u8, u8, u8) {
+    let t = depth.clamp(0.0, 1.0);
+    let lerp = |a: f32, b: f32| (a + (b - a) * t) as u8;
+    (lerp(90.0, 10.0), lerp(170.0, 40.0), lerp(230.0, 120.0))
+}
+
+// 4-neighbors of a cell inside the map
+fn neighbors(index: usize, width: usize, height: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = (index % width, index / width);
+    [
+        (x > 0).then(|| index - 1),
+        (x + 1 < width).then(|| index + 1),
+        (y > 0).then(|| index - width),
+        (y + 1 < height).then(|| index + width),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+// all 8 neighbors of a cell inside the map with their distance
+fn neighbors8(index: usize, width: usize, height: usize) -> impl Iterator<Item = (usize, f32)> {
+    let (x, y) = ((index % width) as i64, (index / width) as i64);
+    [
+        (-1, -1),
+        (0, -1),
+        (1, -1),
+        (-1, 0),
+        (1, 0),
+        (-1, 1),
+        (0, 1),
+        (1, 1),
+    ]
+    .into_iter()
+    .filter_map(move |(dx, dy)| {
+        let (nx, ny) = (x + dx, y + dy);
+        let inside = nx >= 0 && ny >= 0 && nx < width as i64 && ny < height as i64;
+        let distance = if dx != 0 && dy != 0 {
+            std::f32::consts::SQRT_2
+        } else {
+            1.0
+        };
+        inside.then(|| (ny as usize * width + nx as usize, distance))
+    })
+}
+
+// cell on the shore of a growing lake, the heap hands out the lowest one first
+#[derive(PartialEq)]
+struct Shore {
+    height: f32,
+    index: usize,
+}
+
+impl Eq for Shore {}
+
+impl Ord for Shore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .height
+            .total_cmp(&self.height)
+            .then(other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for Shore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// a lake stamped into the lake map
+pub struct Lake {
+    pub level: f32,
+    // cells under water, without the shoreline around them
+    pub cells: Vec<usize>,
+    // circle around the cells, in cells
+    pub center: (f32, f32),
+    pub radius: f32,
+    // cells the shoreline smoothing took in and gave up
+    pub reshaped: (usize, usize),
+}
+
+impl Lake {
+    // whether the cell at `x`, `y` is within `spacing` cells of the bounding circle
+    fn near(&self, x: f32, y: f32, spacing: f32) -> bool {
+        (x - self.center.0).hypot(y - self.center.1) < self.radius + spacing
+    }
+}
+
+// what flows into a basin and what evaporates from the lake in it, and how the biomes shift
+// that by `biome_influence`. without a biome map everything is neutral.
+pub struct WaterBalance<'a> {
+    // cells draining into every pit of the terrain along the steepest way down, 0 elsewhere
+    sink_area: Vec<f32>,
+    // biome ids, empty before the biomes are generated
+    biome_map: &'a [u8],
+}
+
+// cells draining into every cell along the steepest way down, the cell itself included, and
+// whether the water stops there because no neighbor is lower. the sea takes no part in it.
+fn flow_accumulation(heightmap: &Heightmap, ocean: &[bool]) -> (Vec<f32>, Vec<bool>) {
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let data = heightmap.data();
+    let mut order: Vec<usize> = (0..data.len()).collect();
+    order.sort_unstable_by(|&a, &b| data[b].total_cmp(&data[a]));
+    // going from high to low, every cell hands its area on to its lowest neighbor
+    let mut area = vec![1.0f32; data.len()];
+    let mut sink = vec![false; data.len()];
+    for i in order.into_iter().filter(|&i| !ocean[i]) {
+        let lowest = neighbors8(i, width, height)
+            .map(|(n, distance)| (n, (data[i] - data[n]) / distance))
+            .filter(|&(_, drop)| drop > 0.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        match lowest {
+            Some((n, _)) => area[n] += area[i],
+            None => sink[i] = true,
+        }
+    }
+    (area, sink)
+}
+
+impl<'a> WaterBalance<'a> {
+    pub fn new(heightmap: &Heightmap, ocean: &[bool], biome_map: &'a [u8]) -> Self {
+        let data = heightmap.data();
+        let (area, sink) = flow_accumulation(heightmap, ocean);
+        let sink_area = area
+            .into_iter()
+            .zip(sink)
+            .map(|(area, sink)| if sink { area } else { 0.0 })
+            .collect();
+        let biome_map = if biome_map.len() == data.len() {
+            biome_map
+        } else {
+            &[]
+        };
+        Self {
+            sink_area,
+            biome_map,
+        }
+    }
+
+    fn biome(&self, cell: usize) -> Option<Biome> {
+        self.biome_map.get(cell).copied().and_then(Biome::from_id)
+    }
+
+    // lake area in cells at which as much water evaporates from a basin as flows into it
+    // from the pits among `cells`. a humid biome at `center` brings up to twice the water
+    // and a hot one takes up to twice as much away.
+    fn equilibrium_area(&self, water_config: &WaterConfig, cells: &[usize], center: usize) -> f32 {
+        let catchment: f32 = cells.iter().map(|&i| self.sink_area[i]).sum();
+        let influence = biome_influence(water_config);
+        let (temperature, humidity) = self.biome(center).map_or((0.5, 0.5), biome_climate);
+        let wet = 1.0 + (humidity * 2.0 - 1.0) * influence;
+        let hot = 1.0 + (temperature * 2.0 - 1.0) * influence;
+        let inflow = water_config.base_inflow.max(0.0) / 100.0 * RUNOFF_SHARE * wet;
+        let evaporation = water_config.base_evaporation.max(0.0) / 100.0 * hot;
+        if evaporation <= 0.0 {
+            return f32::INFINITY;
+        }
+        catchment * inflow / evaporation
+    }
+
+    // chance that a lake attempt at `cell` goes ahead at all
+    fn lake_chance(&self, water_config: &WaterConfig, cell: usize) -> f32 {
+        let chance = self.biome(cell).map_or(1.0, biome_lake_chance);
+        1.0 - (1.0 - chance) * biome_influence(water_config)
+    }
+
+    // flow a river rising at `cell` starts with
+    fn source_flow(&self, water_config: &WaterConfig, cell: usize) -> f32 {
+        match self.biome(cell) {
+            Some(Biome::Snow | Biome::Tundra) => 1.0 + GLACIER_FLOW * biome_influence(water_config),
+            _ => 1.0,
+        }
+    }
+}
+
+fn biome_influence(water_config: &WaterConfig) -> f32 {
+    (water_config.biome_influence / 100.0).clamp(0.0, 1.0)
+}
+
+// where a lake spills into the river that drains it, in map cells
+pub struct LakeOutlet {
+    // middle of the lake
+    pub lake: (f32, f32),
+    pub outlet: (u32, u32),
+}
+
+// steep stretch of a river, in map cells and meters
+pub struct Waterfall {
+    pub position: (u32, u32),
+    pub drop: f32,
+}
+
+// cell of a river line with the width there in cells and the water depth in meters
+pub struct RiverPoint {
+    pub position: (u32, u32),
+    pub width: f32,
+    pub depth: f32,
+}
+
+// a carved river from its source down. a tributary ends on the point where it joins another
+// river and shares everything below with it, so every stretch of water is in one line only.
+pub struct RiverLine {
+    pub points: Vec<RiverPoint>,
+    // index of the line this one runs into
+    pub joins: Option<usize>,
+}
+
+// the cells of a carved path with their width in cells as a line, the depth is read from
+// `river_depths` as it is now
+pub fn river_line(
+    path: Vec<(usize, f32)>,
+    joins: Option<usize>,
+    river_depths: &[f32],
+    width: u32,
+    elevation_range: f32,
+) -> RiverLine {
+    RiverLine {
+        points: path
+            .into_iter()
+            .map(|(cell, river_width)| RiverPoint {
+                position: (cell as u32 % width, cell as u32 / width),
+                width: river_width,
+                depth: river_depths[cell] * elevation_range,
+            })
+            .collect(),
+        joins,
+    }
+}
+
+// everything the water step produces
+pub struct WaterMap {
+    // water level of every lake cell and 0 elsewhere
+    pub lake_map: Vec<f32>,
+    // water above the lake bed of every lake cell, the shoreline is 0 like dry land
+    pub lake_depths: Vec<f32>,
+    // water above the river bed of every river cell and 0 elsewhere, in heightmap units like
+    // `lake_depths`. the valleys are shaped around it.
+    pub river_depths: Vec<f32>,
+    // water surface of every river cell and 0 elsewhere, the river counterpart of `lake_map`
+    pub river_surface: Vec<f32>,
+    // width in cells of the river over every river cell and 0 elsewhere
+    pub river_widths: Vec<f32>,
+    // the input heightmap with the lake beds and river channels carved in
+    pub heightmap: Heightmap,
+    pub outlets: Vec<LakeOutlet>,
+    pub waterfalls: Vec<Waterfall>,
+    // how wet the ground is from 0 to 1, see compute_wetness
+    pub wetness: Vec<f32>,
+    // rivers that didn't reach any water and got a pond or were dropped for it
+    pub ponded_rivers: usize,
+    pub removed_rivers: usize,
+    // random lakes that were made and attempts dropped for being too close to another lake
+    pub lakes_accepted: usize,
+    pub lakes_rejected: usize,
+    // lake seeds placed by hand that didn't make a lake and why
+    pub unplaced_lakes: Vec<((u32, u32), NoLake)>,
+    // land cells between the river source elevations if they were too few to use, the
+    // random rivers rose anywhere on land instead
+    pub small_source_band: Option<usize>,
+    // cells the shoreline smoothing added to and removed from the lakes, and their area in
+    // cells before it
+    pub shore_change: (usize, usize, usize),
+    // cells where a tributary runs into another river
+    pub junctions: Vec<(u32, u32)>,
+    pub rivers: Vec<RiverLine>,
+}
+
+// generator for the `index`th lake attempt or river of a `stream`, so each one comes out the
+// same no matter in which order or on which thread it is made
+fn stream_rng(seed: u32, stream: u64, index: u64) -> StdRng {
+    let mixed = ((seed as u64) << 32) ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ index;
+    StdRng::seed_from_u64(mixed)
 }
 
+// why an attempt made no lake
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoLake {
+    // the spot is outside the map or the elevation band, in the sea or a lake, too dry, or
+    // there is no basin there that holds enough water
+    NoBasin,
+    // the lake would come closer to another one than the spacing allows
+    TooClose,
+}
+
+// what the steps of the water generation read besides the maps they change
+#[derive(Clone, Copy)]
+pub struct WaterContext<'a> {
+    map_config: &'a MapConfig,
+    water_config: &'a WaterConfig,
+    // the open sea, which the water never goes into
+    ocean: &'a [bool],
+    // height in meters of one unit of the heightmap
+    elevation_range: f32,
+    // terrain the rivers find their way down on instead of the heightmap, such as the one
+    // with its pits filled
+    routing: Option<&'a Heightmap>,
+}
+
+impl<'a> WaterContext<'a> {
+    pub fn new(
+        map_config: &'a MapConfig,
+        water_config: &'a WaterConfig,
+        ocean: &'a [bool],
+        elevation_range: f32,
+    ) -> Self {
+        Self {
+            map_config,
+            water_config,
+            ocean,
+            elevation_range,
+            routing: None,
+        }
+    }
+}
+
+// where a lake is tried, in cells, and the lakes it stays `spacing` cells away from
+#[derive(Clone, Copy)]
+pub struct LakeSite<'a> {
+    pub x: f32,
+    pub y: f32,
+    pub others: &'a [Lake],
+    pub spacing: f32,
+}
+
+// `lake_map` holds the water level of every lake cell and 0 elsewhere, depths and capacities
+// are in meters. the lake stays `spacing` cells away from the bounding circles of the
+// `others` of its `site`, and fills its basin as far as the `balance` of inflow and
+// evaporation lets it.
 pub fn generate_lake_at(
-    map_config: &MapConfig,
-    water_config: &WaterConfig,
-    heightmap: &[f32],
+    context: &WaterContext,
+    heightmap: &mut Heightmap,
+    balance: &WaterBalance,
     lake_map: &mut [f32],
-    x: f32,
-    y: f32
-) -> bool {
-    let width = map_config.width as f32;
-    let height = map_config.height as f32;
-    
+    site: &LakeSite,
+    rng: &mut StdRng,
+) -> Result<Lake, NoLake> {
+    let plan = plan_lake(context, heightmap, balance, lake_map, site, rng)?;
+    Ok(place_lake(context, heightmap, lake_map, plan))
+}
+
+// a lake found in the maps but not put into them yet
+struct LakePlan {
+    // cells below the water as flooded and after the shoreline smoothing
+    flooded: Vec<usize>,
+    shape: Vec<usize>,
+    level: f32,
+}
+
+// the part of generate_lake_at that only reads the maps, so many attempts can be planned at
+// once
+fn plan_lake(
+    context: &WaterContext,
+    heightmap: &Heightmap,
+    balance: &WaterBalance,
+    lake_map: &[f32],
+    site: &LakeSite,
+    rng: &mut StdRng,
+) -> Result<LakePlan, NoLake> {
+    let WaterContext {
+        map_config,
+        water_config,
+        ocean,
+        elevation_range,
+        ..
+    } = *context;
+    let LakeSite {
+        x,
+        y,
+        others,
+        spacing,
+    } = *site;
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let cell_area = (map_config.cell_size_m * map_config.cell_size_m) as f32;
+    let data = heightmap.data();
+
     // try to generate a lake by searching for bowl at given coordinates
     // basically a flood fill algorithm
 
     // 1. find a local minimum in the heightmap
-    let mut center_x = x as i32;
-    let mut center_y = y as i32;
-    // TODO: set center to nearest local minimum in heightmap
-
-    // abort if one of the following conditions is met:
-    // - center is out of bounds
-    // - center is too low or too high (check config)
-    // - center is already part of a lake
-
-    // 2. flood fill the area around the center
-    // - find next lowest neighbor (4 directions)
-    // - set new lake height to the new neighbor height if heigher than last lake height
-    // - add all neighbors lower than the lake height
-    // - repeat until stop condition is met
-
-    // stop on one of the following conditions:
-    // - the area is too big (radius)
-    // - the area is too deep
+    if x < 0.0 || y < 0.0 || x >= width as f32 || y >= height as f32 {
+        return Err(NoLake::NoBasin);
+    }
+    let mut center = y as usize * width + x as usize;
+    while let Some(lowest) = neighbors(center, width, height)
+        .filter(|&n| data[n] < data[center])
+        .min_by(|&a, &b| data[a].total_cmp(&data[b]))
+    {
+        center = lowest;
+    }
+
+    // abort if the center is in the sea, outside the elevation band or already in a lake
+    let floor = data[center];
+    if ocean[center]
+        || floor < water_config.min_elevation
+        || floor > water_config.max_elevation
+        || lake_map[center] > 0.0
+    {
+        return Err(NoLake::NoBasin);
+    }
+    let (center_x, center_y) = ((center % width) as f32, (center / width) as f32);
+    if others
+        .iter()
+        .any(|lake| lake.near(center_x, center_y, spacing))
+    {
+        return Err(NoLake::TooClose);
+    }
+
+    // 2. flood fill the area around the center, always taking the lowest cell on the shore.
+    // a shore cell higher than the water raises the level to it, from then on the basin
+    // holds water up to that height without spilling.
+    let max_radius = water_config.max_lake_radius.max(1.0);
+    let mut shore = BinaryHeap::from([Shore {
+        height: floor,
+        index: center,
+    }]);
+    let mut seen = HashSet::from([center]);
+    let mut cells = Vec::new();
+    let mut height_sum = 0.0f64;
+    let mut level = floor;
+    // last level the lake could stop at and the number of cells below it, and whether the
+    // flood ran into the room kept around another lake
+    let mut valid = None;
+    let mut crowded = false;
+    while let Some(Shore { height: h, index }) = shore.pop() {
+        let (cell_x, cell_y) = (index % width, index / width);
+        // water that reaches the sea, the map edge or another lake runs off there
+        let spills = ocean[index]
+            || cell_x == 0
+            || cell_y == 0
+            || cell_x + 1 == width
+            || cell_y + 1 == height
+            || lake_map[index] > 0.0;
+        let (fx, fy) = (cell_x as f32, cell_y as f32);
+        crowded = others.iter().any(|lake| lake.near(fx, fy, spacing));
+        let too_far = (fx - center_x).hypot(fy - center_y) > max_radius;
+        if spills || too_far || crowded {
+            break;
+        }
+        if h > level {
+            let depth = (h - floor) * elevation_range;
+            let volume =
+                (cells.len() as f64 * h as f64 - height_sum) as f32 * elevation_range * cell_area;
+            if depth > water_config.max_lake_depth || volume > water_config.max_capacity {
+                break;
+            }
+            valid = Some((h, cells.len()));
+            level = h;
+        }
+        cells.push(index);
+        height_sum += h as f64;
+        for n in neighbors(index, width, height) {
+            if seen.insert(n) {
+                shore.push(Shore {
+                    height: data[n],
+                    index: n,
+                });
+            }
+        }
+    }
     // when stopped, go back to last valid lake
+    let Some((rim, count)) = valid else {
+        return Err(if crowded {
+            NoLake::TooClose
+        } else {
+            NoLake::NoBasin
+        });
+    };
+    let cells = &cells[..count];
 
-    // 3. lower lake height by a random amount (config)
+    // 3. the water rises until as much evaporates from the lake as flows in, at most up to
+    // the rim. the lake covers the cells below its level, which is found in their heights.
+    let rainfall = rng.gen_range(1.0 - RAINFALL_VARIATION..1.0 + RAINFALL_VARIATION);
+    let area = balance.equilibrium_area(water_config, cells, center) * rainfall;
+    let mut heights: Vec<f32> = cells.iter().map(|&i| data[i]).collect();
+    heights.sort_unstable_by(f32::total_cmp);
+    let level = heights.get(area as usize).map_or(rim, |&h| h.min(rim));
 
     // 4. check all points of the lake
+    let flooded: Vec<usize> = cells.iter().copied().filter(|&i| data[i] < level).collect();
+    let depth = (level - floor) * elevation_range;
+    let volume =
+        flooded.iter().map(|&i| level - data[i]).sum::<f32>() * elevation_range * cell_area;
+    if flooded.is_empty() || depth < water_config.min_depth || volume < water_config.min_capacity {
+        return Err(NoLake::NoBasin);
+    }
+
+    // 5. clean up the shoreline. the lake keeps out of the sea, other lakes and the room
+    // kept around them, so it never grows into a neighbor. islands stay as they are.
+    let shape = if water_config.shore_smoothing > 0 {
+        let islands = lake_islands(&flooded, width, height);
+        let blocked = |i: usize| {
+            let (fx, fy) = ((i % width) as f32, (i / width) as f32);
+            ocean[i]
+                || islands.contains(&i)
+                || std::iter::once(i)
+                    .chain(neighbors(i, width, height))
+                    .any(|n| lake_map[n] > 0.0)
+                || others.iter().any(|lake| lake.near(fx, fy, spacing))
+        };
+        smooth_shoreline(
+            &flooded,
+            data,
+            width,
+            height,
+            water_config.shore_smoothing,
+            blocked,
+        )
+    } else {
+        flooded.clone()
+    };
+    Ok(LakePlan {
+        flooded,
+        shape,
+        level,
+    })
+}
+
+// puts a planned lake into the heightmap and the lake map
+fn place_lake(
+    context: &WaterContext,
+    heightmap: &mut Heightmap,
+    lake_map: &mut [f32],
+    plan: LakePlan,
+) -> Lake {
+    let WaterContext {
+        water_config,
+        ocean,
+        ..
+    } = *context;
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let LakePlan {
+        flooded,
+        shape,
+        level,
+    } = plan;
+    let (flooded, level, reshaped) = if water_config.shore_smoothing > 0 {
+        let level = fit_shoreline(context, heightmap, lake_map, &flooded, &shape, level);
+        let inside: HashSet<usize> = shape.iter().copied().collect();
+        let before: HashSet<usize> = flooded.iter().copied().collect();
+        let added = inside.difference(&before).count();
+        let removed = before.difference(&inside).count();
+        (shape, level, (added, removed))
+    } else {
+        (shape, level, (0, 0))
+    };
+
+    // 6. apply heightmap modification, the bed is deepened by a share of the water above it
+    // so the shoreline stays where it is
+    let deepen = water_config.lake_terrain_modification.max(0.0) / 100.0;
+    let data = heightmap.data_mut();
+    for &i in &flooded {
+        data[i] -= (level - data[i]) * deepen;
+    }
+
+    // 7. expand lake area by 1 pixel in all directions (since height map precision is
+    // 1m/pixel, the actual intersection is somewhere between the pixel below and above the
+    // lake). islands are left out, a small one would be gone otherwise.
+    let islands = lake_islands(&flooded, width, height);
+    let mut shoreline = Vec::new();
+    for &i in &flooded {
+        for n in neighbors(i, width, height) {
+            if lake_map[n] == 0.0 && !ocean[n] && !islands.contains(&n) {
+                shoreline.push(n);
+            }
+        }
+    }
+
+    // 8. add lake to the lake map (using lake height for all pixels in the lake area)
+    for &i in flooded.iter().chain(&shoreline) {
+        lake_map[i] = level;
+    }
+
+    // 9. bounding circle, other lakes keep their distance from it
+    let point = |i: usize| ((i % width) as f32, (i / width) as f32);
+    let count = flooded.len() as f32;
+    let (sum_x, sum_y) = flooded
+        .iter()
+        .map(|&i| point(i))
+        .fold((0.0, 0.0), |(sum_x, sum_y), (x, y)| (sum_x + x, sum_y + y));
+    let center = (sum_x / count, sum_y / count);
+    let radius = flooded
+        .iter()
+        .map(|&i| point(i))
+        .map(|(x, y)| (x - center.0).hypot(y - center.1))
+        .fold(0.0, f32::max);
+    Lake {
+        level,
+        cells: flooded,
+        center,
+        radius,
+        reshaped,
+    }
+}
+
+// ground the lake `cells` close in on every side, which are the cells of the box around the
+// lake that can't be reached from its edge without crossing the lake
+fn lake_islands(cells: &[usize], width: usize, height: usize) -> HashSet<usize> {
+    if cells.is_empty() {
+        return HashSet::new();
+    }
+    let lake: HashSet<usize> = cells.iter().copied().collect();
+    let (mut x0, mut y0, mut x1, mut y1) = (usize::MAX, usize::MAX, 0, 0);
+    for &i in cells {
+        let (x, y) = (i % width, i / width);
+        (x0, y0) = (x0.min(x), y0.min(y));
+        (x1, y1) = (x1.max(x), y1.max(y));
+    }
+    let inside = |i: usize| {
+        let (x, y) = (i % width, i / width);
+        x >= x0 && x <= x1 && y >= y0 && y <= y1
+    };
+    let box_cells = (y0..=y1).flat_map(|y| (x0..=x1).map(move |x| y * width + x));
+    let mut stack: Vec<usize> = box_cells
+        .clone()
+        .filter(|&i| {
+            let (x, y) = (i % width, i / width);
+            (x == x0 || x == x1 || y == y0 || y == y1) && !lake.contains(&i)
+        })
+        .collect();
+    let mut outside: HashSet<usize> = stack.iter().copied().collect();
+    while let Some(i) = stack.pop() {
+        for n in neighbors(i, width, height) {
+            if inside(n) && !lake.contains(&n) && outside.insert(n) {
+                stack.push(n);
+            }
+        }
+    }
+    box_cells
+        .filter(|i| !lake.contains(i) && !outside.contains(i))
+        .collect()
+}
+
+// the lake cells after a morphological opening and closing with a disk of `radius` cells,
+// which takes off spits and single cells and fills holes and notches in the shoreline. no
+// `blocked` cell becomes lake, and of what is left only the part holding the lowest cell is
+// kept. the cells stay as they are if nothing is left.
+fn smooth_shoreline(
+    cells: &[usize],
+    data: &[f32],
+    width: usize,
+    height: usize,
+    radius: u32,
+    blocked: impl Fn(usize) -> bool,
+) -> Vec<usize> {
+    let r = radius as i64;
+    let disk: Vec<(i64, i64)> = (-r..=r)
+        .flat_map(|dy| (-r..=r).map(move |dx| (dx, dy)))
+        .filter(|&(dx, dy)| dx * dx + dy * dy <= r * r)
+        .collect();
+    // box around the lake with room for the closing on every side
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+    for &i in cells {
+        let (x, y) = (i % width, i / width);
+        (min_x, min_y) = (min_x.min(x), min_y.min(y));
+        (max_x, max_y) = (max_x.max(x), max_y.max(y));
+    }
+    let (x0, y0) = (
+        min_x.saturating_sub(radius as usize),
+        min_y.saturating_sub(radius as usize),
+    );
+    let x1 = (max_x + radius as usize).min(width - 1);
+    let y1 = (max_y + radius as usize).min(height - 1);
+    let (bw, bh) = ((x1 - x0 + 1) as i64, (y1 - y0 + 1) as i64);
+    let global = |p: usize| (y0 + p / bw as usize) * width + x0 + p % bw as usize;
+
+    let mut mask = vec![false; (bw * bh) as usize];
+    for &i in cells {
+        mask[(i / width - y0) * bw as usize + i % width - x0] = true;
+    }
+    // erosion keeps the cells the whole disk around fits in, dilation those it touches
+    let morph = |mask: &[bool], erode: bool| -> Vec<bool> {
+        (0..mask.len())
+            .map(|p| {
+                let (x, y) = (p as i64 % bw, p as i64 / bw);
+                let mut hits = disk.iter().map(|&(dx, dy)| {
+                    let (nx, ny) = (x + dx, y + dy);
+                    nx >= 0 && ny >= 0 && nx < bw && ny < bh && mask[(ny * bw + nx) as usize]
+                });
+                if erode {
+                    hits.all(|hit| hit)
+                } else {
+                    hits.any(|hit| hit)
+                }
+            })
+            .collect()
+    };
+    // a lake narrower than the disk everywhere would vanish, it is only closed then
+    let opened = morph(&morph(&mask, true), false);
+    let opened = if opened.contains(&true) { opened } else { mask };
+    let closed = morph(&morph(&opened, false), true);
+    let open: Vec<bool> = (0..closed.len())
+        .map(|p| closed[p] && !blocked(global(p)))
+        .collect();
+
+    let Some(start) = (0..open.len())
+        .filter(|&p| open[p])
+        .min_by(|&a, &b| data[global(a)].total_cmp(&data[global(b)]))
+    else {
+        return cells.to_vec();
+    };
+    let mut seen = HashSet::from([global(start)]);
+    let mut stack = vec![global(start)];
+    let mut shape = Vec::new();
+    while let Some(i) = stack.pop() {
+        shape.push(i);
+        for n in neighbors(i, width, height) {
+            let (x, y) = (n % width, n / width);
+            if x < x0 || y < y0 || x > x1 || y > y1 {
+                continue;
+            }
+            if open[(y - y0) * bw as usize + x - x0] && seen.insert(n) {
+                stack.push(n);
+            }
+        }
+    }
+    shape
+}
+
+// water level for the smoothed `shape` of a lake, the mean height of the ground around it
+// but never above the old `level`. the terrain is then fitted to it: ground the lake took in
+// is cut below the water, cells it gave up are filled to the shore and the banks within
+// `shore_smoothing` cells ease down into the water. returns the new level.
+fn fit_shoreline(
+    context: &WaterContext,
+    heightmap: &mut Heightmap,
+    lake_map: &[f32],
+    flooded: &[usize],
+    shape: &[usize],
+    level: f32,
+) -> f32 {
+    let WaterContext {
+        water_config,
+        ocean,
+        elevation_range,
+        ..
+    } = *context;
+    let feather = water_config.shore_smoothing;
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let data = heightmap.data_mut();
+    let inside: HashSet<usize> = shape.iter().copied().collect();
+    let dry = |i: usize| !inside.contains(&i) && !ocean[i] && lake_map[i] == 0.0;
+
+    // rings of dry ground around the shape, the first one is the shore
+    let mut rings: Vec<Vec<usize>> = Vec::new();
+    let mut seen = inside.clone();
+    let mut front = shape.to_vec();
+    for _ in 0..feather.max(1) {
+        let ring: Vec<usize> = front
+            .iter()
+            .flat_map(|&i| neighbors(i, width, height))
+            .filter(|&n| dry(n) && seen.insert(n))
+            .collect();
+        front = ring.clone();
+        rings.push(ring);
+    }
+
+    let lowest = shape.iter().map(|&i| data[i]).fold(f32::INFINITY, f32::min);
+    let shore = &rings[0];
+    let mean = shore.iter().map(|&i| data[i]).sum::<f32>() / shore.len().max(1) as f32;
+    let level = if mean > lowest {
+        mean.min(level)
+    } else {
+        level
+    };
+
+    let cut = SHORE_CUT_DEPTH / elevation_range.max(1e-6);
+    for &i in shape {
+        if data[i] >= level {
+            data[i] = level - cut;
+        }
+    }
+    for &i in flooded.iter().filter(|&&i| dry(i)) {
+        data[i] = data[i].max(level);
+    }
+    for (d, ring) in rings.iter().enumerate() {
+        let keep = (d + 1) as f32 / (feather + 1) as f32;
+        for &i in ring {
+            data[i] = level + (data[i] - level).max(0.0) * keep;
+        }
+    }
+    level
+}
+
+// way out of a lake over the lowest pass around it, found by flooding outwards from the
+// shoreline. the path runs from the shoreline over the pass to the first cell beyond it,
+// which is lower than the pass, in the sea, in another lake or on the map edge. none if
+// there is no pass within `MAX_OUTLET_SEARCH` cells.
+fn lake_outlet(
+    lake: &Lake,
+    heightmap: &Heightmap,
+    ocean: &[bool],
+    lake_map: &[f32],
+) -> Option<Vec<usize>> {
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let data = heightmap.data();
+    let mut open = BinaryHeap::new();
+    // cell the flood came from, the shoreline cells point to themselves
+    let mut parent = HashMap::new();
+    for &i in &lake.cells {
+        for n in neighbors(i, width, height) {
+            // the shoreline is the part of the lake above the water
+            if lake_map[n] == lake.level && data[n] >= lake.level && !parent.contains_key(&n) {
+                parent.insert(n, n);
+                open.push(Shore {
+                    height: data[n],
+                    index: n,
+                });
+            }
+        }
+    }
+    let mut pass = f32::NEG_INFINITY;
+    while let Some(Shore { height: h, index }) = open.pop() {
+        if parent.len() > MAX_OUTLET_SEARCH {
+            return None;
+        }
+        let (x, y) = (index % width, index / width);
+        let beyond = h < pass
+            || ocean[index]
+            || (lake_map[index] > 0.0 && lake_map[index] != lake.level)
+            || x == 0
+            || y == 0
+            || x + 1 == width
+            || y + 1 == height;
+        if beyond {
+            let mut path = vec![index];
+            while parent[&path[path.len() - 1]] != path[path.len() - 1] {
+                path.push(parent[&path[path.len() - 1]]);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        pass = pass.max(h);
+        for n in neighbors(index, width, height) {
+            if lake_map[n] != lake.level && !parent.contains_key(&n) {
+                parent.insert(n, index);
+                open.push(Shore {
+                    height: data[n],
+                    index: n,
+                });
+            }
+        }
+    }
+    None
+}
+
+// heights the random river sources are picked from, the band of the water config cut to
+// the land and up to its highest cell. a band with too few cells is dropped for all of the
+// land, the second value is the number of cells it had then.
+fn source_band(
+    water_config: &WaterConfig,
+    heightmap: &Heightmap,
+    ocean: &[bool],
+    sea_level: f32,
+) -> ((f32, f32), Option<usize>) {
+    // land cells between the heights and the highest of them
+    let land = |low: f32, high: f32| {
+        heightmap
+            .data()
+            .par_iter()
+            .zip(ocean)
+            .filter(|&(&h, &sea)| !sea && h >= low && h <= high)
+            .map(|(&h, _)| (1, h))
+            .reduce(|| (0, low), |a, b| (a.0 + b.0, a.1.max(b.1)))
+    };
+    let low = water_config.river_source_min_elevation.max(sea_level);
+    let (cells, top) = land(low, water_config.river_source_max_elevation);
+    if cells >= MIN_SOURCE_BAND_CELLS {
+        return ((low, top), None);
+    }
+    ((sea_level, land(sea_level, f32::INFINITY).1), Some(cells))
+}
+
+// random land cell in the `band` that isn't water yet and keeps `river_source_spacing` away
+// from the other `sources`. a cell is taken with a chance growing from a tenth at the bottom
+// of the band to certain at the top, so rivers rise in the mountains more often.
+fn pick_river_source(
+    context: &WaterContext,
+    heightmap: &Heightmap,
+    band: (f32, f32),
+    lake_map: &[f32],
+    river_owners: &[u32],
+    sources: &[usize],
+    rng: &mut StdRng,
+) -> Option<usize> {
+    let WaterContext {
+        map_config,
+        water_config,
+        ocean,
+        ..
+    } = *context;
+    let spacing = water_config.river_source_spacing.max(0.0) / map_config.cell_size_m as f32;
+    let data = heightmap.data();
+    let width = heightmap.width() as usize;
+    let (low, high) = band;
+    let weight = |h: f32| {
+        if high > low {
+            0.1 + 0.9 * (h - low) / (high - low)
+        } else {
+            1.0
+        }
+    };
+    let distance = |a: usize, b: usize| {
+        let (dx, dy) = (
+            (a % width).abs_diff(b % width),
+            (a / width).abs_diff(b / width),
+        );
+        (dx as f32).hypot(dy as f32)
+    };
+    (0..SOURCE_TRIES).find_map(|_| {
+        let i = rng.gen_range(0..data.len());
+        let h = data[i];
+        let free =
+            h >= low && h <= high && !ocean[i] && lake_map[i] == 0.0 && river_owners[i] == NO_RIVER;
+        let picked = free
+            && rng.gen_range(0.0..1.0) < weight(h)
+            && sources.iter().all(|&s| distance(s, i) >= spacing);
+        picked.then_some(i)
+    })
+}
+
+// follows the terrain downhill from `source` and returns the cells passed with the flow
+// gathered up to each of them. the heading keeps some of its direction from step to step and
+// otherwise turns towards the downhill direction, swung left and right by a slowly changing
+// angle so the river meanders with bends about `meander_length` apart. a step never climbs
+// more than `river_uphill_tolerance` above the lowest point so far, the carving cuts through
+// that much later on. the river ends at the sea, a lake, another river or the map edge, one
+// stuck in a pit fills it into a pond if it is deep enough and ends there either way. the
+// way down is found on the `routing` of the context if it has one.
+fn trace_river(
+    context: &WaterContext,
+    heightmap: &mut Heightmap,
+    balance: &WaterBalance,
+    lake_map: &mut [f32],
+    river_owners: &[u32],
+    rng: &mut StdRng,
+    (source, flow): (usize, f32),
+) -> Vec<(usize, f32)> {
+    let WaterContext {
+        map_config,
+        water_config,
+        ocean,
+        elevation_range,
+        routing,
+    } = *context;
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let inertia = (water_config.river_momentum / 100.0).clamp(0.0, 0.95);
+    let variation = water_config.river_direction_variation.max(0.0).to_radians();
+    let bend_length = (water_config.meander_length / map_config.cell_size_m as f32).max(1.0);
+    let tolerance = water_config.river_uphill_tolerance.max(0.0) / elevation_range.max(1e-6);
+    let wander = Perlin::new().set_seed(rng.r#gen());
+    let surface = routing.unwrap_or(heightmap);
+
+    let (mut x, mut y) = ((source % width) as f32, (source / width) as f32);
+    let mut heading = (0.0f32, 0.0f32);
+    let mut path = vec![(source, flow)];
+    // lowest height so far and where on the path it is
+    let mut lowest = (surface.data()[source], 0);
+    let mut in_pit = false;
+    let cell_at = |x: f32, y: f32| {
+        let (x, y) = (x.round(), y.round());
+        let inside = x >= 0.0 && y >= 0.0 && x < width as f32 && y < height as f32;
+        inside.then(|| y as usize * width + x as usize)
+    };
+    // every step enters a new cell at most once per cell of map size, so a river that runs
+    // longer than this is going in circles
+    for step in 0..4 * (width + height) {
+        let gx = surface.sample(x + 1.0, y) - surface.sample(x - 1.0, y);
+        let gy = surface.sample(x, y + 1.0) - surface.sample(x, y - 1.0);
+        let slope = gx.hypot(gy);
+        let downhill = if slope > 0.0 {
+            (-gx / slope, -gy / slope)
+        } else {
+            heading
+        };
+        // the offset keeps the noise off its lattice, where it is always zero
+        let swing = wander.get([step as f64 / bend_length as f64, 0.5]) as f32 * variation;
+        let (sin, cos) = swing.sin_cos();
+        let bend = (
+            downhill.0 * cos - downhill.1 * sin,
+            downhill.0 * sin + downhill.1 * cos,
+        );
+        let (mut dx, mut dy) = (
+            heading.0 * inertia + bend.0 * (1.0 - inertia),
+            heading.1 * inertia + bend.1 * (1.0 - inertia),
+        );
+        let length = dx.hypot(dy);
+        if length > 1e-6 {
+            (dx, dy) = (dx / length, dy / length);
+        } else {
+            // flat ground and no heading yet, any direction will do
+            let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+            (dx, dy) = (angle.cos(), angle.sin());
+        }
+        let climbs = |(dx, dy): (f32, f32)| {
+            cell_at(x + dx, y + dy).is_some_and(|cell| surface.data()[cell] > lowest.0 + tolerance)
+        };
+        // a bend that would run uphill gives way to the straight way down, and if even that
+        // climbs the river is in a pit
+        if climbs((dx, dy)) {
+            (dx, dy) = downhill;
+        }
+        if climbs((dx, dy)) {
+            in_pit = true;
+            break;
+        }
+        heading = (dx, dy);
+        x += heading.0;
+        y += heading.1;
+
+        let Some(cell) = cell_at(x, y) else {
+            break;
+        };
+        let (last, flow) = path[path.len() - 1];
+        if cell == last {
+            continue;
+        }
+        path.push((cell, flow + 1.0));
+
+        if ocean[cell] || lake_map[cell] > 0.0 || river_owners[cell] != NO_RIVER {
+            break;
+        }
+        let h = surface.data()[cell];
+        if h < lowest.0 {
+            lowest = (h, path.len() - 1);
+        } else if path.len() - 1 - lowest.1 > MAX_STALL_STEPS {
+            in_pit = true;
+            break;
+        }
+    }
+    if in_pit {
+        // back to the bottom of the pit
+        path.truncate(lowest.1 + 1);
+        let pit = path[lowest.1].0;
+        // a pit too shallow for a pond just ends the river
+        let site = LakeSite {
+            x: (pit % width) as f32,
+            y: (pit / width) as f32,
+            others: &[],
+            spacing: 0.0,
+        };
+        let _ = generate_lake_at(context, heightmap, balance, lake_map, &site, rng);
+    }
+    path
+}
+
+// narrowest and widest a river gets, in cells
+fn river_width_limits(map_config: &MapConfig, water_config: &WaterConfig) -> (f32, f32) {
+    let cell_size = map_config.cell_size_m as f32;
+    let max_width = (water_config.river_width / cell_size).max(1.0);
+    let min_width = (water_config.river_min_width / cell_size).clamp(1.0, max_width);
+    (min_width, max_width)
+}
+
+// marks the cells along a traced river and next to it as belonging to it, so a river traced
+// later stops where it runs into this one even when it crosses it diagonally
+fn mark_river(owners: &mut [u32], path: &[(usize, f32)], river: u32, width: usize) {
+    let height = owners.len() / width;
+    for &(cell, _) in path {
+        for i in std::iter::once(cell).chain(neighbors(cell, width, height)) {
+            if owners[i] == NO_RIVER {
+                owners[i] = river;
+            }
+        }
+    }
+}
+
+// depth of a river channel at a distance from its middle as a share of the full depth, `d`
+// runs from 0 in the middle to 1 at the edge of the channel
+fn channel_profile(profile: RiverProfile, d: f32) -> f32 {
+    match profile {
+        RiverProfile::Parabolic => 1.0 - d * d,
+        // flat bed over the inner 60 percent of the width
+        RiverProfile::Trapezoid => ((1.0 - d) / 0.4).min(1.0),
+    }
+}
+
+// carves a river into the heightmap and stamps its water depth into the river map. the path
+// holds the cells from the source down with the width of the river there in cells, which is
+// at least `river_min_width`. the water surface never rises downstream, so bumps the river
+// crossed are cut through. next to the channel the banks ease from the water surface back
+// into the terrain over `river_spread` percent of the width. where the path runs on into the
+// sea the channel gets shallower until it meets the sea floor at the end of the path.
+fn carve_river(
+    context: &WaterContext,
+    heightmap: &mut Heightmap,
+    lake_map: &[f32],
+    river_depths: &mut [f32],
+    river_surface: &mut [f32],
+    river_widths: &mut [f32],
+    path: &[(usize, f32)],
+) {
+    let WaterContext {
+        map_config,
+        water_config,
+        ocean,
+        elevation_range,
+        ..
+    } = *context;
+    let width = heightmap.width() as i64;
+    let height = heightmap.height() as i64;
+    let (min_width, _) = river_width_limits(map_config, water_config);
+    let depth = water_config.river_depth.max(0.0) / elevation_range.max(1e-6);
+    let spread = water_config.river_spread.max(0.0) / 100.0;
+
+    let data = heightmap.data_mut();
+    let surfaces: Vec<f32> = path
+        .iter()
+        .scan(f32::INFINITY, |surface, &(cell, _)| {
+            *surface = surface.min(data[cell]);
+            Some(*surface)
+        })
+        .collect();
+    let offshore = path
+        .iter()
+        .rev()
+        .take_while(|&&(cell, _)| ocean[cell])
+        .count();
+    let coast = path.len() - offshore;
+    for (k, (&(cell, river_width), &surface)) in path.iter().zip(&surfaces).enumerate() {
+        let fade = 1.0 - (k + 1).saturating_sub(coast) as f32 / (offshore + 1) as f32;
+        let river_width = river_width.max(min_width);
+        let radius = river_width * 0.5 + 0.5;
+        let bank = river_width * spread;
+        let (cx, cy) = ((cell as i64 % width), (cell as i64 / width));
+        let reach = (radius + bank).ceil() as i64;
+        for y in (cy - reach).max(0)..=(cy + reach).min(height - 1) {
+            for x in (cx - reach).max(0)..=(cx + reach).min(width - 1) {
+                let i = (y * width + x) as usize;
+                // lakes already hold water there, so does the sea, which only gets the channel
+                if lake_map[i] > 0.0 {
+                    continue;
+                }
+                let in_sea = ocean[i] && river_depths[i] == 0.0;
+                let distance = ((x - cx) as f32).hypot((y - cy) as f32);
+                if distance < radius {
+                    let profile = channel_profile(water_config.river_profile, distance / radius);
+                    let channel = depth * profile * fade;
+                    data[i] = data[i].min(surface - channel);
+                    if in_sea {
+                        continue;
+                    }
+                    // ground that was already lower than the water doesn't make the river
+                    // deeper, and a river cell always holds some water even without any depth
+                    let water = (surface - data[i]).min(channel).max(f32::MIN_POSITIVE);
+                    river_depths[i] = river_depths[i].max(water);
+                    river_surface[i] = river_surface[i].max(surface);
+                    river_widths[i] = river_widths[i].max(river_width);
+                } else if distance < radius + bank && !in_sea {
+                    let t = (distance - radius) / bank;
+                    let t = t * t * (3.0 - 2.0 * t);
+                    data[i] = data[i].min(surface + (data[i] - surface) * t);
+                }
+            }
+        }
+    }
+    let depths = &*river_depths;
+    shape_valley(
+        context, heightmap, lake_map, depths, path, &surfaces, min_width,
+    );
+}
+
+// lowers the ground along a carved river into a valley `valley_width_mult` times as wide as
+// the river, which is at least `min_width` like in carve_river. the valley is narrow at the
+// source and grows to its full width over `VALLEY_TAPER_CELLS`. its walls ease from the
+// water surface in `surfaces` back into the terrain, so it never reaches below the river and
+// leaves lakes, the sea and other rivers alone.
+fn shape_valley(
+    context: &WaterContext,
+    heightmap: &mut Heightmap,
+    lake_map: &[f32],
+    river_depths: &[f32],
+    path: &[(usize, f32)],
+    surfaces: &[f32],
+    min_width: f32,
+) {
+    let WaterContext {
+        water_config,
+        ocean,
+        ..
+    } = *context;
+    let mult = water_config.valley_width_mult;
+    if mult <= 1.0 {
+        return;
+    }
+    let width = heightmap.width() as i64;
+    let height = heightmap.height() as i64;
+    let profile = water_config.valley_profile.max(0.05);
+    // lowest height every cell gets from any point of the river, so the valley isn't dug
+    // again for every step along it
+    let mut targets: HashMap<usize, f32> = HashMap::new();
+    let data = heightmap.data();
+    for (k, (&(cell, river_width), &surface)) in path.iter().zip(surfaces).enumerate() {
+        let river_width = river_width.max(min_width);
+        let radius = river_width * 0.5 + 0.5;
+        let taper = ((k + 1) as f32 / VALLEY_TAPER_CELLS).min(1.0);
+        let valley = radius + (river_width * 0.5 * (mult - 1.0)) * taper;
+        if valley <= radius {
+            continue;
+        }
+        let (cx, cy) = ((cell as i64 % width), (cell as i64 / width));
+        let reach = valley.ceil() as i64;
+        for y in (cy - reach).max(0)..=(cy + reach).min(height - 1) {
+            for x in (cx - reach).max(0)..=(cx + reach).min(width - 1) {
+                let i = (y * width + x) as usize;
+                let distance = ((x - cx) as f32).hypot((y - cy) as f32);
+                let water = ocean[i] || lake_map[i] > 0.0 || river_depths[i] > 0.0;
+                if water || distance < radius || distance >= valley || data[i] <= surface {
+                    continue;
+                }
+                let t = ((distance - radius) / (valley - radius)).powf(profile);
+                let target = surface + (data[i] - surface) * t;
+                let entry = targets.entry(i).or_insert(f32::INFINITY);
+                *entry = entry.min(target);
+            }
+        }
+    }
+    let data = heightmap.data_mut();
+    for (i, target) in targets {
+        data[i] = data[i].min(target);
+    }
+}
+
+// cells a canal runs through from its first point to its last, each one next to the one
+// before. the points are joined by straight segments or by a catmull-rom curve through them.
+pub fn canal_path(canal: &Canal, width: u32, height: u32) -> Vec<usize> {
+    let points: Vec<(f32, f32)> = canal
+        .points
+        .iter()
+        .map(|&(x, y)| (x.min(width - 1) as f32, y.min(height - 1) as f32))
+        .collect();
+    let mut cells: Vec<usize> = Vec::new();
+    let mut push = |(x, y): (f32, f32)| {
+        let x = (x.round() as i64).clamp(0, width as i64 - 1) as usize;
+        let y = (y.round() as i64).clamp(0, height as i64 - 1) as usize;
+        let cell = y * width as usize + x;
+        if cells.last() != Some(&cell) {
+            cells.push(cell);
+        }
+    };
+    for k in 0..points.len().saturating_sub(1) {
+        let (p1, p2) = (points[k], points[k + 1]);
+        // the ends repeat their point, so the curve starts and stops on them
+        let p0 = if k > 0 { points[k - 1] } else { p1 };
+        let p3 = points.get(k + 2).copied().unwrap_or(p2);
+        let steps = ((p2.0 - p1.0).abs().max((p2.1 - p1.1).abs()) * 2.0)
+            .ceil()
+            .max(1.0);
+        for step in 0..steps as usize {
+            let t = step as f32 / steps;
+            if !canal.smooth {
+                push((p1.0 + (p2.0 - p1.0) * t, p1.1 + (p2.1 - p1.1) * t));
+                continue;
+            }
+            let (t2, t3) = (t * t, t * t * t);
+            let curve = |a: f32, b: f32, c: f32, d: f32| {
+                0.5 * (2.0 * b
+                    + (c - a) * t
+                    + (2.0 * a - 5.0 * b + 4.0 * c - d) * t2
+                    + (3.0 * b - a - 3.0 * c + d) * t3)
+            };
+            push((curve(p0.0, p1.0, p2.0, p3.0), curve(p0.1, p1.1, p2.1, p3.1)));
+        }
+    }
+    if let Some(&last) = points.last() {
+        push(last);
+    }
+    // a curve may step diagonally between two cells, fill the corner so the water connects
+    let mut path = Vec::with_capacity(cells.len());
+    for (k, &cell) in cells.iter().enumerate() {
+        if let Some(&before) = k.checked_sub(1).map(|k| &cells[k]) {
+            let (bx, by) = (before % width as usize, before / width as usize);
+            let (x, y) = (cell % width as usize, cell / width as usize);
+            if bx != x && by != y {
+                path.push(by * width as usize + x);
+            }
+        }
+        path.push(cell);
+    }
+    path
+}
+
+// digs a canal like a river of the canal's own width and depth, without a valley around it.
+// the water runs towards the lower of its two ends. returns the cells it runs through from
+// there with its width in cells, empty for less than 2 points.
+pub fn dig_canal(
+    context: &WaterContext,
+    canal: &Canal,
+    heightmap: &mut Heightmap,
+    lake_map: &[f32],
+    river_depths: &mut [f32],
+    river_surface: &mut [f32],
+    river_widths: &mut [f32],
+) -> Vec<(usize, f32)> {
+    if canal.points.len() < 2 {
+        return Vec::new();
+    }
+    let config = WaterConfig {
+        river_width: canal.width,
+        river_min_width: canal.width,
+        river_depth: canal.depth,
+        valley_width_mult: 1.0,
+        ..context.water_config.clone()
+    };
+    let (river_width, _) = river_width_limits(context.map_config, &config);
+    let mut path: Vec<(usize, f32)> = canal_path(canal, heightmap.width(), heightmap.height())
+        .into_iter()
+        .map(|cell| (cell, river_width))
+        .collect();
+    let ground = |k: usize| heightmap.data()[path[k].0];
+    if ground(0) < ground(path.len() - 1) {
+        path.reverse();
+    }
+    let context = WaterContext {
+        water_config: &config,
+        ..*context
+    };
+    carve_river(
+        &context,
+        heightmap,
+        lake_map,
+        river_depths,
+        river_surface,
+        river_widths,
+        &path,
+    );
+    path
+}
+
+// paths to carve for a river, which is just the river itself unless it ends in the sea. then
+// its last `estuary_length` widens towards `estuary_max_width` and runs on out over the sea
+// floor. with more than one branch the mouth splits into distributaries that fan out from
+// where the widening starts, with sandbars just below the sea level between them.
+fn estuary_paths(
+    context: &WaterContext,
+    heightmap: &mut Heightmap,
+    path: &[(usize, f32)],
+) -> Vec<Vec<(usize, f32)>> {
+    let WaterContext {
+        map_config,
+        water_config,
+        ocean,
+        elevation_range,
+        ..
+    } = *context;
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let sea_level = map_config.sea_level as f32;
+    let cell_size = map_config.cell_size_m as f32;
+    let length = water_config.estuary_length / cell_size;
+    let at_sea = path.last().is_some_and(|&(cell, _)| ocean[cell]);
+    if !at_sea || length < 1.0 || path.len() < 2 {
+        return vec![path.to_vec()];
+    }
+    let point = |cell: usize| ((cell % width) as f32, (cell / width) as f32);
+
+    // where the widening starts, `length` up the river from the mouth
+    let mut start = path.len() - 1;
+    let mut along = 0.0;
+    while start > 0 && along < length {
+        let (a, b) = (point(path[start].0), point(path[start - 1].0));
+        along += (a.0 - b.0).hypot(a.1 - b.1);
+        start -= 1;
+    }
+    let (mouth_x, mouth_y) = point(path[path.len() - 1].0);
+    let (back_x, back_y) = point(path[path.len().saturating_sub(ESTUARY_HEADING_CELLS)].0);
+    let (dx, dy) = (mouth_x - back_x, mouth_y - back_y);
+    let norm = dx.hypot(dy).max(1e-6);
+    let (dx, dy) = (dx / norm, dy / norm);
+
+    // middle line of the estuary with the widened width and how far down it each point is
+    let estuary = path.len() - start;
+    let max_width = water_config.estuary_max_width / cell_size;
+    let mut middle: Vec<(f32, f32, f32, f32)> = path[start..]
+        .iter()
+        .enumerate()
+        .map(|(i, &(cell, river_width))| {
+            let t = i as f32 / (estuary - 1).max(1) as f32;
+            let (x, y) = point(cell);
+            (
+                x,
+                y,
+                river_width + (max_width - river_width).max(0.0) * t * t,
+                t,
+            )
+        })
+        .collect();
+    let mouth_width = middle[middle.len() - 1].2;
+    for k in 1..=(length * 0.5).ceil() as usize {
+        let (x, y) = (mouth_x + dx * k as f32, mouth_y + dy * k as f32);
+        if x < 0.0 || y < 0.0 || x > (width - 1) as f32 || y > (height - 1) as f32 {
+            break;
+        }
+        middle.push((x, y, mouth_width, 1.0));
+    }
+
+    let cell_at = |x: f32, y: f32| y.round() as usize * width + x.round() as usize;
+    let branches = water_config.estuary_branches.clamp(1, 3) as usize;
+    if branches == 1 {
+        let mut path = path[..start].to_vec();
+        path.extend(middle.iter().map(|&(x, y, w, _)| (cell_at(x, y), w)));
+        path.dedup_by_key(|entry| entry.0);
+        return vec![path];
+    }
+
+    // lateral offset of a branch or sandbar at a point, the branches share the width
+    let (nx, ny) = (-dy, dx);
+    let offset = |&(x, y, w, t): &(f32, f32, f32, f32), lane: f32| {
+        let side = (lane - (branches - 1) as f32 * 0.5) * w / branches as f32 * t;
+        (x + nx * side, y + ny * side)
+    };
+    let bar = SANDBAR_DEPTH / elevation_range.max(1e-6);
+    let data = heightmap.data_mut();
+    for lane in 0..branches - 1 {
+        for p in middle.iter().filter(|p| p.3 >= 0.5) {
+            let (x, y) = offset(p, lane as f32 + 0.5);
+            let radius = (p.2 / branches as f32 * 0.5).max(1.0);
+            let reach = radius.ceil() as i64;
+            let (cx, cy) = (x.round() as i64, y.round() as i64);
+            for by in (cy - reach).max(0)..=(cy + reach).min(height as i64 - 1) {
+                for bx in (cx - reach).max(0)..=(cx + reach).min(width as i64 - 1) {
+                    let i = by as usize * width + bx as usize;
+                    if ((bx - cx) as f32).hypot((by - cy) as f32) < radius {
+                        data[i] = data[i].max(sea_level - bar);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut paths = vec![path[..=start].to_vec()];
+    for lane in 0..branches {
+        let mut branch: Vec<(usize, f32)> = middle
+            .iter()
+            .map(|p| {
+                let (x, y) = offset(p, lane as f32);
+                let x = x.clamp(0.0, (width - 1) as f32);
+                let y = y.clamp(0.0, (height - 1) as f32);
+                (cell_at(x, y), p.2 / branches as f32 * BRANCH_WIDTH_SHARE)
+            })
+            .collect();
+        branch.dedup_by_key(|entry| entry.0);
+        paths.push(branch);
+    }
+    paths
+}
+
+// copy of the heights with every depression filled up to its spill point (priority flood).
+// flats rise by `epsilon` per cell away from the outlet, so every cell has a lower neighbor
+// and water flows off the map or into an `outlets` cell, such as the sea. depressions below
+// the sea level that aren't outlets are filled like any other.
+fn fill_depressions(heightmap: &Heightmap, outlets: &[bool], epsilon: f32) -> Vec<f32> {
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let mut filled = heightmap.data().to_vec();
+    let mut done = vec![false; filled.len()];
+    let mut open = BinaryHeap::new();
+    for (i, &h) in filled.iter().enumerate() {
+        let (x, y) = (i % width, i / width);
+        if outlets[i] || x == 0 || y == 0 || x + 1 == width || y + 1 == height {
+            done[i] = true;
+            open.push(Shore {
+                height: h,
+                index: i,
+            });
+        }
+    }
+    while let Some(Shore { height: h, index }) = open.pop() {
+        for (n, _) in neighbors8(index, width, height) {
+            if !done[n] {
+                done[n] = true;
+                filled[n] = filled[n].max(h + epsilon);
+                open.push(Shore {
+                    height: filled[n],
+                    index: n,
+                });
+            }
+        }
+    }
+    filled
+}
+
+// river paths of the drainage network, each from its head down to the sea, a lake, the map
+// edge or the river it joins. the heights are routed with the steepest of the 8 directions
+// over the filled map, every land cell that drains more than `threshold` cells is a river.
+// the second value of a path entry is the width in cells, it grows from the narrowest to the
+// widest of `widths` with the log of the catchment, which already holds the water of every
+// tributary.
+fn drainage_paths(
+    water_config: &WaterConfig,
+    (min_width, max_width): (f32, f32),
+    heightmap: &Heightmap,
+    ocean: &[bool],
+    lake_map: &[f32],
+    epsilon: f32,
+) -> Vec<Vec<(usize, f32)>> {
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let filled = fill_depressions(heightmap, ocean, epsilon);
+
+    let downstream: Vec<Option<usize>> = (0..filled.len())
+        .map(|i| {
+            if ocean[i] {
+                return None;
+            }
+            neighbors8(i, width, height)
+                .map(|(n, distance)| (n, (filled[i] - filled[n]) / distance))
+                .filter(|&(_, drop)| drop > 0.0)
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(n, _)| n)
+        })
+        .collect();
+
+    // every cell drains into a lower one, so going from high to low visits a cell only after
+    // everything upstream of it
+    let mut order: Vec<usize> = (0..filled.len()).collect();
+    order.sort_unstable_by(|&a, &b| filled[b].total_cmp(&filled[a]));
+    let mut catchment = vec![1.0f32; filled.len()];
+    for &i in &order {
+        if let Some(n) = downstream[i] {
+            catchment[n] += catchment[i];
+        }
+    }
+
+    let threshold = water_config.flow_threshold.max(1.0);
+    let is_river = |i: usize| catchment[i] >= threshold && !ocean[i] && lake_map[i] == 0.0;
+    let mut has_source = vec![false; filled.len()];
+    for (i, down) in downstream.iter().enumerate() {
+        if let Some(n) = down.filter(|_| is_river(i)) {
+            has_source[n] = true;
+        }
+    }
 
-    // 5. apply heightmap modification (slightly lower the heightmap in the lake area)
+    let growth = LOG_WIDTH_GROWTH * water_config.river_speed.max(0.0) / 100.0;
+    let mut visited = vec![false; filled.len()];
+    let mut paths = Vec::new();
+    for head in (0..filled.len()).filter(|&i| is_river(i) && !has_source[i]) {
+        let mut path = Vec::new();
+        let mut cell = Some(head);
+        while let Some(i) = cell {
+            let river_width = min_width + (catchment[i] / threshold).ln() * growth;
+            path.push((i, river_width.min(max_width)));
+            // the junction with another river is the last cell of this one
+            if visited[i] || !is_river(i) {
+                break;
+            }
+            visited[i] = true;
+            cell = downstream[i];
+        }
+        paths.push(path);
+    }
+    paths
+}
 
-    // 6. expand lake area by 1 pixel in all directions (since height map precision is 1m/pixel, the actual intersection is somewhere between the pixel below and above the lake)
+// stretches where the water surface of a river falls more than `waterfall_drop` within one
+// or two cells. a run of such cells is a single waterfall placed in its middle, with the
+// whole drop from above the first steep cell to the last one.
+fn find_waterfalls(
+    water_config: &WaterConfig,
+    heightmap: &Heightmap,
+    path: &[(usize, f32)],
+    elevation_range: f32,
+) -> Vec<Waterfall> {
+    let width = heightmap.width() as usize;
+    let threshold = water_config.waterfall_drop.max(0.0) / elevation_range.max(1e-6);
+    let surfaces: Vec<f32> = path
+        .iter()
+        .scan(f32::INFINITY, |surface, &(cell, _)| {
+            *surface = surface.min(heightmap.data()[cell]);
+            Some(*surface)
+        })
+        .collect();
+    // where the fall the cell is part of starts
+    let lip = |i: usize| {
+        if i >= 1 && surfaces[i - 1] - surfaces[i] > threshold {
+            Some(i - 1)
+        } else if i >= 2 && surfaces[i - 2] - surfaces[i] > threshold {
+            Some(i - 2)
+        } else {
+            None
+        }
+    };
+    let mut waterfalls = Vec::new();
+    let mut i = 0;
+    while i < path.len() {
+        let Some(top) = lip(i) else {
+            i += 1;
+            continue;
+        };
+        let start = i;
+        while i + 1 < path.len() && lip(i + 1).is_some() {
+            i += 1;
+        }
+        let middle = path[(start + i) / 2].0;
+        waterfalls.push(Waterfall {
+            position: ((middle % width) as u32, (middle / width) as u32),
+            drop: (surfaces[top] - surfaces[i]) * elevation_range,
+        });
+        i += 1;
+    }
+    waterfalls
+}
 
-    // 7. add lake to the lake map (using lake height for aal pixels in the lake area)
-    
-    // 8. return true if lake was generated, false if not
+// how wet the ground is, from 0 to 1. the ground is soaked next to lakes and slow stretches
+// of rivers and dries out over `wetland_radius`, the coast does the same and so does ground
+// a lot of water drains through, each as far as its weight goes. the wettest of them counts,
+// and steep ground sheds part of it by the slope weight. flat lowland close to the sea that
+// barely drains stays damp anywhere. everything is scaled by `wetland_strength`.
+pub fn compute_wetness(
+    map_config: &MapConfig,
+    water_config: &WaterConfig,
+    heightmap: &Heightmap,
+    ocean: &[bool],
+    lake_map: &[f32],
+    river_depths: &[f32],
+    elevation_range: f32,
+) -> Vec<f32> {
+    let (width, height) = (heightmap.width(), heightmap.height());
+    let sea_level = map_config.sea_level as f32;
+    let slope = compute_slope_map(heightmap, map_config.cell_size_m, elevation_range);
+    let water: Vec<bool> = (0..heightmap.len())
+        .map(|i| lake_map[i] > 0.0 || (river_depths[i] > 0.0 && slope.data()[i] < SLOW_RIVER_SLOPE))
+        .collect();
+    let distance = distance_transform(&water, width, height);
+    let coast_distance = distance_transform(ocean, width, height);
+    let (area, _) = flow_accumulation(heightmap, ocean);
+    let radius = (water_config.wetland_radius / map_config.cell_size_m as f32).max(1.0);
+    let strength = (water_config.wetland_strength / 100.0).clamp(0.0, 1.0);
+    let weight = |percent: f32| (percent / 100.0).clamp(0.0, 1.0);
+    let water_weight = weight(water_config.wetness_water_weight);
+    let ocean_weight = weight(water_config.wetness_ocean_weight);
+    let flow_weight = weight(water_config.wetness_flow_weight);
+    let slope_weight = weight(water_config.wetness_slope_weight);
+    heightmap
+        .data()
+        .iter()
+        .enumerate()
+        .map(|(i, &h)| {
+            if ocean[i] {
+                return 0.0;
+            }
+            let shore = (1.0 - distance[i] / radius).max(0.0);
+            let coast = (1.0 - coast_distance[i] / radius).max(0.0);
+            let flow = (area[i].ln() / WET_CATCHMENT.ln()).clamp(0.0, 1.0);
+            let near = (shore * water_weight)
+                .max(coast * ocean_weight)
+                .max(flow * flow_weight);
+            let steep = (slope.data()[i] / DRY_SLOPE).min(1.0);
+            let held = near * (1.0 - steep * slope_weight);
+            let flat = (1.0 - slope.data()[i] / LOWLAND_SLOPE).max(0.0);
+            // inland depressions below the sea level count as the lowest land there is
+            let low = (1.0 - (h - sea_level) / LOWLAND_HEIGHT).clamp(0.0, 1.0);
+            held.max(flat * low * 0.5) * strength
+        })
+        .collect()
+}
 
-    
-    false
+// whether the last cell of a river is in the sea, a lake, another river or at the map edge
+fn ends_in_water(
+    path: &[(usize, f32)],
+    heightmap: &Heightmap,
+    ocean: &[bool],
+    lake_map: &[f32],
+    river_depths: &[f32],
+) -> bool {
+    let width = heightmap.width() as usize;
+    let height = heightmap.height() as usize;
+    let Some(&(end, _)) = path.last() else {
+        return false;
+    };
+    let (x, y) = (end % width, end / width);
+    ocean[end]
+        || lake_map[end] > 0.0
+        || river_depths[end] > 0.0
+        || x <= 1
+        || y <= 1
+        || x + 2 >= width
+        || y + 2 >= height
 }
 
+// bowl dug around `center` and filled up to `level`
+fn dig_pond(
+    heightmap: &mut Heightmap,
+    lake_map: &mut [f32],
+    center: usize,
+    radius: f32,
+    depth: f32,
+    level: f32,
+) {
+    let width = heightmap.width() as i64;
+    let height = heightmap.height() as i64;
+    let data = heightmap.data_mut();
+    let (cx, cy) = (center as i64 % width, center as i64 / width);
+    let reach = radius.ceil() as i64;
+    for y in (cy - reach).max(0)..=(cy + reach).min(height - 1) {
+        for x in (cx - reach).max(0)..=(cx + reach).min(width - 1) {
+            let d = ((x - cx) as f32).hypot((y - cy) as f32) / radius;
+            let i = (y * width + x) as usize;
+            if d >= 1.0 || lake_map[i] > 0.0 {
+                continue;
+            }
+            data[i] = data[i].min(level - depth * (1.0 - d * d));
+            if data[i] < level {
+                lake_map[i] = level;
+            }
+        }
+    }
+}
 
+// what became of a river
+#[derive(PartialEq)]
+enum Placed {
+    Carved,
+    // carved and given a pond at its dead end
+    Ponded,
+    // dropped because it didn't reach any water
+    Removed,
+}
+
+// carves a river. with `enforce_outlet` a river that doesn't end in water either gets a pond
+// dug at its end or is dropped, as set by `dead_end_remedy`.
+fn place_river(
+    context: &WaterContext,
+    heightmap: &mut Heightmap,
+    lake_map: &mut [f32],
+    river_depths: &mut [f32],
+    river_surface: &mut [f32],
+    river_widths: &mut [f32],
+    path: &[(usize, f32)],
+) -> Placed {
+    let WaterContext {
+        water_config,
+        ocean,
+        elevation_range,
+        ..
+    } = *context;
+    let dead_end = water_config.enforce_outlet
+        && !ends_in_water(path, heightmap, ocean, lake_map, river_depths);
+    if dead_end && water_config.dead_end_remedy == DeadEndRemedy::Remove {
+        return Placed::Removed;
+    }
+    // water surface where the river ends, before the channel is cut
+    let level = path
+        .iter()
+        .map(|&(cell, _)| heightmap.data()[cell])
+        .fold(f32::INFINITY, f32::min);
+    for branch in estuary_paths(context, heightmap, path) {
+        carve_river(
+            context,
+            heightmap,
+            lake_map,
+            river_depths,
+            river_surface,
+            river_widths,
+            &branch,
+        );
+    }
+    if !dead_end {
+        return Placed::Carved;
+    }
+    let &(end, river_width) = path.last().unwrap();
+    let depth = water_config.river_depth.max(water_config.min_depth) / elevation_range.max(1e-6);
+    let radius = (river_width * POND_RADIUS_PER_WIDTH).max(MIN_POND_RADIUS);
+    dig_pond(heightmap, lake_map, end, radius, depth, level);
+    Placed::Ponded
+}
+
+// cells and widths of a carved river, with the line it joins and the cell of the junction
+type CarvedLine = (Vec<(usize, f32)>, Option<(usize, usize)>);
 
 pub fn generate_water_map(
     map_config: &MapConfig,
     water_config: &WaterConfig,
-    heightmap: &[f32],
+    heightmap: &Heightmap,
     biome_map: &[u8],
+    elevation_range: f32,
     seed: u32,
-) -> (egui::ColorImage, ImageBuffer<Rgba<u8>, Vec<u8>>, Vec<f32>, Vec<f32>) {
-    let width = map_config.width;
-    let height = map_config.height;
-    let mut rng = StdRng::seed_from_u64(seed as u64);
+    progress: &Progress,
+) -> Option<WaterMap> {
+    let width = heightmap.width();
+    let height = heightmap.height();
 
     let mut lake_map = vec![0.0f32; (width * height) as usize];
-    let mut river_map = vec![0.0f32; (width * height) as usize];
-    let mut adjusted_height_map = heightmap.iter().map(|&h| h).collect::<Vec<f32>>();
+    let mut river_depths = vec![0.0f32; (width * height) as usize];
+    let mut river_surface = vec![0.0f32; (width * height) as usize];
+    let mut river_widths = vec![0.0f32; (width * height) as usize];
+    let mut adjusted_height_map = heightmap.clone();
+    // the sea as it was before any carving, channels cut below the sea level stay rivers
+    let ocean = compute_ocean_mask(
+        heightmap,
+        map_config.sea_level as f32,
+        map_config.ocean_seed,
+    );
+    let balance = WaterBalance::new(heightmap, &ocean, biome_map);
+    let mut context = WaterContext::new(map_config, water_config, &ocean, elevation_range);
 
-    let water_map_buf = std::sync::Mutex::new(&mut lake_map);
-    let river_map_buf = std::sync::Mutex::new(&mut river_map);
-    let adjusted_height_map_buf = std::sync::Mutex::new(&mut adjusted_height_map);
+    // lakes at random positions, more attempts are made while there are fewer than the
+    // minimum, up to a limit so a map without basins can't hang
+    let extra_attempts = water_config
+        .min_lake_n
+        .saturating_mul(EXTRA_ATTEMPTS_PER_LAKE);
+    let max_attempts = water_config.lake_attempts.saturating_add(extra_attempts);
+    let particles = match water_config.river_mode {
+        RiverMode::Particles => water_config.river_count,
+        RiverMode::Drainage => 0,
+    };
+    // a step per lake attempt and river, and one for carving the rivers in
+    let river_attempts = water_config.river_sources.len() + particles as usize;
+    progress.set_total(max_attempts as usize + river_attempts + 1);
+    let mut lakes = Vec::new();
+    let mut unplaced_lakes = Vec::new();
+    for (index, &(x, y)) in water_config.lake_seeds.iter().enumerate() {
+        if x >= width || y >= height {
+            continue;
+        }
+        let mut rng = stream_rng(seed, PLACED_LAKE_STREAM, index as u64);
+        let site = LakeSite {
+            x: x as f32 + 0.5,
+            y: y as f32 + 0.5,
+            others: &lakes,
+            spacing: 0.0,
+        };
+        let lake = generate_lake_at(
+            &context,
+            &mut adjusted_height_map,
+            &balance,
+            &mut lake_map,
+            &site,
+            &mut rng,
+        );
+        match lake {
+            Ok(lake) => lakes.push(lake),
+            Err(reason) => unplaced_lakes.push(((x, y), reason)),
+        }
+    }
+    let spacing = water_config.min_lake_spacing.max(0.0);
+    let (mut accepted, mut rejected) = (0, 0);
+    // the attempts of a batch are planned in parallel against the maps as they were before
+    // it and then placed in the order of the attempts. a plan that runs into a lake placed
+    // earlier in the same batch is dropped, so the result never depends on the threads.
+    let margin = water_config.shore_smoothing as f32 + 1.0;
+    let mut start = 0;
+    'attempts: while start < max_attempts {
+        if progress.is_cancelled() {
+            return None;
+        }
+        let batch: Vec<u32> = (start..max_attempts.min(start.saturating_add(LAKE_BATCH))).collect();
+        start = start.saturating_add(LAKE_BATCH);
+        let batch_size = batch.len();
+        let plans: Vec<(f32, f32, Result<LakePlan, NoLake>)> = batch
+            .par_iter()
+            .map(|&attempt| {
+                let mut rng = stream_rng(seed, LAKE_STREAM, attempt as u64);
+                let x = rng.gen_range(0.0..width as f32);
+                let y = rng.gen_range(0.0..height as f32);
+                if lakes.iter().any(|lake| lake.near(x, y, spacing)) {
+                    return (x, y, Err(NoLake::TooClose));
+                }
+                // the climate keeps this spot dry
+                let cell = (y as u32 * width + x as u32) as usize;
+                let chance = balance.lake_chance(water_config, cell);
+                if chance < 1.0 && rng.gen_range(0.0..1.0) >= chance {
+                    return (x, y, Err(NoLake::NoBasin));
+                }
+                let site = LakeSite {
+                    x,
+                    y,
+                    others: &lakes,
+                    spacing,
+                };
+                let plan = plan_lake(
+                    &context,
+                    &adjusted_height_map,
+                    &balance,
+                    &lake_map,
+                    &site,
+                    &mut rng,
+                );
+                (x, y, plan)
+            })
+            .collect();
 
-    // TODO: generate lakes
+        let before = lakes.len();
+        for (attempt, (x, y, plan)) in batch.into_iter().zip(plans) {
+            let count = lakes.len() as u32;
+            if count >= water_config.max_lake_n
+                || (attempt >= water_config.lake_attempts && count >= water_config.min_lake_n)
+            {
+                break 'attempts;
+            }
+            if lakes.iter().any(|lake| lake.near(x, y, spacing)) {
+                rejected += 1;
+                continue;
+            }
+            let plan = match plan {
+                Ok(plan) => plan,
+                Err(NoLake::TooClose) => {
+                    rejected += 1;
+                    continue;
+                }
+                Err(NoLake::NoBasin) => continue,
+            };
+            let (w, h) = (width as usize, height as usize);
+            let clear = plan.shape.iter().all(|&i| {
+                let (fx, fy) = ((i % w) as f32, (i / w) as f32);
+                std::iter::once(i)
+                    .chain(neighbors(i, w, h))
+                    .all(|n| lake_map[n] == 0.0)
+                    && !lakes[before..]
+                        .iter()
+                        .any(|lake| lake.near(fx, fy, spacing + margin))
+            });
+            if clear {
+                lakes.push(place_lake(
+                    &context,
+                    &mut adjusted_height_map,
+                    &mut lake_map,
+                    plan,
+                ));
+                accepted += 1;
+            } else {
+                // ran into a lake placed earlier in the batch
+                rejected += 1;
+            }
+        }
+        progress.add(batch_size);
+    }
+    if (lakes.len() as u32) < water_config.min_lake_n {
+        eprintln!(
+            "Only found room for {} of at least {} lakes",
+            lakes.len(),
+            water_config.min_lake_n
+        );
+    }
+    let shore_change = lakes
+        .iter()
+        .fold((0, 0, 0), |(added, removed, area), lake| {
+            let (a, r) = lake.reshaped;
+            (added + a, removed + r, area + lake.cells.len() - a + r)
+        });
 
-    // TODO: generate rivers
+    // pits in the terrain trap the rivers, the filled copy only guides them unless it is
+    // applied to the terrain as well. the lakes drain like the sea.
+    let epsilon = water_config.fill_epsilon.max(0.0) / elevation_range.max(1e-6);
+    let mut routing = None;
+    if water_config.fill_pits || water_config.apply_pit_fill {
+        let outlets: Vec<bool> = ocean
+            .iter()
+            .zip(&lake_map)
+            .map(|(&sea, &lake)| sea || lake > 0.0)
+            .collect();
+        let filled = fill_depressions(&adjusted_height_map, &outlets, epsilon);
+        let filled = Heightmap::from_vec(filled, width, height);
+        if water_config.apply_pit_fill {
+            adjusted_height_map = filled;
+        } else {
+            routing = Some(filled);
+        }
+    }
+    context.routing = routing.as_ref();
 
+    // drained lakes spill into a river at the lowest point of their shore. it carries more
+    // water the bigger the lake is and ends in the sea or the next lake down.
+    let sea_level = map_config.sea_level as f32;
+    let growth = WIDTH_GROWTH * water_config.river_speed.max(0.0) / 100.0;
+    let drainage = (water_config.base_drainage / 100.0).clamp(0.0, 1.0);
+    // rivers are traced first and carved once all of them are known. a river that runs into
+    // another one adds its flow to it from the junction on, so the river below widens.
+    let mut rivers: Vec<Vec<(usize, f32)>> = Vec::new();
+    // the lake and the pass an outlet river drains it over
+    let mut river_outlets: Vec<Option<LakeOutlet>> = Vec::new();
+    let mut river_owners = vec![NO_RIVER; (width * height) as usize];
+    for (index, lake) in lakes.iter().enumerate() {
+        let mut rng = stream_rng(seed, OUTLET_STREAM, index as u64);
+        if rng.gen_range(0.0..1.0) >= drainage {
+            continue;
+        }
+        let Some(mut channel) = lake_outlet(lake, &adjusted_height_map, &ocean, &lake_map) else {
+            continue;
+        };
+        // a shoreline cell on the map edge is already beyond the lake, there is no channel
+        if channel.len() < 2 {
+            continue;
+        }
+        let flow = lake.cells.len() as f32 * drainage * OUTLET_FLOW_PER_CELL;
+        let outlet = channel[0];
+        // a channel over the pass that runs into an earlier river joins it right there
+        let path: Vec<(usize, f32)> = match channel
+            .iter()
+            .position(|&cell| river_owners[cell] != NO_RIVER)
+        {
+            Some(joined) => channel[..=joined]
+                .iter()
+                .map(|&cell| (cell, flow))
+                .collect(),
+            None => {
+                let beyond = channel.pop().unwrap();
+                // the channel over the pass is cut down to the shoreline, from there on the
+                // river finds its own way
+                channel
+                    .into_iter()
+                    .map(|cell| (cell, flow))
+                    .chain(trace_river(
+                        &context,
+                        &mut adjusted_height_map,
+                        &balance,
+                        &mut lake_map,
+                        &river_owners,
+                        &mut rng,
+                        (beyond, flow),
+                    ))
+                    .collect()
+            }
+        };
+        mark_river(
+            &mut river_owners,
+            &path,
+            rivers.len() as u32,
+            width as usize,
+        );
+        rivers.push(path);
+        river_outlets.push(Some(LakeOutlet {
+            lake: lake.center,
+            outlet: (outlet as u32 % width, outlet as u32 / width),
+        }));
+    }
 
-    // placeholders
-    let preview = ImageBuffer::new(width, height);
-    let preview_image = egui::ColorImage::new(
-        [width as usize, height as usize],
-        egui::Color32::from_black_alpha(0),
-    );
+    // rivers from high ground down to the sea or a lake. they are traced one after the other
+    // so a later river can end where it meets an earlier one.
+    let (band, small_band) = source_band(water_config, &adjusted_height_map, &ocean, sea_level);
+    // only random rivers rise in the band
+    let small_source_band = small_band.filter(|_| particles > 0);
+    let mut sources = Vec::new();
+    // the sources placed by hand come first, in either mode
+    let placed: Vec<usize> = water_config
+        .river_sources
+        .iter()
+        .filter(|&&(x, y)| x < width && y < height)
+        .map(|&(x, y)| (y * width + x) as usize)
+        .collect();
+    for attempt in 0..placed.len() + particles as usize {
+        if progress.is_cancelled() {
+            return None;
+        }
+        progress.add(1);
+        let mut rng = stream_rng(seed, RIVER_STREAM, attempt as u64);
+        let source = match placed.get(attempt) {
+            Some(&cell) => {
+                let free = !ocean[cell] && lake_map[cell] == 0.0 && river_owners[cell] == NO_RIVER;
+                free.then_some(cell)
+            }
+            None => pick_river_source(
+                &context,
+                &adjusted_height_map,
+                band,
+                &lake_map,
+                &river_owners,
+                &sources,
+                &mut rng,
+            ),
+        };
+        let Some(source) = source else {
+            continue;
+        };
+        sources.push(source);
+        let path = trace_river(
+            &context,
+            &mut adjusted_height_map,
+            &balance,
+            &mut lake_map,
+            &river_owners,
+            &mut rng,
+            (source, balance.source_flow(water_config, source)),
+        );
+        if path.len() >= MIN_RIVER_LENGTH {
+            mark_river(
+                &mut river_owners,
+                &path,
+                rivers.len() as u32,
+                width as usize,
+            );
+            rivers.push(path);
+            river_outlets.push(None);
+        }
+    }
+
+    // a river only ever joins one traced before it, so going backwards hands every river
+    // the flow of all its tributaries before it is passed on further down. the tributary
+    // ends at the junction, below it there is only the one channel, which takes on the extra
+    // flow over a few cells instead of jumping to the new width.
+    let mut junctions = Vec::new();
+    // the river each one joins and the cell of the junction
+    let mut joins: Vec<Option<(usize, usize)>> = vec![None; rivers.len()];
+    for river in (0..rivers.len()).rev() {
+        let Some(&(end, flow)) = rivers[river].last() else {
+            continue;
+        };
+        let joined = river_owners[end];
+        if joined == NO_RIVER || joined as usize >= river {
+            continue;
+        }
+        let (end_x, end_y) = (end % width as usize, end / width as usize);
+        let distance = |&(cell, _): &(usize, f32)| {
+            (cell % width as usize).abs_diff(end_x) + (cell / width as usize).abs_diff(end_y)
+        };
+        let below = &mut rivers[joined as usize];
+        let junction = (0..below.len())
+            .min_by_key(|&i| distance(&below[i]))
+            .unwrap_or(0);
+        for (i, entry) in below[junction..].iter_mut().enumerate() {
+            let blend = ((i + 1) as f32 / JUNCTION_BLEND_CELLS as f32).min(1.0);
+            entry.1 += flow * blend;
+        }
+        let cell = below[junction].0;
+        junctions.push((cell as u32 % width, cell as u32 / width));
+        joins[river] = Some((joined as usize, cell));
+    }
+
+    if progress.is_cancelled() {
+        return None;
+    }
+    let (min_width, max_width) = river_width_limits(map_config, water_config);
+    let (mut ponded, mut removed) = (0, 0);
+    let mut outlets = Vec::new();
+    let mut waterfalls = Vec::new();
+    // paths of the rivers that were kept with the line they join and the junction cell, a
+    // river runs into one carved before it so that line is known by then
+    let mut lines: Vec<CarvedLine> = Vec::new();
+    let mut line_of_river = vec![None; rivers.len()];
+    for (river, (path, outlet)) in rivers.iter().zip(river_outlets).enumerate() {
+        let path: Vec<(usize, f32)> = path
+            .iter()
+            .map(|&(cell, flow)| (cell, (min_width + flow * growth).min(max_width)))
+            .collect();
+        let falls = find_waterfalls(water_config, &adjusted_height_map, &path, elevation_range);
+        match place_river(
+            &context,
+            &mut adjusted_height_map,
+            &mut lake_map,
+            &mut river_depths,
+            &mut river_surface,
+            &mut river_widths,
+            &path,
+        ) {
+            Placed::Carved => {}
+            Placed::Ponded => ponded += 1,
+            Placed::Removed => {
+                removed += 1;
+                continue;
+            }
+        }
+        outlets.extend(outlet);
+        waterfalls.extend(falls);
+        let joined = joins[river].and_then(|(below, cell)| Some((line_of_river[below]?, cell)));
+        line_of_river[river] = Some(lines.len());
+        lines.push((path, joined));
+    }
+
+    if water_config.river_mode == RiverMode::Drainage {
+        let paths = drainage_paths(
+            water_config,
+            (min_width, max_width),
+            &adjusted_height_map,
+            &ocean,
+            &lake_map,
+            epsilon,
+        );
+        // every cell but the last of a path is its own, the last one is the junction with the
+        // river it joins if that is any
+        let first = lines.len();
+        let mut line_of_cell = HashMap::new();
+        for (i, path) in paths.iter().enumerate() {
+            for &(cell, _) in &path[..path.len() - 1] {
+                line_of_cell.insert(cell, first + i);
+            }
+        }
+        for path in paths {
+            // a path ending on a river carved before it is a tributary, its catchment already
+            // widens the river below
+            if let Some(&(end, _)) = path.last()
+                && river_depths[end] > 0.0
+            {
+                junctions.push((end as u32 % width, end as u32 / width));
+            }
+            waterfalls.extend(find_waterfalls(
+                water_config,
+                &adjusted_height_map,
+                &path,
+                elevation_range,
+            ));
+            for branch in estuary_paths(&context, &mut adjusted_height_map, &path) {
+                carve_river(
+                    &context,
+                    &mut adjusted_height_map,
+                    &lake_map,
+                    &mut river_depths,
+                    &mut river_surface,
+                    &mut river_widths,
+                    &branch,
+                );
+            }
+            let joined = path
+                .last()
+                .and_then(|&(end, _)| Some((*line_of_cell.get(&end)?, end)));
+            lines.push((path, joined));
+        }
+    }
+
+    for canal in &water_config.canals {
+        let path = dig_canal(
+            &context,
+            canal,
+            &mut adjusted_height_map,
+            &lake_map,
+            &mut river_depths,
+            &mut river_surface,
+            &mut river_widths,
+        );
+        if !path.is_empty() {
+            lines.push((path, None));
+        }
+    }
+
+    // the depths are read once every river is carved, where rivers meet the deeper one won
+    let rivers: Vec<RiverLine> = lines
+        .into_iter()
+        .map(|(mut path, joined)| {
+            if let Some((_, junction)) = joined {
+                let &(end, river_width) = path.last().unwrap();
+                if end != junction {
+                    path.push((junction, river_width));
+                }
+            }
+            let joins = joined.map(|(line, _)| line);
+            river_line(path, joins, &river_depths, width, elevation_range)
+        })
+        .collect();
 
-    
+    let wetness = compute_wetness(
+        map_config,
+        water_config,
+        &adjusted_height_map,
+        &ocean,
+        &lake_map,
+        &river_depths,
+        elevation_range,
+    );
+    let lake_depths = lake_map
+        .iter()
+        .zip(adjusted_height_map.data())
+        .map(|(&level, &h)| {
+            if level > 0.0 {
+                (level - h).max(0.0)
+            } else {
+                0.0
+            }
+        })
+        .collect();
 
-    (
-        preview_image,
-        preview,
+    progress.add(1);
+    Some(WaterMap {
         lake_map,
-        river_map,
-    )
-}
\ No newline at end of file
+        lake_depths,
+        river_depths,
+        river_surface,
+        river_widths,
+        heightmap: adjusted_height_map,
+        outlets,
+        waterfalls,
+        wetness,
+        ponded_rivers: ponded,
+        removed_rivers: removed,
+        lakes_accepted: accepted,
+        lakes_rejected: rejected,
+        unplaced_lakes,
+        small_source_band,
+        shore_change,
+        junctions,
+        rivers,
+    })
+}
+
+// lakes and rivers drawn over a preview of the map, such as the terrain or biome preview.
+// `base` may be smaller than the map, one preview pixel then shows every few map cells like
+// in build_preview. nothing is drawn where the water surface is under the sea level, so a
+// channel cut below the sea still shows as long as its water stands above it. `lakes` holds
+// the lake map and depths, `rivers` the river surface and widths.
+pub fn composite_water(
+    base: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    heightmap: &Heightmap,
+    lakes: (&[f32], &[f32]),
+    rivers: (&[f32], &[f32]),
+    water_config: &WaterConfig,
+    map_config: &MapConfig,
+    elevation_range: f32,
+) -> (egui::ColorImage, ImageBuffer<Rgba<u8>, Vec<u8>>) {
+    let (lake_map, lake_depths) = lakes;
+    let (river_surface, river_widths) = rivers;
+    let sea_level = map_config.sea_level as f32;
+    let step = heightmap.width().div_ceil(base.width().max(1)).max(1);
+    let max_depth = (water_config.max_lake_depth / elevation_range.max(1e-6)).max(1e-6);
+    let (min_width, max_width) = river_width_limits(map_config, water_config);
+    let mut image = base.clone();
+    for (px, py, pixel) in image.enumerate_pixels_mut() {
+        let (x, y) = (px * step, py * step);
+        if x >= heightmap.width() || y >= heightmap.height() {
+            continue;
+        }
+        let i = heightmap.index(x, y);
+        let surface = if lake_map[i] > 0.0 {
+            lake_map[i]
+        } else if river_surface[i] > 0.0 {
+            river_surface[i]
+        } else {
+            heightmap.data()[i]
+        };
+        if surface < sea_level {
+            continue;
+        }
+        let (color, alpha) = if lake_map[i] > 0.0 {
+            (get_color_for_water(lake_depths[i] / max_depth), LAKE_ALPHA)
+        } else if river_widths[i] > 0.0 {
+            let t = (river_widths[i] - min_width) / (max_width - min_width).max(1.0);
+            let t = t.clamp(0.0, 1.0);
+            (RIVER_COLOR, MIN_RIVER_ALPHA + (1.0 - MIN_RIVER_ALPHA) * t)
+        } else {
+            continue;
+        };
+        let blend =
+            |under: u8, over: u8| (under as f32 + (over as f32 - under as f32) * alpha) as u8;
+        pixel[0] = blend(pixel[0], color.0);
+        pixel[1] = blend(pixel[1], color.1);
+        pixel[2] = blend(pixel[2], color.2);
+    }
+    let pixels = image
+        .pixels()
+        .map(|p| egui::Color32::from_rgb(p[0], p[1], p[2]))
+        .collect();
+    let color_image = egui::ColorImage {
+        size: [image.width() as usize, image.height() as usize],
+        pixels,
+    };
+    (color_image, image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELEVATION_RANGE: f32 = 1000.0;
+
+    // heightmap with the height `f(x, y)` in every cell
+    fn test_map(width: u32, height: u32, f: impl Fn(f32, f32) -> f32) -> Heightmap {
+        let data = (0..width * height)
+            .map(|i| f((i % width) as f32, (i / width) as f32))
+            .collect();
+        Heightmap::from_vec(data, width, height)
+    }
+
+    // water config that only makes what the test asks for
+    fn quiet_water() -> WaterConfig {
+        WaterConfig {
+            lake_attempts: 0,
+            river_count: 0,
+            ..WaterConfig::default()
+        }
+    }
+
+    fn water(
+        map_config: &MapConfig,
+        water_config: &WaterConfig,
+        heightmap: &Heightmap,
+    ) -> WaterMap {
+        generate_water_map(
+            map_config,
+            water_config,
+            heightmap,
+            &[],
+            ELEVATION_RANGE,
+            1,
+            &Progress::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rivers_in_a_bowl_end_in_water() {
+        // a closed bowl without any sea, every river runs into the middle
+        let heightmap = test_map(96, 96, |x, y| {
+            let d = (x - 48.0).hypot(y - 48.0) / 48.0;
+            0.5 + 0.3 * d * d
+        });
+        let map_config = MapConfig {
+            sea_level: 0.1,
+            cell_size_m: 10.0,
+            ..MapConfig::default()
+        };
+        let water_config = WaterConfig {
+            river_sources: vec![(20, 20), (80, 24), (48, 88), (10, 60)],
+            enforce_outlet: true,
+            dead_end_remedy: DeadEndRemedy::Pond,
+            ..quiet_water()
+        };
+        let water = water(&map_config, &water_config, &heightmap);
+        assert!(!water.rivers.is_empty());
+        for line in &water.rivers {
+            let &(x, y) = &line.points.last().unwrap().position;
+            let edge = x <= 1 || y <= 1 || x + 2 >= 96 || y + 2 >= 96;
+            let in_lake = water.lake_map[(y * 96 + x) as usize] > 0.0;
+            assert!(
+                in_lake || line.joins.is_some() || edge,
+                "river ends dry at {x}, {y}"
+            );
+        }
+    }
+
+    #[test]
+    fn filled_crater_drains_to_the_border() {
+        // a plane falling towards the left edge with a crater dug into it
+        let heightmap = test_map(64, 64, |x, y| {
+            let d = (x - 40.0).hypot(y - 32.0);
+            let rim = 0.1 * (1.0 - (d - 8.0).abs() / 4.0).max(0.0);
+            let pit = if d < 8.0 { 0.2 * (1.0 - d / 8.0) } else { 0.0 };
+            0.3 + x * 0.002 + rim - pit
+        });
+        let outlets = vec![false; heightmap.len()];
+        let filled = fill_depressions(&heightmap, &outlets, 1e-5);
+
+        // follows the steepest way down from the bottom of the crater
+        let mut cell = 32 * 64 + 40;
+        for _ in 0..filled.len() {
+            let (x, y) = (cell % 64, cell / 64);
+            if x == 0 || y == 0 || x == 63 || y == 63 {
+                return;
+            }
+            let lowest = neighbors8(cell, 64, 64)
+                .map(|(n, _)| n)
+                .min_by(|&a, &b| filled[a].total_cmp(&filled[b]))
+                .unwrap();
+            assert!(filled[lowest] < filled[cell], "flow is stuck at {x}, {y}");
+            cell = lowest;
+        }
+        panic!("flow never reached the border");
+    }
+
+    // a valley falling towards the sea on the left with two sources on its right half, one on
+    // either side
+    fn valley() -> (MapConfig, WaterConfig, Heightmap) {
+        let heightmap = test_map(128, 64, |x, y| 0.1 + x * 0.004 + (y - 32.0).abs() * 0.003);
+        let map_config = MapConfig {
+            sea_level: 0.2,
+            cell_size_m: 10.0,
+            ..MapConfig::default()
+        };
+        let water_config = WaterConfig {
+            river_sources: vec![(110, 16), (110, 48)],
+            river_direction_variation: 0.0,
+            // no widening mouth, which would fan out into the sea
+            estuary_length: 0.0,
+            ..quiet_water()
+        };
+        (map_config, water_config, heightmap)
+    }
+
+    #[test]
+    fn rivers_merge_into_one_wider_channel() {
+        let (map_config, water_config, heightmap) = valley();
+        let merged = water(&map_config, &water_config, &heightmap);
+        assert_eq!(merged.junctions.len(), 1);
+        let (junction_x, _) = merged.junctions[0];
+        let tributary = merged
+            .rivers
+            .iter()
+            .find(|line| line.joins.is_some())
+            .unwrap();
+        assert!(tributary.points.iter().all(|p| p.position.0 >= junction_x));
+
+        // below the junction every column of the valley holds a single channel
+        let sea = compute_ocean_mask(&heightmap, map_config.sea_level as f32, None);
+        for x in 0..junction_x as usize {
+            let column: Vec<bool> = (0..64)
+                .map(|y| merged.river_widths[y * 128 + x] > 0.0 && !sea[y * 128 + x])
+                .collect();
+            let channels = column.windows(2).filter(|w| !w[0] && w[1]).count();
+            assert!(channels <= 1, "{channels} channels in column {x}");
+        }
+
+        // the same river on its own stays narrower below the junction
+        let single_config = WaterConfig {
+            river_sources: water_config.river_sources[..1].to_vec(),
+            ..water_config
+        };
+        let single = water(&map_config, &single_config, &heightmap);
+        let main = &merged.rivers[0].points;
+        let alone = &single.rivers[0].points;
+        let below = main.iter().position(|p| p.position.0 < junction_x).unwrap();
+        assert_eq!(main[below].position, alone[below].position);
+        assert!(main[below].width > alone[below].width);
+    }
+
+    // the same map generated on one thread and on several
+    #[test]
+    fn water_does_not_depend_on_the_threads() {
+        // an island with bumps that hold a few lakes
+        let heightmap = test_map(128, 128, |x, y| {
+            let d = (x - 64.0).hypot(y - 64.0) / 64.0;
+            0.7 - 0.5 * d + 0.1 * ((x * 0.15).sin() * (y * 0.13).cos())
+        });
+        let map_config = MapConfig {
+            sea_level: 0.2,
+            cell_size_m: 10.0,
+            ..MapConfig::default()
+        };
+        let water_config = WaterConfig {
+            min_lake_n: 3,
+            base_drainage: 100.0,
+            enforce_outlet: true,
+            river_source_spacing: 100.0,
+            ..WaterConfig::default()
+        };
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| water(&map_config, &water_config, &heightmap))
+        };
+        // every map of the run as raw bytes
+        let bytes = |water: WaterMap| {
+            [
+                water.heightmap.into_data(),
+                water.lake_map,
+                water.river_depths,
+                water.river_surface,
+                water.river_widths,
+                water.wetness,
+            ]
+            .concat()
+            .into_iter()
+            .flat_map(f32::to_le_bytes)
+            .collect::<Vec<u8>>()
+        };
+        let single = run(1);
+        assert!(single.lakes_accepted > 0 && !single.rivers.is_empty());
+        let many = run(rayon::current_num_threads().max(4));
+        assert!(bytes(single) == bytes(many));
+    }
+
+    #[test]
+    fn a_bump_in_a_lake_stays_an_island() {
+        // bowl of radius 40 with a bump above the rim in the middle
+        let heightmap = test_map(128, 128, |x, y| {
+            let d = (x - 64.0).hypot(y - 64.0);
+            if d < 4.0 {
+                0.55
+            } else {
+                0.3 + 0.2 * (d / 40.0).min(1.5)
+            }
+        });
+        let map_config = MapConfig {
+            sea_level: 0.1,
+            ..MapConfig::default()
+        };
+        let bump: Vec<usize> = (0..heightmap.len())
+            .filter(|&i| ((i % 128) as f32 - 64.0).hypot((i / 128) as f32 - 64.0) < 4.0)
+            .collect();
+        for shore_smoothing in [0, 2] {
+            let water_config = WaterConfig {
+                lake_seeds: vec![(64, 80)],
+                max_lake_radius: 100.0,
+                max_lake_depth: 200.0,
+                max_capacity: 1e12,
+                base_inflow: 100.0,
+                base_evaporation: 0.0,
+                shore_smoothing,
+                ..quiet_water()
+            };
+            let water = water(&map_config, &water_config, &heightmap);
+            assert!(water.lake_map.iter().filter(|&&l| l > 0.0).count() > 1000);
+            assert!(bump.iter().all(|&i| water.lake_map[i] == 0.0));
+        }
+    }
+}